@@ -0,0 +1,645 @@
+//! ELF core dump generation for processes killed by core-dumping signals.
+//!
+//! The dumping thread freezes its siblings, collects their register state and
+//! writes an `ET_CORE` file named after [`core_pattern`] into the current
+//! working directory of the process. The file is truncated to `RLIMIT_CORE`.
+
+use alloc::{
+    borrow::Cow,
+    collections::btree_map::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult};
+use axfs::{FS_CONTEXT, OpenOptions};
+use axhal::{
+    paging::MappingFlags,
+    time::{TimeValue, wall_time},
+    uspace::UserContext,
+};
+use axsync::Mutex;
+use axtask::{current, future::block_on};
+use event_listener::{Event, listener};
+use linkme::distributed_slice;
+use linux_raw_sys::general::{AT_NULL, RLIMIT_CORE};
+use memory_addr::{PAGE_SIZE_4K, VirtAddr, align_up_4k};
+use spin::RwLock;
+use starry_core::{
+    sysctl::{SYSCTLS, Sysctl, SysctlKind},
+    task::{AsThread, Thread, get_task},
+    timer::timeout,
+};
+use starry_process::Pid;
+use starry_signal::{SignalInfo, SignalSet};
+use starry_vm::VmPtr;
+
+static CORE_PATTERN: RwLock<Cow<'static, str>> = RwLock::new(Cow::Borrowed("core"));
+
+/// Returns the template used to name core files.
+pub fn core_pattern() -> String {
+    CORE_PATTERN.read().to_string()
+}
+
+/// Sets the template used to name core files.
+///
+/// Supported specifiers are `%p` (pid), `%i` (tid), `%e` (command name), `%s`
+/// (signal number), `%t` (time of dump in seconds) and `%%`.
+pub fn set_core_pattern(pattern: &str) -> AxResult<()> {
+    let pattern = pattern.trim_end_matches('\n');
+    if pattern.is_empty() || pattern.len() > 127 {
+        return Err(AxError::InvalidInput);
+    }
+    *CORE_PATTERN.write() = Cow::Owned(pattern.to_string());
+    Ok(())
+}
+
+#[distributed_slice(SYSCTLS)]
+static CORE_PATTERN_SYSCTL: Sysctl = Sysctl {
+    path: "kernel/core_pattern",
+    mode: 0o644,
+    kind: SysctlKind::Str {
+        get: core_pattern,
+        set: set_core_pattern,
+    },
+};
+
+#[cfg(target_arch = "x86_64")]
+const ELF_NGREG: usize = 27;
+#[cfg(target_arch = "aarch64")]
+const ELF_NGREG: usize = 34;
+#[cfg(target_arch = "riscv64")]
+const ELF_NGREG: usize = 32;
+#[cfg(target_arch = "loongarch64")]
+const ELF_NGREG: usize = 45;
+
+#[cfg(target_arch = "x86_64")]
+const ELF_MACHINE: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+const ELF_MACHINE: u16 = 183;
+#[cfg(target_arch = "riscv64")]
+const ELF_MACHINE: u16 = 243;
+#[cfg(target_arch = "loongarch64")]
+const ELF_MACHINE: u16 = 258;
+
+#[cfg(target_arch = "riscv64")]
+const ELF_FLAGS: u32 = 0x5; // RVC, double-float ABI
+#[cfg(not(target_arch = "riscv64"))]
+const ELF_FLAGS: u32 = 0;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_PRPSINFO: u32 = 3;
+const NT_AUXV: u32 = 6;
+const NT_FILE: u32 = 0x4649_4c45;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+/// How long the dumping thread waits for its siblings to stop.
+const FREEZE_TIMEOUT: Duration = Duration::from_millis(100);
+
+type GRegs = [u64; ELF_NGREG];
+
+/// Converts the saved user context to the `elf_gregset_t` layout of the
+/// architecture.
+fn user_regs(uctx: &UserContext) -> GRegs {
+    // SAFETY: `UserContext` starts with the general purpose registers and
+    // only whole machine words of it are read.
+    #[cfg(not(target_arch = "x86_64"))]
+    let raw = unsafe {
+        core::slice::from_raw_parts(
+            uctx as *const UserContext as *const usize,
+            size_of::<UserContext>() / size_of::<usize>(),
+        )
+    };
+    let mut regs = [0u64; ELF_NGREG];
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "riscv64")] {
+            // pc, ra, sp, gp, tp, t0-t2, s0-s1, a0-a7, s2-s11, t3-t6
+            for (dst, src) in regs.iter_mut().zip(raw.iter()).skip(1) {
+                *dst = *src as u64;
+            }
+            regs[0] = uctx.ip() as u64;
+        } else if #[cfg(target_arch = "aarch64")] {
+            // x0-x30, sp, pc, pstate
+            for (dst, src) in regs.iter_mut().zip(raw.iter()).take(31) {
+                *dst = *src as u64;
+            }
+            regs[31] = uctx.sp() as u64;
+            regs[32] = uctx.ip() as u64;
+        } else if #[cfg(target_arch = "loongarch64")] {
+            // r0-r31, orig_a0, era, badv, reserved
+            for (dst, src) in regs.iter_mut().zip(raw.iter()).take(32) {
+                *dst = *src as u64;
+            }
+            regs[33] = uctx.ip() as u64;
+        } else {
+            // The trap frame is laid out differently from `user_regs_struct`:
+            // r15-r12, rbp, rbx, r11-r8, rax, rcx, rdx, rsi, rdi, orig_rax,
+            // rip, cs, eflags, rsp, ss, fs_base, gs_base, ds, es, fs, gs
+            let tf = &**uctx;
+            regs.copy_from_slice(&[
+                tf.r15,
+                tf.r14,
+                tf.r13,
+                tf.r12,
+                tf.rbp,
+                tf.rbx,
+                tf.r11,
+                tf.r10,
+                tf.r9,
+                tf.r8,
+                tf.rax,
+                tf.rcx,
+                tf.rdx,
+                tf.rsi,
+                tf.rdi,
+                // Not stopped in a system call that could be restarted.
+                u64::MAX,
+                tf.rip,
+                tf.cs,
+                tf.rflags,
+                tf.rsp,
+                tf.ss,
+                uctx.tls() as u64,
+                uctx.gs_base,
+                0,
+                0,
+                0,
+                0,
+            ]);
+        }
+    }
+    regs
+}
+
+/// Per-thread state recorded into `NT_PRSTATUS`.
+struct ThreadState {
+    tid: Pid,
+    regs: GRegs,
+    pending: u64,
+    blocked: u64,
+    utime: TimeValue,
+    stime: TimeValue,
+}
+
+impl ThreadState {
+    fn capture(thr: &Thread, uctx: &UserContext) -> Self {
+        let sigset = |set: SignalSet| unsafe { core::mem::transmute_copy::<SignalSet, u64>(&set) };
+        let (utime, stime) = thr.time.borrow().output();
+        Self {
+            tid: current().id().as_u64() as Pid,
+            regs: user_regs(uctx),
+            pending: sigset(thr.signal.pending()),
+            blocked: sigset(thr.signal.blocked()),
+            utime,
+            stime,
+        }
+    }
+}
+
+/// A core dump in progress for a process.
+struct DumpContext {
+    threads: Mutex<Vec<ThreadState>>,
+    finished: AtomicBool,
+    event: Event,
+}
+
+static DUMPS: Mutex<BTreeMap<Pid, Arc<DumpContext>>> = Mutex::new(BTreeMap::new());
+
+/// Stops the current thread while a sibling is writing a core dump.
+///
+/// Called on the return-to-user path. The thread's registers are handed to the
+/// dumping thread, and the call blocks until the dump has been written.
+pub fn freeze_if_dumping(thr: &Thread, uctx: &UserContext) {
+    let Some(ctx) = DUMPS.lock().get(&thr.proc_data.proc.pid()).cloned() else {
+        return;
+    };
+    ctx.threads.lock().push(ThreadState::capture(thr, uctx));
+    ctx.event.notify(usize::MAX);
+
+    while !ctx.finished.load(Ordering::Acquire) {
+        listener!(ctx.event => listener);
+        if ctx.finished.load(Ordering::Acquire) {
+            break;
+        }
+        block_on(listener);
+    }
+}
+
+/// Writes a core dump for the current process, which is being killed by
+/// `sig`.
+pub fn do_coredump(thr: &Thread, uctx: &UserContext, sig: &SignalInfo) {
+    let proc_data = &thr.proc_data;
+    let pid = proc_data.proc.pid();
+
+    let limit = proc_data.rlim.read()[RLIMIT_CORE].current;
+    if limit == 0 {
+        return;
+    }
+
+    let ctx = {
+        let mut dumps = DUMPS.lock();
+        if dumps.contains_key(&pid) {
+            // Another thread is already dumping, just stop here.
+            drop(dumps);
+            freeze_if_dumping(thr, uctx);
+            return;
+        }
+        let ctx = Arc::new(DumpContext {
+            threads: Mutex::new(vec![ThreadState::capture(thr, uctx)]),
+            finished: AtomicBool::new(false),
+            event: Event::new(),
+        });
+        dumps.insert(pid, ctx.clone());
+        ctx
+    };
+
+    let curr_tid = current().id().as_u64() as Pid;
+    let siblings = proc_data
+        .proc
+        .threads()
+        .into_iter()
+        .filter(|tid| *tid != curr_tid)
+        .collect::<Vec<_>>();
+    for tid in &siblings {
+        if let Ok(task) = get_task(*tid) {
+            task.interrupt();
+        }
+    }
+    // Threads blocked in uninterruptible waits will not report in, their
+    // registers are simply left out of the dump.
+    while ctx.threads.lock().len() <= siblings.len() {
+        listener!(ctx.event => listener);
+        if ctx.threads.lock().len() > siblings.len() {
+            break;
+        }
+        if block_on(timeout(Some(FREEZE_TIMEOUT), listener)).is_err() {
+            break;
+        }
+    }
+
+    let threads = core::mem::take(&mut *ctx.threads.lock());
+    if let Err(err) = write_core(thr, sig, &threads, limit) {
+        warn!("{:?}: failed to write core dump: {err:?}", proc_data.proc);
+    }
+
+    DUMPS.lock().remove(&pid);
+    ctx.finished.store(true, Ordering::Release);
+    ctx.event.notify(usize::MAX);
+}
+
+fn expand_pattern(thr: &Thread, sig: &SignalInfo) -> AxResult<String> {
+    let pattern = core_pattern();
+    if pattern.starts_with('|') {
+        // Piping into a user space helper is not supported.
+        return Err(AxError::Unsupported);
+    }
+
+    let mut name = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            name.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => name.push('%'),
+            Some('p') => name += &thr.proc_data.proc.pid().to_string(),
+            Some('i') => name += &current().id().as_u64().to_string(),
+            Some('e') => name += &current().name().replace('/', "!"),
+            Some('s') => name += &(sig.signo() as u32).to_string(),
+            Some('t') => name += &wall_time().as_secs().to_string(),
+            _ => {}
+        }
+    }
+    Ok(name)
+}
+
+/// A memory region to be recorded as a `PT_LOAD` segment.
+struct Region {
+    start: usize,
+    end: usize,
+    flags: MappingFlags,
+    /// Whether the contents of the region are written to the core file.
+    dump: bool,
+}
+
+fn collect_regions(thr: &Thread) -> Vec<Region> {
+    let aspace = thr.proc_data.aspace.lock();
+    aspace
+        .areas()
+        .filter(|area| area.flags().contains(MappingFlags::USER))
+        .map(|area| {
+            let flags = area.flags();
+            Region {
+                start: area.start().as_usize(),
+                end: area.end().as_usize(),
+                flags,
+                dump: flags.contains(MappingFlags::READ | MappingFlags::WRITE),
+            }
+        })
+        .collect()
+}
+
+fn read_auxv(thr: &Thread) -> Vec<u8> {
+    let sp = thr.proc_data.initial_sp();
+    if sp == 0 {
+        return Vec::new();
+    }
+    let word = |addr: usize| (addr as *const usize).vm_read().ok();
+
+    // Skip argc, argv[] and envp[] to reach the auxiliary vector.
+    let mut addr = sp + size_of::<usize>();
+    for _ in 0..2 {
+        loop {
+            let Some(ptr) = word(addr) else {
+                return Vec::new();
+            };
+            addr += size_of::<usize>();
+            if ptr == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut auxv = Vec::new();
+    for _ in 0..64 {
+        let (Some(ty), Some(val)) = (word(addr), word(addr + size_of::<usize>())) else {
+            break;
+        };
+        auxv.extend_from_slice(&(ty as u64).to_le_bytes());
+        auxv.extend_from_slice(&(val as u64).to_le_bytes());
+        if ty == AT_NULL as usize {
+            break;
+        }
+        addr += 2 * size_of::<usize>();
+    }
+    auxv
+}
+
+struct NoteWriter(Vec<u8>);
+
+impl NoteWriter {
+    fn put(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.put(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.put(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.put(&v.to_le_bytes());
+    }
+
+    fn align(&mut self) {
+        self.0.resize(self.0.len().next_multiple_of(4), 0);
+    }
+
+    fn note(&mut self, ty: u32, desc: &[u8]) {
+        const NAME: &[u8] = b"CORE\0";
+        self.u32(NAME.len() as u32);
+        self.u32(desc.len() as u32);
+        self.u32(ty);
+        self.put(NAME);
+        self.align();
+        self.put(desc);
+        self.align();
+    }
+}
+
+fn timeval(time: TimeValue) -> [u8; 16] {
+    let mut buf = [0; 16];
+    buf[..8].copy_from_slice(&time.as_secs().to_le_bytes());
+    buf[8..].copy_from_slice(&(time.subsec_micros() as u64).to_le_bytes());
+    buf
+}
+
+fn prstatus(state: &ThreadState, thr: &Thread, sig: &SignalInfo) -> Vec<u8> {
+    let proc = &thr.proc_data.proc;
+    let mut w = NoteWriter(Vec::new());
+    // struct elf_siginfo
+    w.u32(sig.signo() as u32);
+    w.u32(0);
+    w.u32(0);
+    // pr_cursig + padding
+    w.u16(sig.signo() as u16);
+    w.u16(0);
+    w.u64(state.pending);
+    w.u64(state.blocked);
+    w.u32(state.tid);
+    w.u32(proc.parent().map_or(0, |p| p.pid()));
+    w.u32(proc.group().pgid());
+    w.u32(proc.group().session().sid());
+    w.put(&timeval(state.utime));
+    w.put(&timeval(state.stime));
+    w.put(&[0; 32]); // pr_cutime, pr_cstime
+    for reg in state.regs {
+        w.u64(reg);
+    }
+    // pr_fpvalid + padding
+    w.u32(0);
+    w.u32(0);
+    w.0
+}
+
+fn prpsinfo(thr: &Thread) -> Vec<u8> {
+    let proc = &thr.proc_data.proc;
+    let mut w = NoteWriter(Vec::new());
+    // pr_state, pr_sname, pr_zomb, pr_nice + padding
+    w.put(&[0, b'R', 0, 0, 0, 0, 0, 0]);
+    w.u64(0); // pr_flag
+    w.u32(0); // pr_uid
+    w.u32(0); // pr_gid
+    w.u32(proc.pid());
+    w.u32(proc.parent().map_or(0, |p| p.pid()));
+    w.u32(proc.group().pgid());
+    w.u32(proc.group().session().sid());
+
+    let mut fname = [0u8; 16];
+    let name = current().name();
+    let len = name.len().min(15);
+    fname[..len].copy_from_slice(&name.as_bytes()[..len]);
+    w.put(&fname);
+
+    let mut psargs = [0u8; 80];
+    let cmdline = thr.proc_data.cmdline.read().join(" ");
+    let len = cmdline.len().min(79);
+    psargs[..len].copy_from_slice(&cmdline.as_bytes()[..len]);
+    w.put(&psargs);
+    w.0
+}
+
+fn file_note(thr: &Thread) -> Vec<u8> {
+    let files = thr
+        .proc_data
+        .file_mappings
+        .lock()
+        .iter()
+        .filter_map(|(start, mapping)| {
+            let path = mapping.loc.absolute_path().ok()?.to_string();
+            Some((start, mapping.end, mapping.offset, path))
+        })
+        .collect::<Vec<_>>();
+
+    let mut w = NoteWriter(Vec::new());
+    w.u64(files.len() as u64);
+    w.u64(PAGE_SIZE_4K as u64);
+    for (start, end, offset, _) in &files {
+        w.u64(start.as_usize() as u64);
+        w.u64(end.as_usize() as u64);
+        // The offset is in units of the page size above.
+        w.u64(offset / PAGE_SIZE_4K as u64);
+    }
+    for (.., path) in &files {
+        w.put(path.as_bytes());
+        w.put(&[0]);
+    }
+    w.0
+}
+
+/// Sink that silently drops everything past `RLIMIT_CORE`.
+struct CoreFile {
+    file: axfs::File,
+    limit: u64,
+}
+
+impl CoreFile {
+    fn write_at(&self, data: &[u8], offset: u64) -> AxResult<()> {
+        if offset >= self.limit {
+            return Ok(());
+        }
+        let len = data.len().min((self.limit - offset) as usize);
+        self.file.write_at(&mut &data[..len], offset)?;
+        Ok(())
+    }
+}
+
+fn write_core(thr: &Thread, sig: &SignalInfo, threads: &[ThreadState], limit: u64) -> AxResult<()> {
+    let name = expand_pattern(thr, sig)?;
+    let regions = collect_regions(thr);
+
+    let mut notes = NoteWriter(Vec::new());
+    // The faulting thread comes first, as debuggers expect.
+    for state in threads {
+        notes.note(NT_PRSTATUS, &prstatus(state, thr, sig));
+        if state.tid == threads[0].tid {
+            notes.note(NT_PRPSINFO, &prpsinfo(thr));
+            let auxv = read_auxv(thr);
+            if !auxv.is_empty() {
+                notes.note(NT_AUXV, &auxv);
+            }
+            notes.note(NT_FILE, &file_note(thr));
+        }
+    }
+    let notes = notes.0;
+
+    let phnum = regions.len() + 1;
+    let notes_offset = EHDR_SIZE + phnum * PHDR_SIZE;
+    let mut data_offset = align_up_4k(notes_offset + notes.len());
+
+    let mut header = NoteWriter(Vec::with_capacity(notes_offset));
+    // e_ident: ELFCLASS64, ELFDATA2LSB, EV_CURRENT, ELFOSABI_NONE
+    header.put(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    header.put(&[0; 8]);
+    header.u16(4); // ET_CORE
+    header.u16(ELF_MACHINE);
+    header.u32(1);
+    header.u64(0); // e_entry
+    header.u64(EHDR_SIZE as u64); // e_phoff
+    header.u64(0); // e_shoff
+    header.u32(ELF_FLAGS);
+    header.u16(EHDR_SIZE as u16);
+    header.u16(PHDR_SIZE as u16);
+    header.u16(phnum.min(0xffff) as u16);
+    header.u16(0); // e_shentsize
+    header.u16(0); // e_shnum
+    header.u16(0); // e_shstrndx
+
+    // PT_NOTE
+    header.u32(4);
+    header.u32(0);
+    header.u64(notes_offset as u64);
+    header.u64(0);
+    header.u64(0);
+    header.u64(notes.len() as u64);
+    header.u64(0);
+    header.u64(4);
+
+    let mut loads = Vec::with_capacity(regions.len());
+    for region in &regions {
+        let size = region.end - region.start;
+        let file_size = if region.dump { size } else { 0 };
+        let mut p_flags = 0;
+        if region.flags.contains(MappingFlags::EXECUTE) {
+            p_flags |= 1;
+        }
+        if region.flags.contains(MappingFlags::WRITE) {
+            p_flags |= 2;
+        }
+        if region.flags.contains(MappingFlags::READ) {
+            p_flags |= 4;
+        }
+        // PT_LOAD
+        header.u32(1);
+        header.u32(p_flags);
+        header.u64(data_offset as u64);
+        header.u64(region.start as u64);
+        header.u64(0);
+        header.u64(file_size as u64);
+        header.u64(size as u64);
+        header.u64(PAGE_SIZE_4K as u64);
+
+        loads.push(data_offset);
+        data_offset += file_size;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true).mode(0o600);
+    let file = options
+        .open(&FS_CONTEXT.lock(), name.as_str())?
+        .into_file()?;
+    info!("{:?}: dumping core to {name}", thr.proc_data.proc);
+
+    let core = CoreFile { file, limit };
+    core.write_at(&header.0, 0)?;
+    core.write_at(&notes, notes_offset as u64)?;
+
+    let mut page = vec![0u8; PAGE_SIZE_4K];
+    for (region, offset) in regions.iter().zip(loads) {
+        if !region.dump {
+            continue;
+        }
+        for addr in (region.start..region.end).step_by(PAGE_SIZE_4K) {
+            let file_offset = (offset + addr - region.start) as u64;
+            if file_offset >= limit {
+                break;
+            }
+            // Pages that were never touched or cannot be read are left as
+            // holes in the file.
+            let aspace = thr.proc_data.aspace.lock();
+            let vaddr = VirtAddr::from_usize(addr);
+            if aspace.page_table().query(vaddr).is_err() || aspace.read(vaddr, &mut page).is_err() {
+                continue;
+            }
+            drop(aspace);
+            core.write_at(&page, file_offset)?;
+        }
+    }
+
+    let total = (data_offset as u64).min(limit);
+    core.file.set_len(total)?;
+    Ok(())
+}
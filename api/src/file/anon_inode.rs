@@ -0,0 +1,37 @@
+//! Inodes of the files on no filesystem.
+//!
+//! Timerfds, eventfds, signalfds, epoll instances and pipes live on no
+//! filesystem, yet programs tell open files apart by `(st_dev, st_ino)`.
+//! Each of them holds an [`AnonInode`]: an inode number of its own on
+//! [`ANON_INODE_DEV`], kept for its lifetime and never handed out again.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::Kstat;
+
+/// The device of the files on no filesystem, the unnamed device 0:14
+pub const ANON_INODE_DEV: u64 = 14;
+
+static NEXT_INO: AtomicU64 = AtomicU64::new(1);
+
+/// The inode of a file on no filesystem
+#[derive(Debug)]
+pub struct AnonInode(u64);
+
+impl AnonInode {
+    /// Allocates an inode number.
+    pub fn new() -> Self {
+        Self(NEXT_INO.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Returns the stat of the file, of `mode`.
+    pub fn kstat(&self, mode: u32) -> Kstat {
+        Kstat {
+            dev: ANON_INODE_DEV,
+            ino: self.0,
+            nlink: 1,
+            mode,
+            ..Default::default()
+        }
+    }
+}
@@ -8,25 +8,53 @@
 
 use alloc::{
     borrow::Cow,
-    collections::vec_deque::VecDeque,
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
     sync::{Arc, Weak},
     task::Wake,
+    vec::Vec,
 };
 use core::{
     any::Any,
     hash::{Hash, Hasher},
-    sync::atomic::{AtomicBool, Ordering},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     task::{Context, Waker},
 };
 
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, LinuxError};
 use axpoll::{IoEvents, PollSet, Pollable};
+use axsync::Mutex;
 use bitflags::bitflags;
 use hashbrown::HashMap;
 use kspin::SpinNoPreempt;
-use linux_raw_sys::general::{EPOLLET, EPOLLONESHOT, epoll_event};
+use linkme::distributed_slice;
+use linux_raw_sys::general::{EPOLLET, EPOLLEXCLUSIVE, EPOLLONESHOT, epoll_event};
+use starry_core::{
+    critical::{CriticalSection, LockLevel, defer},
+    sysctl::{SYSCTLS, Sysctl, SysctlKind},
+};
+
+use crate::file::{
+    AnonInode, FileLike, Kstat, SealedBuf, SealedBufMut, get_file_like, readiness,
+    usage::{Charge, Resource},
+};
 
-use crate::file::{FileLike, Kstat, SealedBuf, SealedBufMut, get_file_like};
+static MAX_USER_WATCHES: AtomicUsize = AtomicUsize::new(1 << 19);
+
+#[distributed_slice(SYSCTLS)]
+static MAX_USER_WATCHES_SYSCTL: Sysctl = Sysctl {
+    path: "fs/epoll/max_user_watches",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || MAX_USER_WATCHES.load(Ordering::Relaxed) as _,
+        set: |value| MAX_USER_WATCHES.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: i32::MAX as _,
+    },
+};
+
+/// Most epoll instances that may be nested below one another, as on Linux.
+const EPOLL_MAX_NESTS: usize = 4;
 
 pub struct EpollEvent {
     pub events: IoEvents,
@@ -39,6 +67,7 @@ bitflags! {
     pub struct EpollFlags: u32 {
         const EDGE_TRIGGER = EPOLLET;
         const ONESHOT = EPOLLONESHOT;
+        const EXCLUSIVE = EPOLLEXCLUSIVE;
     }
 }
 
@@ -47,8 +76,9 @@ bitflags! {
 enum TriggerMode {
     /// Level-triggered: until the condition is cleared
     Level,
-    /// Edge-triggered: only notify when the condition changes
-    Edge,
+    /// Edge-triggered: only notify when the file wakes the interest, with
+    /// the wake count `seen` at the last notification
+    Edge { seen: u64 },
     /// One-shot: notify only once
     OneShot { fired: bool },
 }
@@ -58,7 +88,7 @@ impl TriggerMode {
         if flags.contains(EpollFlags::ONESHOT) {
             TriggerMode::OneShot { fired: false }
         } else if flags.contains(EpollFlags::EDGE_TRIGGER) {
-            TriggerMode::Edge
+            TriggerMode::Edge { seen: 0 }
         } else {
             TriggerMode::Level
         }
@@ -71,8 +101,8 @@ impl TriggerMode {
                 // LT: always notify
                 (true, *self)
             }
-            // if we could wake, we need notify
-            TriggerMode::Edge => (true, TriggerMode::Edge),
+            // handled by `consume_edge`
+            TriggerMode::Edge { .. } => (true, *self),
             TriggerMode::OneShot { fired } => {
                 // ONESHOT: 只触发一次
                 if *fired {
@@ -99,8 +129,16 @@ enum ConsumeResult {
     EventAndRemove(EpollEvent),
     // no event and should remove ready list
     NoEvent,
+    // edge-triggered, already removed from ready list and its waker armed
+    Armed(Option<EpollEvent>),
 }
 
+/// Interests are keyed by the fd number together with the open file
+/// description it referred to when added, as on Linux. Closing that fd while
+/// a dup keeps the description open leaves the interest in place; it keeps
+/// firing and can no longer be deleted by fd. It goes away only with the
+/// description itself. The same description added through two fds makes two
+/// interests.
 #[derive(Clone)]
 struct EntryKey {
     fd: i32,
@@ -119,6 +157,12 @@ impl EntryKey {
     fn get_file(&self) -> Option<Arc<dyn FileLike>> {
         self.file.upgrade()
     }
+
+    /// Whether the open file description is still open through some fd.
+    #[inline]
+    fn is_live(&self) -> bool {
+        self.file.strong_count() > 0
+    }
 }
 
 impl Hash for EntryKey {
@@ -139,6 +183,9 @@ struct EpollInterest {
     event: EpollEvent,
     mode: SpinNoPreempt<TriggerMode>,
     in_ready_queue: AtomicBool,
+    /// Times the file has woken the interest.
+    wakes: AtomicU64,
+    exclusive: bool,
 }
 
 impl EpollInterest {
@@ -148,6 +195,8 @@ impl EpollInterest {
             event,
             mode: SpinNoPreempt::new(TriggerMode::from_flags(flags)),
             in_ready_queue: AtomicBool::new(false),
+            wakes: AtomicU64::new(0),
+            exclusive: flags.contains(EpollFlags::EXCLUSIVE),
         }
     }
 
@@ -156,11 +205,6 @@ impl EpollInterest {
         self.mode.lock().is_enabled()
     }
 
-    #[inline]
-    fn is_in_queue(&self) -> bool {
-        self.in_ready_queue.load(Ordering::Acquire)
-    }
-
     #[inline]
     fn try_mark_in_queue(&self) -> bool {
         self.in_ready_queue
@@ -173,9 +217,13 @@ impl EpollInterest {
         self.in_ready_queue.store(false, Ordering::Release);
     }
 
-    fn consume(&self, file: &dyn FileLike) -> ConsumeResult {
-        let current_events = file.poll();
-        let matched = current_events & self.event.events;
+    fn consume(&self, file: &dyn FileLike, arm: impl FnOnce()) -> ConsumeResult {
+        let _section = CriticalSection::enter(LockLevel::Epoll);
+        let mode = *self.mode.lock();
+        if let TriggerMode::Edge { seen } = mode {
+            return self.consume_edge(file, seen, arm);
+        }
+        let matched = readiness(file, self.event.events);
 
         // not ready
         if matched.is_empty() {
@@ -203,9 +251,89 @@ impl EpollInterest {
         // shoud still keep in ready?
         match *mode {
             TriggerMode::Level => ConsumeResult::EventAndKeep(event),
-            TriggerMode::Edge | TriggerMode::OneShot { .. } => ConsumeResult::EventAndRemove(event),
+            TriggerMode::Edge { .. } | TriggerMode::OneShot { .. } => {
+                ConsumeResult::EventAndRemove(event)
+            }
         }
     }
+
+    /// Takes the events of an edge-triggered interest, if the file has woken
+    /// it since they were last taken. The waker is armed again before the
+    /// wakes are counted and readiness is checked: a wake after that queues
+    /// the interest afresh, while one before it, whose events this covers,
+    /// is not reported twice.
+    fn consume_edge(&self, file: &dyn FileLike, seen: u64, arm: impl FnOnce()) -> ConsumeResult {
+        self.mark_not_in_queue();
+        arm();
+        let wakes = self.wakes.load(Ordering::Acquire);
+        if wakes == seen {
+            return ConsumeResult::Armed(None);
+        }
+        let matched = readiness(file, self.event.events);
+        trace!("consume edge fd: {} matches {:?}", self.key.fd, matched);
+        if matched.is_empty() {
+            return ConsumeResult::Armed(None);
+        }
+        *self.mode.lock() = TriggerMode::Edge { seen: wakes };
+        ConsumeResult::Armed(Some(EpollEvent {
+            events: matched,
+            user_data: self.event.user_data,
+        }))
+    }
+}
+
+/// The `EPOLLEXCLUSIVE` interest queued for each file and not taken yet, by
+/// the address of the file. While one is, wakes of the file queue no other
+/// exclusive interest in it, so that a single epoll instance is woken
+/// instead of all of them. It is given up as soon as it is taken, whatever
+/// it reports, so the next wake may go to another instance.
+static EXCLUSIVE_QUEUED: SpinNoPreempt<BTreeMap<usize, Weak<EpollInterest>>> =
+    SpinNoPreempt::new(BTreeMap::new());
+
+impl EpollInterest {
+    fn file_addr(&self) -> usize {
+        self.key.file.as_ptr() as *const () as usize
+    }
+
+    /// Marks this exclusive interest as queued, like `try_mark_in_queue`.
+    /// Fails with `Err` instead if another one is queued for the file.
+    fn try_mark_exclusive_in_queue(self: &Arc<Self>) -> Result<bool, ()> {
+        let mut queued = EXCLUSIVE_QUEUED.lock();
+        let addr = self.file_addr();
+        if let Some(other) = queued.get(&addr).and_then(Weak::upgrade)
+            && !Arc::ptr_eq(&other, self)
+            && Weak::ptr_eq(&other.key.file, &self.key.file)
+            && other.in_ready_queue.load(Ordering::Acquire)
+        {
+            return Err(());
+        }
+        if !self.try_mark_in_queue() {
+            return Ok(false);
+        }
+        queued.insert(addr, Arc::downgrade(self));
+        Ok(true)
+    }
+
+    /// Gives up the claim of this interest, if it holds it.
+    fn release_exclusive(&self) {
+        if !self.exclusive {
+            return;
+        }
+        let mut queued = EXCLUSIVE_QUEUED.lock();
+        let addr = self.file_addr();
+        if queued
+            .get(&addr)
+            .is_some_and(|other| ptr::eq(other.as_ptr(), self))
+        {
+            queued.remove(&addr);
+        }
+    }
+}
+
+impl Drop for EpollInterest {
+    fn drop(&mut self) {
+        self.release_exclusive();
+    }
 }
 
 struct InterestWaker {
@@ -219,6 +347,19 @@ impl Wake for InterestWaker {
     }
 
     fn wake_by_ref(self: &Arc<Self>) {
+        // Counted at once, so that an edge-triggered interest taken before
+        // the deferred queueing below still sees the wake.
+        if let Some(interest) = self.interest.upgrade() {
+            interest.wakes.fetch_add(1, Ordering::AcqRel);
+        }
+        // The waking code may hold locks that polling this epoll takes again.
+        let this = self.clone();
+        defer(move || this.queue());
+    }
+}
+
+impl InterestWaker {
+    fn queue(self: Arc<Self>) {
         let Some(epoll) = self.epoll.upgrade() else {
             return;
         };
@@ -227,7 +368,28 @@ impl Wake for InterestWaker {
             return;
         };
 
-        if interest.try_mark_in_queue() {
+        // A waker armed before a one-shot interest fired must not make the
+        // instance readable for an interest that reports nothing.
+        if !interest.is_enabled() {
+            return;
+        }
+        let marked = if interest.exclusive {
+            match interest.try_mark_exclusive_in_queue() {
+                Ok(marked) => marked,
+                Err(()) => {
+                    // Left to the instance already woken, but kept armed
+                    // for the wakes after it.
+                    if let Some(file) = interest.key.get_file() {
+                        let waker = Waker::from(self.clone());
+                        file.register(&mut Context::from_waker(&waker), interest.event.events);
+                    }
+                    return;
+                }
+            }
+        } else {
+            interest.try_mark_in_queue()
+        };
+        if marked {
             epoll
                 .ready_queue
                 .lock()
@@ -242,29 +404,113 @@ impl Wake for InterestWaker {
 }
 
 struct EpollInner {
+    /// Held by `epoll_ctl` and while events are taken, so each sees the
+    /// other whole: no event of a mask replaced by `EPOLL_CTL_MOD` is taken
+    /// once it returns.
+    ctl: Mutex<()>,
     interests: SpinNoPreempt<HashMap<EntryKey, Arc<EpollInterest>>>,
+    /// One watch per interest, charged to the user who created the instance.
+    watches: SpinNoPreempt<Charge>,
     ready_queue: SpinNoPreempt<VecDeque<Weak<EpollInterest>>>,
     poll_ready: PollSet,
+    /// The instances this one has been added to, some perhaps since deleted
+    /// from.
+    parents: SpinNoPreempt<Vec<Weak<EpollInner>>>,
 }
 
-impl Default for EpollInner {
-    fn default() -> Self {
-        Self {
-            interests: SpinNoPreempt::new(HashMap::new()),
-            ready_queue: SpinNoPreempt::new(VecDeque::new()),
-            poll_ready: PollSet::new(),
+impl EpollInner {
+    /// The epoll instances among the interests.
+    fn nested(&self) -> Vec<Arc<EpollInner>> {
+        self.interests
+            .lock()
+            .keys()
+            .filter_map(|key| key.get_file()?.into_any().downcast::<Epoll>().ok())
+            .map(|epoll| epoll.inner.clone())
+            .collect()
+    }
+
+    fn nests(&self, other: &Arc<EpollInner>) -> bool {
+        self.nested()
+            .iter()
+            .any(|nested| Arc::ptr_eq(nested, other))
+    }
+
+    /// Levels of instances nested below this one, failing with `ELOOP` if
+    /// `top` is among them or they go deeper than allowed.
+    fn depth_below(&self, top: &Arc<EpollInner>, depth: usize) -> AxResult<usize> {
+        let mut max = 0;
+        for nested in self.nested() {
+            if Arc::ptr_eq(&nested, top) || depth >= EPOLL_MAX_NESTS {
+                return Err(AxError::from(LinuxError::ELOOP));
+            }
+            max = max.max(1 + nested.depth_below(top, depth + 1)?);
+        }
+        Ok(max)
+    }
+
+    /// Levels of instances this one is nested in, cut short past the limit
+    /// in case concurrent additions made a cycle.
+    fn depth_above(self: &Arc<Self>, depth: usize) -> usize {
+        if depth > EPOLL_MAX_NESTS {
+            return depth;
         }
+        let parents: Vec<_> = {
+            let mut parents = self.parents.lock();
+            parents.retain(|parent| parent.strong_count() > 0);
+            parents.iter().filter_map(Weak::upgrade).collect()
+        };
+        parents
+            .iter()
+            .filter(|parent| parent.nests(self))
+            .map(|parent| 1 + parent.depth_above(depth + 1))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Brings the charge in line with `interests` after removals.
+    fn uncharge(&self, interests: &HashMap<EntryKey, Arc<EpollInterest>>) {
+        self.watches.lock().set(interests.len(), None);
     }
 }
 
-#[derive(Default)]
 pub struct Epoll {
     inner: Arc<EpollInner>,
+    inode: AnonInode,
 }
 
 impl Epoll {
-    pub fn new() -> Self {
-        Self::default()
+    /// Creates an instance whose watches are charged to `uid`.
+    pub fn new(uid: u32) -> Self {
+        Self {
+            inner: Arc::new(EpollInner {
+                ctl: Mutex::new(()),
+                interests: SpinNoPreempt::new(HashMap::new()),
+                watches: SpinNoPreempt::new(Charge::new(uid, Resource::EpollWatches)),
+                ready_queue: SpinNoPreempt::new(VecDeque::new()),
+                poll_ready: PollSet::new(),
+                parents: SpinNoPreempt::new(Vec::new()),
+            }),
+            inode: AnonInode::new(),
+        }
+    }
+
+    /// Number of interests registered.
+    pub fn watches(&self) -> usize {
+        self.inner.interests.lock().len()
+    }
+
+    /// Checks that `nested` may be added to this instance: not the instance
+    /// itself (`EINVAL`), and neither making a cycle nor nesting deeper than
+    /// `EPOLL_MAX_NESTS` (`ELOOP`).
+    fn check_nesting(&self, nested: &Arc<EpollInner>) -> AxResult<()> {
+        if Arc::ptr_eq(nested, &self.inner) {
+            return Err(AxError::InvalidInput);
+        }
+        let below = nested.depth_below(&self.inner, 1)?;
+        if below + 1 + self.inner.depth_above(0) > EPOLL_MAX_NESTS {
+            return Err(AxError::from(LinuxError::ELOOP));
+        }
+        Ok(())
     }
 
     // only register waker, not add to ready queue
@@ -301,7 +547,7 @@ impl Epoll {
             interest: Arc::downgrade(interest),
         }));
 
-        let current = file.poll() & interest.event.events;
+        let current = readiness(file.as_ref(), interest.event.events);
 
         if !current.is_empty() {
             waker.wake_by_ref();
@@ -309,7 +555,7 @@ impl Epoll {
             let mut context = Context::from_waker(&waker);
             file.register(&mut context, interest.event.events);
 
-            let current = file.poll() & interest.event.events;
+            let current = readiness(file.as_ref(), interest.event.events);
             if !current.is_empty() {
                 waker.wake_by_ref();
             }
@@ -317,30 +563,55 @@ impl Epoll {
     }
 
     pub fn add(&self, fd: i32, event: EpollEvent, flags: EpollFlags) -> AxResult<()> {
+        let _ctl = self.inner.ctl.lock();
         let key = EntryKey::new(fd)?;
+        let nested = key
+            .get_file()
+            .and_then(|file| file.into_any().downcast::<Epoll>().ok());
+        if let Some(nested) = &nested {
+            self.check_nesting(&nested.inner)?;
+        }
         let interest = Arc::new(EpollInterest::new(key.clone(), event, flags));
         let mut guard = self.inner.interests.lock();
+        // Drop interests whose description has been closed by every fd.
+        guard.retain(|key, _| key.is_live());
+        self.inner.uncharge(&guard);
         if guard.contains_key(&key) {
             return Err(AxError::AlreadyExists);
         }
+        let limit = MAX_USER_WATCHES.load(Ordering::Relaxed);
+        if !self.inner.watches.lock().set(guard.len() + 1, Some(limit)) {
+            return Err(AxError::from(LinuxError::ENOSPC));
+        }
         guard.insert(key.clone(), Arc::clone(&interest));
         drop(guard);
+        if let Some(nested) = nested {
+            let mut parents = nested.inner.parents.lock();
+            if !parents
+                .iter()
+                .any(|parent| parent.as_ptr() == Arc::as_ptr(&self.inner))
+            {
+                parents.push(Arc::downgrade(&self.inner));
+            }
+        }
         trace!("Epoll add fd: {} interest {:?} ", fd, interest.event.events);
         self.check_and_register_waker(&interest);
         Ok(())
     }
 
     pub fn modify(&self, fd: i32, event: EpollEvent, flags: EpollFlags) -> AxResult<()> {
+        let _ctl = self.inner.ctl.lock();
         let key = EntryKey::new(fd)?;
         let interest = Arc::new(EpollInterest::new(key.clone(), event, flags));
 
         let mut guard = self.inner.interests.lock();
         let old = guard.get_mut(&key).ok_or(AxError::NotFound)?;
-
-        // update new interest if old already in ready queue
-        if old.is_in_queue() {
-            interest.in_ready_queue.store(true, Ordering::Release);
+        if old.exclusive {
+            return Err(AxError::InvalidInput);
         }
+
+        // A queued reference to the old interest no longer upgrades, so the
+        // new one starts out of the queue and is queued afresh if ready.
         *old = Arc::clone(&interest);
         drop(guard);
         trace!(
@@ -353,18 +624,19 @@ impl Epoll {
     }
 
     pub fn delete(&self, fd: i32) -> AxResult<()> {
+        let _ctl = self.inner.ctl.lock();
         let key = EntryKey::new(fd)?;
-        self.inner
-            .interests
-            .lock()
-            .remove(&key)
-            .ok_or(AxError::NotFound)?;
+        let mut guard = self.inner.interests.lock();
+        guard.remove(&key).ok_or(AxError::NotFound)?;
+        self.inner.uncharge(&guard);
+        drop(guard);
         trace!("Epoll: delete fd={fd}");
         Ok(())
     }
 
     pub fn poll_events(&self, out: &mut [epoll_event]) -> AxResult<usize> {
         trace!("Epoll: poll_events called, out.len()={}", out.len());
+        let _ctl = self.inner.ctl.lock();
         let mut count = 0;
         loop {
             let weak_interest = {
@@ -384,10 +656,14 @@ impl Epoll {
             let Some(interest) = weak_interest.upgrade() else {
                 continue; // interest already removed
             };
+            interest.release_exclusive();
 
             let Some(file) = interest.key.get_file() else {
                 // file already closed remove interests
-                self.inner.interests.lock().remove(&interest.key);
+                let mut guard = self.inner.interests.lock();
+                guard.remove(&interest.key);
+                self.inner.uncharge(&guard);
+                drop(guard);
                 interest.mark_not_in_queue();
                 continue;
             };
@@ -397,7 +673,7 @@ impl Epoll {
                 interest.key.fd, interest.event.events
             );
 
-            match interest.consume(file.as_ref()) {
+            match interest.consume(file.as_ref(), || self.register_waker_only(&interest)) {
                 ConsumeResult::EventAndKeep(event) => {
                     out[count] = epoll_event {
                         events: event.events.bits(),
@@ -422,6 +698,14 @@ impl Epoll {
                     interest.mark_not_in_queue();
                     self.register_waker_only(&interest);
                 }
+                ConsumeResult::Armed(Some(event)) => {
+                    out[count] = epoll_event {
+                        events: event.events.bits(),
+                        data: event.user_data,
+                    };
+                    count += 1;
+                }
+                ConsumeResult::Armed(None) => {}
             }
         }
 
@@ -443,7 +727,7 @@ impl FileLike for Epoll {
     }
 
     fn stat(&self) -> AxResult<Kstat> {
-        Ok(Kstat::default())
+        Ok(self.inode.kstat(0o600))
     }
 
     fn path(&self) -> Cow<str> {
@@ -457,10 +741,17 @@ impl FileLike for Epoll {
 
 impl Pollable for Epoll {
     fn poll(&self) -> IoEvents {
-        if self.inner.ready_queue.lock().is_empty() {
-            IoEvents::empty()
-        } else {
+        // Entries of removed interests and closed descriptions are dropped
+        // by `poll_events` and do not make the instance readable.
+        let ready = self.inner.ready_queue.lock().iter().any(|interest| {
+            interest
+                .upgrade()
+                .is_some_and(|interest| interest.key.is_live())
+        });
+        if ready {
             IoEvents::IN
+        } else {
+            IoEvents::empty()
         }
     }
 
@@ -8,9 +8,12 @@ use core::{
 use axerrno::AxError;
 use axio::{Buf, BufMut, Read, Write};
 use axpoll::{IoEvents, PollSet, Pollable};
-use axtask::future::{block_on, poll_io};
+use starry_core::{
+    critical::{CriticalSection, LockLevel},
+    timer::wait_for_io,
+};
 
-use crate::file::{FileLike, Kstat, SealedBuf, SealedBufMut};
+use crate::file::{AnonInode, FileLike, Kstat, SealedBuf, SealedBufMut};
 
 pub struct EventFd {
     count: AtomicU64,
@@ -19,6 +22,7 @@ pub struct EventFd {
 
     poll_rx: PollSet,
     poll_tx: PollSet,
+    inode: AnonInode,
 }
 
 impl EventFd {
@@ -30,8 +34,21 @@ impl EventFd {
 
             poll_rx: PollSet::new(),
             poll_tx: PollSet::new(),
+            inode: AnonInode::new(),
         })
     }
+
+    /// Adds `value` to the counter on behalf of the kernel, e.g. for an AIO
+    /// completion. Never blocks; the counter saturates instead.
+    pub fn signal(&self, value: u64) {
+        let _section = CriticalSection::enter(LockLevel::File);
+        let _ = self
+            .count
+            .fetch_update(Ordering::Release, Ordering::Acquire, |count| {
+                Some(count.saturating_add(value).min(u64::MAX - 1))
+            });
+        self.poll_rx.wake();
+    }
 }
 
 impl FileLike for EventFd {
@@ -40,7 +57,8 @@ impl FileLike for EventFd {
             return Err(AxError::InvalidInput);
         }
 
-        block_on(poll_io(self, IoEvents::IN, self.nonblocking(), || {
+        wait_for_io(self, IoEvents::IN, self.nonblocking(), None, true, || {
+            let _section = CriticalSection::enter(LockLevel::File);
             let result = self
                 .count
                 .fetch_update(Ordering::Release, Ordering::Acquire, |count| {
@@ -59,7 +77,7 @@ impl FileLike for EventFd {
                 }
                 Err(_) => Err(AxError::WouldBlock),
             }
-        }))
+        })
     }
 
     fn write(&self, src: &mut SealedBuf) -> axio::Result<usize> {
@@ -74,7 +92,8 @@ impl FileLike for EventFd {
             return Err(AxError::InvalidInput);
         }
 
-        block_on(poll_io(self, IoEvents::OUT, self.nonblocking(), || {
+        wait_for_io(self, IoEvents::OUT, self.nonblocking(), None, true, || {
+            let _section = CriticalSection::enter(LockLevel::File);
             let result = self
                 .count
                 .fetch_update(Ordering::Release, Ordering::Acquire, |count| {
@@ -91,11 +110,11 @@ impl FileLike for EventFd {
                 }
                 Err(_) => Err(AxError::WouldBlock),
             }
-        }))
+        })
     }
 
     fn stat(&self) -> axio::Result<Kstat> {
-        Ok(Kstat::default())
+        Ok(self.inode.kstat(0o600))
     }
 
     fn nonblocking(&self) -> bool {
@@ -122,6 +141,9 @@ impl Pollable for EventFd {
         let count = self.count.load(Ordering::Acquire);
         events.set(IoEvents::IN, count > 0);
         events.set(IoEvents::OUT, u64::MAX - 1 > count);
+        // Writes never get the counter this far, but Linux reports an
+        // overflowed counter as an error.
+        events.set(IoEvents::ERR, count == u64::MAX);
         events
     }
 
@@ -0,0 +1,639 @@
+//! fanotify groups.
+//!
+//! A group holds marks on inodes, mounts or whole filesystems. Opening,
+//! reading, writing and closing a file queue an event on every group with a
+//! mark covering it, which the listener reads together with a fresh fd to
+//! the file. An event on the same file by the same process as one still
+//! queued is merged into it. A write clears the ignored mask of the marks on
+//! the file, unless they were added with `FAN_MARK_IGNORED_SURV_MODIFY`. A permission event also holds the access up until the listener
+//! writes back its verdict, `fs/fanotify/perm_timeout_ms` passes, or the
+//! group goes away with its last fd; only an explicit denial fails the
+//! access, with `EPERM`.
+
+use alloc::{
+    borrow::Cow,
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    task::Context,
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs::{OpenOptions, OpenResult};
+use axfs_ng_vfs::{Location, Mountpoint, NodeType};
+use axhal::time::monotonic_time;
+use axio::{Buf, BufMut, Read, Write};
+use axpoll::{IoEvents, PollSet, Pollable};
+use axsync::Mutex;
+use axtask::current;
+use linkme::distributed_slice;
+use linux_raw_sys::general::{O_ACCMODE, O_CLOEXEC, O_NONBLOCK, O_RDONLY, O_WRONLY};
+use starry_core::{
+    sysctl::{SYSCTLS, Sysctl, SysctlKind},
+    task::AsThread,
+    timer::wait_for_io,
+};
+use zerocopy::{Immutable, IntoBytes};
+
+use super::{Directory, File, FileLike, Kstat, SealedBuf, SealedBufMut, add_file_like};
+
+pub const FAN_ACCESS: u64 = 0x1;
+pub const FAN_MODIFY: u64 = 0x2;
+pub const FAN_CLOSE_WRITE: u64 = 0x8;
+pub const FAN_CLOSE_NOWRITE: u64 = 0x10;
+pub const FAN_OPEN: u64 = 0x20;
+pub const FAN_Q_OVERFLOW: u64 = 0x4000;
+pub const FAN_OPEN_PERM: u64 = 0x10000;
+pub const FAN_ACCESS_PERM: u64 = 0x20000;
+pub const FAN_EVENT_ON_CHILD: u64 = 0x0800_0000;
+pub const FAN_ONDIR: u64 = 0x4000_0000;
+
+/// Events a mark can ask for.
+pub const FAN_EVENTS: u64 = FAN_ACCESS
+    | FAN_MODIFY
+    | FAN_CLOSE_WRITE
+    | FAN_CLOSE_NOWRITE
+    | FAN_OPEN
+    | FAN_OPEN_PERM
+    | FAN_ACCESS_PERM;
+/// Events that wait for a verdict.
+pub const FAN_PERM_EVENTS: u64 = FAN_OPEN_PERM | FAN_ACCESS_PERM;
+
+pub const FAN_CLASS_NOTIF: u32 = 0x0;
+pub const FAN_CLASS_CONTENT: u32 = 0x4;
+pub const FAN_CLASS_PRE_CONTENT: u32 = 0x8;
+
+const FAN_ALLOW: u32 = 0x1;
+const FAN_DENY: u32 = 0x2;
+const FAN_AUDIT: u32 = 0x10;
+const FAN_NOFD: i32 = -1;
+
+const FANOTIFY_METADATA_VERSION: u8 = 3;
+
+/// `struct fanotify_event_metadata`
+#[repr(C)]
+#[derive(Immutable, IntoBytes)]
+struct EventMetadata {
+    event_len: u32,
+    vers: u8,
+    reserved: u8,
+    metadata_len: u16,
+    mask: u64,
+    fd: i32,
+    pid: i32,
+}
+
+const METADATA_LEN: usize = size_of::<EventMetadata>();
+/// Size of `struct fanotify_response`.
+const RESPONSE_LEN: usize = 8;
+
+static MAX_QUEUED_EVENTS: AtomicUsize = AtomicUsize::new(16384);
+static MAX_USER_MARKS: AtomicUsize = AtomicUsize::new(8192);
+static PERM_TIMEOUT_MS: AtomicUsize = AtomicUsize::new(0);
+
+#[distributed_slice(SYSCTLS)]
+static MAX_QUEUED_EVENTS_SYSCTL: Sysctl = Sysctl {
+    path: "fs/fanotify/max_queued_events",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || MAX_QUEUED_EVENTS.load(Ordering::Relaxed) as _,
+        set: |value| MAX_QUEUED_EVENTS.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: i32::MAX as _,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static MAX_USER_MARKS_SYSCTL: Sysctl = Sysctl {
+    path: "fs/fanotify/max_user_marks",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || MAX_USER_MARKS.load(Ordering::Relaxed) as _,
+        set: |value| MAX_USER_MARKS.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: i32::MAX as _,
+    },
+};
+
+/// How long an access waits for a verdict before it is let through; 0
+/// waits as long as the group lives.
+#[distributed_slice(SYSCTLS)]
+static PERM_TIMEOUT_SYSCTL: Sysctl = Sysctl {
+    path: "fs/fanotify/perm_timeout_ms",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || PERM_TIMEOUT_MS.load(Ordering::Relaxed) as _,
+        set: |value| PERM_TIMEOUT_MS.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: i32::MAX as _,
+    },
+};
+
+/// What a mark is on.
+pub enum MarkTarget {
+    Inode { device: u64, inode: u64 },
+    Mount(Weak<Mountpoint>),
+    Filesystem(u64),
+}
+
+impl MarkTarget {
+    pub fn of(loc: &Location, mount: bool, filesystem: bool) -> Self {
+        let device = loc.mountpoint().device() as u64;
+        if mount {
+            Self::Mount(Arc::downgrade(loc.mountpoint()))
+        } else if filesystem {
+            Self::Filesystem(device)
+        } else {
+            Self::Inode {
+                device,
+                inode: loc.inode(),
+            }
+        }
+    }
+
+    fn is_inode(&self) -> bool {
+        matches!(self, Self::Inode { .. })
+    }
+
+    fn covers(&self, loc: &Location) -> bool {
+        match self {
+            Self::Inode { device, inode } => {
+                *device == loc.mountpoint().device() as u64 && *inode == loc.inode()
+            }
+            Self::Mount(mount) => Weak::as_ptr(mount) == Arc::as_ptr(loc.mountpoint()),
+            Self::Filesystem(device) => *device == loc.mountpoint().device() as u64,
+        }
+    }
+
+    fn same(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                Self::Inode { device, inode },
+                Self::Inode {
+                    device: other_device,
+                    inode: other_inode,
+                },
+            ) => device == other_device && inode == other_inode,
+            (Self::Mount(a), Self::Mount(b)) => Weak::ptr_eq(a, b),
+            (Self::Filesystem(a), Self::Filesystem(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+struct Mark {
+    target: MarkTarget,
+    mask: u64,
+    ignored: u64,
+    /// Whether `ignored` stays when the file is written to.
+    surv_modify: bool,
+}
+
+/// The verdict on one permission event.
+struct Permission {
+    /// `FAN_ALLOW` or `FAN_DENY` once decided, 0 before.
+    verdict: AtomicU32,
+    decided: PollSet,
+}
+
+impl Permission {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            verdict: AtomicU32::new(0),
+            decided: PollSet::new(),
+        })
+    }
+
+    /// Decides, unless decided already.
+    fn decide(&self, verdict: u32) {
+        if self
+            .verdict
+            .compare_exchange(0, verdict, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            self.decided.wake();
+        }
+    }
+}
+
+impl Pollable for Permission {
+    fn poll(&self) -> IoEvents {
+        if self.verdict.load(Ordering::Acquire) == 0 {
+            IoEvents::empty()
+        } else {
+            IoEvents::IN
+        }
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.decided.register(context.waker());
+        }
+    }
+}
+
+struct Event {
+    mask: u64,
+    /// The object, or `None` for an overflow.
+    loc: Option<Location>,
+    pid: u32,
+    permission: Option<Arc<Permission>>,
+}
+
+impl Event {
+    /// Whether `other` can be folded into this queued event: a notification
+    /// on the same file by the same process.
+    fn merges(&self, other: &Event) -> bool {
+        let (Some(a), Some(b)) = (&self.loc, &other.loc) else {
+            return false;
+        };
+        self.permission.is_none()
+            && self.pid == other.pid
+            && a.inode() == b.inode()
+            && a.mountpoint().device() == b.mountpoint().device()
+    }
+}
+
+/// How many of the latest queued events a new one may be merged into.
+const MERGE_WINDOW: usize = 128;
+
+/// Live groups.
+static GROUPS: Mutex<Vec<Weak<Fanotify>>> = Mutex::new(Vec::new());
+/// Number of live groups, so that accesses skip the lookup while there
+/// are none.
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Fanotify {
+    class: u32,
+    event_f_flags: u32,
+    unlimited_queue: bool,
+    unlimited_marks: bool,
+    non_blocking: AtomicBool,
+    marks: Mutex<Vec<Mark>>,
+    queue: Mutex<VecDeque<Event>>,
+    /// Permission events read and not answered yet, by the fd handed out
+    /// with them and the order they were read in. The fd may be closed and
+    /// reused before the answer, so several events can share it; a response
+    /// answers the oldest.
+    pending: Mutex<BTreeMap<(i32, u64), Arc<Permission>>>,
+    /// Read order of the next permission event.
+    next_pending: AtomicU64,
+    poll_rx: PollSet,
+}
+
+impl Fanotify {
+    pub fn new(
+        class: u32,
+        event_f_flags: u32,
+        unlimited_queue: bool,
+        unlimited_marks: bool,
+    ) -> Arc<Self> {
+        let group = Arc::new(Self {
+            class,
+            event_f_flags,
+            unlimited_queue,
+            unlimited_marks,
+            non_blocking: AtomicBool::new(false),
+            marks: Mutex::new(Vec::new()),
+            queue: Mutex::new(VecDeque::new()),
+            pending: Mutex::new(BTreeMap::new()),
+            next_pending: AtomicU64::new(0),
+            poll_rx: PollSet::new(),
+        });
+        let mut groups = GROUPS.lock();
+        groups.retain(|group| group.strong_count() > 0);
+        groups.push(Arc::downgrade(&group));
+        LIVE.fetch_add(1, Ordering::AcqRel);
+        group
+    }
+
+    /// Whether the group may receive permission events.
+    pub fn has_permissions(&self) -> bool {
+        self.class != FAN_CLASS_NOTIF
+    }
+
+    /// Adds `mask`, or `ignored` to the ignored mask, to the mark on
+    /// `target`; `surv_modify` keeps the ignored mask across writes. Fails
+    /// with `ENOSPC` if it would be one mark too many.
+    pub fn add_mark(
+        &self,
+        target: MarkTarget,
+        mask: u64,
+        ignored: u64,
+        surv_modify: bool,
+    ) -> AxResult<()> {
+        let mut marks = self.marks.lock();
+        if let Some(mark) = marks.iter_mut().find(|mark| mark.target.same(&target)) {
+            mark.mask |= mask;
+            mark.ignored |= ignored;
+            mark.surv_modify |= surv_modify;
+            return Ok(());
+        }
+        if !self.unlimited_marks && marks.len() >= MAX_USER_MARKS.load(Ordering::Relaxed) {
+            return Err(AxError::from(LinuxError::ENOSPC));
+        }
+        marks.push(Mark {
+            target,
+            mask,
+            ignored,
+            surv_modify,
+        });
+        Ok(())
+    }
+
+    /// Takes `mask` and `ignored` off the mark on `target`, dropping it once
+    /// it asks for nothing. Fails with `ENOENT` if there is no such mark.
+    pub fn remove_mark(&self, target: &MarkTarget, mask: u64, ignored: u64) -> AxResult<()> {
+        let mut marks = self.marks.lock();
+        let index = marks
+            .iter()
+            .position(|mark| mark.target.same(target))
+            .ok_or(AxError::NotFound)?;
+        let mark = &mut marks[index];
+        mark.mask &= !mask;
+        mark.ignored &= !ignored;
+        if mark.mask & FAN_EVENTS == 0 && mark.ignored == 0 {
+            marks.remove(index);
+        }
+        Ok(())
+    }
+
+    /// Drops all inode marks, or all mount and filesystem marks.
+    pub fn flush_marks(&self, inodes: bool) {
+        self.marks
+            .lock()
+            .retain(|mark| mark.target.is_inode() != inodes);
+    }
+
+    /// The events of `mask` this group wants to hear about on `loc`, whose
+    /// parent is `parent`. A write first clears the ignored masks on `loc`.
+    fn interest(&self, loc: &Location, parent: Option<&Location>, mask: u64) -> u64 {
+        let (mut wanted, mut ignored) = (0, 0);
+        for mark in self.marks.lock().iter_mut() {
+            if mark.target.covers(loc) {
+                if mask & FAN_MODIFY != 0 && !mark.surv_modify {
+                    mark.ignored = 0;
+                }
+                wanted |= mark.mask;
+            } else if mark.mask & FAN_EVENT_ON_CHILD != 0
+                && mark.target.is_inode()
+                && parent.is_some_and(|parent| mark.target.covers(parent))
+            {
+                wanted |= mark.mask;
+            } else {
+                continue;
+            }
+            ignored |= mark.ignored;
+        }
+        if loc.node_type() == NodeType::Directory && wanted & FAN_ONDIR == 0 {
+            return 0;
+        }
+        wanted & !ignored & mask & FAN_EVENTS
+    }
+
+    /// Queues `event`, or an overflow in its place if the queue is full.
+    /// Returns whether it was queued.
+    fn push(&self, event: Event) -> bool {
+        let mut queue = self.queue.lock();
+        if event.permission.is_none()
+            && let Some(last) = queue
+                .iter_mut()
+                .rev()
+                .take(MERGE_WINDOW)
+                .find(|last| last.merges(&event))
+        {
+            last.mask |= event.mask;
+            return true;
+        }
+        let queued =
+            self.unlimited_queue || queue.len() < MAX_QUEUED_EVENTS.load(Ordering::Relaxed);
+        if queued {
+            queue.push_back(event);
+        } else if !queue.back().is_some_and(|last| last.mask == FAN_Q_OVERFLOW) {
+            queue.push_back(Event {
+                mask: FAN_Q_OVERFLOW,
+                loc: None,
+                pid: 0,
+                permission: None,
+            });
+        }
+        drop(queue);
+        self.poll_rx.wake();
+        queued
+    }
+
+    /// Opens `loc` for the listener, without generating events.
+    fn open(&self, loc: &Location) -> AxResult<i32> {
+        let access = self.event_f_flags & O_ACCMODE;
+        let dir = loc.node_type() == NodeType::Directory;
+        let mut options = OpenOptions::new();
+        options
+            .read(dir || access != O_WRONLY)
+            .write(!dir && access != O_RDONLY);
+        let file: Arc<dyn FileLike> = match options.open_loc(loc.clone())? {
+            OpenResult::File(file) => Arc::new(File::new(file).unwatched()),
+            OpenResult::Dir(dir) => Arc::new(Directory::new(dir)),
+        };
+        if self.event_f_flags & O_NONBLOCK != 0 {
+            file.set_nonblocking(true)?;
+        }
+        add_file_like(file, self.event_f_flags & O_CLOEXEC != 0)
+    }
+}
+
+impl Drop for Fanotify {
+    fn drop(&mut self) {
+        LIVE.fetch_sub(1, Ordering::AcqRel);
+        // Nobody is left to answer, so let everyone waiting through.
+        for event in self.queue.lock().drain(..) {
+            if let Some(permission) = event.permission {
+                permission.decide(FAN_ALLOW);
+            }
+        }
+        for permission in self.pending.lock().values() {
+            permission.decide(FAN_ALLOW);
+        }
+    }
+}
+
+/// Reports accesses of `mask` to `loc` to the groups watching it.
+///
+/// For permission events this waits for the verdicts, failing with `EPERM`
+/// if any of them denies the access.
+pub fn notify(loc: &Location, mask: u64) -> AxResult<()> {
+    if LIVE.load(Ordering::Acquire) == 0 {
+        return Ok(());
+    }
+    let groups = GROUPS
+        .lock()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect::<Vec<_>>();
+    let parent = loc.parent();
+    // Files can be closed for the last time by kernel tasks.
+    let pid = current()
+        .try_as_thread()
+        .map_or(0, |thr| thr.proc_data.proc.pid());
+    let mut permissions = Vec::new();
+    for group in &groups {
+        let wanted = group.interest(loc, parent.as_ref(), mask);
+        if wanted == 0 {
+            continue;
+        }
+        let permission = (wanted & FAN_PERM_EVENTS != 0).then(Permission::new);
+        let queued = group.push(Event {
+            mask: wanted,
+            loc: Some(loc.clone()),
+            pid,
+            permission: permission.clone(),
+        });
+        if queued {
+            permissions.extend(permission);
+        }
+    }
+    // Closing a group must not wait for us.
+    drop(groups);
+
+    let deadline = match PERM_TIMEOUT_MS.load(Ordering::Relaxed) {
+        0 => None,
+        ms => Some(monotonic_time() + Duration::from_millis(ms as _)),
+    };
+    for permission in permissions {
+        let verdict =
+            wait_for_io(
+                &*permission,
+                IoEvents::IN,
+                false,
+                deadline,
+                true,
+                || match permission.verdict.load(Ordering::Acquire) {
+                    0 => Err(AxError::WouldBlock),
+                    verdict => Ok(verdict),
+                },
+            );
+        match verdict {
+            Ok(FAN_DENY) => return Err(AxError::OperationNotPermitted),
+            Ok(_) | Err(AxError::TimedOut) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+impl FileLike for Fanotify {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        if dst.remaining_mut() < METADATA_LEN {
+            return Err(AxError::InvalidInput);
+        }
+
+        wait_for_io(self, IoEvents::IN, self.nonblocking(), None, true, || {
+            let mut read = 0;
+            while dst.remaining_mut() >= METADATA_LEN {
+                let Some(event) = self.queue.lock().pop_front() else {
+                    break;
+                };
+                let fd = match &event.loc {
+                    Some(loc) => match self.open(loc) {
+                        Ok(fd) => fd,
+                        Err(err) => {
+                            if let Some(permission) = &event.permission {
+                                permission.decide(FAN_ALLOW);
+                            }
+                            if read == 0 {
+                                return Err(err);
+                            }
+                            break;
+                        }
+                    },
+                    None => FAN_NOFD,
+                };
+                if let Some(permission) = event.permission {
+                    let seq = self.next_pending.fetch_add(1, Ordering::Relaxed);
+                    self.pending.lock().insert((fd, seq), permission);
+                }
+                let metadata = EventMetadata {
+                    event_len: METADATA_LEN as _,
+                    vers: FANOTIFY_METADATA_VERSION,
+                    reserved: 0,
+                    metadata_len: METADATA_LEN as _,
+                    mask: event.mask,
+                    fd,
+                    pid: event.pid as _,
+                };
+                dst.write(metadata.as_bytes())?;
+                read += METADATA_LEN;
+            }
+            if read == 0 {
+                Err(AxError::WouldBlock)
+            } else {
+                Ok(read)
+            }
+        })
+    }
+
+    fn write(&self, src: &mut SealedBuf) -> AxResult<usize> {
+        if !self.has_permissions() || src.remaining() < RESPONSE_LEN {
+            return Err(AxError::InvalidInput);
+        }
+        let mut response = [0; RESPONSE_LEN];
+        src.read(&mut response)?;
+        let fd = i32::from_ne_bytes(response[..4].try_into().unwrap());
+        let verdict = u32::from_ne_bytes(response[4..].try_into().unwrap()) & !FAN_AUDIT;
+        if verdict != FAN_ALLOW && verdict != FAN_DENY {
+            return Err(AxError::InvalidInput);
+        }
+        let permission = {
+            let mut pending = self.pending.lock();
+            let key = *pending
+                .range((fd, 0)..=(fd, u64::MAX))
+                .next()
+                .ok_or(AxError::NotFound)?
+                .0;
+            pending.remove(&key).unwrap()
+        };
+        permission.decide(verdict);
+        Ok(RESPONSE_LEN)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat::default())
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, non_blocking: bool) -> AxResult {
+        self.non_blocking.store(non_blocking, Ordering::Release);
+        Ok(())
+    }
+
+    fn path(&self) -> Cow<str> {
+        "anon_inode:[fanotify]".into()
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl Pollable for Fanotify {
+    fn poll(&self) -> IoEvents {
+        if self.queue.lock().is_empty() {
+            IoEvents::empty()
+        } else {
+            IoEvents::IN
+        }
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.poll_rx.register(context.waker());
+        }
+    }
+}
@@ -1,24 +1,55 @@
-use alloc::{borrow::Cow, string::ToString, sync::Arc};
+use alloc::{
+    borrow::Cow,
+    collections::vec_deque::VecDeque,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+};
 use core::{
     any::Any,
     ffi::c_int,
     hint::likely,
-    sync::atomic::{AtomicBool, Ordering},
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
     task::Context,
 };
 
 use axerrno::{AxError, AxResult};
-use axfs::{FS_CONTEXT, FsContext};
-use axfs_ng_vfs::{Location, Metadata, NodeFlags};
+use axfs::{FS_CONTEXT, FileBackend, FileFlags, FsContext};
+use axfs_ng_vfs::{Location, Metadata, NodeFlags, NodeType};
 use axpoll::{IoEvents, Pollable};
 use axsync::Mutex;
-use axtask::future::{block_on, poll_io};
-use linux_raw_sys::general::{AT_EMPTY_PATH, AT_FDCWD, AT_SYMLINK_NOFOLLOW};
+use axtask::current;
+use linux_raw_sys::general::{
+    AT_EMPTY_PATH, AT_FDCWD, AT_SYMLINK_NOFOLLOW, POSIX_FADV_DONTNEED, POSIX_FADV_NORMAL,
+    POSIX_FADV_RANDOM, POSIX_FADV_SEQUENTIAL, POSIX_FADV_WILLNEED,
+};
+use starry_core::{ioprio::IoPrioClass, task::AsThread, timer::wait_for_io, vfs::MountUse};
 
 use super::{FileLike, Kstat, get_file_like};
-use crate::file::{SealedBuf, SealedBufMut};
-use crate::vfs::readahead::{ReadaheadAction, ReadaheadState, do_sync_readahead, readahead_decide};
-use axio::BufMut;
+use crate::file::{
+    SealedBuf, SealedBufMut,
+    fanotify::{
+        self, FAN_ACCESS, FAN_ACCESS_PERM, FAN_CLOSE_NOWRITE, FAN_CLOSE_WRITE, FAN_MODIFY,
+    },
+};
+use crate::vfs::mounts::{mount_id, mount_ra_pages};
+use crate::vfs::freeze::{FreezeLock, freeze_lock};
+use crate::vfs::MemoryFs;
+use crate::vfs::size_lock::{SizeLock, size_lock};
+use crate::vfs::ra_worker::submit_async_readahead;
+use crate::vfs::readahead::{
+    PAGE_SIZE, RA_HARD_MAX_PAGES, RA_IDLE_MAX_PAGES, RA_MIN_PAGES, RaMode, RaPages,
+    ReadaheadAction, ReadaheadState, async_readahead, do_sync_readahead, offset_to_page,
+    readahead_decide,
+};
+use crate::vfs::writeback::{WritebackRef, throttle_dirtier, writeback};
+use axio::{Buf, BufMut, Seek, SeekFrom};
+
+/// Alignment in bytes of the offsets, lengths and user buffers of direct
+/// I/O, the logical block size
+pub const DIRECT_IO_ALIGN: usize = 512;
 
 pub fn with_fs<R>(dirfd: c_int, f: impl FnOnce(&mut FsContext) -> AxResult<R>) -> AxResult<R> {
     let mut fs = FS_CONTEXT.lock();
@@ -45,7 +76,7 @@ impl ResolveAtResult {
 
     pub fn stat(&self) -> AxResult<Kstat> {
         match self {
-            Self::File(file) => file.metadata().map(|it| metadata_to_kstat(&it)),
+            Self::File(file) => location_to_kstat(file),
             Self::Other(file_like) => file_like.stat(),
         }
     }
@@ -57,6 +88,9 @@ pub fn resolve_at(dirfd: c_int, path: Option<&str>, flags: u32) -> AxResult<Reso
             if flags & AT_EMPTY_PATH == 0 {
                 return Err(AxError::NotFound);
             }
+            if dirfd == AT_FDCWD {
+                return Ok(ResolveAtResult::File(FS_CONTEXT.lock().current_dir().clone()));
+            }
             let file_like = get_file_like(dirfd)?;
             let f = file_like.clone().into_any();
             Ok(if let Some(file) = f.downcast_ref::<File>() {
@@ -78,7 +112,39 @@ pub fn resolve_at(dirfd: c_int, path: Option<&str>, flags: u32) -> AxResult<Reso
     }
 }
 
-pub fn metadata_to_kstat(metadata: &Metadata) -> Kstat {
+/// Returns the metadata of the file at `loc` as reported by the stat family.
+pub fn location_to_kstat(loc: &Location) -> AxResult<Kstat> {
+    kstat_on_mount(loc, mount_id(loc.mountpoint()))
+}
+
+/// Returns the metadata of the file at `loc`, on the mount with `mnt_id`.
+/// Open files know the id of their mount, sparing them the mount table.
+fn kstat_on_mount(loc: &Location, mnt_id: u64) -> AxResult<Kstat> {
+    let kstat = metadata_to_kstat(&loc.metadata()?);
+    Ok(Kstat {
+        // Filesystems without a backing device go by that of the mount,
+        // never by the device a node stands for.
+        dev: match kstat.dev {
+            0 => loc.mountpoint().device() as u64,
+            dev => dev,
+        },
+        mnt_id,
+        ..kstat
+    })
+}
+
+/// Returns the metadata of the file `dirfd` and `path` resolve to, as
+/// [`resolve_at`] does. A file descriptor on its own is asked directly.
+pub fn stat_at(dirfd: c_int, path: Option<&str>, flags: u32) -> AxResult<Kstat> {
+    match path {
+        Some("") | None if flags & AT_EMPTY_PATH != 0 && dirfd != AT_FDCWD => {
+            get_file_like(dirfd)?.stat()
+        }
+        _ => resolve_at(dirfd, path, flags)?.stat(),
+    }
+}
+
+fn metadata_to_kstat(metadata: &Metadata) -> Kstat {
     let ty = metadata.node_type as u8;
     let perm = metadata.mode.bits() as u32;
     let mode = ((ty as u32) << 12) | perm;
@@ -96,6 +162,7 @@ pub fn metadata_to_kstat(metadata: &Metadata) -> Kstat {
         atime: metadata.atime,
         mtime: metadata.mtime,
         ctime: metadata.ctime,
+        mnt_id: 0,
     }
 }
 
@@ -105,15 +172,176 @@ pub struct File {
     nonblock: AtomicBool,
     /// Readahead state for sequential read optimization
     ra_state: ReadaheadState,
+    /// Orders reads against size changes; only regular files have one
+    size_lock: Option<Arc<SizeLock>>,
+    /// Holds writes off while the filesystem is frozen; likewise
+    freeze_lock: Option<Arc<FreezeLock>>,
+    /// Whether accesses are reported to fanotify; not for the files handed
+    /// to its listeners
+    watched: bool,
+    /// Whether I/O bypasses the page cache, as with `O_DIRECT`
+    direct: AtomicBool,
+    /// Dirty pages of the page cache; only regular files backed by one
+    /// have it
+    writeback: Option<WritebackRef>,
+    /// The writeback error sequence as last reported through this file
+    wb_seen: AtomicU32,
+    /// Keeps the mount of the file busy
+    _mount: MountUse,
+    /// Id of the mount the file is on
+    mnt_id: u64,
 }
 
 impl File {
     pub fn new(inner: axfs::File) -> Self {
+        let loc = inner.location();
+        let regular = loc
+            .metadata()
+            .is_ok_and(|it| it.node_type == NodeType::RegularFile);
+        let writeback = match inner.backend() {
+            Ok(backend @ FileBackend::Cached(_)) if regular => Some(writeback(loc, backend)),
+            _ => None,
+        };
         Self {
+            size_lock: regular.then(|| size_lock(loc)),
+            freeze_lock: regular.then(|| freeze_lock(loc)),
+            wb_seen: AtomicU32::new(writeback.as_ref().map_or(0, |wb| wb.errseq())),
+            writeback,
+            ra_state: ReadaheadState::with_default_limit(mount_ra_pages(loc.mountpoint())),
+            _mount: MountUse::new(loc),
+            mnt_id: mount_id(loc.mountpoint()),
             inner,
             nonblock: AtomicBool::new(false),
-            ra_state: ReadaheadState::new(),
+            watched: true,
+            direct: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether I/O bypasses the page cache
+    pub fn is_direct(&self) -> bool {
+        self.direct.load(Ordering::Relaxed)
+    }
+
+    /// Make I/O bypass the page cache or not, as `O_DIRECT` does
+    pub fn set_direct(&self, direct: bool) {
+        self.direct.store(direct, Ordering::Relaxed);
+    }
+
+    /// Check that a user buffer at `addr` may be used for direct I/O,
+    /// failing with `EINVAL` if it is not aligned
+    pub fn check_direct_buffer(&self, addr: usize) -> AxResult<()> {
+        if self.is_direct() && addr % DIRECT_IO_ALIGN != 0 {
+            return Err(AxError::InvalidInput);
         }
+        Ok(())
+    }
+
+    /// Read at `offset` straight from the backend
+    ///
+    /// Pages of the range in the page cache are read from there, so that
+    /// what buffered writers left in them is seen without writing the file
+    /// out first.
+    fn read_direct<B: BufMut>(&self, dst: &mut B, offset: u64) -> AxResult<usize> {
+        let len = dst.remaining_mut();
+        check_direct_range(offset, len)?;
+        let read = || {
+            let mut data = vec![0; len];
+            let read = self.direct_runs(offset, len, |backend, range, _| {
+                backend.read_at(&mut &mut data[range.clone()], offset + range.start as u64)
+            })?;
+            dst.write(&data[..read])
+        };
+        match &self.size_lock {
+            Some(lock) => lock.shared(read),
+            None => read(),
+        }
+    }
+
+    /// Write at `offset` through to the backend
+    ///
+    /// Pages of the range in the page cache cannot be dropped from it, so
+    /// the data is written to them as well, keeping buffered readers
+    /// coherent; writeback later writes the same data out again.
+    fn write_direct<B: Buf>(&self, src: &mut B, offset: u64) -> AxResult<usize> {
+        let len = src.remaining();
+        check_direct_range(offset, len)?;
+        let mut data = vec![0; len];
+        let mut len = 0;
+        while len < data.len() {
+            match src.read(&mut data[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        let data = &data[..len];
+        self.sized_write(Some(offset), len, || {
+            let direct = FileBackend::Direct(self.inner.location().clone());
+            let written = write_all_at(&direct, data, offset)?;
+            self.direct_runs(offset, written, |backend, range, cached| {
+                if cached {
+                    write_all_at(backend, &data[range.clone()], offset + range.start as u64)?;
+                    self.dirtied(offset + range.start as u64, range.len());
+                }
+                Ok(range.len())
+            })?;
+            Ok(written)
+        })
+    }
+
+    /// Split `offset..offset + len` into runs of pages all in the page cache
+    /// or all not, and call `f` on each in order, with the page cache or the
+    /// backend behind it, the run as a range of the buffer, and whether it
+    /// is cached. Stops at the first run `f` does not finish; returns the
+    /// bytes it did.
+    fn direct_runs(
+        &self,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(&FileBackend, Range<usize>, bool) -> AxResult<usize>,
+    ) -> AxResult<usize> {
+        let direct = FileBackend::Direct(self.inner.location().clone());
+        let cache = self
+            .inner
+            .backend()
+            .ok()
+            .filter(|backend| matches!(backend, FileBackend::Cached(_)));
+        let cached = |pos: usize| {
+            cache.is_some_and(|cache| cache.is_page_cached(offset_to_page(offset + pos as u64)))
+        };
+        let mut done = 0;
+        while done < len {
+            let in_cache = cached(done);
+            let mut end = done;
+            while end < len && cached(end) == in_cache {
+                let next_page = (offset + end as u64) / PAGE_SIZE + 1;
+                end = ((next_page * PAGE_SIZE - offset) as usize).min(len);
+            }
+            let backend = match cache {
+                Some(cache) if in_cache => cache,
+                _ => &direct,
+            };
+            let n = f(backend, done..end, in_cache)?;
+            done += n;
+            if done < end {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Stop reporting accesses to fanotify.
+    pub fn unwatched(mut self) -> Self {
+        self.watched = false;
+        self
+    }
+
+    /// Report an access to fanotify, waiting for the verdict on a
+    /// permission event.
+    fn notify(&self, mask: u64) -> AxResult<()> {
+        if !self.watched {
+            return Ok(());
+        }
+        fanotify::notify(self.inner.location(), mask)
     }
 
     pub fn inner(&self) -> &axfs::File {
@@ -124,9 +352,307 @@ impl File {
         self.inner.location().flags().contains(NodeFlags::BLOCKING)
     }
 
-    /// Perform readahead based on current position and read length.
+    /// Move the file position, preparing readahead for reads from there.
+    pub fn seek(&self, pos: SeekFrom) -> AxResult<u64> {
+        let offset = self.inner.seek(pos)?;
+        self.ra_state.reset_on_seek(offset);
+        Ok(offset)
+    }
+
+    /// Read at `offset` without moving the file position.
+    pub fn read_at<B: BufMut>(&self, dst: &mut B, offset: u64) -> AxResult<usize> {
+        self.notify(FAN_ACCESS_PERM)?;
+        let read = match &self.size_lock {
+            _ if self.is_direct() => self.read_direct(dst, offset),
+            Some(lock) => lock.shared(|| self.inner.read_at(dst, offset)),
+            None => self.inner.read_at(dst, offset),
+        }?;
+        self.notify(FAN_ACCESS)?;
+        Ok(read)
+    }
+
+    /// Write at `offset` without moving the file position.
+    pub fn write_at<B: Buf>(&self, src: &mut B, offset: u64) -> AxResult<usize> {
+        let len = src.remaining();
+        let written = if self.is_direct() {
+            self.write_direct(src, offset)?
+        } else {
+            let written =
+                self.sized_write(Some(offset), len, || self.inner.write_at(src, offset))?;
+            self.dirtied(offset, written);
+            self.throttle();
+            written
+        };
+        self.notify(FAN_MODIFY)?;
+        Ok(written)
+    }
+
+    /// Mark the `len` bytes at `offset`, written to the page cache, dirty.
+    fn dirtied(&self, offset: u64, len: usize) {
+        if let Some(writeback) = &self.writeback
+            && len > 0
+        {
+            let start_page = offset_to_page(offset);
+            let end_page = offset_to_page(offset + len as u64 - 1) + 1;
+            writeback.mark_dirty(start_page, end_page - start_page);
+        }
+    }
+
+    /// Hold the writer back while too much of the page cache is dirty.
+    /// Called with no lock held.
+    fn throttle(&self) {
+        if self.writeback.is_some() {
+            throttle_dirtier();
+        }
+    }
+
+    /// Write out the dirty pages of the file and, unless `data_only`, its
+    /// metadata. A writeback error not yet reported through this file is
+    /// reported now, once, even if the writeback that failed was not this
+    /// one.
+    pub fn sync(&self, data_only: bool) -> AxResult<()> {
+        let Some(writeback) = &self.writeback else {
+            return self.inner.sync(data_only);
+        };
+        // Failures land in the error sequence, checked below.
+        let _ = writeback.write_back(0, u32::MAX);
+        if writeback.mapped_write() {
+            self.inner.sync(data_only)?;
+        } else {
+            self.inner.location().sync(data_only)?;
+        }
+        writeback.check_error(&self.wb_seen)
+    }
+
+    /// Write back the dirty pages in `start_page..end_page`, as
+    /// `sync_file_range` does: first waiting for a writeback in progress if
+    /// `wait_before`, then writing them if `write`, and reporting a
+    /// writeback error not yet reported through this file if `wait_after`.
+    ///
+    /// Writing back is synchronous, so the pages written have reached the
+    /// backend on return even without `wait_after`.
+    pub fn sync_range(
+        &self,
+        start_page: u32,
+        end_page: u32,
+        wait_before: bool,
+        write: bool,
+        wait_after: bool,
+    ) -> AxResult<()> {
+        let Some(writeback) = &self.writeback else {
+            return Ok(());
+        };
+        if wait_before {
+            writeback.wait();
+        }
+        if write {
+            // Failures land in the error sequence, checked below.
+            let _ = writeback.write_back(start_page, end_page);
+        }
+        if wait_after {
+            writeback.wait();
+            writeback.check_error(&self.wb_seen)?;
+        }
+        Ok(())
+    }
+
+    /// Run `f`, which may change the size, with no read in progress.
+    fn exclusive<R>(&self, f: impl FnOnce() -> AxResult<R>) -> AxResult<R> {
+        match (&self.size_lock, &self.freeze_lock) {
+            (Some(lock), Some(freeze)) => freeze.write(|| lock.exclusive(f)),
+            _ => f(),
+        }
+    }
+
+    /// Set the size to `f(current size)`, with no read in progress.
+    pub fn resize(&self, f: impl FnOnce(u64) -> u64) -> AxResult<()> {
+        let file = self.inner.access(FileFlags::WRITE)?;
+        let size = self.exclusive(|| {
+            let size = f(file.location().len()?);
+            file.set_len(size).map(|_| size)
+        })?;
+        if let Some(writeback) = &self.writeback {
+            writeback.forget(size.div_ceil(PAGE_SIZE).min(u32::MAX as u64) as u32);
+        }
+        // What is in flight may be past the new end, or stale.
+        self.ra_state.cancel();
+        Ok(())
+    }
+
+    /// Reserve space for `offset..offset + len`, extending the size to
+    /// cover it unless `keep_size`.
+    pub fn allocate(&self, offset: u64, len: u64, keep_size: bool) -> AxResult<()> {
+        let file = self.inner.access(FileFlags::WRITE)?;
+        self.exclusive(|| {
+            let loc = file.location();
+            MemoryFs::reserve(loc, offset, len)?;
+            if !keep_size && offset + len > loc.len()? {
+                file.set_len(offset + len)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Zero `offset..offset + len` without moving the end of file, giving
+    /// back the space of the whole pages in it.
+    pub fn punch_hole(&self, offset: u64, len: u64) -> AxResult<()> {
+        const ZEROS: &[u8] = &[0; PAGE_SIZE as usize];
+        self.inner.access(FileFlags::WRITE)?;
+        self.exclusive(|| {
+            let loc = self.inner.location();
+            let end = (offset + len).min(loc.len()?);
+            let mut pos = offset;
+            while pos < end {
+                let chunk = (end - pos).min(PAGE_SIZE) as usize;
+                match self.inner.write_at(&mut &ZEROS[..chunk], pos)? {
+                    0 => break,
+                    written => pos += written as u64,
+                }
+            }
+            self.dirtied(offset, (pos - offset) as usize);
+            MemoryFs::release(loc, offset, len);
+            Ok(())
+        })
+    }
+
+    /// Run a write of `len` bytes at `offset`, or at the file position if
+    /// `None`. Takes the size lock exclusively only if the write may move the
+    /// end of file.
+    fn sized_write<R>(
+        &self,
+        offset: Option<u64>,
+        len: usize,
+        f: impl FnOnce() -> AxResult<R>,
+    ) -> AxResult<R> {
+        let (Some(lock), Some(freeze)) = (&self.size_lock, &self.freeze_lock) else {
+            return f();
+        };
+        freeze.write(|| self.sized_write_locked(lock, offset, len, f))
+    }
+
+    fn sized_write_locked<R>(
+        &self,
+        lock: &SizeLock,
+        offset: Option<u64>,
+        len: usize,
+        f: impl FnOnce() -> AxResult<R>,
+    ) -> AxResult<R> {
+        let loc = self.inner.location();
+        let append = offset.is_none() && self.inner.access(FileFlags::APPEND).is_ok();
+        let start = || -> AxResult<u64> {
+            Ok(match offset {
+                Some(offset) => offset,
+                None if append => loc.len()?,
+                None => self.inner.position(),
+            })
+        };
+        // Space is taken before any page is dirtied, so running out of it
+        // fails the write itself.
+        let reserve = || MemoryFs::reserve(loc, start()?, len as u64);
+        // With the lock held shared, a write within the size cannot race a
+        // truncate and leaves the size alone.
+        let mut f = Some(f);
+        let done = lock.shared(|| -> AxResult<Option<R>> {
+            if append || start()? + len as u64 > loc.len()? {
+                return Ok(None);
+            }
+            reserve()?;
+            (f.take().unwrap())().map(Some)
+        })?;
+        match done {
+            Some(result) => Ok(result),
+            None => lock.exclusive(|| {
+                reserve()?;
+                (f.unwrap())()
+            }),
+        }
+    }
+
+    /// Maximum readahead size in pages for this open file description
+    pub fn ra_limit(&self) -> u32 {
+        self.ra_state.limit()
+    }
+
+    /// Set the maximum readahead size in pages, 0 disabling readahead.
+    /// Fails with `EINVAL` outside `RA_MIN_PAGES..=RA_HARD_MAX_PAGES`.
+    pub fn set_ra_limit(&self, pages: u32) -> AxResult<()> {
+        if pages != 0 && !(RA_MIN_PAGES..=RA_HARD_MAX_PAGES).contains(&pages) {
+            return Err(AxError::InvalidInput);
+        }
+        self.ra_state.set_limit(pages);
+        Ok(())
+    }
+
+    /// Apply `posix_fadvise` advice to `offset..offset + len`, where a
+    /// `len` of 0 means up to the end of file.
+    pub fn advise(&self, advice: u32, offset: u64, len: u64) -> AxResult<()> {
+        match advice {
+            POSIX_FADV_NORMAL => self.ra_state.set_mode(RaMode::Normal),
+            POSIX_FADV_SEQUENTIAL => self.ra_state.set_mode(RaMode::Sequential),
+            POSIX_FADV_RANDOM => self.ra_state.set_mode(RaMode::Random),
+            POSIX_FADV_WILLNEED => {
+                let end = match len {
+                    0 => u64::MAX,
+                    len => offset.saturating_add(len),
+                };
+                // Read synchronously, so no more than one readahead
+                // window; the rest is read on demand.
+                self.prefetch(offset, end, self.ra_state.limit())?;
+            }
+            POSIX_FADV_DONTNEED => {
+                let end = match len {
+                    0 => u64::MAX,
+                    len => offset.saturating_add(len),
+                };
+                // Only the pages wholly in the range
+                let start_page = offset.div_ceil(PAGE_SIZE).min(u32::MAX as u64) as u32;
+                let end_page = (end / PAGE_SIZE).min(u32::MAX as u64) as u32;
+                if start_page < end_page {
+                    self.invalidate_pages(start_page, end_page - start_page)?;
+                }
+            }
+            // Nothing is kept out of the page cache early.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Give up the cached pages of `start_page..start_page + num_pages`:
+    /// write out the dirty ones and forget the readahead windows over them,
+    /// so that the next read does not count on them being cached.
+    ///
+    /// This is unfinished: the page cache of axfs has no way to evict pages
+    /// on request, so no page is dropped. The pages stay cached, and
+    /// `is_page_cached` keeps reporting them, until it reclaims them itself.
+    pub fn invalidate_pages(&self, start_page: u32, num_pages: u32) -> AxResult<()> {
+        let end_page = start_page.saturating_add(num_pages);
+        self.sync_range(start_page, end_page, true, true, true)?;
+        self.ra_state.invalidate(start_page, num_pages);
+        Ok(())
+    }
+
+    /// Populate the page cache for `offset..end`, up to the end of file and
+    /// at most `max_pages` pages. Neither the file position nor the
+    /// readahead state is touched.
+    pub fn prefetch(&self, offset: u64, end: u64, max_pages: u32) -> AxResult<()> {
+        let Ok(backend) = self.inner.backend() else {
+            return Ok(());
+        };
+        let end = end.min(self.inner.location().len()?);
+        if offset < end {
+            let start_page = offset_to_page(offset);
+            let end_page = end.div_ceil(PAGE_SIZE).min(u32::MAX as u64) as u32;
+            let num_pages = (end_page - start_page).min(max_pages);
+            // Whatever is left out is read on demand.
+            let _ = do_sync_readahead(&self.ra_state, backend, start_page, num_pages);
+        }
+        Ok(())
+    }
+
+    /// Perform readahead based on current position and read length,
+    /// without going past `size`.
     /// Called before actual read to prefetch pages.
-    fn maybe_readahead(&self, read_len: usize) {
+    fn maybe_readahead(&self, read_len: usize, size: u64) {
         // Get the file backend for readahead
         let backend = match self.inner.backend() {
             Ok(b) => b,
@@ -137,66 +663,158 @@ impl File {
         let offset = self.inner.position();
 
         // Decide readahead action
-        let action = readahead_decide(&self.ra_state, backend, offset, read_len);
+        let mut action = readahead_decide(&self.ra_state, backend, offset, read_len, size);
+
+        // Keep background (idle I/O class) readers from flooding the page cache
+        let idle = current()
+            .try_as_thread()
+            .is_some_and(|thr| thr.ioprio().class() == IoPrioClass::Idle);
+        if idle {
+            action = action.capped(RA_IDLE_MAX_PAGES);
+        }
 
         match action {
             ReadaheadAction::Sync {
                 start_page,
                 num_pages,
             } => {
-                // Perform sync readahead
-                do_sync_readahead(backend, start_page, num_pages);
+                // Perform sync readahead; only the read itself may fail
+                let _ = do_sync_readahead(&self.ra_state, backend, start_page, num_pages);
             }
             ReadaheadAction::Async {
                 start_page,
                 num_pages,
             } => {
                 // Perform async readahead
-                let backend = backend.clone();
-                axtask::spawn(move || {
-                    backend.try_prefetch_pages(start_page, num_pages);
-                });
+                let readahead = async_readahead(&self.ra_state, backend, start_page, num_pages);
+                submit_async_readahead(readahead);
             }
             ReadaheadAction::None => {}
         }
     }
 }
 
+/// Write all of `data` at `offset` of `backend`, failing with `EIO` if it
+/// takes no more
+fn write_all_at(backend: &FileBackend, data: &[u8], offset: u64) -> AxResult<usize> {
+    let mut written = 0;
+    while written < data.len() {
+        match backend.write_at(&mut &data[written..], offset + written as u64)? {
+            0 => return Err(AxError::Io),
+            n => written += n,
+        }
+    }
+    Ok(written)
+}
+
+/// Check that a direct transfer of `len` bytes at `offset` is aligned,
+/// failing with `EINVAL` if not
+fn check_direct_range(offset: u64, len: usize) -> AxResult<()> {
+    if offset % DIRECT_IO_ALIGN as u64 != 0 || len % DIRECT_IO_ALIGN != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    Ok(())
+}
+
 fn path_for(loc: &Location) -> Cow<'static, str> {
     loc.absolute_path()
         .map_or_else(|_| "<error>".into(), |f| Cow::Owned(f.to_string()))
 }
 
-impl FileLike for File {
-    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+impl File {
+    fn read_unwatched(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
         let inner = self.inner();
         let read_len = dst.remaining_mut();
 
+        if self.is_direct() {
+            // No readahead either: the cache is what direct I/O avoids
+            let offset = inner.position();
+            let read = self.read_direct(dst, offset)?;
+            inner.seek(SeekFrom::Start(offset + read as u64))?;
+            return Ok(read);
+        }
+
+        if let Some(lock) = &self.size_lock {
+            // The readahead window is clamped to the same size the read
+            // sees.
+            return lock.shared(|| {
+                self.maybe_readahead(read_len, inner.location().len()?);
+                inner.read(dst)
+            });
+        }
+
         // Trigger readahead for sequential access optimization
-        self.maybe_readahead(read_len);
+        self.maybe_readahead(read_len, u64::MAX);
 
         if likely(self.is_blocking()) {
             inner.read(dst)
         } else {
-            block_on(poll_io(self, IoEvents::IN, self.nonblocking(), || {
+            wait_for_io(self, IoEvents::IN, self.nonblocking(), None, true, || {
                 inner.read(dst)
-            }))
+            })
         }
     }
 
-    fn write(&self, src: &mut SealedBuf) -> AxResult<usize> {
+    fn write_unwatched(&self, src: &mut SealedBuf) -> AxResult<usize> {
         let inner = self.inner();
+        if self.is_direct() {
+            let offset = if inner.access(FileFlags::APPEND).is_ok() {
+                inner.location().len()?
+            } else {
+                inner.position()
+            };
+            let written = self.write_direct(src, offset)?;
+            inner.seek(SeekFrom::Start(offset + written as u64))?;
+            return Ok(written);
+        }
+        if self.size_lock.is_some() {
+            let len = src.remaining();
+            let written = self.sized_write(None, len, || {
+                let written = inner.write(src)?;
+                self.dirtied(inner.position() - written as u64, written);
+                Ok(written)
+            })?;
+            self.throttle();
+            return Ok(written);
+        }
         if likely(self.is_blocking()) {
             inner.write(src)
         } else {
-            block_on(poll_io(self, IoEvents::OUT, self.nonblocking(), || {
+            wait_for_io(self, IoEvents::OUT, self.nonblocking(), None, true, || {
                 inner.write(src)
-            }))
+            })
         }
     }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        // The last fd to the open file is gone.
+        let mask = if self.inner.access(FileFlags::WRITE).is_ok() {
+            FAN_CLOSE_WRITE
+        } else {
+            FAN_CLOSE_NOWRITE
+        };
+        let _ = self.notify(mask);
+    }
+}
+
+impl FileLike for File {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        self.notify(FAN_ACCESS_PERM)?;
+        let read = self.read_unwatched(dst)?;
+        self.notify(FAN_ACCESS)?;
+        Ok(read)
+    }
+
+    fn write(&self, src: &mut SealedBuf) -> AxResult<usize> {
+        let written = self.write_unwatched(src)?;
+        self.notify(FAN_MODIFY)?;
+        Ok(written)
+    }
 
     fn stat(&self) -> AxResult<Kstat> {
-        Ok(metadata_to_kstat(&self.inner().location().metadata()?))
+        kstat_on_mount(self.inner().location(), self.mnt_id)
     }
 
     fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
@@ -216,6 +834,24 @@ impl FileLike for File {
         self.nonblock.load(Ordering::Acquire)
     }
 
+    /// The readahead counters of this open file description.
+    fn fdinfo(&self) -> String {
+        let stats = self.ra_state.snapshot();
+        [
+            ("sync_ra_issued", stats.sync_ra_issued),
+            ("async_ra_issued", stats.async_ra_issued),
+            ("pages_prefetched", stats.pages_prefetched),
+            ("cache_hits", stats.cache_hits),
+            ("cache_misses", stats.cache_misses),
+            ("pattern_resets", stats.pattern_resets),
+            ("thrash_shrinks", stats.thrash_shrinks),
+            ("async_ra_cancelled", stats.async_ra_cancelled),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{name}: {value}\n"))
+        .collect()
+    }
+
     fn path(&self) -> Cow<str> {
         path_for(self.inner.location())
     }
@@ -245,17 +881,164 @@ impl Pollable for File {
     }
 }
 
+/// Bytes a directory entry stands for in directory readahead, about the
+/// size of a `linux_dirent64` with a short name, so that a page is 128
+/// entries and the buffer length of getdents64 reads as many as it holds
+const DIR_ENTRY_BYTES: u64 = 32;
+
+/// Entries in a page of directory readahead
+const DIR_PAGE_ENTRIES: u64 = PAGE_SIZE / DIR_ENTRY_BYTES;
+
+/// An entry read ahead of a directory stream
+struct DirEntry {
+    name: String,
+    ino: u64,
+    node_type: NodeType,
+    /// Offset of the entry after it
+    offset: u64,
+}
+
+/// The getdents64 stream of a directory
+///
+/// Directories have no page cache, so readahead fills a buffer of the
+/// entries following the stream position instead. The stream is indexed by
+/// entry, [`DIR_PAGE_ENTRIES`] to a page, and a page is cached when all its
+/// entries are buffered, or the buffer reaches the end of the directory.
+/// Entries created or removed after being read ahead may be missed or still
+/// returned, which POSIX allows until the next `rewinddir`.
+pub struct DirStream {
+    /// Offset of the next entry, as handed out by the filesystem
+    offset: u64,
+    /// Entries returned since the stream was last moved
+    index: u64,
+    /// Entries following `offset`
+    ahead: VecDeque<DirEntry>,
+    /// Whether `ahead` reaches the end of the directory
+    eof: bool,
+    ra_state: ReadaheadState,
+}
+
+impl RaPages for DirStream {
+    fn is_page_cached(&self, page: u32) -> bool {
+        let start = page as u64 * DIR_PAGE_ENTRIES;
+        let buffered = self.index + self.ahead.len() as u64;
+        start >= self.index && (start + DIR_PAGE_ENTRIES <= buffered || self.eof)
+    }
+}
+
+impl DirStream {
+    fn new(loc: &Location, offset: u64) -> Self {
+        let ra_state = ReadaheadState::with_default_limit(mount_ra_pages(loc.mountpoint()));
+        // Directories are only ever read from start to end.
+        ra_state.set_mode(RaMode::Sequential);
+        Self {
+            offset,
+            index: 0,
+            ahead: VecDeque::new(),
+            eof: false,
+            ra_state,
+        }
+    }
+
+    /// Offset of the next entry, for `telldir`
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Move the stream to `offset`, one handed out by getdents64, dropping
+    /// what was read ahead unless it stays put
+    pub fn seek(&mut self, loc: &Location, offset: u64) {
+        if offset != self.offset {
+            *self = Self::new(loc, offset);
+        }
+    }
+
+    /// Feed the entries from the stream position on to `sink` until it
+    /// refuses one, which is left for the next call, reading ahead for a
+    /// caller taking up to `len` bytes of them
+    pub fn read(
+        &mut self,
+        loc: &Location,
+        len: usize,
+        sink: &mut dyn FnMut(&str, u64, NodeType, u64) -> bool,
+    ) -> AxResult<()> {
+        let pos = self.index * DIR_ENTRY_BYTES;
+        match readahead_decide(&self.ra_state, &*self, pos, len, u64::MAX) {
+            ReadaheadAction::Sync {
+                start_page,
+                num_pages,
+            }
+            | ReadaheadAction::Async {
+                start_page,
+                num_pages,
+            } => {
+                let end = (start_page as u64 + num_pages as u64) * DIR_PAGE_ENTRIES;
+                // Only the read itself may fail.
+                let _ = self.read_ahead(loc, end);
+            }
+            ReadaheadAction::None => {}
+        }
+
+        while let Some(entry) = self.ahead.front() {
+            if !sink(&entry.name, entry.ino, entry.node_type, entry.offset) {
+                return Ok(());
+            }
+            self.offset = entry.offset;
+            self.index += 1;
+            self.ahead.pop_front();
+        }
+        loc.read_dir(self.offset, &mut |name: &str, ino, node_type, offset| {
+            if !sink(name, ino, node_type, offset) {
+                return false;
+            }
+            self.offset = offset;
+            self.index += 1;
+            true
+        })?;
+        Ok(())
+    }
+
+    /// Buffer the entries up to the one at index `end` of the stream
+    fn read_ahead(&mut self, loc: &Location, end: u64) -> AxResult<()> {
+        while !self.eof && self.index + (self.ahead.len() as u64) < end {
+            let mut wanted = end - self.index - self.ahead.len() as u64;
+            let from = self.ahead.back().map_or(self.offset, |entry| entry.offset);
+            let ahead = &mut self.ahead;
+            let mut read = 0;
+            loc.read_dir(from, &mut |name: &str, ino, node_type, offset| {
+                ahead.push_back(DirEntry {
+                    name: name.into(),
+                    ino,
+                    node_type,
+                    offset,
+                });
+                read += 1;
+                wanted -= 1;
+                wanted > 0
+            })?;
+            self.eof = read == 0;
+        }
+        Ok(())
+    }
+}
+
 /// Directory wrapper for `axfs::fops::Directory`.
 pub struct Directory {
     inner: Location,
-    pub offset: Mutex<u64>,
+    pub stream: Mutex<DirStream>,
+    /// Keeps the mount of the directory busy
+    _mount: MountUse,
+    /// Id of the mount the directory is on
+    mnt_id: u64,
 }
 
 impl Directory {
     pub fn new(inner: Location) -> Self {
         Self {
+            _mount: MountUse::new(&inner),
+            mnt_id: mount_id(inner.mountpoint()),
+            stream: Mutex::new(DirStream::new(&inner, 0)),
             inner,
-            offset: Mutex::new(0),
         }
     }
 
@@ -275,7 +1058,7 @@ impl FileLike for Directory {
     }
 
     fn stat(&self) -> AxResult<Kstat> {
-        Ok(metadata_to_kstat(&self.inner.metadata()?))
+        kstat_on_mount(&self.inner, self.mnt_id)
     }
 
     fn path(&self) -> Cow<str> {
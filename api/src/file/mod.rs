@@ -1,36 +1,45 @@
+mod anon_inode;
 pub mod epoll;
 pub mod event;
+pub mod fanotify;
 mod fs;
+pub mod mqueue;
 mod net;
 mod pidfd;
 mod pipe;
+mod reuseport;
 pub mod signalfd;
 pub mod timerfd;
+pub mod usage;
 
-use alloc::{borrow::Cow, sync::Arc};
-use core::{any::Any, ffi::c_int, time::Duration};
+use alloc::{borrow::Cow, string::String, sync::Arc};
+use core::{any::Any, ffi::c_int, task::Waker, time::Duration};
 
 use axerrno::{AxError, AxResult};
 use axfs::{FS_CONTEXT, OpenOptions};
 use axfs_ng_vfs::DeviceId;
 use axio::{Buf, BufMut, Read, Write};
-use axpoll::Pollable;
+use axpoll::{IoEvents, PollSet, Pollable};
 use axtask::current;
 use flatten_objects::FlattenObjects;
 use inherit_methods_macro::inherit_methods;
-use linux_raw_sys::general::{RLIMIT_NOFILE, stat, statx, statx_timestamp};
+use linux_raw_sys::general::{
+    RLIMIT_NOFILE, STATX_BASIC_STATS, STATX_MNT_ID, stat, statx, statx_timestamp,
+};
 use spin::RwLock;
 use starry_core::{resources::AX_FILE_LIMIT, task::AsThread};
 
 pub use self::{
-    fs::{Directory, File, ResolveAtResult, metadata_to_kstat, resolve_at, with_fs},
+    anon_inode::{ANON_INODE_DEV, AnonInode},
+    fs::{Directory, File, ResolveAtResult, location_to_kstat, resolve_at, stat_at, with_fs},
     net::Socket,
     pidfd::PidFd,
-    pipe::Pipe,
+    pipe::{Pipe, pipe_max_size},
 };
 use crate::{
     io::IoVectorBufIo,
     mm::{VmBytes, VmBytesMut},
+    vfs::mounts::old_mount_id,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -48,6 +57,8 @@ pub struct Kstat {
     pub atime: Duration,
     pub mtime: Duration,
     pub ctime: Duration,
+    /// Unique id of the mount the file is on, or 0 for files not on any.
+    pub mnt_id: u64,
 }
 
 impl Default for Kstat {
@@ -66,6 +77,7 @@ impl Default for Kstat {
             atime: Duration::default(),
             mtime: Duration::default(),
             ctime: Duration::default(),
+            mnt_id: 0,
         }
     }
 }
@@ -123,8 +135,16 @@ impl From<Kstat> for statx {
         statx.stx_ctime = time_to_statx(&value.ctime);
         statx.stx_mtime = time_to_statx(&value.mtime);
 
-        statx.stx_dev_major = (value.dev >> 32) as _;
-        statx.stx_dev_minor = value.dev as _;
+        let dev = DeviceId(value.dev);
+        statx.stx_dev_major = dev.major();
+        statx.stx_dev_minor = dev.minor();
+
+        statx.stx_mask = STATX_BASIC_STATS;
+        // The old id; `statx` reports the unique one instead when asked to.
+        if value.mnt_id != 0 {
+            statx.stx_mnt_id = old_mount_id(value.mnt_id) as _;
+            statx.stx_mask |= STATX_MNT_ID;
+        }
 
         statx
     }
@@ -262,6 +282,12 @@ pub trait FileLike: Pollable + Send + Sync {
         Ok(())
     }
 
+    /// Returns the lines of the file's own state that its
+    /// `/proc/[pid]/fdinfo` entry ends with.
+    fn fdinfo(&self) -> String {
+        String::new()
+    }
+
     fn from_fd(fd: c_int) -> AxResult<Arc<Self>>
     where
         Self: Sized + 'static,
@@ -280,8 +306,46 @@ pub trait FileLike: Pollable + Send + Sync {
     }
 }
 
+/// Returns the events of `file` that a poller interested in `interest`
+/// should see.
+///
+/// Every [`Pollable`] reports its state with the poll(2) bits of
+/// [`IoEvents`], which share their values with the `EPOLL*` constants:
+///
+/// | state                                                  | bits          |
+/// |--------------------------------------------------------|---------------|
+/// | a read would not block, including at end of file       | IN            |
+/// | urgent or out-of-band data is pending                  | PRI           |
+/// | a 1-byte write (`PIPE_BUF` for pipes) would not block  | OUT           |
+/// | the peer shut down its writing side                    | RDHUP and IN  |
+/// | a pipe without writers, or a fully shut down socket    | HUP           |
+/// | an error is pending, or a pipe without readers         | ERR           |
+///
+/// Files never set `RDNORM` and `WRNORM` themselves; they are derived here
+/// from IN and OUT. ERR and HUP are reported whether or not they were asked
+/// for.
+///
+/// Not every file reports every state yet:
+/// - sockets pass on the network stack's events unchanged, so RDHUP on a
+///   peer's half-close is only seen if the stack itself sets it;
+/// - a pty master never reports HUP, since nothing counts the open files of
+///   its slave; the slave does report HUP once the master is gone.
+pub fn readiness<P: Pollable + ?Sized>(file: &P, interest: IoEvents) -> IoEvents {
+    let mut events = file.poll();
+    if events.contains(IoEvents::IN) {
+        events |= IoEvents::RDNORM;
+    }
+    if events.contains(IoEvents::OUT) {
+        events |= IoEvents::WRNORM;
+    }
+    events & (interest | IoEvents::ERR | IoEvents::HUP)
+}
+
 #[derive(Clone)]
 pub struct FileDescriptor {
+    /// The open file description. Descriptors duplicated, inherited on fork
+    /// or passed over a socket share it, and with it the file position,
+    /// status flags and readahead state.
     pub inner: Arc<dyn FileLike>,
     pub cloexec: bool,
 }
@@ -289,6 +353,19 @@ pub struct FileDescriptor {
 scope_local::scope_local! {
     /// The current file descriptor table.
     pub static FD_TABLE: Arc<RwLock<FlattenObjects<FileDescriptor, AX_FILE_LIMIT>>> = Arc::default();
+    /// Woken when a descriptor of the current process is closed.
+    static FD_CLOSED: PollSet = PollSet::new();
+}
+
+/// Wakes the threads in select(2) or poll(2) after descriptors were closed,
+/// for those watching one of them to find it gone.
+pub fn notify_fd_closed() {
+    FD_CLOSED.wake();
+}
+
+/// Registers `waker` to be woken by the next [`notify_fd_closed`].
+pub fn register_fd_closed(waker: &Waker) {
+    FD_CLOSED.register(waker);
 }
 
 /// Get a file-like object by `fd`.
@@ -318,6 +395,7 @@ pub fn close_file_like(fd: c_int) -> AxResult {
         .remove(fd as usize)
         .ok_or(AxError::BadFileDescriptor)?;
     debug!("close_file_like <= count: {}", Arc::strong_count(&f.inner));
+    notify_fd_closed();
     Ok(())
 }
 
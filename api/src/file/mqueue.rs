@@ -0,0 +1,446 @@
+//! POSIX message queues.
+
+use alloc::{
+    borrow::Cow,
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    format,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    any::Any,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Waker},
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axio::{BufMut, Write};
+use axpoll::{IoEvents, PollSet, Pollable};
+use axsync::Mutex;
+use linkme::distributed_slice;
+use linux_raw_sys::general::{SI_MESGQ, SIGEV_NONE, SIGEV_SIGNAL};
+use starry_core::{
+    sysctl::{SYSCTLS, Sysctl, SysctlKind},
+    task::send_signal_to_process,
+};
+use starry_process::Pid;
+use starry_signal::{SignalInfo, Signo};
+
+use crate::file::{FileLike, Kstat, SealedBuf, SealedBufMut};
+
+/// Highest priority accepted by `mq_send`, exclusive.
+pub const MQ_PRIO_MAX: u32 = 32768;
+
+static MSG_MAX: AtomicUsize = AtomicUsize::new(10);
+static MSGSIZE_MAX: AtomicUsize = AtomicUsize::new(8192);
+static QUEUES_MAX: AtomicUsize = AtomicUsize::new(256);
+
+/// The `/proc/sys/fs/mqueue` limits.
+pub mod limits {
+    use super::*;
+
+    /// Maximum `mq_maxmsg` of a queue.
+    pub fn msg_max() -> usize {
+        MSG_MAX.load(Ordering::Relaxed)
+    }
+
+    /// Sets the maximum `mq_maxmsg` of a queue.
+    pub fn set_msg_max(value: usize) {
+        MSG_MAX.store(value, Ordering::Relaxed);
+    }
+
+    /// Maximum `mq_msgsize` of a queue.
+    pub fn msgsize_max() -> usize {
+        MSGSIZE_MAX.load(Ordering::Relaxed)
+    }
+
+    /// Sets the maximum `mq_msgsize` of a queue.
+    pub fn set_msgsize_max(value: usize) {
+        MSGSIZE_MAX.store(value, Ordering::Relaxed);
+    }
+
+    /// Maximum number of queues in the system.
+    pub fn queues_max() -> usize {
+        QUEUES_MAX.load(Ordering::Relaxed)
+    }
+
+    /// Sets the maximum number of queues in the system.
+    pub fn set_queues_max(value: usize) {
+        QUEUES_MAX.store(value, Ordering::Relaxed);
+    }
+}
+
+#[distributed_slice(SYSCTLS)]
+static MSG_MAX_SYSCTL: Sysctl = Sysctl {
+    path: "fs/mqueue/msg_max",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || limits::msg_max() as _,
+        set: |value| limits::set_msg_max(value as _),
+        min: 1,
+        max: 65536,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static MSGSIZE_MAX_SYSCTL: Sysctl = Sysctl {
+    path: "fs/mqueue/msgsize_max",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || limits::msgsize_max() as _,
+        set: |value| limits::set_msgsize_max(value as _),
+        min: 128,
+        max: 16 << 20,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static QUEUES_MAX_SYSCTL: Sysctl = Sysctl {
+    path: "fs/mqueue/queues_max",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || limits::queues_max() as _,
+        set: |value| limits::set_queues_max(value as _),
+        min: 0,
+        max: i32::MAX as _,
+    },
+};
+
+/// A registration made with `mq_notify`.
+#[derive(Clone, Copy)]
+pub struct MqNotify {
+    pub pid: Pid,
+    /// The signal to send, or `None` for `SIGEV_NONE`.
+    pub signo: Option<Signo>,
+    pub value: usize,
+}
+
+struct MqInner {
+    /// priority -> messages, FIFO within a priority
+    messages: BTreeMap<u32, VecDeque<Vec<u8>>>,
+    count: usize,
+    bytes: usize,
+    notify: Option<MqNotify>,
+}
+
+/// A named POSIX message queue. Descriptors refer to it through [`MqFd`].
+pub struct MessageQueue {
+    pub maxmsg: usize,
+    pub msgsize: usize,
+    /// Permission bits, from the `mode` of the creating `mq_open`.
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    inner: Mutex<MqInner>,
+    /// Number of tasks blocked in `mq_receive`.
+    receivers: AtomicUsize,
+    poll_rx: PollSet,
+    poll_tx: PollSet,
+}
+
+impl MessageQueue {
+    pub fn new(maxmsg: usize, msgsize: usize, mode: u32, uid: u32, gid: u32) -> Arc<Self> {
+        Arc::new(Self {
+            maxmsg,
+            msgsize,
+            mode,
+            uid,
+            gid,
+            inner: Mutex::new(MqInner {
+                messages: BTreeMap::new(),
+                count: 0,
+                bytes: 0,
+                notify: None,
+            }),
+            receivers: AtomicUsize::new(0),
+            poll_rx: PollSet::new(),
+            poll_tx: PollSet::new(),
+        })
+    }
+
+    /// Checks whether `uid`/`gid` is granted all bits of `access` (a
+    /// combination of `0o4` for read and `0o2` for write).
+    pub fn check_access(&self, uid: u32, gid: u32, access: u32) -> AxResult<()> {
+        if uid == 0 {
+            return Ok(());
+        }
+        let granted = if uid == self.uid {
+            self.mode >> 6
+        } else if gid == self.gid {
+            self.mode >> 3
+        } else {
+            self.mode
+        };
+        if granted & access & 0o7 == access {
+            Ok(())
+        } else {
+            Err(AxError::PermissionDenied)
+        }
+    }
+
+    /// Returns the number of messages currently queued.
+    pub fn len(&self) -> usize {
+        self.inner.lock().count
+    }
+
+    /// Enqueues a message, failing with `EAGAIN` if the queue is full.
+    pub fn try_send(&self, data: &[u8], prio: u32, sender: Pid) -> AxResult<()> {
+        let mut inner = self.inner.lock();
+        if inner.count >= self.maxmsg {
+            return Err(AxError::WouldBlock);
+        }
+        let was_empty = inner.count == 0;
+        inner
+            .messages
+            .entry(prio)
+            .or_default()
+            .push_back(data.to_vec());
+        inner.count += 1;
+        inner.bytes += data.len();
+
+        // Notification only happens for an empty queue nobody is waiting on,
+        // and the registration is consumed by it.
+        let notify = if was_empty && self.receivers.load(Ordering::Acquire) == 0 {
+            inner.notify.take()
+        } else {
+            None
+        };
+        drop(inner);
+
+        self.poll_rx.wake();
+        if let Some(MqNotify {
+            pid,
+            signo: Some(signo),
+            value,
+        }) = notify
+        {
+            let sig = mq_signal_info(signo, sender, value);
+            let _ = send_signal_to_process(pid, Some(sig));
+        }
+        Ok(())
+    }
+
+    /// Dequeues the oldest message of the highest priority, failing with
+    /// `EAGAIN` if the queue is empty.
+    pub fn try_receive(&self) -> AxResult<(Vec<u8>, u32)> {
+        let mut inner = self.inner.lock();
+        let mut entry = inner.messages.last_entry().ok_or(AxError::WouldBlock)?;
+        let prio = *entry.key();
+        let data = entry.get_mut().pop_front().unwrap();
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+        inner.count -= 1;
+        inner.bytes -= data.len();
+        drop(inner);
+
+        self.poll_tx.wake();
+        Ok((data, prio))
+    }
+
+    /// Runs `f` while accounted as a blocked receiver, which suppresses
+    /// notifications.
+    pub fn receiving<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.receivers.fetch_add(1, Ordering::AcqRel);
+        let result = f();
+        self.receivers.fetch_sub(1, Ordering::AcqRel);
+        result
+    }
+
+    /// Registers a waker to be woken when a message arrives.
+    pub fn register_receiver(&self, waker: &Waker) {
+        self.poll_rx.register(waker);
+    }
+
+    /// Registers a waker to be woken when a message is taken off the queue.
+    pub fn register_sender(&self, waker: &Waker) {
+        self.poll_tx.register(waker);
+    }
+
+    /// Registers the notification of `pid`. Only one registration may exist
+    /// at a time, even for the same process; it has to be removed first.
+    pub fn register_notify(&self, notify: MqNotify) -> AxResult<()> {
+        let mut inner = self.inner.lock();
+        if inner.notify.is_some() {
+            return Err(AxError::ResourceBusy);
+        }
+        inner.notify = Some(notify);
+        Ok(())
+    }
+
+    /// Drops the registration of `pid`, if any.
+    pub fn clear_notify(&self, pid: Pid) {
+        let mut inner = self.inner.lock();
+        if inner.notify.is_some_and(|it| it.pid == pid) {
+            inner.notify = None;
+        }
+    }
+
+    fn status(&self) -> String {
+        let inner = self.inner.lock();
+        let (notify, signo, pid) = match inner.notify {
+            Some(MqNotify {
+                pid, signo: None, ..
+            }) => (SIGEV_NONE, 0, pid),
+            Some(MqNotify {
+                pid,
+                signo: Some(signo),
+                ..
+            }) => (SIGEV_SIGNAL, signo as u32, pid),
+            None => (0, 0, 0),
+        };
+        format!(
+            "QSIZE:{:<10} NOTIFY:{:<5} SIGNO:{:<5} NOTIFY_PID:{:<6}\n",
+            inner.bytes, notify, signo, pid
+        )
+    }
+}
+
+/// Builds the `SI_MESGQ` siginfo carrying the `sigev_value` of the
+/// registration.
+fn mq_signal_info(signo: Signo, sender: Pid, value: usize) -> SignalInfo {
+    let mut sig = SignalInfo::new_user(signo, SI_MESGQ, sender);
+    // `_sifields._rt.si_sigval` follows si_pid and si_uid at offset 24.
+    unsafe {
+        (&raw mut sig.0)
+            .cast::<u8>()
+            .add(24)
+            .cast::<usize>()
+            .write_unaligned(value);
+    }
+    sig
+}
+
+static NAMESPACE: Mutex<BTreeMap<String, Arc<MessageQueue>>> = Mutex::new(BTreeMap::new());
+
+/// The mqueue filesystem namespace.
+pub mod namespace {
+    use super::*;
+
+    /// Looks up a queue by name.
+    pub fn lookup(name: &str) -> Option<Arc<MessageQueue>> {
+        NAMESPACE.lock().get(name).cloned()
+    }
+
+    /// Creates a queue under `name`, or returns the existing one if
+    /// `exclusive` is not set.
+    pub fn create(
+        name: &str,
+        exclusive: bool,
+        make: impl FnOnce() -> AxResult<Arc<MessageQueue>>,
+    ) -> AxResult<Arc<MessageQueue>> {
+        let mut ns = NAMESPACE.lock();
+        if let Some(queue) = ns.get(name) {
+            return if exclusive {
+                Err(AxError::AlreadyExists)
+            } else {
+                Ok(queue.clone())
+            };
+        }
+        if ns.len() >= limits::queues_max() {
+            return Err(AxError::from(LinuxError::ENOSPC));
+        }
+        let queue = make()?;
+        ns.insert(name.into(), queue.clone());
+        Ok(queue)
+    }
+
+    /// Removes `name`. The queue itself lives on until its last descriptor is
+    /// closed.
+    pub fn unlink(name: &str) -> AxResult<()> {
+        NAMESPACE
+            .lock()
+            .remove(name)
+            .map(|_| ())
+            .ok_or(AxError::NotFound)
+    }
+}
+
+/// An open message queue descriptor.
+pub struct MqFd {
+    pub queue: Arc<MessageQueue>,
+    name: String,
+    readable: bool,
+    writable: bool,
+    non_blocking: AtomicBool,
+}
+
+impl MqFd {
+    pub fn new(queue: Arc<MessageQueue>, name: String, readable: bool, writable: bool) -> Self {
+        Self {
+            queue,
+            name,
+            readable,
+            writable,
+            non_blocking: AtomicBool::new(false),
+        }
+    }
+
+    pub fn readable(&self) -> bool {
+        self.readable
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+}
+
+impl FileLike for MqFd {
+    fn read(&self, dst: &mut SealedBufMut) -> AxResult<usize> {
+        // Reading the descriptor yields the queue status, like mqueuefs.
+        let status = self.queue.status();
+        let len = status.len().min(dst.remaining_mut());
+        dst.write(&status.as_bytes()[..len])
+    }
+
+    fn write(&self, _src: &mut SealedBuf) -> AxResult<usize> {
+        Err(AxError::InvalidInput)
+    }
+
+    fn stat(&self) -> AxResult<Kstat> {
+        Ok(Kstat {
+            mode: 0o100000 | self.queue.mode,
+            uid: self.queue.uid,
+            gid: self.queue.gid,
+            size: self.queue.inner.lock().bytes as _,
+            ..Default::default()
+        })
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+
+    fn path(&self) -> Cow<str> {
+        format!("/{}", self.name).into()
+    }
+
+    fn nonblocking(&self) -> bool {
+        self.non_blocking.load(Ordering::Acquire)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> AxResult {
+        self.non_blocking.store(nonblocking, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Pollable for MqFd {
+    fn poll(&self) -> IoEvents {
+        let mut events = IoEvents::empty();
+        let len = self.queue.len();
+        events.set(IoEvents::IN | IoEvents::RDNORM, len > 0);
+        events.set(IoEvents::OUT | IoEvents::WRNORM, len < self.queue.maxmsg);
+        events
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.queue.poll_rx.register(context.waker());
+        }
+        if events.contains(IoEvents::OUT) {
+            self.queue.poll_tx.register(context.waker());
+        }
+    }
+}
@@ -1,24 +1,135 @@
 use alloc::{borrow::Cow, format, sync::Arc};
-use core::{ffi::c_int, ops::Deref, task::Context};
+use core::{
+    ffi::c_int,
+    ops::Deref,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Context,
+    time::Duration,
+};
 
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::time::monotonic_time;
 use axnet::{
-    SocketOps,
+    Shutdown, SocketAddrEx, SocketOps,
     options::{Configurable, GetSocketOption, SetSocketOption},
 };
 use axpoll::{IoEvents, Pollable};
 use linux_raw_sys::general::S_IFSOCK;
+use spin::Once;
+use starry_core::timer::wait_for_io;
 
-use super::{FileLike, Kstat};
+use super::{
+    FileLike, Kstat,
+    reuseport::{self, GroupMember},
+};
 use crate::file::{SealedBuf, SealedBufMut, get_file_like};
 
-pub struct Socket(pub axnet::Socket);
+pub struct Socket {
+    inner: axnet::Socket,
+    reuse_port: AtomicBool,
+    /// Set once a TCP socket with `SO_REUSEPORT` is bound. The socket then
+    /// listens and accepts through its group, and `inner` stays unbound.
+    group: Once<GroupMember>,
+}
+
+impl Socket {
+    pub fn new(inner: axnet::Socket) -> Self {
+        Self {
+            inner,
+            reuse_port: AtomicBool::new(false),
+            group: Once::new(),
+        }
+    }
+
+    /// Only TCP sockets can share a port; for the others `SO_REUSEPORT` is
+    /// not an option at all rather than one that is set but does nothing.
+    fn check_reuse_port(&self) -> AxResult<()> {
+        match self.inner {
+            axnet::Socket::Tcp(_) => Ok(()),
+            _ => Err(AxError::from(LinuxError::ENOPROTOOPT)),
+        }
+    }
+
+    pub fn reuse_port(&self) -> AxResult<bool> {
+        self.check_reuse_port()?;
+        Ok(self.reuse_port.load(Ordering::Relaxed))
+    }
+
+    pub fn set_reuse_port(&self, reuse_port: bool) -> AxResult<()> {
+        self.check_reuse_port()?;
+        self.reuse_port.store(reuse_port, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn bind(&self, addr: SocketAddrEx, uid: u32) -> AxResult<()> {
+        match (&self.inner, addr) {
+            (axnet::Socket::Tcp(_), SocketAddrEx::Ip(addr))
+                if self.reuse_port.load(Ordering::Relaxed) =>
+            {
+                if self.group.is_completed() {
+                    return Err(AxError::InvalidInput);
+                }
+                let member = reuseport::join(addr, uid)?;
+                self.group.call_once(|| member);
+                Ok(())
+            }
+            (_, addr) => self.inner.bind(addr),
+        }
+    }
+
+    pub fn listen(&self) -> AxResult<()> {
+        match self.group.get() {
+            Some(group) => group.listen(),
+            None => self.inner.listen(),
+        }
+    }
+
+    pub fn accept(&self) -> AxResult<axnet::Socket> {
+        let Some(group) = self.group.get() else {
+            return self.inner.accept();
+        };
+        let mut timeout = Duration::ZERO;
+        self.inner
+            .get_option(GetSocketOption::ReceiveTimeout(&mut timeout))?;
+        // A zero SO_RCVTIMEO waits forever.
+        let deadline = (!timeout.is_zero()).then(|| monotonic_time() + timeout);
+        match wait_for_io(
+            self,
+            IoEvents::IN,
+            self.nonblocking(),
+            deadline,
+            true,
+            || group.try_accept(),
+        ) {
+            Err(AxError::TimedOut) => Err(AxError::WouldBlock),
+            result => result,
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> AxResult<()> {
+        match self.group.get() {
+            Some(group) if !matches!(how, Shutdown::Write) => {
+                group.shutdown();
+                Ok(())
+            }
+            Some(_) => Ok(()),
+            None => self.inner.shutdown(how),
+        }
+    }
+
+    pub fn local_addr(&self) -> AxResult<SocketAddrEx> {
+        match self.group.get() {
+            Some(group) => Ok(group.local_addr()),
+            None => self.inner.local_addr(),
+        }
+    }
+}
 
 impl Deref for Socket {
     type Target = axnet::Socket;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.inner
     }
 }
 
@@ -52,7 +163,7 @@ impl FileLike for Socket {
     }
 
     fn set_nonblocking(&self, nonblocking: bool) -> AxResult<()> {
-        self.0
+        self.inner
             .set_option(SetSocketOption::NonBlocking(&nonblocking))
     }
 
@@ -72,10 +183,16 @@ impl FileLike for Socket {
 }
 impl Pollable for Socket {
     fn poll(&self) -> IoEvents {
-        self.0.poll()
+        match self.group.get() {
+            Some(group) => group.poll(),
+            None => self.inner.poll(),
+        }
     }
 
     fn register(&self, context: &mut Context<'_>, events: IoEvents) {
-        self.0.register(context, events);
+        match self.group.get() {
+            Some(group) => group.register(context, events),
+            None => self.inner.register(context, events),
+        }
     }
 }
@@ -2,38 +2,109 @@ use alloc::{borrow::Cow, format, sync::Arc};
 use core::{
     any::Any,
     mem,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     task::Context,
 };
 
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, LinuxError};
 use axio::{Buf, BufMut, Read, Write};
 use axpoll::{IoEvents, PollSet, Pollable};
 use axsync::Mutex;
-use axtask::{
-    current,
-    future::{block_on, poll_io},
-};
+use axtask::current;
+use linkme::distributed_slice;
 use linux_raw_sys::{general::S_IFIFO, ioctl::FIONREAD};
 use memory_addr::PAGE_SIZE_4K;
 use ringbuf::{
     HeapRb,
     traits::{Consumer, Observer, Producer},
 };
-use starry_core::task::{AsThread, send_signal_to_process};
+use starry_core::{
+    critical::{CriticalSection, LockLevel, defer},
+    sysctl::{SYSCTLS, Sysctl, SysctlKind},
+    task::{AsThread, send_signal_to_process},
+    timer::wait_for_io,
+};
 use starry_signal::{SignalInfo, Signo};
 use starry_vm::VmMutPtr;
 
-use super::{FileLike, Kstat};
-use crate::file::{SealedBuf, SealedBufMut};
+use super::{
+    AnonInode, FileLike, Kstat,
+    usage::{Charge, Resource, usage},
+};
+use crate::{
+    file::{SealedBuf, SealedBufMut},
+    syscall::sys::sys_geteuid,
+};
 
 const RING_BUFFER_INIT_SIZE: usize = 65536; // 64 KiB
+/// Size of new pipes of users past the soft limit.
+const RING_BUFFER_MIN_SIZE: usize = 2 * PAGE_SIZE_4K;
+/// Writes up to this size are atomic.
+const PIPE_BUF: usize = 4096;
+
+static PIPE_MAX_SIZE: AtomicUsize = AtomicUsize::new(1 << 20);
+
+/// Returns the largest size an unprivileged `F_SETPIPE_SZ` may set.
+pub fn pipe_max_size() -> usize {
+    PIPE_MAX_SIZE.load(Ordering::Relaxed)
+}
+
+#[distributed_slice(SYSCTLS)]
+static PIPE_MAX_SIZE_SYSCTL: Sysctl = Sysctl {
+    path: "fs/pipe-max-size",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || pipe_max_size() as _,
+        set: |value| PIPE_MAX_SIZE.store(value as _, Ordering::Relaxed),
+        min: PAGE_SIZE_4K as _,
+        max: i32::MAX as _,
+    },
+};
+
+static PIPE_USER_PAGES_SOFT: AtomicUsize = AtomicUsize::new(16384);
+static PIPE_USER_PAGES_HARD: AtomicUsize = AtomicUsize::new(0);
+
+#[distributed_slice(SYSCTLS)]
+static PIPE_USER_PAGES_SOFT_SYSCTL: Sysctl = Sysctl {
+    path: "fs/pipe-user-pages-soft",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || PIPE_USER_PAGES_SOFT.load(Ordering::Relaxed) as _,
+        set: |value| PIPE_USER_PAGES_SOFT.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: i32::MAX as _,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static PIPE_USER_PAGES_HARD_SYSCTL: Sysctl = Sysctl {
+    path: "fs/pipe-user-pages-hard",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || PIPE_USER_PAGES_HARD.load(Ordering::Relaxed) as _,
+        set: |value| PIPE_USER_PAGES_HARD.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: i32::MAX as _,
+    },
+};
+
+/// A pipe user page limit, where 0 means none.
+fn page_limit(limit: &AtomicUsize) -> Option<usize> {
+    Some(limit.load(Ordering::Relaxed)).filter(|limit| *limit != 0)
+}
 
 struct Shared {
     buffer: Mutex<HeapRb<u8>>,
+    /// The pages of `buffer`, charged to the user who made the pipe.
+    pages: Mutex<Charge>,
     poll_rx: PollSet,
     poll_tx: PollSet,
     poll_close: PollSet,
+    /// Set once either end is gone, ahead of the wake on `poll_close`: the
+    /// count of `Arc<Shared>` the woken would go by only drops after it.
+    closed: AtomicBool,
+    /// The inode both ends share.
+    inode: AnonInode,
 }
 
 pub struct Pipe {
@@ -43,17 +114,39 @@ pub struct Pipe {
 }
 impl Drop for Pipe {
     fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
         self.shared.poll_close.wake();
     }
 }
 
 impl Pipe {
-    pub fn new() -> (Pipe, Pipe) {
+    /// Creates a pipe charged to the current user. Users other than root
+    /// past `fs/pipe-user-pages-soft` get a minimal buffer, and those past
+    /// `fs/pipe-user-pages-hard` none at all.
+    pub fn new() -> AxResult<(Pipe, Pipe)> {
+        let uid = sys_geteuid()? as u32;
+        let privileged = uid == 0;
+        let mut size = RING_BUFFER_INIT_SIZE;
+        if !privileged
+            && page_limit(&PIPE_USER_PAGES_SOFT)
+                .is_some_and(|soft| usage(uid, Resource::PipePages) + size / PAGE_SIZE_4K > soft)
+        {
+            size = RING_BUFFER_MIN_SIZE;
+        }
+        let hard = page_limit(&PIPE_USER_PAGES_HARD).filter(|_| !privileged);
+        let mut pages = Charge::new(uid, Resource::PipePages);
+        if !pages.set(size / PAGE_SIZE_4K, hard) {
+            return Err(AxError::from(LinuxError::ENFILE));
+        }
+
         let shared = Arc::new(Shared {
-            buffer: Mutex::new(HeapRb::new(RING_BUFFER_INIT_SIZE)),
+            buffer: Mutex::new(HeapRb::new(size)),
+            pages: Mutex::new(pages),
             poll_rx: PollSet::new(),
             poll_tx: PollSet::new(),
             poll_close: PollSet::new(),
+            closed: AtomicBool::new(false),
+            inode: AnonInode::new(),
         });
         let read_end = Pipe {
             read_side: true,
@@ -65,7 +158,7 @@ impl Pipe {
             shared,
             non_blocking: AtomicBool::new(false),
         };
-        (read_end, write_end)
+        Ok((read_end, write_end))
     }
 
     pub const fn is_read(&self) -> bool {
@@ -77,16 +170,24 @@ impl Pipe {
     }
 
     pub fn closed(&self) -> bool {
-        Arc::strong_count(&self.shared) == 1
+        self.shared.closed.load(Ordering::Acquire)
     }
 
     pub fn capacity(&self) -> usize {
         self.shared.buffer.lock().capacity().get()
     }
 
-    pub fn resize(&self, new_size: usize) -> AxResult<()> {
+    /// Identifies the buffer, which both ends share.
+    pub fn buffer_id(&self) -> usize {
+        Arc::as_ptr(&self.shared) as usize
+    }
+
+    /// Resizes the buffer. A user without `privileged` fails with `EPERM`
+    /// to grow it past a pipe user page limit.
+    pub fn resize(&self, new_size: usize, privileged: bool) -> AxResult<()> {
         let new_size = new_size.div_ceil(PAGE_SIZE_4K).max(1) * PAGE_SIZE_4K;
 
+        let _section = CriticalSection::enter(LockLevel::File);
         let mut buffer = self.shared.buffer.lock();
         if new_size == buffer.capacity().get() {
             return Ok(());
@@ -94,6 +195,14 @@ impl Pipe {
         if new_size < buffer.occupied_len() {
             return Err(AxError::ResourceBusy);
         }
+        let limit = [&PIPE_USER_PAGES_SOFT, &PIPE_USER_PAGES_HARD]
+            .into_iter()
+            .filter_map(page_limit)
+            .min()
+            .filter(|_| !privileged);
+        if !self.shared.pages.lock().set(new_size / PAGE_SIZE_4K, limit) {
+            return Err(AxError::OperationNotPermitted);
+        }
         let old_buffer = mem::replace(&mut *buffer, HeapRb::new(new_size));
         let (left, right) = old_buffer.as_slices();
         buffer.push_slice(left);
@@ -102,13 +211,14 @@ impl Pipe {
     }
 }
 
+/// Sends `SIGPIPE` to the writer once it is out of its critical section,
+/// since a handler may write to the same pipe.
 fn raise_pipe() {
-    let curr = current();
-    send_signal_to_process(
-        curr.as_thread().proc_data.proc.pid(),
-        Some(SignalInfo::new_kernel(Signo::SIGPIPE)),
-    )
-    .expect("Failed to send SIGPIPE");
+    let pid = current().as_thread().proc_data.proc.pid();
+    defer(move || {
+        send_signal_to_process(pid, Some(SignalInfo::new_kernel(Signo::SIGPIPE)))
+            .expect("Failed to send SIGPIPE");
+    });
 }
 
 impl FileLike for Pipe {
@@ -120,7 +230,8 @@ impl FileLike for Pipe {
             return Ok(0);
         }
 
-        block_on(poll_io(self, IoEvents::IN, self.nonblocking(), || {
+        wait_for_io(self, IoEvents::IN, self.nonblocking(), None, true, || {
+            let _section = CriticalSection::enter(LockLevel::File);
             let read = {
                 let cons = self.shared.buffer.lock();
                 let (left, right) = cons.as_slices();
@@ -139,7 +250,7 @@ impl FileLike for Pipe {
             } else {
                 Err(AxError::WouldBlock)
             }
-        }))
+        })
     }
 
     fn write(&self, src: &mut SealedBuf) -> AxResult<usize> {
@@ -153,14 +264,19 @@ impl FileLike for Pipe {
 
         let mut total_written = 0;
 
-        block_on(poll_io(self, IoEvents::OUT, self.nonblocking(), || {
+        let result = wait_for_io(self, IoEvents::OUT, self.nonblocking(), None, true, || {
+            let _section = CriticalSection::enter(LockLevel::File);
             if self.closed() {
                 raise_pipe();
                 return Err(AxError::BrokenPipe);
             }
 
-            let written = {
+            let written = 'written: {
                 let mut prod = self.shared.buffer.lock();
+                // Writes up to `PIPE_BUF` go in whole, once there is room.
+                if size <= PIPE_BUF && prod.vacant_len() < size {
+                    break 'written 0;
+                }
                 let (left, right) = prod.vacant_slices_mut();
                 let mut count = src.read(unsafe { left.assume_init_mut() })?;
                 if count >= left.len() {
@@ -177,14 +293,20 @@ impl FileLike for Pipe {
                 }
             }
             Err(AxError::WouldBlock)
-        }))
+        });
+        match result {
+            // A signal, a fault in `src` or the read end closing cuts the
+            // write short instead of failing it.
+            Err(_) if total_written > 0 => Ok(total_written),
+            result => result,
+        }
     }
 
     fn stat(&self) -> AxResult<Kstat> {
-        Ok(Kstat {
-            mode: S_IFIFO | if self.is_read() { 0o444 } else { 0o222 },
-            ..Default::default()
-        })
+        Ok(self
+            .shared
+            .inode
+            .kstat(S_IFIFO | if self.is_read() { 0o444 } else { 0o222 }))
     }
 
     fn path(&self) -> Cow<str> {
@@ -223,7 +345,8 @@ impl Pollable for Pipe {
             events.set(IoEvents::IN, buf.occupied_len() > 0);
             events.set(IoEvents::HUP, self.closed());
         } else {
-            events.set(IoEvents::OUT, buf.vacant_len() > 0);
+            events.set(IoEvents::OUT, buf.vacant_len() >= PIPE_BUF);
+            events.set(IoEvents::ERR, self.closed());
         }
         events
     }
@@ -0,0 +1,215 @@
+//! `SO_REUSEPORT` groups of TCP listeners.
+//!
+//! The network stack binds each port to one socket, so a group owns a single
+//! listener of its own, bound and listening on behalf of all members. Each
+//! member keeps a private accept queue. Whichever member looks first takes
+//! pending connections off the shared listener and queues each on the member
+//! picked by a hash of its 4-tuple among those listening; a member therefore
+//! only sees, and is only woken for, its own share.
+
+use alloc::{
+    collections::vec_deque::VecDeque,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Context,
+};
+
+use axerrno::{AxError, AxResult};
+use axnet::{
+    SocketAddrEx, SocketOps,
+    options::{Configurable, SetSocketOption},
+    tcp::TcpSocket,
+};
+use axpoll::{IoEvents, PollSet, Pollable};
+use axsync::Mutex;
+use starry_core::critical::{CriticalSection, LockLevel};
+
+struct Member {
+    listening: AtomicBool,
+    queue: Mutex<VecDeque<axnet::Socket>>,
+    poll_accept: PollSet,
+}
+
+struct Group {
+    addr: SocketAddr,
+    uid: u32,
+    listener: axnet::Socket,
+    listening: AtomicBool,
+    /// In order of joining.
+    members: Mutex<Vec<Arc<Member>>>,
+}
+
+/// Live groups. A group goes away with its last member.
+static GROUPS: Mutex<Vec<Weak<Group>>> = Mutex::new(Vec::new());
+
+/// A socket's place in a group.
+pub struct GroupMember {
+    group: Arc<Group>,
+    member: Arc<Member>,
+}
+
+/// FNV-1a over the 4-tuple of a connection.
+fn flow_hash(conn: &axnet::Socket) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let mut feed = |addr: AxResult<SocketAddrEx>| {
+        let Ok(SocketAddrEx::Ip(addr)) = addr else {
+            return;
+        };
+        let ip = match addr {
+            SocketAddr::V4(addr) => addr.ip().to_ipv6_mapped().octets(),
+            SocketAddr::V6(addr) => addr.ip().octets(),
+        };
+        for byte in ip.into_iter().chain(addr.port().to_be_bytes()) {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+        }
+    };
+    feed(conn.peer_addr());
+    feed(conn.local_addr());
+    hash
+}
+
+impl Group {
+    fn new(addr: SocketAddr, uid: u32) -> AxResult<Arc<Self>> {
+        let listener = axnet::Socket::Tcp(TcpSocket::new());
+        listener.set_option(SetSocketOption::NonBlocking(&true))?;
+        listener.bind(SocketAddrEx::Ip(addr))?;
+        // The port is known only now if it was chosen by the stack.
+        let addr = match listener.local_addr()? {
+            SocketAddrEx::Ip(addr) => addr,
+            _ => addr,
+        };
+        Ok(Arc::new(Self {
+            addr,
+            uid,
+            listener,
+            listening: AtomicBool::new(false),
+            members: Mutex::new(Vec::new()),
+        }))
+    }
+
+    /// Moves pending connections off the listener to the accept queues of
+    /// their members.
+    fn distribute(&self) {
+        // Members are woken with the group locked.
+        let _section = CriticalSection::enter(LockLevel::File);
+        let members = self.members.lock();
+        let listening = members
+            .iter()
+            .filter(|member| member.listening.load(Ordering::Acquire))
+            .collect::<Vec<_>>();
+        if listening.is_empty() {
+            // Leave them in the backlog until someone listens again.
+            return;
+        }
+        while let Ok(conn) = self.listener.accept() {
+            let member = listening[(flow_hash(&conn) % listening.len() as u64) as usize];
+            member.queue.lock().push_back(conn);
+            member.poll_accept.wake();
+        }
+    }
+}
+
+/// Binds a socket with `SO_REUSEPORT` set to `addr`, joining the group on
+/// it or starting one. Fails with `EADDRINUSE` if the group on `addr`
+/// belongs to another user.
+pub fn join(addr: SocketAddr, uid: u32) -> AxResult<GroupMember> {
+    let mut groups = GROUPS.lock();
+    groups.retain(|group| group.strong_count() > 0);
+    let existing = groups
+        .iter()
+        .filter_map(Weak::upgrade)
+        .find(|group| addr.port() != 0 && group.addr == addr);
+    let group = match existing {
+        Some(group) if group.uid != uid => return Err(AxError::AddrInUse),
+        Some(group) => group,
+        None => {
+            let group = Group::new(addr, uid)?;
+            groups.push(Arc::downgrade(&group));
+            group
+        }
+    };
+    drop(groups);
+
+    let member = Arc::new(Member {
+        listening: AtomicBool::new(false),
+        queue: Mutex::new(VecDeque::new()),
+        poll_accept: PollSet::new(),
+    });
+    group.members.lock().push(member.clone());
+    Ok(GroupMember { group, member })
+}
+
+impl GroupMember {
+    /// The address the group is bound to.
+    pub fn local_addr(&self) -> SocketAddrEx {
+        SocketAddrEx::Ip(self.group.addr)
+    }
+
+    /// Starts receiving a share of the group's connections.
+    pub fn listen(&self) -> AxResult<()> {
+        if !self.group.listening.swap(true, Ordering::AcqRel)
+            && let Err(err) = self.group.listener.listen()
+        {
+            self.group.listening.store(false, Ordering::Release);
+            return Err(err);
+        }
+        self.member.listening.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Stops receiving connections, failing accepts blocked on this member
+    /// with `EINVAL`. Connections already queued on it are dropped.
+    pub fn shutdown(&self) {
+        self.member.listening.store(false, Ordering::Release);
+        self.member.queue.lock().clear();
+        self.member.poll_accept.wake();
+    }
+
+    /// Takes a connection from this member's accept queue.
+    pub fn try_accept(&self) -> AxResult<axnet::Socket> {
+        if !self.member.listening.load(Ordering::Acquire) {
+            return Err(AxError::InvalidInput);
+        }
+        self.group.distribute();
+        self.member
+            .queue
+            .lock()
+            .pop_front()
+            .ok_or(AxError::WouldBlock)
+    }
+}
+
+impl Pollable for GroupMember {
+    fn poll(&self) -> IoEvents {
+        self.group.distribute();
+        if self.member.queue.lock().is_empty() {
+            IoEvents::empty()
+        } else {
+            IoEvents::IN
+        }
+    }
+
+    fn register(&self, context: &mut Context<'_>, events: IoEvents) {
+        if events.contains(IoEvents::IN) {
+            self.member.poll_accept.register(context.waker());
+            // A new connection wakes every member; the one it is for finds
+            // it queued after distributing.
+            self.group.listener.register(context, IoEvents::IN);
+        }
+    }
+}
+
+impl Drop for GroupMember {
+    fn drop(&mut self) {
+        // Connections queued on this member are reset with it; those of
+        // other members stay where they are.
+        self.group
+            .members
+            .lock()
+            .retain(|member| !Arc::ptr_eq(member, &self.member));
+    }
+}
@@ -9,16 +9,13 @@ use core::{
 use axerrno::{AxError, AxResult};
 use axio::{BufMut, Write};
 use axpoll::{IoEvents, PollSet, Pollable};
-use axtask::{
-    current,
-    future::{block_on, poll_io},
-};
+use axtask::current;
 use spin::RwLock;
-use starry_core::task::AsThread;
+use starry_core::{task::AsThread, timer::wait_for_io};
 use starry_signal::{SignalInfo, SignalSet};
 use zerocopy::{Immutable, IntoBytes};
 
-use crate::file::{FileLike, Kstat, SealedBufMut};
+use crate::file::{AnonInode, FileLike, Kstat, SealedBufMut};
 
 /// The size of signalfd_siginfo structure (128 bytes as per Linux
 /// specification)
@@ -83,6 +80,7 @@ pub struct Signalfd {
     mask: RwLock<SignalSet>,
     non_blocking: AtomicBool,
     poll_rx: PollSet,
+    inode: AnonInode,
 }
 
 impl Signalfd {
@@ -91,6 +89,7 @@ impl Signalfd {
             mask: RwLock::new(mask),
             non_blocking: AtomicBool::new(false),
             poll_rx: PollSet::new(),
+            inode: AnonInode::new(),
         })
     }
 
@@ -127,7 +126,7 @@ impl FileLike for Signalfd {
             return Err(AxError::InvalidInput);
         }
 
-        block_on(poll_io(self, IoEvents::IN, self.nonblocking(), || {
+        wait_for_io(self, IoEvents::IN, self.nonblocking(), None, true, || {
             if let Some(sig_info) = self.dequeue_signal() {
                 // Convert SignalInfo to SignalfdSiginfo
                 let sfd_info = SignalfdSiginfo::from_signal_info(&sig_info);
@@ -145,7 +144,7 @@ impl FileLike for Signalfd {
             } else {
                 Err(AxError::WouldBlock)
             }
-        }))
+        })
     }
 
     fn write(&self, _src: &mut crate::file::SealedBuf) -> AxResult<usize> {
@@ -154,7 +153,7 @@ impl FileLike for Signalfd {
     }
 
     fn stat(&self) -> AxResult<Kstat> {
-        Ok(Kstat::default())
+        Ok(self.inode.kstat(0o600))
     }
 
     fn nonblocking(&self) -> bool {
@@ -1,27 +1,79 @@
-use alloc::{borrow::Cow, sync::Arc};
+//! Timer file descriptors.
+//!
+//! A timerfd spawns no task of its own: an armed one is an entry of the
+//! kernel timer wheel, whose callback holds only a weak reference to it.
+//! Closing the last descriptor drops the timerfd, which cancels the entry,
+//! and a callback already taken finds nothing to upgrade and does nothing.
+
+use alloc::{
+    borrow::Cow,
+    collections::btree_map::BTreeMap,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
 use core::{
     any::Any,
     sync::atomic::{AtomicBool, Ordering},
     time::Duration,
 };
 
-use axerrno::{AxError, AxResult};
-use axhal::time::{monotonic_time, wall_time, TimeValue};
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::time::{monotonic_time, TimeValue};
 use axio::{BufMut, Write};
 use axpoll::{Pollable, IoEvents, PollSet};
 use axsync::Mutex;
-use axtask::future::{block_on, poll_io, timeout_at};
-use event_listener::{Event, listener};
-use linux_raw_sys::general::{CLOCK_MONOTONIC, CLOCK_REALTIME, itimerspec};
+use linux_raw_sys::general::{
+    CLOCK_REALTIME, CLOCK_REALTIME_ALARM, TFD_TIMER_ABSTIME, TFD_TIMER_CANCEL_ON_SET, itimerspec,
+};
+use starry_core::{
+    critical::{CriticalSection, LockLevel},
+    timer::{self, TimerHandle, wait_for_io},
+};
 
-use crate::file::{FileLike, Kstat, SealedBuf, SealedBufMut};
+use starry_vm::VmPtr;
+
+use crate::{
+    file::{AnonInode, FileLike, Kstat, SealedBuf, SealedBufMut},
+    time::{ITimerValueLike, timer_clock_time},
+};
 
 #[allow(dead_code)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug)]
 struct TimerState {
     ticks: u64,
     interval: Duration,
     next_expiration: Option<TimeValue>,
+    timer: Option<TimerHandle>,
+    /// The flags of the last `timerfd_settime`
+    flags: u32,
+    /// Set when a step of the clock cancelled the timer, until the next read
+    cancelled: bool,
+    /// How late expirations may fire: the timer slack of the thread that
+    /// armed it, or none for an absolute timer
+    slack: Duration,
+}
+
+/// `_IOW('T', 0, u64)`: sets the expiration count
+const TFD_IOC_SET_TICKS: u32 = 0x4008_5400;
+
+/// The `CLOCK_REALTIME` timerfds, by address, to catch up with steps of the
+/// clock
+static REALTIME_TIMERS: Mutex<BTreeMap<usize, Weak<TimerFd>>> = Mutex::new(BTreeMap::new());
+
+/// Catches the timerfds armed absolute on `CLOCK_REALTIME` up with a step of
+/// the clock: each fires right away if its time has now passed, and those
+/// armed with `TFD_TIMER_CANCEL_ON_SET` are cancelled instead.
+pub fn clock_was_set() {
+    let timers = REALTIME_TIMERS
+        .lock()
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect::<Vec<_>>();
+    for timer in timers {
+        timer.on_clock_set();
+    }
 }
 
 #[allow(dead_code)]
@@ -30,48 +82,79 @@ pub struct TimerFd {
     state: Mutex<TimerState>,
     non_blocking: AtomicBool,
     poll_read: PollSet,
-    update_event: Event,
+    inode: AnonInode,
 }
 
 #[allow(dead_code)]
 impl TimerFd {
+    /// Creates a timerfd on `clockid`, failing with `EINVAL` for a clock a
+    /// timerfd cannot run on.
     pub fn new(clockid: i32, _flags: i32) -> AxResult<Arc<Self>> {
+        if timer_clock_time(clockid as u32).is_none() {
+            return Err(AxError::InvalidInput);
+        }
         let timer = Arc::new(Self {
             clockid,
             state: Mutex::new(TimerState {
                 ticks: 0,
                 interval: Duration::ZERO,
                 next_expiration: None,
+                timer: None,
+                flags: 0,
+                cancelled: false,
+                slack: Duration::ZERO,
             }),
             non_blocking: AtomicBool::new(false),
             poll_read: PollSet::new(),
-            update_event: Event::new(),
+            inode: AnonInode::new(),
         });
-
-        let t = timer.clone();
-        axtask::spawn(move || block_on(t.timer_loop()));
+        if timer.is_realtime() {
+            REALTIME_TIMERS
+                .lock()
+                .insert(Arc::as_ptr(&timer) as usize, Arc::downgrade(&timer));
+        }
         Ok(timer)
     }
 
+    fn is_realtime(&self) -> bool {
+        matches!(self.clockid as u32, CLOCK_REALTIME | CLOCK_REALTIME_ALARM)
+    }
+
     pub fn current_time(&self) -> TimeValue {
-        match self.clockid {
-            c if c == CLOCK_MONOTONIC as i32 => monotonic_time(),
-            c if c == CLOCK_REALTIME as i32 => wall_time(),
-            _ => monotonic_time(),
+        timer_clock_time(self.clockid as u32).unwrap_or_else(monotonic_time)
+    }
+
+    /// Returns the time on the clock an expiration armed with `flags` is
+    /// kept on: the clock of the timer, but for a relative one on the wall
+    /// clock, kept on the monotonic clock as no step of the wall clock
+    /// moves it.
+    fn clock_time(&self, flags: u32) -> TimeValue {
+        if self.is_realtime() && flags & TFD_TIMER_ABSTIME == 0 {
+            monotonic_time()
+        } else {
+            self.current_time()
         }
     }
 
     pub fn set_time(
-        &self,
+        self: &Arc<Self>,
         flags: i32,
         new_value: &itimerspec,
         old_value: Option<&mut itimerspec>,
     ) -> AxResult<()> {
+        let flags = flags as u32;
+        if flags & !(TFD_TIMER_ABSTIME | TFD_TIMER_CANCEL_ON_SET) != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        // Checked before anything changes, `old_value` included.
+        let (interval, value) = new_value.try_into_time_values()?;
+
+        let _section = CriticalSection::enter(LockLevel::File);
         let mut state = self.state.lock();
 
         if let Some(old) = old_value {
             if let Some(exp) = state.next_expiration {
-                let now = self.current_time();
+                let now = self.clock_time(state.flags);
                 let remaining = exp.saturating_sub(now);
 
                 old.it_value.tv_sec = remaining.as_secs() as _;
@@ -86,32 +169,25 @@ impl TimerFd {
             }
         }
 
-        let interval = Duration::new(
-            new_value.it_interval.tv_sec as u64,
-            new_value.it_interval.tv_nsec as u32,
-        );
-
-        let value = Duration::new(
-            new_value.it_value.tv_sec as u64,
-            new_value.it_value.tv_nsec as u32,
-        );
         state.interval = interval;
+        state.flags = flags;
+        state.cancelled = false;
+        state.slack = if flags & TFD_TIMER_ABSTIME != 0 {
+            Duration::ZERO
+        } else {
+            timer::current_timer_slack()
+        };
 
         if value.is_zero() {
             state.next_expiration = None;
+        } else if flags & TFD_TIMER_ABSTIME != 0 {
+            // A reading of the timer's clock
+            state.next_expiration = Some(value);
         } else {
-            // Arm timer
-            let now = self.current_time();
-            let target = if flags & 1 != 0 {
-                TimeValue::from_nanos(value.as_nanos() as u64)
-            } else {
-                // Relative time
-                now + value
-            };
-            state.next_expiration = Some(target);
+            state.next_expiration = Some(self.clock_time(flags) + value);
         }
 
-        self.update_event.notify(usize::MAX);
+        self.arm(&mut state);
         Ok(())
     }
 
@@ -122,7 +198,7 @@ impl TimerFd {
     
     pub fn get_time(&self, curr_value: &mut itimerspec) {
         let state = self.state.lock();
-        let now = self.current_time();
+        let now = self.clock_time(state.flags);
 
         let remaining = state
             .next_expiration
@@ -135,43 +211,82 @@ impl TimerFd {
         curr_value.it_interval.tv_nsec = state.interval.subsec_nanos() as _;
     }
 
-    async fn timer_loop(&self) {
-        loop {
-            let target = {
-                let state = self.state.lock();
-                state.next_expiration
-            };
-
-            if let Some(target_time) = target {
-                // Translate the monotonic-based target to the wall-clock deadline used by timeout_at.
-                let now_mono = self.current_time();
-                let delta = target_time.saturating_sub(now_mono);
-
-                if delta.is_zero() {
-                    let mut state = self.state.lock();
-                    if state.next_expiration == Some(target_time) {
-                        state.ticks += 1;
-                        self.poll_read.wake();
-
-                        if !state.interval.is_zero() {
-                            state.next_expiration = Some(now_mono + state.interval);
-                        } else {
-                            state.next_expiration = None;
-                        }
-                    }
-                    continue;
-                }
-
-                let deadline = wall_time() + delta;
-                listener!(self.update_event => listener);
-                let _ = timeout_at(Some(deadline), listener).await;
-            } else {
-                listener!(self.update_event => listener);
-                let _ = listener.await;
+    /// (Re)arms the wheel timer for the current expiration.
+    fn arm(self: &Arc<Self>, state: &mut TimerState) {
+        if let Some(timer) = state.timer.take() {
+            timer.cancel();
+        }
+        let Some(target) = state.next_expiration else {
+            return;
+        };
+        // The wheel runs on the monotonic clock.
+        let deadline = monotonic_time() + target.saturating_sub(self.clock_time(state.flags));
+        let deadline = timer::apply_slack(deadline, state.slack);
+        let this = Arc::downgrade(self);
+        state.timer = Some(timer::register(deadline, move || {
+            if let Some(this) = this.upgrade() {
+                this.expire(target);
             }
+        }));
+    }
+
+    fn expire(self: &Arc<Self>, target: TimeValue) {
+        let _section = CriticalSection::enter(LockLevel::File);
+        let mut state = self.state.lock();
+        // Re-armed or disarmed after the callback was taken
+        if state.next_expiration != Some(target) {
+            return;
         }
+        state.timer = None;
+        if state.interval.is_zero() {
+            state.ticks += 1;
+            state.next_expiration = None;
+        } else {
+            // Periods follow from the target, not from when the callback
+            // ran, and every period that passed meanwhile counts as a tick.
+            let late = self.clock_time(state.flags).saturating_sub(target);
+            let periods = (late.as_nanos() / state.interval.as_nanos()) as u64 + 1;
+            state.ticks = state.ticks.saturating_add(periods);
+            let advance = state.interval.as_nanos().saturating_mul(periods as u128);
+            let advance = Duration::from_nanos(advance.min(u64::MAX as u128) as u64);
+            state.next_expiration = Some(target.saturating_add(advance));
+        }
+        self.arm(&mut state);
+        drop(state);
+        self.poll_read.wake();
     }
 
+    /// Catches up with a step of `CLOCK_REALTIME`, which only matters to an
+    /// absolute timer.
+    fn on_clock_set(self: &Arc<Self>) {
+        let _section = CriticalSection::enter(LockLevel::File);
+        let mut state = self.state.lock();
+        if state.flags & TFD_TIMER_ABSTIME == 0 || state.next_expiration.is_none() {
+            return;
+        }
+        let cancel = state.flags & TFD_TIMER_CANCEL_ON_SET != 0;
+        if cancel {
+            state.next_expiration = None;
+            state.cancelled = true;
+        }
+        // Fires right away if the target has now passed.
+        self.arm(&mut state);
+        drop(state);
+        if cancel {
+            self.poll_read.wake();
+        }
+    }
+}
+
+impl Drop for TimerFd {
+    fn drop(&mut self) {
+        if let Some(timer) = self.state.get_mut().timer.take() {
+            timer.cancel();
+        }
+        if self.is_realtime() {
+            REALTIME_TIMERS.lock().remove(&(self as *const Self as usize));
+        }
+    }
 }
 
 impl FileLike for TimerFd {
@@ -180,9 +295,14 @@ impl FileLike for TimerFd {
             return Err(AxError::InvalidInput);
         }
 
-        block_on(poll_io(self, IoEvents::IN, self.nonblocking(), || {
+        wait_for_io(self, IoEvents::IN, self.nonblocking(), None, true, || {
+            let _section = CriticalSection::enter(LockLevel::File);
             let mut state = self.state.lock();
-            if state.ticks > 0 {
+            if state.cancelled {
+                state.cancelled = false;
+                state.ticks = 0;
+                Err(AxError::from(LinuxError::ECANCELED))
+            } else if state.ticks > 0 {
                 let ticks = state.ticks;
                 state.ticks = 0;
                 dst.write(&ticks.to_ne_bytes())?;
@@ -190,7 +310,7 @@ impl FileLike for TimerFd {
             } else {
                 Err(AxError::WouldBlock)
             }
-        }))
+        })
     }
 
     fn write(&self, _src: &mut SealedBuf) -> AxResult<usize> {
@@ -198,7 +318,7 @@ impl FileLike for TimerFd {
     }
 
     fn stat(&self) -> AxResult<Kstat> {
-        Ok(Kstat::default())
+        Ok(self.inode.kstat(0o600))
     }
 
     fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
@@ -214,6 +334,39 @@ impl FileLike for TimerFd {
         Ok(())
     }
 
+    fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
+        match cmd {
+            TFD_IOC_SET_TICKS => {
+                let ticks = (arg as *const u64).vm_read()?;
+                let _section = CriticalSection::enter(LockLevel::File);
+                self.state.lock().ticks = ticks;
+                if ticks != 0 {
+                    self.poll_read.wake();
+                }
+                Ok(0)
+            }
+            _ => Err(AxError::NotATty),
+        }
+    }
+
+    fn fdinfo(&self) -> String {
+        let state = self.state.lock();
+        let remaining = state
+            .next_expiration
+            .map_or(Duration::ZERO, |exp| exp.saturating_sub(self.clock_time(state.flags)));
+        format!(
+            "clockid: {}\nticks: {}\nsettime flags: 0{:o}\nit_value: ({}, {})\n\
+             it_interval: ({}, {})\n",
+            self.clockid,
+            state.ticks,
+            state.flags,
+            remaining.as_secs(),
+            remaining.subsec_nanos(),
+            state.interval.as_secs(),
+            state.interval.subsec_nanos(),
+        )
+    }
+
     fn path(&self) -> Cow<str> {
         "anon_inode:[TimerFd]".into()
     }
@@ -222,7 +375,7 @@ impl FileLike for TimerFd {
 impl Pollable for TimerFd {
     fn poll(&self) -> IoEvents {
         let state = self.state.lock();
-        if state.ticks > 0 {
+        if state.ticks > 0 || state.cancelled {
             IoEvents::IN | IoEvents::RDNORM
         } else {
             IoEvents::empty()
@@ -0,0 +1,71 @@
+//! Per-user accounting of kernel memory held through file descriptors.
+//!
+//! Pipe buffers and epoll watches are charged to the effective user that
+//! created them. A [`Charge`] gives back what it holds when dropped, so a
+//! resource is uncharged however it goes away.
+
+use alloc::collections::btree_map::BTreeMap;
+
+use kspin::SpinNoPreempt;
+
+/// A resource charged per user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Resource {
+    /// Pages of pipe buffers.
+    PipePages,
+    /// Interests registered with epoll instances.
+    EpollWatches,
+}
+
+/// Totals by user and resource. Entries dropping to zero are removed.
+static USAGE: SpinNoPreempt<BTreeMap<(u32, Resource), usize>> = SpinNoPreempt::new(BTreeMap::new());
+
+/// How much of `resource` is charged to `uid`.
+pub fn usage(uid: u32, resource: Resource) -> usize {
+    USAGE.lock().get(&(uid, resource)).copied().unwrap_or(0)
+}
+
+/// An amount of a resource charged to a user.
+pub struct Charge {
+    uid: u32,
+    resource: Resource,
+    amount: usize,
+}
+
+impl Charge {
+    /// Creates a charge of nothing.
+    pub const fn new(uid: u32, resource: Resource) -> Self {
+        Self {
+            uid,
+            resource,
+            amount: 0,
+        }
+    }
+
+    /// Changes the amount charged to `amount`. Growing it fails, changing
+    /// nothing, if the user's total would exceed `limit`.
+    pub fn set(&mut self, amount: usize, limit: Option<usize>) -> bool {
+        let mut usage = USAGE.lock();
+        let total = usage.entry((self.uid, self.resource)).or_insert(0);
+        let new_total = *total - self.amount + amount;
+        if amount > self.amount && limit.is_some_and(|limit| new_total > limit) {
+            if *total == 0 {
+                usage.remove(&(self.uid, self.resource));
+            }
+            return false;
+        }
+        if new_total == 0 {
+            usage.remove(&(self.uid, self.resource));
+        } else {
+            *total = new_total;
+        }
+        self.amount = amount;
+        true
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.set(0, None);
+    }
+}
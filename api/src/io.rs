@@ -1,10 +1,19 @@
+use alloc::vec::Vec;
 use core::mem::{self, MaybeUninit};
 
 use axerrno::{AxError, AxResult};
 use axio::{Buf, BufMut, Read, Write};
 use bytemuck::AnyBitPattern;
+use memory_addr::PAGE_SIZE_4K;
 use starry_vm::{VmPtr, vm_read_slice, vm_write_slice};
 
+/// The most segments an I/O vector may have, `IOV_MAX`.
+pub const UIO_MAXIOV: usize = 1024;
+
+/// The most bytes a single read or write moves, the largest page-aligned
+/// `i32`, as on Linux.
+const MAX_RW_COUNT: usize = i32::MAX as usize & !(PAGE_SIZE_4K - 1);
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, AnyBitPattern)]
 pub struct IoVec {
@@ -12,27 +21,42 @@ pub struct IoVec {
     pub iov_len: isize,
 }
 
+/// An I/O vector of user memory, copied in once so that the segments cannot
+/// change under the transfer.
 #[derive(Default)]
 pub struct IoVectorBuf {
-    iovs: *const IoVec,
-    iovcnt: usize,
+    iovs: Vec<IoVec>,
     len: usize,
 }
 
 impl IoVectorBuf {
+    /// Copies in the `iovcnt` segments at `iovs`, failing with `EINVAL` if
+    /// there are more than [`UIO_MAXIOV`] or one has a negative length.
+    /// Empty segments are left out, and the total is cut down to
+    /// `MAX_RW_COUNT`, shortening the segment that crosses it, as Linux does.
     pub fn new(iovs: *const IoVec, iovcnt: usize) -> AxResult<Self> {
-        if iovcnt > 1024 {
+        if iovcnt > UIO_MAXIOV {
             return Err(AxError::InvalidInput);
         }
+        let mut segments = Vec::with_capacity(iovcnt);
         let mut len = 0;
         for i in 0..iovcnt {
-            let iov = iovs.wrapping_add(i).vm_read()?;
+            let mut iov = iovs.wrapping_add(i).vm_read()?;
             if iov.iov_len < 0 {
                 return Err(AxError::InvalidInput);
             }
-            len += iov.iov_len as usize;
+            let seg_len = (iov.iov_len as usize).min(MAX_RW_COUNT - len);
+            if seg_len == 0 {
+                continue;
+            }
+            iov.iov_len = seg_len as isize;
+            len += seg_len;
+            segments.push(iov);
         }
-        Ok(Self { iovs, iovcnt, len })
+        Ok(Self {
+            iovs: segments,
+            len,
+        })
     }
 
     pub fn read_with(
@@ -40,11 +64,7 @@ impl IoVectorBuf {
         mut f: impl FnMut(*const u8, usize) -> AxResult<usize>,
     ) -> AxResult<usize> {
         let mut count = 0;
-        for i in 0..self.iovcnt {
-            let iov = self.iovs.wrapping_add(i).vm_read()?;
-            if iov.iov_len == 0 {
-                continue;
-            }
+        for iov in &self.iovs {
             let read = f(iov.iov_base, iov.iov_len as usize)?;
             if read == 0 {
                 break;
@@ -59,11 +79,7 @@ impl IoVectorBuf {
         mut f: impl FnMut(*mut u8, usize) -> AxResult<usize>,
     ) -> AxResult<usize> {
         let mut count = 0;
-        for i in 0..self.iovcnt {
-            let iov = self.iovs.wrapping_add(i).vm_read()?;
-            if iov.iov_len == 0 {
-                continue;
-            }
+        for iov in &self.iovs {
             let written = f(iov.iov_base, iov.iov_len as usize)?;
             if written == 0 {
                 break;
@@ -82,6 +98,9 @@ impl IoVectorBuf {
     }
 }
 
+/// An [`IoVectorBuf`] gone through in order, as the source of a write or the
+/// destination of a read. A fault once some bytes were moved ends the
+/// transfer short with them, as on Linux.
 pub struct IoVectorBufIo {
     inner: IoVectorBuf,
     start: usize,
@@ -89,37 +108,42 @@ pub struct IoVectorBufIo {
 }
 
 impl IoVectorBufIo {
-    fn skip_empty(&mut self) -> AxResult<()> {
-        while self.start < self.inner.iovcnt {
-            let iov = self.inner.iovs.wrapping_add(self.start).vm_read()?;
-            if iov.iov_len as usize > self.offset {
-                break;
-            }
-            self.offset = 0;
+    /// Returns where the rest of the current segment starts and its length,
+    /// or `None` at the end.
+    fn current(&self) -> Option<(*mut u8, usize)> {
+        let iov = self.inner.iovs.get(self.start)?;
+        Some((
+            iov.iov_base.wrapping_add(self.offset),
+            iov.iov_len as usize - self.offset,
+        ))
+    }
+
+    fn advance(&mut self, len: usize) {
+        self.offset += len;
+        self.inner.len -= len;
+        if self.offset == self.inner.iovs[self.start].iov_len as usize {
             self.start += 1;
+            self.offset = 0;
         }
-        Ok(())
     }
 }
 
 impl Read for IoVectorBufIo {
     fn read(&mut self, buf: &mut [u8]) -> AxResult<usize> {
         let mut count = 0;
-        loop {
-            self.skip_empty()?;
-            if self.start >= self.inner.iovcnt {
-                break;
-            }
-            let iov = self.inner.iovs.wrapping_add(self.start).vm_read()?;
-            let len = (iov.iov_len as usize - self.offset).min(buf.len() - count);
-            if len == 0 {
-                break;
-            }
-            vm_read_slice(iov.iov_base.wrapping_add(self.offset), unsafe {
+        while count < buf.len()
+            && let Some((base, len)) = self.current()
+        {
+            let len = len.min(buf.len() - count);
+            let dst = unsafe {
                 mem::transmute::<&mut [u8], &mut [MaybeUninit<u8>]>(&mut buf[count..count + len])
-            })?;
-            self.offset += len;
-            self.inner.len -= len;
+            };
+            match vm_read_slice(base, dst) {
+                Ok(()) => {}
+                Err(_) if count > 0 => break,
+                Err(err) => return Err(err.into()),
+            }
+            self.advance(len);
             count += len;
         }
         Ok(count)
@@ -135,22 +159,16 @@ impl Buf for IoVectorBufIo {
 impl Write for IoVectorBufIo {
     fn write(&mut self, buf: &[u8]) -> AxResult<usize> {
         let mut count = 0;
-        loop {
-            self.skip_empty()?;
-            if self.start >= self.inner.iovcnt {
-                break;
-            }
-            let iov = self.inner.iovs.wrapping_add(self.start).vm_read()?;
-            let len = (iov.iov_len as usize - self.offset).min(buf.len() - count);
-            if len == 0 {
-                break;
+        while count < buf.len()
+            && let Some((base, len)) = self.current()
+        {
+            let len = len.min(buf.len() - count);
+            match vm_write_slice(base, &buf[count..count + len]) {
+                Ok(()) => {}
+                Err(_) if count > 0 => break,
+                Err(err) => return Err(err.into()),
             }
-            vm_write_slice(
-                iov.iov_base.wrapping_add(self.offset),
-                &buf[count..count + len],
-            )?;
-            self.offset += len;
-            self.inner.len -= len;
+            self.advance(len);
             count += len;
         }
         Ok(count)
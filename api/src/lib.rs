@@ -10,9 +10,11 @@ extern crate axlog;
 
 extern crate alloc;
 
+pub mod coredump;
 pub mod file;
 pub mod io;
 pub mod mm;
+pub mod oom;
 pub mod signal;
 pub mod socket;
 pub mod syscall;
@@ -25,12 +27,15 @@ pub mod vfs;
 pub fn init() {
     info!("Initialize VFS...");
     vfs::mount_all().expect("Failed to mount vfs");
+    vfs::page_cache::init();
 
-    info!("Initialize /proc/interrupts...");
+    info!("Initialize timers...");
+    starry_core::timer::spawn_timer_task();
+    starry_core::time::set_clock_was_set_fn(file::timerfd::clock_was_set);
     axtask::register_timer_callback(|_| {
         time::inc_irq_cnt();
+        starry_core::timer::check_expiry();
+        // Charge the interrupted task for the tick, advancing CPU timers.
+        starry_core::task::poll_timer(&axtask::current());
     });
-
-    info!("Initialize alarm...");
-    starry_core::time::spawn_alarm_task();
 }
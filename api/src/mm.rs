@@ -1,4 +1,4 @@
-use alloc::string::String;
+use alloc::{string::String, sync::Arc};
 use core::{
     alloc::Layout,
     ffi::c_char,
@@ -8,19 +8,29 @@ use core::{
 };
 
 use axerrno::{AxError, AxResult};
+use axfs::FileBackend;
+use axfs_ng_vfs::{Location, MetadataUpdate};
 use axhal::{
     paging::MappingFlags,
     trap::{PAGE_FAULT, register_trap_handler},
 };
 use axio::{Buf, BufMut, Read, Write};
 use axtask::current;
-use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr, VirtAddrRange};
 use starry_core::{
-    mm::{access_user_memory, is_accessing_user_memory},
-    task::AsThread,
+    mm::{FaultAround, access_user_memory, is_accessing_user_memory},
+    task::{AsThread, ProcessData},
+    time::realtime,
 };
 use starry_vm::{vm_load_until_nul, vm_read_slice, vm_write_slice};
 
+use crate::vfs::{
+    MemoryFs,
+    mounts::mount_ra_pages,
+    readahead::{ReadaheadState, fault_readahead, offset_to_page},
+    writeback::writeback,
+};
+
 fn check_region(start: VirtAddr, layout: Layout, access_flags: MappingFlags) -> AxResult<()> {
     let align = layout.align();
     if start.as_usize() & (align - 1) != 0 {
@@ -249,10 +259,119 @@ fn handle_page_fault(vaddr: VirtAddr, access_flags: MappingFlags) -> bool {
         return false;
     };
 
-    thr.proc_data
-        .aspace
-        .lock()
-        .handle_page_fault(vaddr, access_flags)
+    check_file_fault(&thr.proc_data, vaddr, access_flags)
+        && thr
+            .proc_data
+            .aspace
+            .lock()
+            .handle_page_fault(vaddr, access_flags)
+}
+
+/// Checks a fault on a shared file mapping against the file before it is
+/// handled. Returns `false` if it must raise `SIGBUS` instead: the page is
+/// wholly past the end of file, or there is no room left to write it.
+///
+/// A write is accounted to the file as a write syscall would be, and marks
+/// the page dirty.
+pub fn check_file_fault(
+    proc_data: &ProcessData,
+    vaddr: VirtAddr,
+    access_flags: MappingFlags,
+) -> bool {
+    let mappings = proc_data.file_mappings.lock();
+    let Some((mapping, offset)) = mappings.find(vaddr) else {
+        return true;
+    };
+    if !mapping.shared {
+        return true;
+    }
+    let page = offset & !(PAGE_SIZE_4K as u64 - 1);
+    match mapping.loc.len() {
+        Ok(size) if page < size => {}
+        _ => return false,
+    }
+    if access_flags.contains(MappingFlags::WRITE) {
+        if MemoryFs::reserve(&mapping.loc, page, PAGE_SIZE_4K as u64).is_err() {
+            return false;
+        }
+        writeback(&mapping.loc, &mapping.backend).mark_dirty(offset_to_page(page), 1);
+        let _ = mapping.loc.update_metadata(MetadataUpdate {
+            mtime: Some(realtime()),
+            ..Default::default()
+        });
+    }
+    true
+}
+
+/// The readahead state of a file mapping, apart from that of the file
+/// mapped, so that faults and reads do not break each other's pattern.
+struct MappingReadahead {
+    state: ReadaheadState,
+    backend: FileBackend,
+    loc: Location,
+}
+
+impl FaultAround for MappingReadahead {
+    fn fault_around(&self, offset: u64) -> usize {
+        let Ok(size) = self.loc.len() else {
+            return 1;
+        };
+        fault_readahead(&self.state, &self.backend, offset_to_page(offset), size) as usize
+    }
+}
+
+/// Returns the readahead for faults on a mapping of `backend`, if it has a
+/// page cache to read ahead into.
+pub fn mapping_readahead(backend: &FileBackend, loc: &Location) -> Option<Arc<dyn FaultAround>> {
+    matches!(backend, FileBackend::Cached(_)).then(|| {
+        Arc::new(MappingReadahead {
+            state: ReadaheadState::with_default_limit(mount_ra_pages(loc.mountpoint())),
+            backend: backend.clone(),
+            loc: loc.clone(),
+        }) as _
+    })
+}
+
+/// Reads ahead for a user fault on a file mapping at `vaddr`, before it is
+/// handled. Returns the pages from the faulting one on that are cached, for
+/// [`map_around`] to map once the fault is handled.
+pub fn fault_readahead_at(proc_data: &ProcessData, vaddr: VirtAddr) -> Option<VirtAddrRange> {
+    let mappings = proc_data.file_mappings.lock();
+    let (mapping, offset) = mappings.find(vaddr)?;
+    let readahead = mapping.readahead.clone()?;
+    let end = mapping.end;
+    drop(mappings);
+
+    let pages = readahead.fault_around(offset);
+    let start = vaddr.align_down_4k();
+    let len = pages.min((end - start) / PAGE_SIZE_4K) * PAGE_SIZE_4K;
+    Some(VirtAddrRange::from_start_size(start, len))
+}
+
+/// Maps the pages of `range` not mapped yet, after the fault on its first
+/// page was handled, so that a sequential scan of a mapping does not fault
+/// on every page.
+pub fn map_around(proc_data: &ProcessData, range: VirtAddrRange) {
+    let mut aspace = proc_data.aspace.lock();
+    let Some((flags, area_end)) = aspace
+        .find_area(range.start)
+        .map(|area| (area.flags(), area.end()))
+    else {
+        return;
+    };
+    if !flags.contains(MappingFlags::READ) {
+        return;
+    }
+    let end = range.end.min(area_end);
+    let mut vaddr = range.start + PAGE_SIZE_4K;
+    while vaddr < end {
+        if aspace.page_table().query(vaddr).is_err()
+            && !aspace.handle_page_fault(vaddr, MappingFlags::READ)
+        {
+            break;
+        }
+        vaddr += PAGE_SIZE_4K;
+    }
 }
 
 pub fn vm_load_string(ptr: *const c_char) -> AxResult<String> {
@@ -0,0 +1,331 @@
+//! Memory overcommit policy and the OOM killer.
+//!
+//! Private writable and shared anonymous mappings, the heap and the copies
+//! made by `fork` are committed to up front, as set by
+//! `vm/overcommit_memory`: refused only when they could never fit (0),
+//! always granted (1), or refused past the commit limit (2), which is a
+//! `vm/overcommit_ratio` percent share of RAM; swap areas back no memory
+//! yet, so they add nothing.
+//!
+//! Overcommitted memory may still run out when it is touched. A user page
+//! fault that fails for lack of memory then reclaims clean pages of the page
+//! cache if it can, and otherwise kills the process with the highest
+//! badness, waits for it to exit, tears down its address space and retries.
+//! A fault the memory mapping layer fails while pages are left, as a file
+//! mapping failing to read its page does, raises `SIGBUS` instead. A victim
+//! that does not exit within [`OOM_WAIT`] is passed over from then on, and
+//! the next one is killed.
+//! Badness is the memory a process has committed to, which stands
+//! in for its resident set as no per-page accounting exists, shifted by the
+//! `oom_score_adj` of its main thread; `-1000` makes it unkillable.
+
+use alloc::sync::Arc;
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicU32, Ordering},
+    task::Poll,
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult};
+use axhal::paging::MappingFlags;
+use axsync::Mutex;
+use axtask::{
+    current,
+    future::{block_on, interruptible},
+};
+use linkme::distributed_slice;
+use memory_addr::{PAGE_SIZE_4K, VirtAddr, VirtAddrRange};
+use starry_core::{
+    mm::Commitments,
+    sysctl::{SYSCTLS, Sysctl, SysctlKind},
+    task::{AsThread, ProcessData, for_each_process, get_task, send_signal_to_process},
+    timer::timeout,
+};
+use starry_process::Pid;
+use starry_signal::{SignalInfo, Signo};
+
+use crate::vfs::page_cache::shrink_page_cache;
+
+/// Heuristic overcommit: only what could never fit is refused.
+const OVERCOMMIT_GUESS: u32 = 0;
+/// Every commitment is granted.
+const OVERCOMMIT_ALWAYS: u32 = 1;
+/// Commitments past the commit limit are refused.
+const OVERCOMMIT_NEVER: u32 = 2;
+
+/// The `oom_score_adj` of processes never killed.
+pub const OOM_SCORE_ADJ_MIN: i32 = -1000;
+/// The highest `oom_score_adj`.
+pub const OOM_SCORE_ADJ_MAX: i32 = 1000;
+
+static OVERCOMMIT_MEMORY: AtomicU32 = AtomicU32::new(OVERCOMMIT_GUESS);
+static OVERCOMMIT_RATIO: AtomicU32 = AtomicU32::new(50);
+
+#[distributed_slice(SYSCTLS)]
+static OVERCOMMIT_MEMORY_SYSCTL: Sysctl = Sysctl {
+    path: "vm/overcommit_memory",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || OVERCOMMIT_MEMORY.load(Ordering::Relaxed) as _,
+        set: |value| OVERCOMMIT_MEMORY.store(value as _, Ordering::Relaxed),
+        min: OVERCOMMIT_GUESS as _,
+        max: OVERCOMMIT_NEVER as _,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static OVERCOMMIT_RATIO_SYSCTL: Sysctl = Sysctl {
+    path: "vm/overcommit_ratio",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || OVERCOMMIT_RATIO.load(Ordering::Relaxed) as _,
+        set: |value| OVERCOMMIT_RATIO.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: u32::MAX as _,
+    },
+};
+
+/// Pages of the page cache reclaimed at once before killing.
+const RECLAIM_BATCH: usize = 32;
+
+/// The process being killed for memory, or 0.
+static OOM_VICTIM: AtomicU32 = AtomicU32::new(0);
+
+/// How long a fault waits for its victim to exit.
+const OOM_WAIT: Duration = Duration::from_secs(1);
+
+/// Victims that did not exit in time, passed over until they do, or 0. A
+/// fixed array, as nothing may be allocated while out of memory; when it is
+/// full the oldest is forgotten.
+static OOM_SKIPPED: Mutex<[Pid; 8]> = Mutex::new([0; 8]);
+
+/// Free pages under which a failed fault is taken as out of memory: enough
+/// for the page, its page tables and a fault-around batch.
+const FAULT_RESERVE_PAGES: usize = 16;
+
+/// Returns the pages of RAM.
+pub fn total_pages() -> usize {
+    let allocator = axalloc::global_allocator();
+    allocator.used_pages() + allocator.available_pages()
+}
+
+/// Returns the bytes that may be committed with overcommit off.
+pub fn commit_limit() -> usize {
+    let ratio = OVERCOMMIT_RATIO.load(Ordering::Relaxed) as usize;
+    (total_pages().saturating_mul(ratio) / 100).saturating_mul(PAGE_SIZE_4K)
+}
+
+/// Returns the limit on the committed total for committing `size` more
+/// bytes, failing with `ENOMEM` if they are refused outright.
+fn limit_for(size: usize) -> AxResult<Option<usize>> {
+    match OVERCOMMIT_MEMORY.load(Ordering::Relaxed) {
+        OVERCOMMIT_ALWAYS => Ok(None),
+        OVERCOMMIT_NEVER => Ok(Some(commit_limit())),
+        _ => {
+            if size > total_pages().saturating_mul(PAGE_SIZE_4K) {
+                Err(AxError::NoMemory)
+            } else {
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Commits `range` of the address space of `proc_data`, failing with
+/// `ENOMEM` if the policy refuses it. A `noreserve` range is left
+/// uncommitted, unless overcommit is off.
+pub fn commit(proc_data: &ProcessData, range: VirtAddrRange, noreserve: bool) -> AxResult<()> {
+    if noreserve && OVERCOMMIT_MEMORY.load(Ordering::Relaxed) != OVERCOMMIT_NEVER {
+        return Ok(());
+    }
+    let limit = limit_for(range.size())?;
+    if proc_data.commitments.lock().insert(range, limit) {
+        Ok(())
+    } else {
+        Err(AxError::NoMemory)
+    }
+}
+
+/// Commits a copy of what `proc_data` has committed to, for a fork,
+/// failing with `ENOMEM` if the policy refuses it.
+pub fn commit_fork(proc_data: &ProcessData) -> AxResult<Commitments> {
+    let commitments = proc_data.commitments.lock();
+    let limit = limit_for(commitments.size())?;
+    commitments.try_clone(limit).ok_or(AxError::NoMemory)
+}
+
+/// Returns the `oom_score_adj` of the main thread of `proc_data`.
+fn oom_score_adj(proc_data: &ProcessData) -> Option<i32> {
+    let task = get_task(proc_data.proc.pid()).ok()?;
+    Some(task.try_as_thread()?.oom_score_adj())
+}
+
+/// Returns the badness of `proc_data` out of `total` pages, or `None` if it
+/// is never killed.
+fn badness(proc_data: &ProcessData, total: usize) -> Option<isize> {
+    let adj = oom_score_adj(proc_data)?;
+    if adj <= OOM_SCORE_ADJ_MIN || proc_data.proc.is_init() || proc_data.proc.is_zombie() {
+        return None;
+    }
+    if OOM_SKIPPED.lock().contains(&proc_data.proc.pid()) {
+        return None;
+    }
+    let pages = proc_data.commitments.lock().size() / PAGE_SIZE_4K;
+    Some(pages as isize + adj as isize * (total / 1000) as isize)
+}
+
+/// Returns the score shown in `/proc/[pid]/oom_score`, from 0 to 1333.
+pub fn oom_score(proc_data: &ProcessData) -> usize {
+    let total = total_pages().max(1);
+    badness(proc_data, total).map_or(0, |badness| {
+        ((1000 + badness * 1000 / total as isize) * 2 / 3).max(0) as usize
+    })
+}
+
+/// Frees memory, by reclaiming cached pages or else by killing a process.
+/// Returns `true` once pages were reclaimed or another process was killed
+/// and its memory released, so the allocation may be retried, or
+/// `false` if the current process is being killed, with `SIGKILL` pending.
+///
+/// Nothing is allocated on the way, which is what just failed.
+fn out_of_memory() -> bool {
+    if shrink_page_cache(RECLAIM_BATCH) > 0 {
+        return true;
+    }
+    let curr_pid = current().as_thread().proc_data.proc.pid();
+    loop {
+        let victim = OOM_VICTIM.load(Ordering::Acquire);
+        if victim == 0 {
+            break;
+        }
+        // Another fault is already killing; its victim's memory will do.
+        if victim == curr_pid {
+            return false;
+        }
+        axtask::yield_now();
+        if OOM_VICTIM.load(Ordering::Acquire) == 0 {
+            return true;
+        }
+    }
+
+    for pid in OOM_SKIPPED.lock().iter_mut() {
+        let alive = get_task(*pid).is_ok_and(|task| {
+            task.try_as_thread()
+                .is_some_and(|thr| !thr.proc_data.proc.is_zombie())
+        });
+        if *pid != 0 && !alive {
+            *pid = 0;
+        }
+    }
+    let total = total_pages().max(1);
+    let mut chosen: Option<(isize, Arc<ProcessData>)> = None;
+    for_each_process(|proc_data| {
+        if let Some(badness) = badness(&proc_data, total)
+            && chosen.as_ref().is_none_or(|(max, _)| badness > *max)
+        {
+            chosen = Some((badness, proc_data));
+        }
+    });
+    let victim = chosen.map(|(_, proc_data)| proc_data);
+    let pid = victim
+        .as_ref()
+        .map_or(curr_pid, |proc_data| proc_data.proc.pid());
+    if OOM_VICTIM
+        .compare_exchange(0, pid, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        // Raced with another fault; wait for its kill instead.
+        return out_of_memory();
+    }
+    warn!("Out of memory: killing process {pid}");
+    let _ = send_signal_to_process(pid, Some(SignalInfo::new_kernel(Signo::SIGKILL)));
+    let retry = match victim {
+        Some(proc_data) if pid != curr_pid => {
+            if wait_exit(&proc_data) {
+                reap(&proc_data);
+            } else {
+                warn!("Out of memory: process {pid} did not exit, passing over it");
+                skip(pid);
+            }
+            true
+        }
+        _ => false,
+    };
+    OOM_VICTIM.store(0, Ordering::Release);
+    retry
+}
+
+/// Passes over `pid` when choosing victims until it exits.
+fn skip(pid: Pid) {
+    let mut skipped = OOM_SKIPPED.lock();
+    match skipped.iter_mut().find(|skipped| **skipped == 0) {
+        Some(slot) => *slot = pid,
+        None => {
+            skipped.rotate_left(1);
+            skipped[skipped.len() - 1] = pid;
+        }
+    }
+}
+
+/// Waits up to [`OOM_WAIT`] for `proc_data` to exit, returning whether it
+/// did. A signal to the waiting task cuts the wait short.
+fn wait_exit(proc_data: &ProcessData) -> bool {
+    let _ = block_on(interruptible(timeout(
+        Some(OOM_WAIT),
+        poll_fn(|cx| {
+            if proc_data.proc.is_zombie() {
+                return Poll::Ready(());
+            }
+            proc_data.exit_event.register(cx.waker());
+            if proc_data.proc.is_zombie() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }),
+    )));
+    proc_data.proc.is_zombie()
+}
+
+/// Releases the memory of `proc_data`, which has exited, without waiting
+/// for its parent to reap it.
+fn reap(proc_data: &ProcessData) {
+    // A `CLONE_VM` child still runs on the address space.
+    if Arc::strong_count(&proc_data.aspace) == 1 {
+        proc_data.aspace.lock().clear();
+    }
+    proc_data.commitments.lock().clear();
+}
+
+/// Handles a user page fault of the current process at `addr`, killing
+/// processes while it fails for lack of memory. Returns the signal to raise
+/// if it cannot be handled: `SIGSEGV` if the address is not mapped for the
+/// access, `SIGBUS` if the backend failed the page for another reason.
+pub fn handle_user_fault(
+    proc_data: &ProcessData,
+    addr: VirtAddr,
+    flags: MappingFlags,
+) -> Option<Signo> {
+    loop {
+        let mut aspace = proc_data.aspace.lock();
+        if aspace.handle_page_fault(addr, flags) {
+            return None;
+        }
+        let mapped = aspace
+            .find_area(addr)
+            .is_some_and(|area| area.flags().contains(flags));
+        drop(aspace);
+        if !mapped {
+            return Some(Signo::SIGSEGV);
+        }
+        // The backends only tell that they failed; with pages to spare, it
+        // was not for lack of them.
+        if axalloc::global_allocator().available_pages() >= FAULT_RESERVE_PAGES {
+            return Some(Signo::SIGBUS);
+        }
+        if !out_of_memory() {
+            return None;
+        }
+    }
+}
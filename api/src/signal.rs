@@ -1,6 +1,6 @@
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use axerrno::AxResult;
+use axerrno::{AxError, AxResult};
 use axhal::uspace::UserContext;
 use axtask::current;
 use starry_core::task::{AsThread, Thread};
@@ -23,7 +23,7 @@ pub fn check_signals(
             do_exit(signo as i32, true);
         }
         SignalOSAction::CoreDump => {
-            // TODO: implement core dump
+            crate::coredump::do_coredump(thr, uctx, &sig);
             do_exit(128 + signo as i32, true);
         }
         SignalOSAction::Stop => {
@@ -50,17 +50,48 @@ pub fn unblock_next_signal() -> bool {
     BLOCK_NEXT_SIGNAL_CHECK.swap(false, Ordering::SeqCst)
 }
 
+/// Delivers the pending signals on the way back to user space. A mask saved
+/// by an interrupted wait is restored when the first handler returns, or
+/// right away if no handler runs.
+pub fn deliver_signals(thr: &Thread, uctx: &mut UserContext) {
+    let mut restore = thr.take_saved_blocked();
+    while check_signals(thr, uctx, restore) {
+        restore = None;
+    }
+    if let Some(old) = restore {
+        thr.signal.set_blocked(old);
+    }
+}
+
+/// Runs `f` with the signal mask replaced by `blocked`, if any.
+///
+/// A pending signal the temporary mask unblocks interrupts `f` as soon as
+/// it would wait. If `f` is interrupted, the temporary mask stays in place
+/// until the signal is delivered on the way back to user space, so that a
+/// signal it let through is the one handled.
 pub fn with_replacen_blocked<R>(
     blocked: Option<SignalSet>,
     f: impl FnOnce() -> AxResult<R>,
 ) -> AxResult<R> {
     let curr = current();
-    let sig = &curr.as_thread().signal;
+    let thr = curr.as_thread();
 
-    let old_blocked = blocked.map(|set| sig.set_blocked(set));
-    f().inspect(|_| {
-        if let Some(old) = old_blocked {
-            sig.set_blocked(old);
+    let old_blocked = blocked.map(|set| {
+        let old = thr.signal.set_blocked(set);
+        // A signal sent while blocked interrupted no one; if the temporary
+        // mask lets it through, the wait in `f` must still end for it.
+        if !(thr.signal.pending() & !set).is_empty() {
+            curr.interrupt();
+        }
+        old
+    });
+    let result = f();
+    if let Some(old) = old_blocked {
+        if matches!(result, Err(AxError::Interrupted)) {
+            thr.set_saved_blocked(old);
+        } else {
+            thr.signal.set_blocked(old);
         }
-    })
+    }
+    result
 }
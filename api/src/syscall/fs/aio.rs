@@ -0,0 +1,520 @@
+//! Legacy Linux AIO (`io_setup`, `io_submit`, `io_getevents`, ...).
+//!
+//! Requests are run by worker tasks, one per request. Workers have no user
+//! address space, so a write copies its data in at submission and a read
+//! keeps its data in the kernel until the completion is reaped, when it is
+//! copied to the user buffers by the reaping thread.
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::{
+    future::poll_fn,
+    iter,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::Poll,
+};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs::FileFlags;
+use axpoll::PollSet;
+use axsync::Mutex;
+use axtask::future::{block_on, interruptible};
+use bytemuck::{AnyBitPattern, Zeroable};
+use linkme::distributed_slice;
+use linux_raw_sys::general::{MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE, timespec};
+use memory_addr::PAGE_SIZE_4K;
+use starry_core::{
+    sysctl::{SYSCTLS, Sysctl, SysctlKind},
+    timer,
+};
+use starry_signal::SignalSet;
+use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
+
+use crate::{
+    file::{File, FileLike, event::EventFd, get_file_like},
+    io::{IoVec, UIO_MAXIOV},
+    mm::{UserConstPtr, nullable},
+    signal::with_replacen_blocked,
+    syscall::{
+        mm::{sys_mmap, sys_munmap},
+        signal::check_sigset_size,
+    },
+    time::TimeValueLike,
+};
+
+const IOCB_CMD_PREAD: u16 = 0;
+const IOCB_CMD_PWRITE: u16 = 1;
+const IOCB_CMD_PREADV: u16 = 7;
+const IOCB_CMD_PWRITEV: u16 = 8;
+
+const IOCB_FLAG_RESFD: u32 = 1;
+
+/// Total number of events of all contexts, `/proc/sys/fs/aio-nr`.
+static AIO_NR: AtomicUsize = AtomicUsize::new(0);
+static AIO_MAX_NR: AtomicUsize = AtomicUsize::new(65536);
+
+#[distributed_slice(SYSCTLS)]
+static AIO_NR_SYSCTL: Sysctl = Sysctl {
+    path: "fs/aio-nr",
+    mode: 0o444,
+    kind: SysctlKind::Uint {
+        get: || AIO_NR.load(Ordering::Relaxed) as _,
+        set: |_| {},
+        min: 0,
+        max: 0,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static AIO_MAX_NR_SYSCTL: Sysctl = Sysctl {
+    path: "fs/aio-max-nr",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || AIO_MAX_NR.load(Ordering::Relaxed) as _,
+        set: |value| AIO_MAX_NR.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: u32::MAX as _,
+    },
+};
+
+/// `struct iocb`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+pub struct Iocb {
+    aio_data: u64,
+    aio_key: u32,
+    aio_rw_flags: i32,
+    aio_lio_opcode: u16,
+    aio_reqprio: i16,
+    aio_fildes: u32,
+    aio_buf: u64,
+    aio_nbytes: u64,
+    aio_offset: i64,
+    aio_reserved2: u64,
+    aio_flags: u32,
+    aio_resfd: u32,
+}
+
+/// `struct io_event`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct IoEvent {
+    data: u64,
+    obj: u64,
+    res: i64,
+    res2: i64,
+}
+
+/// `struct aio_ring`, the header of the page `io_setup` maps.
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable)]
+struct AioRing {
+    id: u32,
+    nr: u32,
+    head: u32,
+    tail: u32,
+    magic: u32,
+    compat_features: u32,
+    incompat_features: u32,
+    header_length: u32,
+}
+
+/// `struct __aio_sigset`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct AioSigset {
+    sigmask: UserConstPtr<SignalSet>,
+    sigsetsize: usize,
+}
+
+enum Op {
+    Read { segments: Vec<(usize, usize)> },
+    Write { data: Vec<u8> },
+}
+
+struct Completion {
+    event: IoEvent,
+    /// Data of a successful read and the user buffers it goes to.
+    read: Option<(Vec<u8>, Vec<(usize, usize)>)>,
+}
+
+#[derive(Default)]
+struct AioState {
+    /// `aio_data` of the requests in flight, by iocb address.
+    in_flight: BTreeMap<u64, u64>,
+    completed: Vec<Completion>,
+}
+
+/// An AIO context.
+pub struct AioContext {
+    /// Capacity, in requests not yet reaped.
+    nr_events: usize,
+    /// The user address of the ring page, which is also the context id.
+    ring: usize,
+    state: Mutex<AioState>,
+    /// Woken on every completion.
+    poll_complete: PollSet,
+}
+
+impl AioContext {
+    fn completed(&self) -> usize {
+        self.state.lock().completed.len()
+    }
+
+    fn in_flight(&self) -> usize {
+        self.state.lock().in_flight.len()
+    }
+
+    fn complete(&self, completion: Completion, resfd: Option<&EventFd>) {
+        {
+            let mut state = self.state.lock();
+            state.in_flight.remove(&completion.event.obj);
+            state.completed.push(completion);
+        }
+        self.poll_complete.wake();
+        if let Some(resfd) = resfd {
+            resfd.signal(1);
+        }
+    }
+}
+
+impl Drop for AioContext {
+    fn drop(&mut self) {
+        AIO_NR.fetch_sub(self.nr_events, Ordering::Relaxed);
+    }
+}
+
+scope_local::scope_local! {
+    /// The AIO contexts of the current process, by id.
+    static AIO_CONTEXTS: Mutex<BTreeMap<usize, Arc<AioContext>>> = Mutex::new(BTreeMap::new());
+}
+
+fn context(ctx_id: usize) -> AxResult<Arc<AioContext>> {
+    AIO_CONTEXTS
+        .lock()
+        .get(&ctx_id)
+        .cloned()
+        .ok_or(AxError::InvalidInput)
+}
+
+pub fn sys_io_setup(nr_events: u32, ctxp: *mut usize) -> AxResult<isize> {
+    debug!("sys_io_setup <= nr_events: {nr_events}");
+    if nr_events == 0 || ctxp.vm_read()? != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let nr_events = nr_events as usize;
+    AIO_NR
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |nr| {
+            (nr + nr_events <= AIO_MAX_NR.load(Ordering::Relaxed)).then_some(nr + nr_events)
+        })
+        .map_err(|_| AxError::WouldBlock)?;
+
+    // The ring page only carries a header. Its magic is not `AIO_RING_MAGIC`,
+    // which tells libaio to reap through `io_getevents` instead of reading
+    // the ring.
+    let ring = match sys_mmap(
+        0,
+        PAGE_SIZE_4K,
+        PROT_READ | PROT_WRITE,
+        MAP_PRIVATE | MAP_ANONYMOUS,
+        -1,
+        0,
+    ) {
+        Ok(ring) => ring as usize,
+        Err(err) => {
+            AIO_NR.fetch_sub(nr_events, Ordering::Relaxed);
+            return Err(err);
+        }
+    };
+    let ctx = Arc::new(AioContext {
+        nr_events,
+        ring,
+        state: Mutex::default(),
+        poll_complete: PollSet::new(),
+    });
+    (ring as *mut AioRing).vm_write(AioRing {
+        nr: nr_events as _,
+        header_length: size_of::<AioRing>() as _,
+        ..AioRing::zeroed()
+    })?;
+    ctxp.vm_write(ring)?;
+    AIO_CONTEXTS.lock().insert(ring, ctx);
+    Ok(0)
+}
+
+pub fn sys_io_destroy(ctx_id: usize) -> AxResult<isize> {
+    debug!("sys_io_destroy <= ctx_id: {ctx_id:#x}");
+    let ctx = AIO_CONTEXTS
+        .lock()
+        .remove(&ctx_id)
+        .ok_or(AxError::InvalidInput)?;
+    // Requests cannot be cancelled, so wait for them to finish.
+    block_on(poll_fn(|cx| {
+        if ctx.in_flight() == 0 {
+            return Poll::Ready(());
+        }
+        ctx.poll_complete.register(cx.waker());
+        if ctx.in_flight() == 0 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }));
+    let _ = sys_munmap(ctx.ring, PAGE_SIZE_4K);
+    Ok(0)
+}
+
+fn load_segments(iov: *const IoVec, iovcnt: usize) -> AxResult<Vec<(usize, usize)>> {
+    if iovcnt > UIO_MAXIOV {
+        return Err(AxError::InvalidInput);
+    }
+    (0..iovcnt)
+        .map(|i| {
+            let iov = iov.wrapping_add(i).vm_read()?;
+            if iov.iov_len < 0 {
+                return Err(AxError::InvalidInput);
+            }
+            Ok((iov.iov_base as usize, iov.iov_len as usize))
+        })
+        .collect()
+}
+
+fn submit_one(ctx: &Arc<AioContext>, obj: u64) -> AxResult<()> {
+    let iocb = (obj as *const Iocb).vm_read()?;
+    if iocb.aio_reserved2 != 0 || iocb.aio_offset < 0 {
+        return Err(AxError::InvalidInput);
+    }
+
+    let file = get_file_like(iocb.aio_fildes as _)?;
+    let file = file
+        .into_any()
+        .downcast::<File>()
+        .map_err(|_| AxError::InvalidInput)?;
+    let resfd = if iocb.aio_flags & IOCB_FLAG_RESFD != 0 {
+        let resfd = get_file_like(iocb.aio_resfd as _)?;
+        Some(
+            resfd
+                .into_any()
+                .downcast::<EventFd>()
+                .map_err(|_| AxError::InvalidInput)?,
+        )
+    } else {
+        None
+    };
+
+    let buf = iocb.aio_buf as usize;
+    let len = iocb.aio_nbytes as usize;
+    let op = match iocb.aio_lio_opcode {
+        IOCB_CMD_PREAD | IOCB_CMD_PREADV => {
+            file.inner()
+                .access(FileFlags::READ)
+                .map_err(|_| AxError::BadFileDescriptor)?;
+            let segments = if iocb.aio_lio_opcode == IOCB_CMD_PREAD {
+                vec![(buf, len)]
+            } else {
+                load_segments(buf as _, len)?
+            };
+            Op::Read { segments }
+        }
+        IOCB_CMD_PWRITE | IOCB_CMD_PWRITEV => {
+            file.inner()
+                .access(FileFlags::WRITE)
+                .map_err(|_| AxError::BadFileDescriptor)?;
+            let data = if iocb.aio_lio_opcode == IOCB_CMD_PWRITE {
+                vm_load(buf as *const u8, len)?
+            } else {
+                let mut data = Vec::new();
+                for (base, len) in load_segments(buf as _, len)? {
+                    data.extend(vm_load(base as *const u8, len)?);
+                }
+                data
+            };
+            Op::Write { data }
+        }
+        _ => return Err(AxError::InvalidInput),
+    };
+
+    {
+        let mut state = ctx.state.lock();
+        if state.in_flight.len() + state.completed.len() >= ctx.nr_events {
+            return Err(AxError::WouldBlock);
+        }
+        // Requests are told apart by iocb address, on completion and by
+        // io_cancel, so an iocb is not reused before it completes.
+        if state.in_flight.contains_key(&obj) {
+            return Err(AxError::InvalidInput);
+        }
+        state.in_flight.insert(obj, iocb.aio_data);
+    }
+
+    let ctx = ctx.clone();
+    let offset = iocb.aio_offset as u64;
+    // TODO: submit O_DIRECT requests to the block layer instead of a task
+    axtask::spawn_with_name(
+        move || {
+            let (res, read) = match op {
+                Op::Read { segments } => {
+                    let mut data = vec![0; segments.iter().map(|(_, len)| len).sum()];
+                    match file.read_at(&mut data.as_mut_slice(), offset) {
+                        Ok(n) => {
+                            data.truncate(n);
+                            (n as i64, Some((data, segments)))
+                        }
+                        Err(err) => (-(LinuxError::from(err).code() as i64), None),
+                    }
+                }
+                Op::Write { data } => match file.write_at(&mut data.as_slice(), offset) {
+                    Ok(n) => (n as i64, None),
+                    Err(err) => (-(LinuxError::from(err).code() as i64), None),
+                },
+            };
+            let event = IoEvent {
+                data: iocb.aio_data,
+                obj,
+                res,
+                res2: 0,
+            };
+            ctx.complete(Completion { event, read }, resfd.as_deref());
+        },
+        "aio".into(),
+    );
+    Ok(())
+}
+
+pub fn sys_io_submit(ctx_id: usize, nr: isize, iocbpp: *const u64) -> AxResult<isize> {
+    debug!("sys_io_submit <= ctx_id: {ctx_id:#x}, nr: {nr}");
+    if nr < 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let ctx = context(ctx_id)?;
+    for i in 0..nr as usize {
+        let obj = iocbpp.wrapping_add(i).vm_read()?;
+        if let Err(err) = submit_one(&ctx, obj) {
+            // Report the error only if nothing was submitted.
+            return if i == 0 { Err(err) } else { Ok(i as _) };
+        }
+    }
+    Ok(nr)
+}
+
+pub fn sys_io_cancel(ctx_id: usize, iocb: *const Iocb, _result: *mut IoEvent) -> AxResult<isize> {
+    debug!("sys_io_cancel <= ctx_id: {ctx_id:#x}, iocb: {iocb:?}");
+    let ctx = context(ctx_id)?;
+    // Every request runs to completion once started.
+    if ctx.state.lock().in_flight.contains_key(&(iocb as u64)) {
+        Err(AxError::from(LinuxError::EINPROGRESS))
+    } else {
+        Err(AxError::InvalidInput)
+    }
+}
+
+fn do_getevents(
+    ctx_id: usize,
+    min_nr: isize,
+    nr: isize,
+    events: *mut IoEvent,
+    timeout: UserConstPtr<timespec>,
+    sigmask: Option<SignalSet>,
+) -> AxResult<isize> {
+    let ctx = context(ctx_id)?;
+    if min_nr < 0 || nr < 0 || min_nr > nr {
+        return Err(AxError::InvalidInput);
+    }
+    let (min_nr, nr) = (min_nr as usize, nr as usize);
+    let timeout = nullable!(timeout.get_as_ref())?
+        .map(|ts| ts.try_into_time_value())
+        .transpose()?;
+
+    let interrupted = with_replacen_blocked(sigmask, || {
+        Ok(matches!(
+            block_on(timer::timeout(
+                timeout,
+                interruptible(poll_fn(|cx| {
+                    if ctx.completed() >= min_nr {
+                        return Poll::Ready(());
+                    }
+                    ctx.poll_complete.register(cx.waker());
+                    if ctx.completed() >= min_nr {
+                        Poll::Ready(())
+                    } else {
+                        Poll::Pending
+                    }
+                })),
+            )),
+            Ok(Err(_))
+        ))
+    })?;
+
+    let reaped: Vec<Completion> = {
+        let mut state = ctx.state.lock();
+        let count = state.completed.len().min(nr);
+        state.completed.drain(..count).collect()
+    };
+    if reaped.is_empty() && interrupted {
+        return Err(AxError::Interrupted);
+    }
+    let mut reaped = reaped.into_iter();
+    let mut count = 0;
+    while let Some(mut completion) = reaped.next() {
+        let mut failed = None;
+        if let Some((data, segments)) = &completion.read {
+            let mut rest = data.as_slice();
+            for &(base, len) in segments {
+                let (chunk, tail) = rest.split_at(len.min(rest.len()));
+                if let Err(err) = vm_write_slice(base as *mut u8, chunk) {
+                    failed = Some(-(LinuxError::from(err).code() as i64));
+                    break;
+                }
+                rest = tail;
+            }
+        }
+        if let Some(res) = failed {
+            completion.event.res = res;
+            completion.read = None;
+        }
+        if let Err(err) = events.wrapping_add(count).vm_write(completion.event) {
+            // The completions not written out go back, to be reaped by the
+            // next call.
+            let mut state = ctx.state.lock();
+            state
+                .completed
+                .splice(0..0, iter::once(completion).chain(reaped));
+            return if count == 0 {
+                Err(err.into())
+            } else {
+                Ok(count as _)
+            };
+        }
+        count += 1;
+    }
+    Ok(count as _)
+}
+
+pub fn sys_io_getevents(
+    ctx_id: usize,
+    min_nr: isize,
+    nr: isize,
+    events: *mut IoEvent,
+    timeout: UserConstPtr<timespec>,
+) -> AxResult<isize> {
+    debug!("sys_io_getevents <= ctx_id: {ctx_id:#x}, min_nr: {min_nr}, nr: {nr}");
+    do_getevents(ctx_id, min_nr, nr, events, timeout, None)
+}
+
+pub fn sys_io_pgetevents(
+    ctx_id: usize,
+    min_nr: isize,
+    nr: isize,
+    events: *mut IoEvent,
+    timeout: UserConstPtr<timespec>,
+    sig: UserConstPtr<AioSigset>,
+) -> AxResult<isize> {
+    debug!("sys_io_pgetevents <= ctx_id: {ctx_id:#x}, min_nr: {min_nr}, nr: {nr}");
+    let sigmask = if let Some(sig) = nullable!(sig.get_as_ref())? {
+        check_sigset_size(sig.sigsetsize)?;
+        let set = sig.sigmask;
+        nullable!(set.get_as_ref())?.copied()
+    } else {
+        None
+    };
+    do_getevents(ctx_id, min_nr, nr, events, timeout, sigmask)
+}
@@ -1,4 +1,4 @@
-use alloc::{ffi::CString, vec, vec::Vec};
+use alloc::{ffi::CString, string::ToString, vec, vec::Vec};
 use core::{
     ffi::{c_char, c_int},
     mem::offset_of,
@@ -7,20 +7,26 @@ use core::{
 
 use axerrno::{AxError, AxResult};
 use axfs::{FS_CONTEXT, FsContext};
-use axfs_ng_vfs::{MetadataUpdate, NodePermission, NodeType, path::Path};
-use axhal::time::wall_time;
+use axfs_ng_vfs::{DeviceId, MetadataUpdate, NodePermission, NodeType, path::Path};
 use axtask::current;
 use linux_raw_sys::{
     general::*,
-    ioctl::{FIONBIO, TIOCGWINSZ},
+    ioctl::{FIFREEZE, FIONBIO, FITHAW, TIOCGWINSZ},
 };
-use starry_core::task::AsThread;
+use starry_core::{task::AsThread, time::realtime};
 use starry_vm::{VmPtr, vm_write_slice};
 
 use crate::{
     file::{Directory, FileLike, get_file_like, resolve_at, with_fs},
     mm::vm_load_string,
+    syscall::sys::{sys_getegid, sys_geteuid},
     time::TimeValueLike,
+    vfs::{
+        MemoryFs,
+        freeze::{freeze_lock, write_in},
+        mounts::use_fs_mounts,
+        writeback::write_back_device,
+    },
 };
 
 /// The ioctl() system call manipulates the underlying device parameters
@@ -28,6 +34,24 @@ use crate::{
 pub fn sys_ioctl(fd: i32, cmd: u32, arg: usize) -> AxResult<isize> {
     debug!("sys_ioctl <= fd: {fd}, cmd: {cmd}, arg: {arg}");
     let f = get_file_like(fd)?;
+    if cmd == FIFREEZE || cmd == FITHAW {
+        if sys_geteuid()? != 0 {
+            return Err(AxError::OperationNotPermitted);
+        }
+        let loc = resolve_at(fd, None, AT_EMPTY_PATH)?
+            .into_file()
+            .ok_or(AxError::OperationNotSupported)?;
+        let lock = freeze_lock(&loc);
+        if cmd == FIFREEZE {
+            lock.freeze(|| {
+                write_back_device(loc.mountpoint().device() as u64)?;
+                loc.filesystem().flush()
+            })?;
+        } else {
+            lock.thaw()?;
+        }
+        return Ok(0);
+    }
     if cmd == FIONBIO {
         let val = (arg as *const u8).vm_read()?;
         if val != 0 && val != 1 {
@@ -57,14 +81,21 @@ pub fn sys_chdir(path: *const c_char) -> AxResult<isize> {
     let mut fs = FS_CONTEXT.lock();
     let entry = fs.resolve(path)?;
     fs.set_current_dir(entry)?;
+    use_fs_mounts(&fs);
     Ok(0)
 }
 
 pub fn sys_fchdir(dirfd: i32) -> AxResult<isize> {
     debug!("sys_fchdir <= dirfd: {dirfd}");
 
-    let entry = with_fs(dirfd, |fs| Ok(fs.current_dir().clone()))?;
-    FS_CONTEXT.lock().set_current_dir(entry)?;
+    // O_PATH directory fds are plain `Directory`s as well.
+    let dir = Directory::from_fd(dirfd)?;
+    if dir.inner().node_type() != NodeType::Directory {
+        return Err(AxError::NotADirectory);
+    }
+    let mut fs = FS_CONTEXT.lock();
+    fs.set_current_dir(dir.inner().clone())?;
+    use_fs_mounts(&fs);
     Ok(0)
 }
 
@@ -83,6 +114,7 @@ pub fn sys_chroot(path: *const c_char) -> AxResult<isize> {
         return Err(AxError::NotADirectory);
     }
     *fs = FsContext::new(loc);
+    use_fs_mounts(&fs);
     Ok(0)
 }
 
@@ -94,11 +126,53 @@ pub fn sys_mkdirat(dirfd: i32, path: *const c_char, mode: u32) -> AxResult<isize
     let mode = NodePermission::from_bits_truncate(mode as u16);
 
     with_fs(dirfd, |fs| {
-        fs.create_dir(path, mode)?;
+        write_in(fs, &path, || fs.create_dir(&path, mode))?;
         Ok(0)
     })
 }
 
+pub fn sys_mknodat(dirfd: i32, path: *const c_char, mode: u32, dev: u64) -> AxResult<isize> {
+    let path = vm_load_string(path)?;
+    debug!("sys_mknodat <= dirfd: {dirfd}, path: {path}, mode: {mode:#o}, dev: {dev:#x}");
+
+    let node_type = match mode & S_IFMT {
+        0 | S_IFREG => NodeType::RegularFile,
+        S_IFCHR => NodeType::CharacterDevice,
+        S_IFBLK => NodeType::BlockDevice,
+        S_IFIFO => NodeType::Fifo,
+        S_IFSOCK => NodeType::Socket,
+        S_IFDIR => return Err(AxError::OperationNotPermitted),
+        _ => return Err(AxError::InvalidInput),
+    };
+    let is_device = matches!(node_type, NodeType::CharacterDevice | NodeType::BlockDevice);
+    if is_device && sys_geteuid()? != 0 {
+        return Err(AxError::OperationNotPermitted);
+    }
+
+    let mode = mode & 0o7777 & !current().as_thread().proc_data.umask();
+    let mode = NodePermission::from_bits_truncate(mode as u16);
+
+    let (dir, name) = with_fs(dirfd, |fs| fs.resolve_nonexistent(Path::new(&path)))?;
+    // Only memory filesystems can record a device number.
+    if is_device && !MemoryFs::contains(&dir) {
+        return Err(AxError::OperationNotPermitted);
+    }
+    let loc = freeze_lock(&dir).write(|| dir.create(name, node_type, mode))?;
+    loc.update_metadata(MetadataUpdate {
+        owner: Some((sys_geteuid()? as _, sys_getegid()? as _)),
+        ..Default::default()
+    })?;
+    if is_device {
+        MemoryFs::set_rdev(&loc, DeviceId(dev))?;
+    }
+    Ok(0)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn sys_mknod(path: *const c_char, mode: u32, dev: u64) -> AxResult<isize> {
+    sys_mknodat(AT_FDCWD, path, mode, dev)
+}
+
 // Directory buffer for getdents64 syscall
 struct DirBuffer {
     buf: Vec<u8>,
@@ -154,19 +228,14 @@ pub fn sys_getdents64(fd: i32, buf: *mut u8, len: usize) -> AxResult<isize> {
     let mut buffer = DirBuffer::new(len);
 
     let dir = Directory::from_fd(fd)?;
-    let mut dir_offset = dir.offset.lock();
+    let mut stream = dir.stream.lock();
 
     let mut has_remaining = false;
 
-    dir.inner()
-        .read_dir(*dir_offset, &mut |name: &str, ino, node_type, offset| {
-            has_remaining = true;
-            if !buffer.write_entry(ino, offset as _, node_type, name.as_bytes()) {
-                return false;
-            }
-            *dir_offset = offset;
-            true
-        })?;
+    stream.read(dir.inner(), len, &mut |name, ino, node_type, offset| {
+        has_remaining = true;
+        buffer.write_entry(ino, offset as _, node_type, name.as_bytes())
+    })?;
 
     if has_remaining && buffer.offset == 0 {
         return Err(AxError::InvalidInput);
@@ -196,11 +265,21 @@ pub fn sys_linkat(
          new_path: {new_path}, flags: {flags}"
     );
 
-    if flags != 0 {
-        warn!("Unsupported flags: {flags}");
+    if flags & !(AT_SYMLINK_FOLLOW | AT_EMPTY_PATH) != 0 {
+        return Err(AxError::InvalidInput);
     }
-
-    let old = resolve_at(old_dirfd, old_path.as_deref(), flags)?
+    // Linking an open file into a directory is privileged.
+    if flags & AT_EMPTY_PATH != 0 && sys_geteuid()? != 0 {
+        return Err(AxError::NotFound);
+    }
+    // Unlike the other *at() calls, linkat does not follow symlinks unless
+    // asked to.
+    let resolve_flags = if flags & AT_SYMLINK_FOLLOW != 0 {
+        flags & AT_EMPTY_PATH
+    } else {
+        flags & AT_EMPTY_PATH | AT_SYMLINK_NOFOLLOW
+    };
+    let old = resolve_at(old_dirfd, old_path.as_deref(), resolve_flags)?
         .into_file()
         .ok_or(AxError::BadFileDescriptor)?;
     if old.is_dir() {
@@ -209,7 +288,7 @@ pub fn sys_linkat(
     let (new_dir, new_name) =
         with_fs(new_dirfd, |fs| fs.resolve_nonexistent(Path::new(&new_path)))?;
 
-    new_dir.link(new_name, &old)?;
+    freeze_lock(&new_dir).write(|| new_dir.link(new_name, &old))?;
     Ok(0)
 }
 
@@ -228,12 +307,17 @@ pub fn sys_unlinkat(dirfd: i32, path: *const c_char, flags: usize) -> AxResult<i
 
     debug!("sys_unlinkat <= dirfd: {dirfd}, path: {path:?}, flags: {flags}");
 
+    if flags & !(AT_REMOVEDIR as usize) != 0 {
+        return Err(AxError::InvalidInput);
+    }
     with_fs(dirfd, |fs| {
-        if flags == AT_REMOVEDIR as _ {
-            fs.remove_dir(path)?;
-        } else {
-            fs.remove_file(path)?;
-        }
+        write_in(fs, &path, || {
+            if flags & AT_REMOVEDIR as usize != 0 {
+                fs.remove_dir(&path)
+            } else {
+                fs.remove_file(&path)
+            }
+        })?;
         Ok(0)
     })
 }
@@ -251,19 +335,33 @@ pub fn sys_unlink(path: *const c_char) -> AxResult<isize> {
 pub fn sys_getcwd(buf: *mut u8, size: isize) -> AxResult<isize> {
     let size: usize = size.try_into().map_err(|_| AxError::BadAddress)?;
     if buf.is_null() {
-        return Ok(0);
+        return Err(AxError::BadAddress);
     }
 
-    let cwd = FS_CONTEXT.lock().current_dir().absolute_path()?;
+    let cwd = {
+        let fs = FS_CONTEXT.lock();
+        let dir = fs.current_dir();
+        // The path is generated from the dentry parents, so it follows renames.
+        let cwd = dir.absolute_path()?.to_string();
+        // A removed directory is no longer reachable through its path.
+        let meta = dir.metadata()?;
+        let reachable = fs
+            .resolve(cwd.as_str())
+            .and_then(|it| it.metadata())
+            .is_ok_and(|it| it.inode == meta.inode && it.device == meta.device);
+        if !reachable {
+            return Err(AxError::NotFound);
+        }
+        cwd
+    };
     debug!("sys_getcwd => cwd: {cwd}");
 
-    let cwd = CString::new(cwd.as_str()).map_err(|_| AxError::InvalidInput)?;
+    let cwd = CString::new(cwd).map_err(|_| AxError::InvalidInput)?;
     let cwd = cwd.as_bytes_with_nul();
 
     if cwd.len() <= size {
         vm_write_slice(buf, cwd)?;
-        // FIXME: it is said that this should return 0
-        Ok(buf.as_ptr() as _)
+        Ok(cwd.len() as _)
     } else {
         Err(AxError::OutOfRange)
     }
@@ -284,7 +382,7 @@ pub fn sys_symlinkat(
     debug!("sys_symlinkat <= target: {target:?}, new_dirfd: {new_dirfd}, linkpath: {linkpath:?}");
 
     with_fs(new_dirfd, |fs| {
-        fs.symlink(target, linkpath)?;
+        write_in(fs, &linkpath, || fs.symlink(&target, &linkpath))?;
         Ok(0)
     })
 }
@@ -336,6 +434,13 @@ pub fn sys_fchownat(
     flags: u32,
 ) -> AxResult<isize> {
     let path = path.nullable().map(vm_load_string).transpose()?;
+    debug!(
+        "sys_fchownat <= dirfd: {dirfd}, path: {path:?}, uid: {uid}, gid: {gid}, flags: {flags}"
+    );
+
+    if flags & !(AT_EMPTY_PATH | AT_SYMLINK_NOFOLLOW) != 0 {
+        return Err(AxError::InvalidInput);
+    }
     let loc = resolve_at(dirfd, path.as_deref(), flags)?
         .into_file()
         .ok_or(AxError::BadFileDescriptor)?;
@@ -370,13 +475,22 @@ pub fn sys_fchmod(fd: i32, mode: u32) -> AxResult<isize> {
 
 pub fn sys_fchmodat(dirfd: i32, path: *const c_char, mode: u32, flags: u32) -> AxResult<isize> {
     let path = path.nullable().map(vm_load_string).transpose()?;
-    resolve_at(dirfd, path.as_deref(), flags)?
+    debug!("sys_fchmodat <= dirfd: {dirfd}, path: {path:?}, mode: {mode:#o}, flags: {flags}");
+
+    if flags & !(AT_EMPTY_PATH | AT_SYMLINK_NOFOLLOW) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let loc = resolve_at(dirfd, path.as_deref(), flags)?
         .into_file()
-        .ok_or(AxError::BadFileDescriptor)?
-        .update_metadata(MetadataUpdate {
-            mode: Some(NodePermission::from_bits_truncate(mode as u16)),
-            ..Default::default()
-        })?;
+        .ok_or(AxError::BadFileDescriptor)?;
+    // The mode of a symlink is meaningless and cannot be changed.
+    if loc.node_type() == NodeType::Symlink {
+        return Err(AxError::OperationNotSupported);
+    }
+    loc.update_metadata(MetadataUpdate {
+        mode: Some(NodePermission::from_bits_truncate(mode as u16)),
+        ..Default::default()
+    })?;
     Ok(0)
 }
 
@@ -417,7 +531,7 @@ pub fn sys_utime(path: *const c_char, times: *const utimbuf) -> AxResult<isize>
             Duration::from_secs(times.modtime as _),
         )
     } else {
-        let time = wall_time();
+        let time = realtime();
         (time, time)
     };
     update_times(AT_FDCWD, path, Some(atime), Some(mtime), 0)?;
@@ -434,7 +548,7 @@ pub fn sys_utimes(
         let [atime, mtime] = unsafe { times.vm_read_uninit()?.assume_init() };
         (atime.try_into_time_value()?, mtime.try_into_time_value()?)
     } else {
-        let time = wall_time();
+        let time = realtime();
         (time, time)
     };
     update_times(AT_FDCWD, path, Some(atime), Some(mtime), 0)?;
@@ -447,13 +561,20 @@ pub fn sys_utimensat(
     times: *const [timespec; 2],
     mut flags: u32,
 ) -> AxResult<isize> {
+    if flags & !(AT_EMPTY_PATH | AT_SYMLINK_NOFOLLOW) != 0 {
+        return Err(AxError::InvalidInput);
+    }
     if path.is_null() {
+        // Changes the times of `dirfd` itself, which is never a symlink.
+        if flags & AT_SYMLINK_NOFOLLOW != 0 {
+            return Err(AxError::InvalidInput);
+        }
         flags |= AT_EMPTY_PATH;
     }
     fn utime_to_duration(time: &timespec) -> Option<AxResult<Duration>> {
         match time.tv_nsec {
             val if val == UTIME_OMIT as _ => None,
-            val if val == UTIME_NOW as _ => Some(Ok(wall_time())),
+            val if val == UTIME_NOW as _ => Some(Ok(realtime())),
             _ => Some(time.try_into_time_value()),
         }
     }
@@ -466,7 +587,7 @@ pub fn sys_utimensat(
             utime_to_duration(&mtime).transpose()?,
         )
     } else {
-        let time = wall_time();
+        let time = realtime();
         (Some(time), Some(time))
     };
     if atime.is_none() && mtime.is_none() {
@@ -505,11 +626,21 @@ pub fn sys_renameat2(
          new_path: {new_path}, flags: {flags}"
     );
 
+    // Exchanging and whiteouts are not supported by any filesystem here.
+    if flags & !RENAME_NOREPLACE != 0 {
+        return Err(AxError::InvalidInput);
+    }
     let (old_dir, old_name) = with_fs(old_dirfd, |fs| fs.resolve_parent(Path::new(&old_path)))?;
     let (new_dir, new_name) =
         with_fs(new_dirfd, |fs| fs.resolve_nonexistent(Path::new(&new_path)))?;
+    if flags & RENAME_NOREPLACE != 0
+        && with_fs(new_dirfd, |fs| fs.resolve_no_follow(&new_path)).is_ok()
+    {
+        return Err(AxError::AlreadyExists);
+    }
 
-    old_dir.rename(&old_name, &new_dir, new_name)?;
+    // A rename within one filesystem; across them it fails anyway.
+    freeze_lock(&old_dir).write(|| old_dir.rename(&old_name, &new_dir, new_name))?;
     Ok(0)
 }
 
@@ -518,7 +649,11 @@ pub fn sys_sync() -> AxResult<isize> {
     Ok(0)
 }
 
-pub fn sys_syncfs(_fd: i32) -> AxResult<isize> {
-    warn!("dummy sys_syncfs");
+pub fn sys_syncfs(fd: i32) -> AxResult<isize> {
+    debug!("sys_syncfs <= fd: {fd}");
+    let loc = resolve_at(fd, None, AT_EMPTY_PATH)?
+        .into_file()
+        .ok_or(AxError::OperationNotSupported)?;
+    loc.filesystem().flush()?;
     Ok(0)
 }
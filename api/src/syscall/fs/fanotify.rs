@@ -0,0 +1,133 @@
+use core::ffi::{c_char, c_int};
+
+use axerrno::{AxError, AxResult};
+use axfs_ng_vfs::NodeType;
+use linux_raw_sys::general::{
+    AT_EMPTY_PATH, AT_SYMLINK_NOFOLLOW, O_ACCMODE, O_APPEND, O_CLOEXEC, O_DSYNC, O_LARGEFILE,
+    O_NOATIME, O_NONBLOCK, O_SYNC,
+};
+use starry_vm::VmPtr;
+
+use crate::{
+    file::{
+        FileLike, add_file_like,
+        fanotify::{
+            FAN_CLASS_CONTENT, FAN_CLASS_PRE_CONTENT, FAN_EVENT_ON_CHILD, FAN_EVENTS, FAN_ONDIR,
+            FAN_PERM_EVENTS, Fanotify, MarkTarget,
+        },
+        resolve_at,
+    },
+    mm::vm_load_string,
+    syscall::sys::sys_geteuid,
+};
+
+const FAN_CLOEXEC: u32 = 0x1;
+const FAN_NONBLOCK: u32 = 0x2;
+const FAN_CLASS_MASK: u32 = FAN_CLASS_CONTENT | FAN_CLASS_PRE_CONTENT;
+const FAN_UNLIMITED_QUEUE: u32 = 0x10;
+const FAN_UNLIMITED_MARKS: u32 = 0x20;
+
+const FAN_MARK_ADD: u32 = 0x1;
+const FAN_MARK_REMOVE: u32 = 0x2;
+const FAN_MARK_DONT_FOLLOW: u32 = 0x4;
+const FAN_MARK_ONLYDIR: u32 = 0x8;
+const FAN_MARK_MOUNT: u32 = 0x10;
+const FAN_MARK_IGNORED_MASK: u32 = 0x20;
+const FAN_MARK_IGNORED_SURV_MODIFY: u32 = 0x40;
+const FAN_MARK_FLUSH: u32 = 0x80;
+const FAN_MARK_FILESYSTEM: u32 = 0x100;
+
+pub fn sys_fanotify_init(flags: u32, event_f_flags: u32) -> AxResult<isize> {
+    debug!("sys_fanotify_init <= flags: {flags:#x}, event_f_flags: {event_f_flags:#o}");
+
+    if sys_geteuid()? != 0 {
+        return Err(AxError::OperationNotPermitted);
+    }
+    let known =
+        FAN_CLOEXEC | FAN_NONBLOCK | FAN_CLASS_MASK | FAN_UNLIMITED_QUEUE | FAN_UNLIMITED_MARKS;
+    let class = flags & FAN_CLASS_MASK;
+    if flags & !known != 0 || class == FAN_CLASS_MASK {
+        return Err(AxError::InvalidInput);
+    }
+    let f_flags =
+        O_ACCMODE | O_APPEND | O_CLOEXEC | O_DSYNC | O_LARGEFILE | O_NOATIME | O_NONBLOCK | O_SYNC;
+    if event_f_flags & !f_flags != 0 || event_f_flags & O_ACCMODE == O_ACCMODE {
+        return Err(AxError::InvalidInput);
+    }
+
+    let group = Fanotify::new(
+        class,
+        event_f_flags,
+        flags & FAN_UNLIMITED_QUEUE != 0,
+        flags & FAN_UNLIMITED_MARKS != 0,
+    );
+    group.set_nonblocking(flags & FAN_NONBLOCK != 0)?;
+    add_file_like(group as _, flags & FAN_CLOEXEC != 0).map(|fd| fd as _)
+}
+
+pub fn sys_fanotify_mark(
+    fd: c_int,
+    flags: u32,
+    mask: u64,
+    dirfd: c_int,
+    path: *const c_char,
+) -> AxResult<isize> {
+    let path = path.nullable().map(vm_load_string).transpose()?;
+    debug!(
+        "sys_fanotify_mark <= fd: {fd}, flags: {flags:#x}, mask: {mask:#x}, dirfd: {dirfd}, path: \
+         {path:?}"
+    );
+
+    let known = FAN_MARK_ADD
+        | FAN_MARK_REMOVE
+        | FAN_MARK_DONT_FOLLOW
+        | FAN_MARK_ONLYDIR
+        | FAN_MARK_MOUNT
+        | FAN_MARK_IGNORED_MASK
+        | FAN_MARK_IGNORED_SURV_MODIFY
+        | FAN_MARK_FLUSH
+        | FAN_MARK_FILESYSTEM;
+    let action = flags & (FAN_MARK_ADD | FAN_MARK_REMOVE | FAN_MARK_FLUSH);
+    let mount = flags & FAN_MARK_MOUNT != 0;
+    let filesystem = flags & FAN_MARK_FILESYSTEM != 0;
+    if flags & !known != 0 || action.count_ones() != 1 || (mount && filesystem) {
+        return Err(AxError::InvalidInput);
+    }
+    let group = Fanotify::from_fd(fd)?;
+
+    if action == FAN_MARK_FLUSH {
+        group.flush_marks(!mount && !filesystem);
+        return Ok(0);
+    }
+    if mask == 0 || mask & !(FAN_EVENTS | FAN_ONDIR | FAN_EVENT_ON_CHILD) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    if mask & FAN_PERM_EVENTS != 0 && !group.has_permissions() && action == FAN_MARK_ADD {
+        return Err(AxError::InvalidInput);
+    }
+
+    let mut resolve_flags = 0;
+    if path.is_none() {
+        resolve_flags |= AT_EMPTY_PATH;
+    }
+    if flags & FAN_MARK_DONT_FOLLOW != 0 {
+        resolve_flags |= AT_SYMLINK_NOFOLLOW;
+    }
+    let loc = resolve_at(dirfd, path.as_deref(), resolve_flags)?
+        .into_file()
+        .ok_or(AxError::InvalidInput)?;
+    if flags & FAN_MARK_ONLYDIR != 0 && loc.node_type() != NodeType::Directory {
+        return Err(AxError::NotADirectory);
+    }
+
+    let target = MarkTarget::of(&loc, mount, filesystem);
+    let ignore = flags & FAN_MARK_IGNORED_MASK != 0;
+    let (mask, ignored) = if ignore { (0, mask) } else { (mask, 0) };
+    if action == FAN_MARK_ADD {
+        let surv_modify = ignore && flags & FAN_MARK_IGNORED_SURV_MODIFY != 0;
+        group.add_mark(target, mask, ignored, surv_modify)?;
+    } else {
+        group.remove_mark(&target, mask, ignored)?;
+    }
+    Ok(0)
+}
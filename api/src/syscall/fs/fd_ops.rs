@@ -1,30 +1,38 @@
-use alloc::{format, string::ToString, sync::Arc};
+use alloc::{borrow::ToOwned, format, string::ToString, sync::Arc};
 use core::{
     ffi::{c_char, c_int},
     mem,
     ops::{Deref, DerefMut},
 };
 
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, LinuxError};
 use axfs::{FS_CONTEXT, FileBackend, OpenOptions, OpenResult};
 use axfs_ng_vfs::{DirEntry, FileNode, Location, NodePermission, NodeType, Reference};
 use axtask::current;
 use bitflags::bitflags;
 use linux_raw_sys::general::*;
-use starry_core::{task::AsThread, vfs::Device};
+use starry_core::{
+    task::AsThread,
+    vfs::{Device, find_device},
+};
 
 use crate::{
     file::{
-        Directory, FD_TABLE, File, FileLike, Pipe, add_file_like, close_file_like, get_file_like,
-        with_fs,
+        Directory, FD_TABLE, File, FileLike, Pipe, add_file_like, close_file_like,
+        fanotify::{self, FAN_OPEN, FAN_OPEN_PERM},
+        get_file_like, notify_fd_closed, pipe_max_size, with_fs,
     },
     mm::{UserPtr, vm_load_string},
     syscall::sys::{sys_getegid, sys_geteuid},
-    vfs::dev::tty,
+    vfs::{dev::tty, freeze::write_in},
 };
 
 /// Convert open flags to [`OpenOptions`].
-fn flags_to_options(flags: c_int, mode: __kernel_mode_t, (uid, gid): (u32, u32)) -> OpenOptions {
+pub(super) fn flags_to_options(
+    flags: c_int,
+    mode: __kernel_mode_t,
+    (uid, gid): (u32, u32),
+) -> OpenOptions {
     let flags = flags as u32;
     let mut options = OpenOptions::new();
     options.mode(mode).user(uid, gid);
@@ -54,15 +62,40 @@ fn flags_to_options(flags: c_int, mode: __kernel_mode_t, (uid, gid): (u32, u32))
     if flags & O_NOFOLLOW != 0 {
         options.no_follow(true);
     }
-    if flags & O_DIRECT != 0 {
-        options.direct(true);
-    }
+    // O_DIRECT is handled by the file, which keeps the page cache so that
+    // F_SETFL can turn it off again.
     options
 }
 
-fn add_to_fd(result: OpenResult, flags: u32) -> AxResult<i32> {
+pub(super) fn add_to_fd(result: OpenResult, flags: u32) -> AxResult<i32> {
+    let loc = match &result {
+        OpenResult::File(file) => file.location().clone(),
+        OpenResult::Dir(dir) => dir.clone(),
+    };
+    let watched = flags & O_PATH == 0;
+    if watched {
+        fanotify::notify(&loc, FAN_OPEN_PERM)?;
+    }
     let f: Arc<dyn FileLike> = match result {
         OpenResult::File(mut file) => {
+            // Device nodes outside devfs, as made by mknod, open the driver
+            // registered under their number.
+            let loc = file.location();
+            let node_type = loc.node_type();
+            if matches!(node_type, NodeType::CharacterDevice | NodeType::BlockDevice)
+                && loc.entry().downcast::<Device>().is_err()
+            {
+                let rdev = loc.metadata()?.rdev;
+                let device = find_device(rdev).ok_or(AxError::from(LinuxError::ENXIO))?;
+                let entry = DirEntry::new_file(
+                    FileNode::new(device),
+                    node_type,
+                    Reference::new(None, loc.name().to_owned()),
+                );
+                let loc = Location::new(loc.mountpoint().clone(), entry);
+                file = axfs::File::new(FileBackend::Direct(loc), file.flags());
+            }
+
             // /dev/xx handling
             if let Ok(device) = file.location().entry().downcast::<Device>() {
                 let inner = device.inner().as_any();
@@ -98,13 +131,18 @@ fn add_to_fd(result: OpenResult, flags: u32) -> AxResult<i32> {
                     file = axfs::File::new(FileBackend::Direct(loc), file.flags());
                 }
             }
-            Arc::new(File::new(file))
+            let file = File::new(file);
+            file.set_direct(flags & O_DIRECT != 0);
+            Arc::new(file)
         }
         OpenResult::Dir(dir) => Arc::new(Directory::new(dir)),
     };
     if flags & O_NONBLOCK != 0 {
         f.set_nonblocking(true)?;
     }
+    if watched {
+        fanotify::notify(&loc, FAN_OPEN)?;
+    }
     add_file_like(f, flags & O_CLOEXEC != 0)
 }
 
@@ -126,9 +164,15 @@ pub fn sys_openat(
     let mode = mode & !current().as_thread().proc_data.umask();
 
     let options = flags_to_options(flags, mode, (sys_geteuid()? as _, sys_getegid()? as _));
-    with_fs(dirfd, |fs| options.open(fs, path))
-        .and_then(|it| add_to_fd(it, flags as _))
-        .map(|fd| fd as isize)
+    with_fs(dirfd, |fs| {
+        if flags & (O_CREAT | O_TRUNC) as i32 != 0 {
+            write_in(fs, &path, || options.open(fs, &path))
+        } else {
+            options.open(fs, &path)
+        }
+    })
+    .and_then(|it| add_to_fd(it, flags as _))
+    .map(|fd| fd as isize)
 }
 
 /// Open a file by `filename` and insert it into the file descriptor table.
@@ -171,6 +215,7 @@ pub fn sys_close_range(first: i32, last: i32, flags: u32) -> AxResult<isize> {
 
     let cloexec = flags.contains(CloseRangeFlags::CLOEXEC);
     let mut fd_table = FD_TABLE.write();
+    let mut closed = false;
     if let Some(max_index) = fd_table.ids().next_back() {
         for fd in first..=last.min(max_index as i32) {
             if cloexec {
@@ -178,10 +223,14 @@ pub fn sys_close_range(first: i32, last: i32, flags: u32) -> AxResult<isize> {
                     f.cloexec = true;
                 }
             } else {
-                fd_table.remove(fd as _);
+                closed |= fd_table.remove(fd as _).is_some();
             }
         }
     }
+    drop(fd_table);
+    if closed {
+        notify_fd_closed();
+    }
 
     Ok(0)
 }
@@ -228,14 +277,23 @@ pub fn sys_dup3(old_fd: c_int, new_fd: c_int, flags: c_int) -> AxResult<isize> {
         .ok_or(AxError::BadFileDescriptor)?;
     f.cloexec = flags.contains(Dup3Flags::O_CLOEXEC);
 
-    fd_table.remove(new_fd as _);
+    let closed = fd_table.remove(new_fd as _).is_some();
     fd_table
         .add_at(new_fd as _, f)
         .map_err(|_| AxError::BadFileDescriptor)?;
+    drop(fd_table);
+    if closed {
+        notify_fd_closed();
+    }
 
     Ok(new_fd as _)
 }
 
+/// Set the maximum readahead size in pages of a regular file; StarryOS only.
+const F_SET_RA_PAGES: u32 = F_LINUX_SPECIFIC_BASE + 64;
+/// Get the maximum readahead size in pages of a regular file; StarryOS only.
+const F_GET_RA_PAGES: u32 = F_LINUX_SPECIFIC_BASE + 65;
+
 pub fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> AxResult<isize> {
     debug!("sys_fcntl <= fd: {fd} cmd: {cmd} arg: {arg}");
 
@@ -250,7 +308,11 @@ pub fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> AxResult<isize> {
             Ok(0)
         }
         F_SETFL => {
-            get_file_like(fd)?.set_nonblocking(arg & (O_NONBLOCK as usize) > 0)?;
+            let f = get_file_like(fd)?;
+            f.set_nonblocking(arg & (O_NONBLOCK as usize) > 0)?;
+            if let Ok(file) = f.into_any().downcast::<File>() {
+                file.set_direct(arg & O_DIRECT as usize != 0);
+            }
             Ok(0)
         }
         F_GETFL => {
@@ -260,6 +322,11 @@ pub fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> AxResult<isize> {
             if f.nonblocking() {
                 ret |= O_NONBLOCK;
             }
+            if let Ok(file) = f.clone().into_any().downcast::<File>()
+                && file.is_direct()
+            {
+                ret |= O_DIRECT;
+            }
 
             let perm = NodePermission::from_bits_truncate(f.stat()?.mode as _);
             if perm.contains(NodePermission::OWNER_WRITE) {
@@ -295,7 +362,17 @@ pub fn sys_fcntl(fd: c_int, cmd: c_int, arg: usize) -> AxResult<isize> {
         }
         F_SETPIPE_SZ => {
             let pipe = Pipe::from_fd(fd)?;
-            pipe.resize(arg)?;
+            let privileged = sys_geteuid()? == 0;
+            if arg > pipe_max_size() && !privileged {
+                return Err(AxError::OperationNotPermitted);
+            }
+            pipe.resize(arg, privileged)?;
+            Ok(0)
+        }
+        F_GET_RA_PAGES => Ok(File::from_fd(fd)?.ra_limit() as _),
+        F_SET_RA_PAGES => {
+            let pages = arg.try_into().map_err(|_| AxError::InvalidInput)?;
+            File::from_fd(fd)?.set_ra_limit(pages)?;
             Ok(0)
         }
         _ => {
@@ -0,0 +1,141 @@
+//! File handles, as made by `name_to_handle_at` and opened by
+//! `open_by_handle_at`.
+//!
+//! Only files on memory filesystems have handles. Disk filesystems do not
+//! expose inode generations, which a handle needs to tell a file from a
+//! later one reusing its inode number, so `name_to_handle_at` fails with
+//! `EOPNOTSUPP` on them.
+
+use core::ffi::{c_char, c_int};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axfs_ng_vfs::Location;
+use bytemuck::{AnyBitPattern, NoUninit, bytes_of, pod_read_unaligned};
+use linux_raw_sys::general::{
+    AT_EMPTY_PATH, AT_SYMLINK_FOLLOW, AT_SYMLINK_NOFOLLOW, O_CREAT, O_EXCL,
+};
+use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
+
+use super::fd_ops::{add_to_fd, flags_to_options};
+use crate::{
+    file::resolve_at,
+    mm::vm_load_string,
+    syscall::sys::{sys_getegid, sys_geteuid},
+    vfs::{MemoryFs, mounts},
+};
+
+const MAX_HANDLE_SZ: u32 = 128;
+/// Report the unique 64-bit mount id instead of the old 32-bit one.
+const AT_HANDLE_MNT_ID_UNIQUE: u32 = 0x1;
+/// 64-bit inode number and 32-bit generation, as used by tmpfs on Linux.
+const FILEID_INO64_GEN: i32 = 0x81;
+
+/// Header of `struct file_handle`; `f_handle` follows it.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern, NoUninit)]
+pub struct FileHandleHeader {
+    handle_bytes: u32,
+    handle_type: i32,
+}
+
+/// The opaque part of the handles we hand out. It does not name the mount,
+/// which `open_by_handle_at` takes from `mount_fd`.
+#[repr(C, packed)]
+#[derive(Clone, Copy, AnyBitPattern, NoUninit)]
+struct HandleFid {
+    ino: u64,
+    generation: u32,
+}
+
+const FID_SIZE: u32 = size_of::<HandleFid>() as u32;
+
+pub fn sys_name_to_handle_at(
+    dirfd: c_int,
+    path: *const c_char,
+    handle: *mut FileHandleHeader,
+    mount_id: *mut u8,
+    flags: u32,
+) -> AxResult<isize> {
+    let path = path.nullable().map(vm_load_string).transpose()?;
+    debug!("sys_name_to_handle_at <= dirfd: {dirfd}, path: {path:?}, flags: {flags:#x}");
+
+    if flags & !(AT_SYMLINK_FOLLOW | AT_EMPTY_PATH | AT_HANDLE_MNT_ID_UNIQUE) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let resolve_flags = if flags & AT_SYMLINK_FOLLOW != 0 {
+        flags & AT_EMPTY_PATH
+    } else {
+        flags & AT_EMPTY_PATH | AT_SYMLINK_NOFOLLOW
+    };
+    let loc = resolve_at(dirfd, path.as_deref(), resolve_flags)?
+        .into_file()
+        .ok_or(AxError::OperationNotSupported)?;
+
+    let header = handle.vm_read()?;
+    if header.handle_bytes > MAX_HANDLE_SZ {
+        return Err(AxError::InvalidInput);
+    }
+    if header.handle_bytes < FID_SIZE {
+        // Tell the caller how much room a handle needs.
+        handle.vm_write(FileHandleHeader {
+            handle_bytes: FID_SIZE,
+            ..header
+        })?;
+        return Err(AxError::from(LinuxError::EOVERFLOW));
+    }
+
+    let (ino, generation) = MemoryFs::encode_handle(&loc).ok_or(AxError::OperationNotSupported)?;
+    let mnt_id = mounts::mount_id(loc.mountpoint());
+    let fid = HandleFid { ino, generation };
+    handle.vm_write(FileHandleHeader {
+        handle_bytes: FID_SIZE,
+        handle_type: FILEID_INO64_GEN,
+    })?;
+    vm_write_slice(handle.wrapping_add(1).cast::<u8>(), bytes_of(&fid))?;
+    if flags & AT_HANDLE_MNT_ID_UNIQUE != 0 {
+        mount_id.cast::<u64>().vm_write(mnt_id)?;
+    } else {
+        mount_id
+            .cast::<c_int>()
+            .vm_write(mounts::old_mount_id(mnt_id) as c_int)?;
+    }
+    Ok(0)
+}
+
+pub fn sys_open_by_handle_at(
+    mount_fd: c_int,
+    handle: *const FileHandleHeader,
+    flags: i32,
+) -> AxResult<isize> {
+    debug!("sys_open_by_handle_at <= mount_fd: {mount_fd}, flags: {flags:#o}");
+
+    if sys_geteuid()? != 0 {
+        return Err(AxError::OperationNotPermitted);
+    }
+    // The handle is looked up on the filesystem `mount_fd` is on.
+    let mount = resolve_at(mount_fd, None, AT_EMPTY_PATH)?
+        .into_file()
+        .ok_or(AxError::BadFileDescriptor)?;
+
+    let header = handle.vm_read()?;
+    if header.handle_bytes == 0 || header.handle_bytes > MAX_HANDLE_SZ {
+        return Err(AxError::InvalidInput);
+    }
+    if header.handle_type != FILEID_INO64_GEN || header.handle_bytes != FID_SIZE {
+        return Err(AxError::from(LinuxError::ESTALE));
+    }
+    // `f_handle` is only 4-byte aligned.
+    let fid: HandleFid = pod_read_unaligned(&vm_load(
+        handle.wrapping_add(1).cast::<u8>(),
+        FID_SIZE as usize,
+    )?);
+
+    let mountpoint = mount.mountpoint().clone();
+    let entry = MemoryFs::decode_handle(&mountpoint.root_location(), fid.ino, fid.generation)?;
+    let loc = Location::new(mountpoint, entry);
+
+    // The file exists by definition, so creation flags are ignored.
+    let flags = flags & !((O_CREAT | O_EXCL) as i32);
+    let options = flags_to_options(flags, 0, (sys_geteuid()? as _, sys_getegid()? as _));
+    add_to_fd(options.open_loc(loc)?, flags as _).map(|fd| fd as isize)
+}
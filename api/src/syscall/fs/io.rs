@@ -6,19 +6,27 @@ use core::{
 
 use axerrno::{AxError, AxResult};
 use axfs::{FS_CONTEXT, FileFlags, OpenOptions};
-use axio::{Seek, SeekFrom};
+use axfs_ng_vfs::NodeType;
+use axio::SeekFrom;
 use axpoll::{IoEvents, Pollable};
 use axtask::current;
-use linux_raw_sys::general::__kernel_off_t;
+use linux_raw_sys::general::{__kernel_off_t, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
 use starry_vm::{VmMutPtr, VmPtr};
 use syscalls::Sysno;
 
 use crate::{
-    file::{File, FileLike, Pipe, SealedBuf, SealedBufMut, get_file_like},
+    file::{Directory, File, FileLike, Pipe, SealedBuf, SealedBufMut, get_file_like},
     io::{IoVec, IoVectorBuf},
     mm::{UserConstPtr, VmBytes, VmBytesMut},
+    vfs::{
+        freeze::freeze_lock, readahead::PAGE_SIZE, size_lock::size_lock, tunables::ra_max_pages,
+    },
 };
 
+const SYNC_FILE_RANGE_WAIT_BEFORE: u32 = 0x1;
+const SYNC_FILE_RANGE_WRITE: u32 = 0x2;
+const SYNC_FILE_RANGE_WAIT_AFTER: u32 = 0x4;
+
 struct DummyFd;
 impl FileLike for DummyFd {
     fn read(&self, _dst: &mut SealedBufMut) -> AxResult<usize> {
@@ -59,12 +67,23 @@ pub fn sys_dummy_fd(sysno: Sysno) -> AxResult<isize> {
     DummyFd.add_to_fd_table(false).map(|fd| fd as isize)
 }
 
+/// Fails with `EINVAL` if `f` is a file opened for direct I/O and the user
+/// buffer at `addr` is not aligned for it.
+fn check_direct_buffer(f: &Arc<dyn FileLike>, addr: usize) -> AxResult<()> {
+    match f.clone().into_any().downcast::<File>() {
+        Ok(file) => file.check_direct_buffer(addr),
+        Err(_) => Ok(()),
+    }
+}
+
 /// Read data from the file indicated by `fd`.
 ///
 /// Return the read size if success.
 pub fn sys_read(fd: i32, buf: *mut u8, len: usize) -> AxResult<isize> {
     debug!("sys_read <= fd: {fd}, buf: {buf:p}, len: {len}");
-    Ok(get_file_like(fd)?.read(&mut VmBytesMut::new(buf, len).into())? as _)
+    let f = get_file_like(fd)?;
+    check_direct_buffer(&f, buf as usize)?;
+    Ok(f.read(&mut VmBytesMut::new(buf, len).into())? as _)
 }
 
 pub fn sys_readv(fd: i32, iov: *const IoVec, iovcnt: usize) -> AxResult<isize> {
@@ -79,7 +98,9 @@ pub fn sys_readv(fd: i32, iov: *const IoVec, iovcnt: usize) -> AxResult<isize> {
 /// Return the written size if success.
 pub fn sys_write(fd: i32, buf: *mut u8, len: usize) -> AxResult<isize> {
     debug!("sys_write <= fd: {fd}, buf: {buf:p}, len: {len}");
-    Ok(get_file_like(fd)?.write(&mut VmBytes::new(buf, len).into())? as _)
+    let f = get_file_like(fd)?;
+    check_direct_buffer(&f, buf as usize)?;
+    Ok(f.write(&mut VmBytes::new(buf, len).into())? as _)
 }
 
 pub fn sys_writev(fd: i32, iov: *const IoVec, iovcnt: usize) -> AxResult<isize> {
@@ -97,7 +118,22 @@ pub fn sys_lseek(fd: c_int, offset: __kernel_off_t, whence: c_int) -> AxResult<i
         2 => SeekFrom::End(offset as _),
         _ => return Err(AxError::InvalidInput),
     };
-    let off = File::from_fd(fd)?.inner().seek(pos)?;
+    if let Ok(dir) = Directory::from_fd(fd) {
+        // `rewinddir`, `seekdir` and `telldir`, with the offsets handed out
+        // by getdents64
+        let mut stream = dir.stream.lock();
+        let offset = match pos {
+            SeekFrom::Start(off) => off,
+            SeekFrom::Current(delta) => stream
+                .offset()
+                .checked_add_signed(delta)
+                .ok_or(AxError::InvalidInput)?,
+            SeekFrom::End(_) => return Err(AxError::InvalidInput),
+        };
+        stream.seek(dir.inner(), offset);
+        return Ok(offset as _);
+    }
+    let off = File::from_fd(fd)?.seek(pos)?;
     Ok(off as _)
 }
 
@@ -111,14 +147,16 @@ pub fn sys_truncate(path: UserConstPtr<c_char>, length: __kernel_off_t) -> AxRes
         .write(true)
         .open(&FS_CONTEXT.lock(), path)?
         .into_file()?;
-    file.access(FileFlags::WRITE)?.set_len(length as _)?;
+    let file = file.access(FileFlags::WRITE)?;
+    freeze_lock(file.location())
+        .write(|| size_lock(file.location()).exclusive(|| file.set_len(length as _)))?;
     Ok(0)
 }
 
 pub fn sys_ftruncate(fd: c_int, length: __kernel_off_t) -> AxResult<isize> {
     debug!("sys_ftruncate <= {fd} {length}");
     let f = File::from_fd(fd)?;
-    f.inner().access(FileFlags::WRITE)?.set_len(length as _)?;
+    f.resize(|_| length as _)?;
     Ok(0)
 }
 
@@ -129,27 +167,73 @@ pub fn sys_fallocate(
     len: __kernel_off_t,
 ) -> AxResult<isize> {
     debug!("sys_fallocate <= fd: {fd}, mode: {mode}, offset: {offset}, len: {len}");
-    if mode != 0 {
+    if offset < 0 || len <= 0 {
         return Err(AxError::InvalidInput);
     }
+    let (offset, len) = (offset as u64, len as u64);
     let f = File::from_fd(fd)?;
-    let inner = f.inner();
-    let file = inner.access(FileFlags::WRITE)?;
-    file.set_len(file.location().len()?.max(offset as u64 + len as u64))?;
+    match mode {
+        0 => f.allocate(offset, len, false)?,
+        FALLOC_FL_KEEP_SIZE => f.allocate(offset, len, true)?,
+        _ if mode == FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE => f.punch_hole(offset, len)?,
+        _ => return Err(AxError::OperationNotSupported),
+    }
     Ok(0)
 }
 
+/// Writes out a file, or the entries of a directory.
+fn sync_fd(fd: c_int, data_only: bool) -> AxResult<()> {
+    if let Ok(dir) = Directory::from_fd(fd) {
+        // Entries are filesystem metadata; writing it out persists the
+        // creations, renames and unlinks made in the directory.
+        return dir.inner().filesystem().flush();
+    }
+    File::from_fd(fd)?.sync(data_only)
+}
+
 pub fn sys_fsync(fd: c_int) -> AxResult<isize> {
     debug!("sys_fsync <= {fd}");
-    let f = File::from_fd(fd)?;
-    f.inner().sync(false)?;
+    sync_fd(fd, false)?;
     Ok(0)
 }
 
 pub fn sys_fdatasync(fd: c_int) -> AxResult<isize> {
     debug!("sys_fdatasync <= {fd}");
-    let f = File::from_fd(fd)?;
-    f.inner().sync(true)?;
+    sync_fd(fd, true)?;
+    Ok(0)
+}
+
+pub fn sys_sync_file_range(
+    fd: c_int,
+    offset: __kernel_off_t,
+    nbytes: __kernel_off_t,
+    flags: u32,
+) -> AxResult<isize> {
+    debug!(
+        "sys_sync_file_range <= fd: {fd}, offset: {offset}, nbytes: {nbytes}, flags: {flags:#x}"
+    );
+    let known = SYNC_FILE_RANGE_WAIT_BEFORE | SYNC_FILE_RANGE_WRITE | SYNC_FILE_RANGE_WAIT_AFTER;
+    if flags & !known != 0 || offset < 0 || nbytes < 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let f = File::from_fd(fd).map_err(|_| AxError::BadFileDescriptor)?;
+    if f.inner().location().node_type() != NodeType::RegularFile {
+        return Err(AxError::BadFileDescriptor);
+    }
+
+    let start_page = (offset as u64 / PAGE_SIZE).min(u32::MAX as u64) as u32;
+    // A zero length, or one past the largest offset, means up to the end.
+    let end_page = match (offset as u64).checked_add(nbytes as u64) {
+        Some(end) if nbytes != 0 => end.div_ceil(PAGE_SIZE).min(u32::MAX as u64) as u32,
+        _ => u32::MAX,
+    };
+    f.sync_range(
+        start_page,
+        end_page,
+        flags & SYNC_FILE_RANGE_WAIT_BEFORE != 0,
+        flags & SYNC_FILE_RANGE_WRITE != 0,
+        flags & SYNC_FILE_RANGE_WAIT_AFTER != 0,
+    )?;
     Ok(0)
 }
 
@@ -163,9 +247,29 @@ pub fn sys_fadvise64(
     if Pipe::from_fd(fd).is_ok() {
         return Err(AxError::BrokenPipe);
     }
-    if advice > 5 {
+    if advice > 5 || len < 0 {
         return Err(AxError::InvalidInput);
     }
+    // Advice on anything but a regular file is accepted and ignored.
+    if let Ok(f) = File::from_fd(fd) {
+        f.advise(advice, offset as u64, len as u64)?;
+    } else {
+        get_file_like(fd)?;
+    }
+    Ok(0)
+}
+
+pub fn sys_readahead(fd: c_int, offset: __kernel_off_t, count: usize) -> AxResult<isize> {
+    debug!("sys_readahead <= fd: {fd}, offset: {offset}, count: {count}");
+    let f = File::from_fd(fd).map_err(|_| AxError::BadFileDescriptor)?;
+    f.inner()
+        .access(FileFlags::READ)
+        .map_err(|_| AxError::BadFileDescriptor)?;
+    if offset < 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let offset = offset as u64;
+    f.prefetch(offset, offset.saturating_add(count as u64), ra_max_pages())?;
     Ok(0)
 }
 
@@ -174,9 +278,8 @@ pub fn sys_pread64(fd: c_int, buf: *mut u8, len: usize, offset: __kernel_off_t)
     if offset < 0 {
         return Err(AxError::InvalidInput);
     }
-    let read = f
-        .inner()
-        .read_at(&mut VmBytesMut::new(buf, len), offset as _)?;
+    f.check_direct_buffer(buf as usize)?;
+    let read = f.read_at(&mut VmBytesMut::new(buf, len), offset as _)?;
     Ok(read as _)
 }
 
@@ -190,9 +293,8 @@ pub fn sys_pwrite64(
         return Ok(0);
     }
     let f = File::from_fd(fd)?;
-    let write = f
-        .inner()
-        .write_at(&mut VmBytes::new(buf, len), offset as _)?;
+    f.check_direct_buffer(buf as usize)?;
+    let write = f.write_at(&mut VmBytes::new(buf, len), offset as _)?;
     Ok(write as _)
 }
 
@@ -223,8 +325,7 @@ pub fn sys_preadv2(
 ) -> AxResult<isize> {
     debug!("sys_preadv2 <= fd: {fd}, iovcnt: {iovcnt}, offset: {offset}, flags: {_flags}");
     let f = File::from_fd(fd)?;
-    f.inner()
-        .read_at(&mut IoVectorBuf::new(iov, iovcnt)?.into_io(), offset as _)
+    f.read_at(&mut IoVectorBuf::new(iov, iovcnt)?.into_io(), offset as _)
         .map(|n| n as _)
 }
 
@@ -261,7 +362,7 @@ impl SendFile {
             SendFile::Direct(file) => file.read(&mut buf.into()),
             SendFile::Offset(file, offset) => {
                 let off = offset.vm_read()?;
-                let bytes_read = file.inner().read_at(&mut buf, off)?;
+                let bytes_read = file.read_at(&mut buf, off)?;
                 offset.vm_write(off + bytes_read as u64)?;
                 Ok(bytes_read)
             }
@@ -273,7 +374,7 @@ impl SendFile {
             SendFile::Direct(file) => file.write(&mut buf.into()),
             SendFile::Offset(file, offset) => {
                 let off = offset.vm_read()?;
-                let bytes_written = file.inner().write_at(&mut buf, off)?;
+                let bytes_written = file.write_at(&mut buf, off)?;
                 offset.vm_write(off + bytes_written as u64)?;
                 Ok(bytes_written)
             }
@@ -1,6 +1,9 @@
+mod aio;
 mod ctl;
 mod event;
+mod fanotify;
 mod fd_ops;
+mod handle;
 mod io;
 mod memfd;
 mod mount;
@@ -11,5 +14,5 @@ mod stat;
 mod timerfd;
 
 pub use self::{
-    ctl::*, event::*, fd_ops::*, io::*, memfd::*, mount::*, pidfd::*, pipe::*, signalfd::*, stat::*, timerfd::*,
+    aio::*, ctl::*, event::*, fanotify::*, fd_ops::*, handle::*, io::*, memfd::*, mount::*, pidfd::*, pipe::*, signalfd::*, stat::*, timerfd::*,
 };
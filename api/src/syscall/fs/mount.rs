@@ -1,38 +1,304 @@
+use alloc::vec::Vec;
 use core::ffi::{c_char, c_void};
 
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, LinuxError};
 use axfs::FS_CONTEXT;
+use bytemuck::{AnyBitPattern, Zeroable};
+use starry_core::vfs::{mount_users, pin_detached};
+use starry_vm::{VmMutPtr, VmPtr, vm_write_slice};
 
-use crate::{mm::vm_load_string, vfs::MemoryFs};
+use crate::{
+    mm::vm_load_string,
+    vfs::{MemoryFs, mounts, readahead::PAGE_SIZE},
+};
+
+const MNT_FORCE: i32 = 1;
+const MNT_DETACH: i32 = 2;
+const MNT_EXPIRE: i32 = 4;
+const UMOUNT_NOFOLLOW: i32 = 8;
 
 pub fn sys_mount(
     source: *const c_char,
     target: *const c_char,
     fs_type: *const c_char,
     _flags: i32,
-    _data: *const c_void,
+    data: *const c_void,
 ) -> AxResult<isize> {
     let source = vm_load_string(source)?;
     let target = vm_load_string(target)?;
     let fs_type = vm_load_string(fs_type)?;
-    debug!("sys_mount <= source: {source:?}, target: {target:?}, fs_type: {fs_type:?}");
+    let options = data
+        .cast::<c_char>()
+        .nullable()
+        .map(vm_load_string)
+        .transpose()?
+        .unwrap_or_default();
+    debug!(
+        "sys_mount <= source: {source:?}, target: {target:?}, fs_type: {fs_type:?}, options: \
+         {options:?}"
+    );
 
     if fs_type != "tmpfs" {
         return Err(AxError::NoSuchDevice);
     }
 
-    let fs = MemoryFs::new();
+    let fs = MemoryFs::with_capacity(tmpfs_capacity(&options)?);
+    let ra_pages = read_ahead_pages(&options)?;
+    mounts::check_ra_pages(ra_pages)?;
 
-    let target = FS_CONTEXT.lock().resolve(target)?;
-    target.mount(&fs)?;
+    let fs_context = FS_CONTEXT.lock();
+    let location = fs_context.resolve(&target)?;
+    location.mount(&fs)?;
+    let mounted = fs_context.resolve(&target)?;
+    mounts::record_mount(&location, &mounted, &source, &options);
+    mounts::set_mount_ra_pages(mounted.mountpoint(), ra_pages)?;
 
     Ok(0)
 }
 
-pub fn sys_umount2(target: *const c_char, _flags: i32) -> AxResult<isize> {
+/// Parses the `size=` option of a tmpfs mount into a number of pages, 0
+/// meaning no limit.
+fn tmpfs_capacity(options: &str) -> AxResult<u64> {
+    let Some(size) = options.split(',').find_map(|opt| opt.strip_prefix("size=")) else {
+        return Ok(0);
+    };
+    let (digits, shift) = match size.as_bytes().last() {
+        Some(b'k' | b'K') => (&size[..size.len() - 1], 10),
+        Some(b'm' | b'M') => (&size[..size.len() - 1], 20),
+        Some(b'g' | b'G') => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    let bytes = digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|it| it.checked_mul(1 << shift))
+        .ok_or(AxError::InvalidInput)?;
+    Ok(bytes.div_ceil(PAGE_SIZE))
+}
+
+/// Parses the `read_ahead_kb=` option of a mount into the readahead limit
+/// in pages of its files.
+fn read_ahead_pages(options: &str) -> AxResult<Option<u32>> {
+    let Some(kb) = options
+        .split(',')
+        .find_map(|opt| opt.strip_prefix("read_ahead_kb="))
+    else {
+        return Ok(None);
+    };
+    let kb = kb.parse::<u64>().map_err(|_| AxError::InvalidInput)?;
+    let pages = kb.saturating_mul(1024) / PAGE_SIZE;
+    Ok(Some(pages.min(u32::MAX as u64) as u32))
+}
+
+pub fn sys_umount2(target: *const c_char, flags: i32) -> AxResult<isize> {
     let target = vm_load_string(target)?;
-    debug!("sys_umount2 <= target: {target:?}");
-    let target = FS_CONTEXT.lock().resolve(target)?;
+    debug!("sys_umount2 <= target: {target:?}, flags: {flags:#x}");
+
+    if flags & !(MNT_FORCE | MNT_DETACH | MNT_EXPIRE | UMOUNT_NOFOLLOW) != 0
+        || (flags & MNT_EXPIRE != 0 && flags & (MNT_FORCE | MNT_DETACH) != 0)
+    {
+        return Err(AxError::InvalidInput);
+    }
+
+    let target = {
+        let fs = FS_CONTEXT.lock();
+        if flags & UMOUNT_NOFOLLOW != 0 {
+            fs.resolve_no_follow(target)?
+        } else {
+            fs.resolve(target)?
+        }
+    };
+
+    // MNT_DETACH takes the mount out of the namespace right away, pinning
+    // it until its last user goes. MNT_FORCE has nothing to abort as no
+    // filesystem has remote requests.
+    let mountpoint = target.mountpoint().clone();
+    if flags & MNT_DETACH == 0 {
+        let users = mount_users(&mountpoint);
+        if users != 0 {
+            warn!(
+                "umount: {} is busy with {users} users",
+                target.absolute_path()?
+            );
+            return Err(AxError::ResourceBusy);
+        }
+    }
+
     target.unmount()?;
+    if flags & MNT_DETACH != 0 {
+        pin_detached(&mountpoint);
+    }
+    mounts::record_unmount(&mountpoint);
     Ok(0)
 }
+
+/// `struct mnt_id_req`, up to `MNT_ID_REQ_SIZE_VER0`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+pub struct MntIdReq {
+    size: u32,
+    spare: u32,
+    mnt_id: u64,
+    param: u64,
+}
+
+/// `struct statmount`.
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable)]
+pub struct StatMount {
+    size: u32,
+    mnt_opts: u32,
+    mask: u64,
+    sb_dev_major: u32,
+    sb_dev_minor: u32,
+    sb_magic: u64,
+    sb_flags: u32,
+    fs_type: u32,
+    mnt_id: u64,
+    mnt_parent_id: u64,
+    mnt_id_old: u32,
+    mnt_parent_id_old: u32,
+    mnt_attr: u64,
+    mnt_propagation: u64,
+    mnt_peer_group: u64,
+    mnt_master: u64,
+    propagate_from: u64,
+    mnt_root: u32,
+    mnt_point: u32,
+    mnt_ns_id: u64,
+    spare: [u64; 49],
+}
+
+const STATMOUNT_SB_BASIC: u64 = 0x1;
+const STATMOUNT_MNT_BASIC: u64 = 0x2;
+const STATMOUNT_PROPAGATE_FROM: u64 = 0x4;
+const STATMOUNT_MNT_ROOT: u64 = 0x8;
+const STATMOUNT_MNT_POINT: u64 = 0x10;
+const STATMOUNT_FS_TYPE: u64 = 0x20;
+const STATMOUNT_MNT_OPTS: u64 = 0x80;
+
+const MNT_ID_REQ_SIZE_VER0: u32 = 24;
+const LSMT_ROOT: u64 = u64::MAX;
+const LISTMOUNT_REVERSE: u32 = 1;
+
+/// Every mount is private; there are no peer groups to propagate with.
+const MS_PRIVATE: u64 = 1 << 18;
+
+fn load_mnt_id_req(req: *const MntIdReq) -> AxResult<MntIdReq> {
+    let req = req.vm_read()?;
+    if req.size < MNT_ID_REQ_SIZE_VER0 {
+        return Err(AxError::InvalidInput);
+    }
+    Ok(req)
+}
+
+pub fn sys_statmount(
+    req: *const MntIdReq,
+    buf: *mut StatMount,
+    bufsize: usize,
+    flags: u32,
+) -> AxResult<isize> {
+    let req = load_mnt_id_req(req)?;
+    debug!(
+        "sys_statmount <= mnt_id: {:#x}, mask: {:#x}, bufsize: {bufsize}",
+        req.mnt_id, req.param
+    );
+    if flags != 0 {
+        return Err(AxError::InvalidInput);
+    }
+
+    let entry = mounts::lookup(req.mnt_id)?;
+    let parent_old = mounts::lookup(entry.parent).map_or(0, |parent| parent.old_id());
+    let mask = req.param;
+    let mut sm = StatMount::zeroed();
+    let mut strings = Vec::new();
+    let mut push_string = |value: &str| {
+        let offset = strings.len() as u32;
+        strings.extend_from_slice(value.as_bytes());
+        strings.push(0);
+        offset
+    };
+
+    if mask & STATMOUNT_SB_BASIC != 0 {
+        let mountpoint = entry.mountpoint().ok_or(AxError::NotFound)?;
+        let dev = mountpoint.device() as u64;
+        sm.sb_dev_major = (dev >> 32) as _;
+        sm.sb_dev_minor = dev as _;
+        sm.sb_magic = mountpoint.root_location().filesystem().stat()?.fs_type as _;
+        sm.mask |= STATMOUNT_SB_BASIC;
+    }
+    if mask & STATMOUNT_MNT_BASIC != 0 {
+        sm.mnt_id = entry.id;
+        sm.mnt_parent_id = entry.parent;
+        sm.mnt_id_old = entry.old_id();
+        sm.mnt_parent_id_old = parent_old;
+        sm.mnt_propagation = MS_PRIVATE;
+        sm.mask |= STATMOUNT_MNT_BASIC;
+    }
+    if mask & STATMOUNT_PROPAGATE_FROM != 0 {
+        sm.mask |= STATMOUNT_PROPAGATE_FROM;
+    }
+    if mask & STATMOUNT_MNT_ROOT != 0 {
+        // There are no bind mounts, so every mount shows its whole tree.
+        sm.mnt_root = push_string("/");
+        sm.mask |= STATMOUNT_MNT_ROOT;
+    }
+    if mask & STATMOUNT_MNT_POINT != 0 {
+        sm.mnt_point = push_string(&entry.path);
+        sm.mask |= STATMOUNT_MNT_POINT;
+    }
+    if mask & STATMOUNT_FS_TYPE != 0 {
+        sm.fs_type = push_string(&entry.fs_type);
+        sm.mask |= STATMOUNT_FS_TYPE;
+    }
+    if mask & STATMOUNT_MNT_OPTS != 0 && !entry.options.is_empty() {
+        sm.mnt_opts = push_string(&entry.options);
+        sm.mask |= STATMOUNT_MNT_OPTS;
+    }
+
+    let header = size_of::<StatMount>();
+    if bufsize < header + strings.len() {
+        return Err(AxError::from(LinuxError::EOVERFLOW));
+    }
+    sm.size = (header + strings.len()) as _;
+    buf.vm_write(sm)?;
+    vm_write_slice(buf.cast::<u8>().wrapping_add(header), &strings)?;
+    Ok(0)
+}
+
+pub fn sys_listmount(
+    req: *const MntIdReq,
+    mnt_ids: *mut u64,
+    nr_mnt_ids: usize,
+    flags: u32,
+) -> AxResult<isize> {
+    let req = load_mnt_id_req(req)?;
+    debug!(
+        "sys_listmount <= mnt_id: {:#x}, last: {:#x}, nr: {nr_mnt_ids}, flags: {flags:#x}",
+        req.mnt_id, req.param
+    );
+    if flags & !LISTMOUNT_REVERSE != 0 {
+        return Err(AxError::InvalidInput);
+    }
+
+    let parent = if req.mnt_id == LSMT_ROOT {
+        mounts::mount_id(FS_CONTEXT.lock().root_dir().mountpoint())
+    } else {
+        req.mnt_id
+    };
+    let mut ids = mounts::children(parent)?;
+    // `param` is the last id returned by the previous call, to continue
+    // after it.
+    let last = req.param;
+    if flags & LISTMOUNT_REVERSE != 0 {
+        ids.reverse();
+        ids.retain(|&id| last == 0 || id < last);
+    } else {
+        ids.retain(|&id| id > last);
+    }
+    ids.truncate(nr_mnt_ids);
+
+    let bytes: Vec<u8> = ids.iter().flat_map(|id| id.to_ne_bytes()).collect();
+    vm_write_slice(mnt_ids.cast::<u8>(), &bytes)?;
+    Ok(ids.len() as _)
+}
@@ -4,12 +4,13 @@ use axerrno::{AxError, AxResult};
 use axfs::FS_CONTEXT;
 use axfs_ng_vfs::{Location, NodePermission};
 use linux_raw_sys::general::{
-    __kernel_fsid_t, AT_EMPTY_PATH, R_OK, W_OK, X_OK, stat, statfs, statx,
+    __kernel_fsid_t, AT_EACCESS, AT_EMPTY_PATH, AT_NO_AUTOMOUNT, AT_STATX_SYNC_TYPE,
+    AT_SYMLINK_NOFOLLOW, R_OK, STATX_MNT_ID, STATX_MNT_ID_UNIQUE, W_OK, X_OK, stat, statfs, statx,
 };
 use starry_vm::{VmMutPtr, VmPtr};
 
 use crate::{
-    file::{File, FileLike, resolve_at},
+    file::{File, FileLike, resolve_at, stat_at},
     mm::vm_load_string,
 };
 
@@ -35,7 +36,7 @@ pub fn sys_fstat(fd: i32, statbuf: *mut stat) -> AxResult<isize> {
 /// Return 0 if success.
 #[cfg(target_arch = "x86_64")]
 pub fn sys_lstat(path: *const c_char, statbuf: *mut stat) -> AxResult<isize> {
-    use linux_raw_sys::general::{AT_FDCWD, AT_SYMLINK_NOFOLLOW};
+    use linux_raw_sys::general::AT_FDCWD;
 
     sys_fstatat(AT_FDCWD, path, statbuf, AT_SYMLINK_NOFOLLOW)
 }
@@ -50,8 +51,11 @@ pub fn sys_fstatat(
 
     debug!("sys_fstatat <= dirfd: {dirfd}, path: {path:?}, flags: {flags}");
 
-    let loc = resolve_at(dirfd, path.as_deref(), flags)?;
-    statbuf.vm_write(loc.stat()?.into())?;
+    // Nothing is ever automounted, so `AT_NO_AUTOMOUNT` changes nothing.
+    if flags & !(AT_EMPTY_PATH | AT_SYMLINK_NOFOLLOW | AT_NO_AUTOMOUNT) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    statbuf.vm_write(stat_at(dirfd, path.as_deref(), flags)?.into())?;
 
     Ok(0)
 }
@@ -60,7 +64,7 @@ pub fn sys_statx(
     dirfd: c_int,
     path: *const c_char,
     flags: u32,
-    _mask: u32,
+    mask: u32,
     statxbuf: *mut statx,
 ) -> AxResult<isize> {
     // `statx()` uses pathname, dirfd, and flags to identify the target
@@ -93,7 +97,21 @@ pub fn sys_statx(
     let path = path.nullable().map(vm_load_string).transpose()?;
     debug!("sys_statx <= dirfd: {dirfd}, path: {path:?}, flags: {flags}");
 
-    statxbuf.vm_write(resolve_at(dirfd, path.as_deref(), flags)?.stat()?.into())?;
+    // Metadata is always up to date, so neither sync type changes anything;
+    // asking for both is an error.
+    if flags & !(AT_EMPTY_PATH | AT_SYMLINK_NOFOLLOW | AT_NO_AUTOMOUNT | AT_STATX_SYNC_TYPE) != 0
+        || flags & AT_STATX_SYNC_TYPE == AT_STATX_SYNC_TYPE
+    {
+        return Err(AxError::InvalidInput);
+    }
+
+    let kstat = stat_at(dirfd, path.as_deref(), flags)?;
+    let mut statx = statx::from(kstat);
+    if mask & STATX_MNT_ID_UNIQUE != 0 && kstat.mnt_id != 0 {
+        statx.stx_mnt_id = kstat.mnt_id;
+        statx.stx_mask = statx.stx_mask & !STATX_MNT_ID | STATX_MNT_ID_UNIQUE;
+    }
+    statxbuf.vm_write(statx)?;
 
     Ok(0)
 }
@@ -109,6 +127,11 @@ pub fn sys_faccessat2(dirfd: c_int, path: *const c_char, mode: u32, flags: u32)
     let path = path.nullable().map(vm_load_string).transpose()?;
     debug!("sys_faccessat2 <= dirfd: {dirfd}, path: {path:?}, mode: {mode}, flags: {flags}");
 
+    if flags & !(AT_EACCESS | AT_EMPTY_PATH | AT_SYMLINK_NOFOLLOW) != 0
+        || mode & !(R_OK | W_OK | X_OK) != 0
+    {
+        return Err(AxError::InvalidInput);
+    }
     let file = resolve_at(dirfd, path.as_deref(), flags)?;
 
     if mode == 0 {
@@ -2,11 +2,12 @@ use core::time::Duration;
 
 use axerrno::{AxError, AxResult};
 use axpoll::IoEvents;
-use axtask::future::{self, block_on, poll_io};
+use axtask::future::{block_on, poll_io};
 use bitflags::bitflags;
 use linux_raw_sys::general::{
     EPOLL_CLOEXEC, EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLL_CTL_MOD, epoll_event, timespec,
 };
+use starry_core::timer;
 use starry_signal::SignalSet;
 
 use crate::{
@@ -16,10 +17,16 @@ use crate::{
     },
     mm::{UserConstPtr, UserPtr, nullable},
     signal::with_replacen_blocked,
-    syscall::signal::check_sigset_size,
+    syscall::{signal::check_sigset_size, sys::sys_geteuid},
     time::TimeValueLike,
 };
 
+/// Events an `EPOLLEXCLUSIVE` interest may be added with, as on Linux.
+const EXCLUSIVE_EVENTS: IoEvents = IoEvents::IN
+    .union(IoEvents::OUT)
+    .union(IoEvents::ERR)
+    .union(IoEvents::HUP);
+
 bitflags! {
     /// Flags for the `epoll_create` syscall.
     #[derive(Debug, Clone, Copy, Default)]
@@ -31,7 +38,7 @@ bitflags! {
 pub fn sys_epoll_create1(flags: u32) -> AxResult<isize> {
     let flags = EpollCreateFlags::from_bits(flags).ok_or(AxError::InvalidInput)?;
     debug!("sys_epoll_create1 <= flags: {flags:?}");
-    Epoll::new()
+    Epoll::new(sys_geteuid()? as _)
         .add_to_fd_table(flags.contains(EpollCreateFlags::CLOEXEC))
         .map(|fd| fd as isize)
 }
@@ -61,10 +68,20 @@ pub fn sys_epoll_ctl(
     match op {
         EPOLL_CTL_ADD => {
             let (event, flags) = parse_event()?;
+            if flags.contains(EpollFlags::EXCLUSIVE)
+                && (!EXCLUSIVE_EVENTS.contains(event.events)
+                    || flags.contains(EpollFlags::ONESHOT)
+                    || Epoll::from_fd(fd).is_ok())
+            {
+                return Err(AxError::InvalidInput);
+            }
             epoll.add(fd, event, flags)?;
         }
         EPOLL_CTL_MOD => {
             let (event, flags) = parse_event()?;
+            if flags.contains(EpollFlags::EXCLUSIVE) {
+                return Err(AxError::InvalidInput);
+            }
             epoll.modify(fd, event, flags)?;
         }
         EPOLL_CTL_DEL => {
@@ -95,7 +112,7 @@ fn do_epoll_wait(
 
     with_replacen_blocked(
         nullable!(sigmask.get_as_ref())?.copied(),
-        || match block_on(future::timeout(
+        || match block_on(timer::timeout(
             timeout,
             poll_io(epoll.as_ref(), IoEvents::IN, false, || {
                 epoll.poll_events(events)
@@ -107,6 +124,16 @@ fn do_epoll_wait(
     )
 }
 
+#[cfg(target_arch = "x86_64")]
+pub fn sys_epoll_wait(
+    epfd: i32,
+    events: UserPtr<epoll_event>,
+    maxevents: i32,
+    timeout: i32,
+) -> AxResult<isize> {
+    sys_epoll_pwait(epfd, events, maxevents, timeout, UserConstPtr::default(), 0)
+}
+
 pub fn sys_epoll_pwait(
     epfd: i32,
     events: UserPtr<epoll_event>,
@@ -115,11 +142,8 @@ pub fn sys_epoll_pwait(
     sigmask: UserConstPtr<SignalSet>,
     sigsetsize: usize,
 ) -> AxResult<isize> {
-    let timeout = match timeout {
-        -1 => None,
-        t if t >= 0 => Some(Duration::from_millis(t as u64)),
-        _ => return Err(AxError::InvalidInput),
-    };
+    // Any negative timeout waits forever, as on Linux.
+    let timeout = u64::try_from(timeout).ok().map(Duration::from_millis);
     do_epoll_wait(epfd, events, maxevents, timeout, sigmask, sigsetsize)
 }
 
@@ -1,24 +1,307 @@
+//! select(2), poll(2) and epoll(7).
+//!
+//! Readiness comes from [`readiness`], shared with the epoll instances, so
+//! all three report the same events. select(2) folds them into its three
+//! sets with `SELECT_READ`, `SELECT_WRITE` and `SELECT_EXCEPT`, like Linux.
+
 mod epoll;
 mod poll;
 mod select;
 
-use alloc::{sync::Arc, vec::Vec};
-use core::task::Context;
+use alloc::{
+    boxed::Box,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    ffi::c_int,
+    mem::{self, ManuallyDrop},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, RawWaker, RawWakerVTable, Waker},
+};
 
+use axerrno::AxResult;
 use axpoll::{IoEvents, Pollable};
+use kspin::SpinNoPreempt;
 
 pub use self::{epoll::*, poll::*, select::*};
-use crate::file::FileLike;
+use crate::file::{FD_TABLE, FileLike, readiness, register_fd_closed};
+
+/// Events that mark a descriptor as ready in `readfds`.
+const SELECT_READ: IoEvents = IoEvents::IN
+    .union(IoEvents::RDNORM)
+    .union(IoEvents::RDHUP)
+    .union(IoEvents::HUP)
+    .union(IoEvents::ERR);
+/// Events that mark a descriptor as ready in `writefds`.
+const SELECT_WRITE: IoEvents = IoEvents::OUT.union(IoEvents::WRNORM).union(IoEvents::ERR);
+/// Events that mark a descriptor as ready in `exceptfds`.
+const SELECT_EXCEPT: IoEvents = IoEvents::PRI;
+
+/// The files select(2) and poll(2) wait on, each with a waker of its own.
+/// Readiness is checked in full at first, and after that only for the
+/// files woken or armed again since, so a wake costs the files it touched
+/// rather than all of them.
+///
+/// The files are held, so one whose descriptor another thread closes stays
+/// open for the wait; a close of any descriptor wakes it as well, to look up
+/// the descriptors again and drop those gone.
+///
+/// The wakers point into the shared [`FdWakes`] instead of being allocated
+/// one by one, so a wait makes the same few allocations however many
+/// descriptors it watches.
+struct FdPollSet {
+    files: Vec<FdFile>,
+    wakes: Arc<FdWakes>,
+    /// Swapped with either list of `wakes` to go through it, with the same
+    /// room, so that wakes meanwhile push to an empty list.
+    scratch: SpinNoPreempt<Vec<usize>>,
+}
+
+struct FdFile {
+    fd: c_int,
+    file: Arc<dyn FileLike>,
+    events: IoEvents,
+}
+
+/// What the wakers of an [`FdPollSet`] share. Both lists hold each file
+/// at most once and have room for all of them, so a wake never allocates.
+struct FdWakes {
+    /// The waker of the waiting task.
+    waker: SpinNoPreempt<Option<Waker>>,
+    /// What the waker of each file points to, and last that of the waker
+    /// woken by closes.
+    slots: Box<[FdSlot]>,
+    /// The files whose waker is to be registered again.
+    disarmed: SpinNoPreempt<Vec<usize>>,
+    /// The files to check at the next pass.
+    updated: SpinNoPreempt<Vec<usize>>,
+    /// Whether a descriptor may have been closed since the last lookup.
+    closed: AtomicBool,
+}
+
+/// What a waker of an [`FdPollSet`] points to.
+struct FdSlot {
+    /// The state this is part of, on which each waker holds a strong count.
+    wakes: *const FdWakes,
+    index: usize,
+    /// Whether the waker is registered.
+    armed: AtomicBool,
+    /// Whether the file is in `updated`.
+    pending: AtomicBool,
+    /// Whether the descriptor of the file has been closed.
+    gone: AtomicBool,
+}
+
+impl FdWakes {
+    fn close_index(&self) -> usize {
+        self.slots.len() - 1
+    }
+
+    fn mark_updated(&self, index: usize) {
+        if !self.slots[index].pending.swap(true, Ordering::AcqRel) {
+            self.updated.lock().push(index);
+        }
+    }
+
+    fn wake(&self, index: usize) {
+        let disarmed = self.slots[index].armed.swap(false, Ordering::AcqRel);
+        if index == self.close_index() {
+            self.closed.store(true, Ordering::Release);
+        } else {
+            if disarmed {
+                self.disarmed.lock().push(index);
+            }
+            self.mark_updated(index);
+        }
+        let waker = self.waker.lock().clone();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+static FD_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+    fd_waker_clone,
+    fd_waker_wake,
+    fd_waker_wake_by_ref,
+    fd_waker_drop,
+);
+
+// SAFETY (all four): `slot` points to an `FdSlot` in the `FdWakes` it
+// names, which stays alive for the strong count the waker holds on it.
+
+unsafe fn fd_waker_clone(slot: *const ()) -> RawWaker {
+    let wakes = unsafe { (*(slot as *const FdSlot)).wakes };
+    unsafe { Arc::increment_strong_count(wakes) };
+    RawWaker::new(slot, &FD_WAKER_VTABLE)
+}
+
+unsafe fn fd_waker_wake(slot: *const ()) {
+    unsafe {
+        fd_waker_wake_by_ref(slot);
+        fd_waker_drop(slot);
+    }
+}
+
+unsafe fn fd_waker_wake_by_ref(slot: *const ()) {
+    let slot = unsafe { &*(slot as *const FdSlot) };
+    unsafe { &*slot.wakes }.wake(slot.index);
+}
+
+unsafe fn fd_waker_drop(slot: *const ()) {
+    let wakes = unsafe { (*(slot as *const FdSlot)).wakes };
+    unsafe { Arc::decrement_strong_count(wakes) };
+}
+
+impl FdPollSet {
+    /// Gathers the open files among `fds`, each given with the events wanted
+    /// of it and a key handed back in the same order. Those not open are
+    /// passed to `closed`: select(2) fails on them, poll(2) reports
+    /// `POLLNVAL` and goes on.
+    fn gather<K>(
+        fds: impl IntoIterator<Item = (K, c_int, IoEvents)>,
+        mut closed: impl FnMut(K) -> AxResult<()>,
+    ) -> AxResult<(Self, Vec<K>)> {
+        let fd_table = FD_TABLE.read();
+        let mut files = Vec::new();
+        let mut keys = Vec::new();
+        for (key, fd, events) in fds {
+            match fd_table.get(fd as usize) {
+                Some(desc) => {
+                    files.push(FdFile {
+                        fd,
+                        file: desc.inner.clone(),
+                        events,
+                    });
+                    keys.push(key);
+                }
+                None => closed(key)?,
+            }
+        }
+        drop(fd_table);
+
+        let count = files.len();
+        let wakes = Arc::new_cyclic(|this: &Weak<FdWakes>| FdWakes {
+            waker: SpinNoPreempt::new(None),
+            slots: (0..=count)
+                .map(|index| FdSlot {
+                    wakes: this.as_ptr(),
+                    index,
+                    armed: AtomicBool::new(false),
+                    pending: AtomicBool::new(index < count),
+                    gone: AtomicBool::new(false),
+                })
+                .collect(),
+            disarmed: SpinNoPreempt::new((0..count).collect()),
+            updated: SpinNoPreempt::new((0..count).collect()),
+            closed: AtomicBool::new(false),
+        });
+        let scratch = SpinNoPreempt::new(Vec::with_capacity(count));
+        Ok((
+            Self {
+                files,
+                wakes,
+                scratch,
+            },
+            keys,
+        ))
+    }
+
+    /// Returns the descriptor of the file at `index`.
+    fn fd(&self, index: usize) -> c_int {
+        self.files[index].fd
+    }
+
+    /// Returns the waker of the slot at `index`, borrowing the strong count
+    /// `self` holds.
+    fn waker(&self, index: usize) -> ManuallyDrop<Waker> {
+        let slot = &self.wakes.slots[index] as *const FdSlot as *const ();
+        // SAFETY: the slot is alive as long as `self`, and the waker is never
+        // dropped; its clones take a strong count of their own.
+        ManuallyDrop::new(unsafe { Waker::from_raw(RawWaker::new(slot, &FD_WAKER_VTABLE)) })
+    }
+
+    /// Calls `f` with the position, file and wanted events of each file
+    /// whose readiness may have changed since the last call: all of them
+    /// at first, and then those woken or armed since. Files whose
+    /// descriptor is gone are skipped.
+    fn for_each_updated(&self, mut f: impl FnMut(usize, &dyn FileLike, IoEvents)) {
+        let mut updated = mem::take(&mut *self.scratch.lock());
+        mem::swap(&mut updated, &mut *self.wakes.updated.lock());
+        for &index in updated.iter() {
+            let slot = &self.wakes.slots[index];
+            // Cleared first, so that a wake while polling marks it again.
+            slot.pending.store(false, Ordering::Release);
+            if slot.gone.load(Ordering::Acquire) {
+                continue;
+            }
+            let file = &self.files[index];
+            f(index, file.file.as_ref(), file.events);
+        }
+        updated.clear();
+        *self.scratch.lock() = updated;
+    }
+
+    /// Returns the positions of the files whose descriptor has been closed,
+    /// or now refers to another file, since the last call. They are left out
+    /// of the waits from then on.
+    fn take_closed(&self) -> Vec<usize> {
+        if !self.wakes.closed.swap(false, Ordering::AcqRel) {
+            return Vec::new();
+        }
+        let fd_table = FD_TABLE.read();
+        let mut closed = Vec::new();
+        for (index, file) in self.files.iter().enumerate() {
+            let gone = &self.wakes.slots[index].gone;
+            if gone.load(Ordering::Acquire) {
+                continue;
+            }
+            let open = fd_table.get(file.fd as usize).is_some_and(|desc| {
+                ptr::addr_eq(Arc::as_ptr(&desc.inner), Arc::as_ptr(&file.file))
+            });
+            if !open {
+                gone.store(true, Ordering::Release);
+                closed.push(index);
+            }
+        }
+        closed
+    }
+}
 
-struct FdPollSet(pub Vec<(Arc<dyn FileLike>, IoEvents)>);
 impl Pollable for FdPollSet {
     fn poll(&self) -> IoEvents {
         unreachable!()
     }
 
     fn register(&self, context: &mut Context<'_>, _events: IoEvents) {
-        for (file, events) in &self.0 {
-            file.register(context, *events);
+        let wakes = &self.wakes;
+        *wakes.waker.lock() = Some(context.waker().clone());
+        let close = wakes.close_index();
+        if !wakes.slots[close].armed.swap(true, Ordering::AcqRel) {
+            register_fd_closed(&self.waker(close));
+            // A close before it was armed is looked for at the next pass.
+            wakes.closed.store(true, Ordering::Release);
+        }
+        // Taken out first, as a wake while registering pushes to it.
+        let mut disarmed = mem::take(&mut *self.scratch.lock());
+        mem::swap(&mut disarmed, &mut *wakes.disarmed.lock());
+        for &index in disarmed.iter() {
+            let slot = &wakes.slots[index];
+            if slot.gone.load(Ordering::Acquire) {
+                continue;
+            }
+            slot.armed.store(true, Ordering::Release);
+            // Whatever changed before it was armed again is seen at the
+            // next pass.
+            wakes.mark_updated(index);
+            let file = &self.files[index];
+            let waker = self.waker(index);
+            file.file
+                .register(&mut Context::from_waker(&waker), file.events);
         }
+        disarmed.clear();
+        *self.scratch.lock() = disarmed;
     }
 }
@@ -3,11 +3,11 @@ use alloc::vec::Vec;
 use axerrno::{AxError, AxResult};
 use axhal::time::TimeValue;
 use axpoll::IoEvents;
-use axtask::future::{self, block_on, poll_io};
 use linux_raw_sys::general::{POLLNVAL, pollfd, timespec};
+use starry_core::timer;
 use starry_signal::SignalSet;
 
-use super::FdPollSet;
+use super::{FdPollSet, readiness};
 use crate::{
     file::get_file_like,
     mm::{UserConstPtr, UserPtr, nullable},
@@ -23,68 +23,79 @@ fn do_poll(
 ) -> AxResult<isize> {
     debug!("do_poll fds={poll_fds:?} timeout={timeout:?}");
 
-    let mut res = 0isize;
-    let mut fds = Vec::with_capacity(poll_fds.len());
-    let mut revents = Vec::with_capacity(poll_fds.len());
-    for fd in poll_fds.iter_mut() {
-        if fd.fd == -1 {
-            // Skip -1
+    if timeout.is_some_and(|timeout| timeout.is_zero()) {
+        return with_replacen_blocked(sigmask, || poll_once(poll_fds));
+    }
+    let mut interests = Vec::with_capacity(poll_fds.len());
+    for (index, fd) in poll_fds.iter_mut().enumerate() {
+        fd.revents = 0;
+        // Negative descriptors are ignored, with no events reported.
+        if fd.fd < 0 {
             continue;
         }
-        match get_file_like(fd.fd) {
-            Ok(f) => {
-                fds.push((
-                    f,
-                    IoEvents::from_bits(fd.events as _).ok_or(AxError::InvalidInput)?
-                        | IoEvents::ALWAYS_POLL,
-                ));
-                revents.push(&mut fd.revents);
-            }
-            Err(_) => {
-                // If the fd is invalid, set revents to POLLNVAL
-                fd.revents = POLLNVAL as _;
-                res += 1;
-            }
-        }
+        let events = IoEvents::from_bits(fd.events as _).ok_or(AxError::InvalidInput)?;
+        interests.push((index, fd.fd, events | IoEvents::ALWAYS_POLL));
     }
-    if res > 0 {
-        return Ok(res);
-    }
-    let fds = FdPollSet(fds);
+    // Descriptors not open, or closed during the wait, count as ready, with
+    // `POLLNVAL`.
+    let mut invalid = 0usize;
+    let (fds, indices) = FdPollSet::gather(interests, |index| {
+        poll_fds[index].revents = POLLNVAL as _;
+        invalid += 1;
+        Ok(())
+    })?;
 
     with_replacen_blocked(sigmask, || {
-        match block_on(future::timeout(
-            timeout,
-            poll_io(&fds, IoEvents::empty(), false, || {
-                let mut res = 0usize;
-                for ((fd, events), revents) in fds.0.iter().zip(revents.iter_mut()) {
-                    let mut result = fd.poll();
-                    if result.contains(IoEvents::IN) {
-                        result |= IoEvents::RDNORM;
-                    }
-                    if result.contains(IoEvents::OUT) {
-                        result |= IoEvents::WRNORM;
-                    }
-                    result &= *events;
-
-                    **revents = result.bits() as _;
-                    if **revents != 0 {
-                        res += 1;
-                    }
-                }
-                if res > 0 {
-                    Ok(res as _)
-                } else {
-                    Err(AxError::WouldBlock)
+        let deadline = timeout.map(timer::deadline_after);
+        match timer::wait_for_io(&fds, IoEvents::empty(), false, deadline, true, || {
+            for i in fds.take_closed() {
+                poll_fds[indices[i]].revents = POLLNVAL as _;
+                invalid += 1;
+            }
+            let mut res = invalid;
+            fds.for_each_updated(|i, file, events| {
+                let revents = readiness(file, events).bits() as _;
+                poll_fds[indices[i]].revents = revents;
+                if revents != 0 {
+                    res += 1;
                 }
-            }),
-        )) {
-            Ok(r) => r,
-            Err(_) => Ok(0),
+            });
+            if res > 0 {
+                Ok(res as _)
+            } else {
+                Err(AxError::WouldBlock)
+            }
+        }) {
+            Err(AxError::TimedOut) => Ok(0),
+            result => result,
         }
     })
 }
 
+/// Fills in the events of the descriptors ready right now, for a zero
+/// timeout: a single pass, with nothing registered to wait on.
+fn poll_once(poll_fds: &mut [pollfd]) -> AxResult<isize> {
+    // All the events are checked before any descriptor is, as when waiting.
+    for fd in poll_fds.iter_mut() {
+        fd.revents = 0;
+        if fd.fd >= 0 && IoEvents::from_bits(fd.events as _).is_none() {
+            return Err(AxError::InvalidInput);
+        }
+    }
+    let mut res = 0;
+    for fd in poll_fds.iter_mut().filter(|fd| fd.fd >= 0) {
+        let events = IoEvents::from_bits_retain(fd.events as _) | IoEvents::ALWAYS_POLL;
+        fd.revents = match get_file_like(fd.fd) {
+            Ok(file) => readiness(file.as_ref(), events).bits() as _,
+            Err(_) => POLLNVAL as _,
+        };
+        if fd.revents != 0 {
+            res += 1;
+        }
+    }
+    Ok(res)
+}
+
 #[cfg(target_arch = "x86_64")]
 pub fn sys_poll(fds: UserPtr<pollfd>, nfds: u32, timeout: i32) -> AxResult<isize> {
     let fds = fds.get_as_mut_slice(nfds as usize)?;
@@ -1,25 +1,26 @@
-use alloc::vec::Vec;
-use core::{fmt, time::Duration};
+use core::{ffi::c_ulong, fmt, future, time::Duration};
 
 use axerrno::{AxError, AxResult};
+#[cfg(target_arch = "x86_64")]
+use axhal::time::monotonic_time;
 use axpoll::IoEvents;
-use axtask::future::{self, block_on, poll_io};
+use axtask::future::{block_on, interruptible};
 use bitmaps::Bitmap;
-use linux_raw_sys::{
-    general::*,
-    select_macros::{FD_ISSET, FD_SET, FD_ZERO},
-};
+use linux_raw_sys::{general::*, select_macros::FD_ISSET};
+use starry_core::timer;
 use starry_signal::SignalSet;
 
-use super::FdPollSet;
+use super::{FdPollSet, SELECT_EXCEPT, SELECT_READ, SELECT_WRITE, readiness};
 use crate::{
-    file::FD_TABLE,
+    file::get_file_like,
     mm::{UserConstPtr, UserPtr, nullable},
     signal::with_replacen_blocked,
     syscall::signal::check_sigset_size,
     time::TimeValueLike,
 };
 
+const FD_SET_WORDS: usize = __FD_SETSIZE as usize / c_ulong::BITS as usize;
+
 struct FdSet(Bitmap<{ __FD_SETSIZE as usize }>);
 
 impl FdSet {
@@ -61,9 +62,9 @@ fn do_select(
         None
     };
 
-    let mut readfds = nullable!(readfds.get_as_mut())?;
-    let mut writefds = nullable!(writefds.get_as_mut())?;
-    let mut exceptfds = nullable!(exceptfds.get_as_mut())?;
+    let readfds = nullable!(readfds.get_as_mut())?;
+    let writefds = nullable!(writefds.get_as_mut())?;
+    let exceptfds = nullable!(exceptfds.get_as_mut())?;
 
     let read_set = FdSet::new(nfds as _, readfds.as_deref());
     let write_set = FdSet::new(nfds as _, writefds.as_deref());
@@ -74,96 +75,156 @@ fn do_select(
          {except_set:?}] timeout: {timeout:?}"
     );
 
-    let fd_table = FD_TABLE.read();
-    let fd_bitmap = read_set.0 | write_set.0 | except_set.0;
-    let fd_count = fd_bitmap.len();
-    let mut fds = Vec::with_capacity(fd_count);
-    let mut fd_indices = Vec::with_capacity(fd_count);
-    for fd in fd_bitmap.into_iter() {
-        let f = fd_table
-            .get(fd)
-            .ok_or(AxError::BadFileDescriptor)?
-            .inner
-            .clone();
-        let mut events = IoEvents::empty();
-        events.set(IoEvents::IN, read_set.0.get(fd));
-        events.set(IoEvents::OUT, write_set.0.get(fd));
-        events.set(IoEvents::ERR, except_set.0.get(fd));
-        if !events.is_empty() {
-            fds.push((f, events));
-            fd_indices.push(fd);
+    let sets = [read_set, write_set, except_set];
+    // Written back only once the wait is over; an interrupted one leaves the
+    // caller's sets alone.
+    let mut ready = [Bitmap::<{ __FD_SETSIZE as usize }>::new(); 3];
+    let res = if timeout == Some(Duration::ZERO) {
+        with_replacen_blocked(sigmask.copied(), || select_once(&sets, &mut ready))?
+    } else if sets.iter().all(|set| set.0.is_empty()) {
+        with_replacen_blocked(sigmask.copied(), || sleep(timeout))?
+    } else {
+        wait_ready(sets, &mut ready, timeout, sigmask)?
+    };
+
+    for (set, ready) in [readfds, writefds, exceptfds].into_iter().zip(ready) {
+        if let Some(set) = set {
+            set.fds_bits = fd_bits(&ready);
         }
     }
+    Ok(res)
+}
 
-    drop(fd_table);
-    let fds = FdPollSet(fds);
+/// Returns the words of an `fd_set` with the descriptors in `ready` set.
+fn fd_bits(ready: &Bitmap<{ __FD_SETSIZE as usize }>) -> [c_ulong; FD_SET_WORDS] {
+    let mut bits = [0; FD_SET_WORDS];
+    for fd in ready.into_iter() {
+        bits[fd / c_ulong::BITS as usize] |= 1 << (fd % c_ulong::BITS as usize);
+    }
+    bits
+}
 
-    if let Some(readfds) = readfds.as_deref_mut() {
-        unsafe { FD_ZERO(readfds) };
+/// Sleeps for `timeout` or until a signal, with no descriptors to wait on,
+/// as `select(0, NULL, NULL, NULL, &tv)` is used for.
+fn sleep(timeout: Option<Duration>) -> AxResult<isize> {
+    let sleep = async {
+        match timeout {
+            Some(timeout) => timer::sleep_until(timer::deadline_after(timeout)).await,
+            None => future::pending().await,
+        }
+    };
+    match block_on(interruptible(sleep)) {
+        Ok(()) => Ok(0),
+        Err(_) => Err(AxError::Interrupted),
     }
-    if let Some(writefds) = writefds.as_deref_mut() {
-        unsafe { FD_ZERO(writefds) };
+}
+
+const SELECT_MASKS: [IoEvents; 3] = [SELECT_READ, SELECT_WRITE, SELECT_EXCEPT];
+
+/// Returns the descriptors in the sets, each with the events wanted of it.
+fn interests(sets: &[FdSet; 3]) -> impl Iterator<Item = (usize, IoEvents)> + '_ {
+    let fd_bitmap = sets[0].0 | sets[1].0 | sets[2].0;
+    fd_bitmap.into_iter().map(move |fd| {
+        let mut events = IoEvents::empty();
+        for (set, mask) in sets.iter().zip(SELECT_MASKS) {
+            events.set(mask, set.0.get(fd));
+        }
+        (fd, events)
+    })
+}
+
+/// Marks `fd` in `ready` for each set it is in and has `events` for,
+/// returning how many marks were made.
+fn mark_ready(
+    ready: &mut [Bitmap<{ __FD_SETSIZE as usize }>; 3],
+    fd: usize,
+    interested: IoEvents,
+    events: IoEvents,
+) -> usize {
+    let mut marks = 0;
+    for (ready, mask) in ready.iter_mut().zip(SELECT_MASKS) {
+        if interested.contains(mask) && events.intersects(mask) {
+            marks += 1;
+            ready.set(fd, true);
+        }
     }
-    if let Some(exceptfds) = exceptfds.as_deref_mut() {
-        unsafe { FD_ZERO(exceptfds) };
+    marks
+}
+
+/// Marks in `ready` the descriptors in the sets ready for them right now,
+/// for a zero timeout: a single pass, with nothing registered to wait on.
+fn select_once(
+    sets: &[FdSet; 3],
+    ready: &mut [Bitmap<{ __FD_SETSIZE as usize }>; 3],
+) -> AxResult<isize> {
+    let mut res = 0;
+    for (fd, interested) in interests(sets) {
+        let file = get_file_like(fd as _)?;
+        res += mark_ready(ready, fd, interested, readiness(file.as_ref(), interested));
     }
+    Ok(res as _)
+}
+
+/// Waits until some descriptor in the sets is ready for them, and marks it
+/// in `ready`, returning how many marks were made.
+fn wait_ready(
+    sets: [FdSet; 3],
+    ready: &mut [Bitmap<{ __FD_SETSIZE as usize }>; 3],
+    timeout: Option<Duration>,
+    sigmask: Option<&SignalSet>,
+) -> AxResult<isize> {
+    // The descriptors are kept by the set, so no keys are needed.
+    let (fds, _) = FdPollSet::gather(
+        interests(&sets).map(|(fd, events)| ((), fd as _, events)),
+        |_| Err(AxError::BadFileDescriptor),
+    )?;
+
     with_replacen_blocked(sigmask.copied(), || {
-        match block_on(future::timeout(
-            timeout,
-            poll_io(&fds, IoEvents::empty(), false, || {
-                let mut res = 0usize;
-                for ((fd, interested), index) in fds.0.iter().zip(fd_indices.iter().copied()) {
-                    let events = fd.poll() & *interested;
-                    if events.contains(IoEvents::IN)
-                        && let Some(set) = readfds.as_deref_mut()
-                    {
-                        res += 1;
-                        unsafe { FD_SET(index as _, set) };
-                    }
-                    if events.contains(IoEvents::OUT)
-                        && let Some(set) = writefds.as_deref_mut()
-                    {
-                        res += 1;
-                        unsafe { FD_SET(index as _, set) };
-                    }
-                    if events.contains(IoEvents::ERR)
-                        && let Some(set) = exceptfds.as_deref_mut()
-                    {
-                        res += 1;
-                        unsafe { FD_SET(index as _, set) };
-                    }
-                }
-                if res > 0 {
-                    return Ok(res as _);
-                }
+        let deadline = timeout.map(timer::deadline_after);
+        match timer::wait_for_io(&fds, IoEvents::empty(), false, deadline, true, || {
+            // Linux looks the descriptors up at each pass and fails on one
+            // gone.
+            if !fds.take_closed().is_empty() {
+                return Err(AxError::BadFileDescriptor);
+            }
+            let mut res = 0usize;
+            fds.for_each_updated(|i, file, interested| {
+                let events = readiness(file, interested);
+                res += mark_ready(ready, fds.fd(i) as _, interested, events);
+            });
+            if res > 0 {
+                return Ok(res as _);
+            }
 
-                Err(AxError::WouldBlock)
-            }),
-        )) {
-            Ok(r) => r,
-            Err(_) => Ok(0),
+            Err(AxError::WouldBlock)
+        }) {
+            Err(AxError::TimedOut) => Ok(0),
+            result => result,
         }
     })
 }
 
+/// Unlike `pselect6`, this writes the time not slept back to `timeout`,
+/// however the wait ends, as Linux does.
 #[cfg(target_arch = "x86_64")]
 pub fn sys_select(
     nfds: u32,
     readfds: UserPtr<__kernel_fd_set>,
     writefds: UserPtr<__kernel_fd_set>,
     exceptfds: UserPtr<__kernel_fd_set>,
-    timeout: UserConstPtr<timeval>,
+    timeout: UserPtr<timeval>,
 ) -> AxResult<isize> {
-    do_select(
-        nfds,
-        readfds,
-        writefds,
-        exceptfds,
-        nullable!(timeout.get_as_ref())?
-            .map(|it| it.try_into_time_value())
-            .transpose()?,
-        0.into(),
-    )
+    let timeout_ptr = nullable!(timeout.get_as_mut())?;
+    let timeout = timeout_ptr
+        .as_deref()
+        .map(|it| it.try_into_time_value())
+        .transpose()?;
+    let start = monotonic_time();
+    let result = do_select(nfds, readfds, writefds, exceptfds, timeout, 0.into());
+    if let (Some(tv), Some(timeout)) = (timeout_ptr, timeout) {
+        *tv = timeval::from_time_value(timeout.saturating_sub(monotonic_time() - start));
+    }
+    result
 }
 
 #[repr(C)]
@@ -1,11 +1,28 @@
 use core::sync::atomic::{AtomicI32, Ordering};
 
+/// flags for sys_shmget, sys_msgget, sys_semget
+const IPC_PRIVATE: i32 = 0;
+const IPC_CREAT: i32 = 0o1000;
+const IPC_EXCL: i32 = 0o2000;
+const IPC_NOWAIT: i32 = 0o4000;
+
+const IPC_RMID: u32 = 0;
+
+const IPC_SET: u32 = 1;
+
+const IPC_STAT: u32 = 2;
+
+/// Set by glibc on some architectures to request the 64-bit structures.
+const IPC_64: u32 = 0x100;
+
 static IPC_ID: AtomicI32 = AtomicI32::new(0);
 
 fn next_ipc_id() -> i32 {
     IPC_ID.fetch_add(1, Ordering::Relaxed)
 }
 
+mod mqueue;
+mod msg;
 mod shm;
 
-pub use self::shm::*;
+pub use self::{mqueue::*, msg::*, shm::*};
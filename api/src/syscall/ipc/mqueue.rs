@@ -0,0 +1,286 @@
+use alloc::{string::String, sync::Arc};
+use core::{ffi::c_char, future::poll_fn, task::Poll, time::Duration};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axtask::{
+    current,
+    future::{block_on, interruptible},
+};
+use bytemuck::AnyBitPattern;
+use linux_raw_sys::general::{
+    O_ACCMODE, O_CLOEXEC, O_CREAT, O_EXCL, O_NONBLOCK, O_RDONLY, O_RDWR, O_WRONLY, SIGEV_NONE,
+    SIGEV_SIGNAL, mq_attr, timespec,
+};
+use starry_core::{task::AsThread, timer::timeout_at_realtime};
+use starry_signal::Signo;
+use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
+
+use crate::{
+    file::{
+        FileLike,
+        mqueue::{MQ_PRIO_MAX, MessageQueue, MqFd, MqNotify, limits, namespace},
+    },
+    mm::vm_load_string,
+    syscall::sys::{sys_getegid, sys_geteuid},
+    time::TimeValueLike,
+};
+
+/// The subset of `struct sigevent` used by `mq_notify`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+pub struct SigEvent {
+    sigev_value: usize,
+    sigev_signo: i32,
+    sigev_notify: i32,
+    _pad: [i32; 12],
+}
+
+fn load_name(name: *const c_char) -> AxResult<String> {
+    let name = vm_load_string(name)?;
+    // The mqueue filesystem is flat; glibc strips the leading slash.
+    let name = name.strip_prefix('/').unwrap_or(&name);
+    if name.is_empty() {
+        return Err(AxError::NotFound);
+    }
+    if name.contains('/') {
+        return Err(AxError::PermissionDenied);
+    }
+    if name.len() > 255 {
+        return Err(AxError::NameTooLong);
+    }
+    Ok(name.into())
+}
+
+fn mq_fd(mqdes: i32) -> AxResult<Arc<MqFd>> {
+    MqFd::from_fd(mqdes)
+}
+
+fn load_deadline(abs_timeout: *const timespec) -> AxResult<Option<Duration>> {
+    let Some(ts) = abs_timeout.nullable() else {
+        return Ok(None);
+    };
+    // FIXME: AnyBitPattern
+    let ts = unsafe { ts.vm_read_uninit()?.assume_init() };
+    Ok(Some(ts.try_into_time_value()?))
+}
+
+pub fn sys_mq_open(
+    name: *const c_char,
+    oflag: u32,
+    mode: u32,
+    attr: *const mq_attr,
+) -> AxResult<isize> {
+    let name = load_name(name)?;
+    debug!("sys_mq_open <= name: {name:?}, oflag: {oflag:#o}, mode: {mode:#o}");
+
+    let (readable, writable) = match oflag & O_ACCMODE {
+        O_RDONLY => (true, false),
+        O_WRONLY => (false, true),
+        O_RDWR => (true, true),
+        _ => return Err(AxError::InvalidInput),
+    };
+
+    let (uid, gid) = (sys_geteuid()? as u32, sys_getegid()? as u32);
+    let mut created = false;
+    let queue = if oflag & O_CREAT != 0 {
+        namespace::create(&name, oflag & O_EXCL != 0, || {
+            created = true;
+            let mode = mode & 0o777 & !current().as_thread().proc_data.umask();
+            let Some(attr) = attr.nullable() else {
+                return Ok(MessageQueue::new(
+                    limits::msg_max(),
+                    limits::msgsize_max(),
+                    mode,
+                    uid,
+                    gid,
+                ));
+            };
+            // FIXME: AnyBitPattern
+            let attr = unsafe { attr.vm_read_uninit()?.assume_init() };
+            if attr.mq_maxmsg <= 0
+                || attr.mq_msgsize <= 0
+                || attr.mq_maxmsg as usize > limits::msg_max()
+                || attr.mq_msgsize as usize > limits::msgsize_max()
+            {
+                return Err(AxError::InvalidInput);
+            }
+            Ok(MessageQueue::new(
+                attr.mq_maxmsg as _,
+                attr.mq_msgsize as _,
+                mode,
+                uid,
+                gid,
+            ))
+        })?
+    } else {
+        namespace::lookup(&name).ok_or(AxError::NotFound)?
+    };
+    // The creator gets the access it asked for whatever the mode.
+    if !created {
+        let access = ((readable as u32) << 2) | ((writable as u32) << 1);
+        queue.check_access(uid, gid, access)?;
+    }
+
+    let file = MqFd::new(queue, name, readable, writable);
+    file.set_nonblocking(oflag & O_NONBLOCK != 0)?;
+    file.add_to_fd_table(oflag & O_CLOEXEC != 0)
+        .map(|fd| fd as isize)
+}
+
+pub fn sys_mq_unlink(name: *const c_char) -> AxResult<isize> {
+    let name = load_name(name)?;
+    debug!("sys_mq_unlink <= name: {name:?}");
+    namespace::unlink(&name)?;
+    Ok(0)
+}
+
+pub fn sys_mq_timedsend(
+    mqdes: i32,
+    msg_ptr: *const u8,
+    msg_len: usize,
+    msg_prio: u32,
+    abs_timeout: *const timespec,
+) -> AxResult<isize> {
+    debug!("sys_mq_timedsend <= mqdes: {mqdes}, msg_len: {msg_len}, msg_prio: {msg_prio}");
+
+    let file = mq_fd(mqdes)?;
+    if !file.writable() {
+        return Err(AxError::BadFileDescriptor);
+    }
+    let queue = &file.queue;
+    if msg_len > queue.msgsize {
+        return Err(AxError::from(LinuxError::EMSGSIZE));
+    }
+    if msg_prio >= MQ_PRIO_MAX {
+        return Err(AxError::InvalidInput);
+    }
+    let data = vm_load(msg_ptr, msg_len)?;
+    let deadline = load_deadline(abs_timeout)?;
+    let pid = current().as_thread().proc_data.proc.pid();
+
+    let nonblocking = file.nonblocking();
+    block_on(timeout_at_realtime(
+        deadline,
+        interruptible(poll_fn(|cx| match queue.try_send(&data, msg_prio, pid) {
+            Err(AxError::WouldBlock) if !nonblocking => {
+                queue.register_sender(cx.waker());
+                // A message may have been taken off before the waker was
+                // registered.
+                match queue.try_send(&data, msg_prio, pid) {
+                    Err(AxError::WouldBlock) => Poll::Pending,
+                    result => Poll::Ready(result),
+                }
+            }
+            result => Poll::Ready(result),
+        })),
+    ))
+    .map_err(|_| AxError::TimedOut)???;
+    Ok(0)
+}
+
+pub fn sys_mq_timedreceive(
+    mqdes: i32,
+    msg_ptr: *mut u8,
+    msg_len: usize,
+    msg_prio: *mut u32,
+    abs_timeout: *const timespec,
+) -> AxResult<isize> {
+    debug!("sys_mq_timedreceive <= mqdes: {mqdes}, msg_len: {msg_len}");
+
+    let file = mq_fd(mqdes)?;
+    if !file.readable() {
+        return Err(AxError::BadFileDescriptor);
+    }
+    let queue = &file.queue;
+    if msg_len < queue.msgsize {
+        return Err(AxError::from(LinuxError::EMSGSIZE));
+    }
+    let deadline = load_deadline(abs_timeout)?;
+
+    let nonblocking = file.nonblocking();
+    let (data, prio) = queue
+        .receiving(|| {
+            block_on(timeout_at_realtime(
+                deadline,
+                interruptible(poll_fn(|cx| match queue.try_receive() {
+                    Err(AxError::WouldBlock) if !nonblocking => {
+                        queue.register_receiver(cx.waker());
+                        // A message may have arrived before the waker was
+                        // registered.
+                        match queue.try_receive() {
+                            Err(AxError::WouldBlock) => Poll::Pending,
+                            result => Poll::Ready(result),
+                        }
+                    }
+                    result => Poll::Ready(result),
+                })),
+            ))
+        })
+        .map_err(|_| AxError::TimedOut)???;
+
+    vm_write_slice(msg_ptr, &data)?;
+    if let Some(msg_prio) = msg_prio.nullable() {
+        msg_prio.vm_write(prio)?;
+    }
+    Ok(data.len() as isize)
+}
+
+pub fn sys_mq_notify(mqdes: i32, sevp: *const SigEvent) -> AxResult<isize> {
+    debug!("sys_mq_notify <= mqdes: {mqdes}");
+
+    let file = mq_fd(mqdes)?;
+    let pid = current().as_thread().proc_data.proc.pid();
+
+    let Some(sevp) = sevp.nullable() else {
+        file.queue.clear_notify(pid);
+        return Ok(0);
+    };
+    let sev = sevp.vm_read()?;
+    let signo = match sev.sigev_notify as u32 {
+        SIGEV_NONE => None,
+        SIGEV_SIGNAL => Some(Signo::from_repr(sev.sigev_signo as u8).ok_or(AxError::InvalidInput)?),
+        // TODO: SIGEV_THREAD is implemented by libc on top of netlink
+        _ => return Err(AxError::InvalidInput),
+    };
+    file.queue.register_notify(MqNotify {
+        pid,
+        signo,
+        value: sev.sigev_value,
+    })?;
+    Ok(0)
+}
+
+pub fn sys_mq_getsetattr(
+    mqdes: i32,
+    newattr: *const mq_attr,
+    oldattr: *mut mq_attr,
+) -> AxResult<isize> {
+    debug!("sys_mq_getsetattr <= mqdes: {mqdes}");
+
+    let file = mq_fd(mqdes)?;
+    let queue = &file.queue;
+    let old = mq_attr {
+        mq_flags: if file.nonblocking() {
+            O_NONBLOCK as _
+        } else {
+            0
+        },
+        mq_maxmsg: queue.maxmsg as _,
+        mq_msgsize: queue.msgsize as _,
+        mq_curmsgs: queue.len() as _,
+        __reserved: [0; 4],
+    };
+
+    if let Some(newattr) = newattr.nullable() {
+        // FIXME: AnyBitPattern
+        let new = unsafe { newattr.vm_read_uninit()?.assume_init() };
+        if new.mq_flags as u32 & !O_NONBLOCK != 0 {
+            return Err(AxError::InvalidInput);
+        }
+        file.set_nonblocking(new.mq_flags as u32 & O_NONBLOCK != 0)?;
+    }
+    if let Some(oldattr) = oldattr.nullable() {
+        oldattr.vm_write(old)?;
+    }
+    Ok(0)
+}
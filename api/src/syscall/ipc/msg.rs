@@ -0,0 +1,210 @@
+use alloc::sync::Arc;
+use core::{future::poll_fn, task::Poll};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axtask::{
+    current,
+    future::{block_on, interruptible},
+};
+use linux_raw_sys::{ctypes::c_long, general::__kernel_time_t};
+use starry_core::{
+    msg::{MSG_MANAGER, MsgQueue, MsgSelector, MsqidDs, msgmax, msgmnb},
+    shm::IpcPerm,
+    task::AsThread,
+    time::realtime,
+};
+use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
+
+use super::{
+    IPC_64, IPC_CREAT, IPC_EXCL, IPC_NOWAIT, IPC_PRIVATE, IPC_RMID, IPC_SET, IPC_STAT, next_ipc_id,
+};
+use crate::syscall::sys::{sys_getegid, sys_geteuid};
+
+const MSG_NOERROR: i32 = 0o10000;
+const MSG_EXCEPT: i32 = 0o20000;
+
+fn now() -> __kernel_time_t {
+    realtime().as_secs() as _
+}
+
+fn credentials() -> AxResult<(u32, u32)> {
+    Ok((sys_geteuid()? as _, sys_getegid()? as _))
+}
+
+fn get_queue(msqid: i32) -> AxResult<Arc<MsgQueue>> {
+    if msqid < 0 {
+        return Err(AxError::InvalidInput);
+    }
+    MSG_MANAGER.lock().get(msqid).ok_or(AxError::InvalidInput)
+}
+
+pub fn sys_msgget(key: i32, msgflg: i32) -> AxResult<isize> {
+    debug!("sys_msgget <= key: {key}, msgflg: {msgflg:#o}");
+
+    let (uid, gid) = credentials()?;
+    let mut msg_manager = MSG_MANAGER.lock();
+
+    if key != IPC_PRIVATE
+        && let Some(msqid) = msg_manager.get_msqid_by_key(key)
+    {
+        if msgflg & IPC_CREAT != 0 && msgflg & IPC_EXCL != 0 {
+            return Err(AxError::AlreadyExists);
+        }
+        let queue = msg_manager.get(msqid).ok_or(AxError::NotFound)?;
+        let access = ((msgflg as u32) >> 6) & 0o7;
+        queue.inner.lock().ds.msg_perm.check(uid, gid, access)?;
+        return Ok(msqid as isize);
+    }
+
+    if key != IPC_PRIVATE && msgflg & IPC_CREAT == 0 {
+        return Err(AxError::NotFound);
+    }
+
+    let msqid = next_ipc_id();
+    let perm = IpcPerm::new(key, (msgflg & 0o777) as _, uid, gid);
+    let queue = Arc::new(MsgQueue::new(msqid, perm, now()));
+    msg_manager.insert((key != IPC_PRIVATE).then_some(key), queue);
+
+    Ok(msqid as isize)
+}
+
+pub fn sys_msgsnd(msqid: i32, msgp: *const u8, msgsz: usize, msgflg: i32) -> AxResult<isize> {
+    debug!("sys_msgsnd <= msqid: {msqid}, msgsz: {msgsz}, msgflg: {msgflg:#o}");
+
+    if msgsz > msgmax() {
+        return Err(AxError::InvalidInput);
+    }
+    let queue = get_queue(msqid)?;
+    let (uid, gid) = credentials()?;
+    queue.inner.lock().ds.msg_perm.check(uid, gid, 0o2)?;
+
+    let mtype = msgp.cast::<c_long>().vm_read()?;
+    if mtype <= 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let data = vm_load(msgp.wrapping_add(size_of::<c_long>()), msgsz)?;
+
+    let pid = current().as_thread().proc_data.proc.pid();
+    let mut data = Some(data);
+    block_on(interruptible(poll_fn(|cx| {
+        let mut inner = queue.inner.lock();
+        if inner.removed {
+            return Poll::Ready(Err(AxError::from(LinuxError::EIDRM)));
+        }
+        if inner.has_room(msgsz) {
+            inner.push(mtype, data.take().unwrap(), pid, now());
+            drop(inner);
+            queue.poll_recv.wake();
+            Poll::Ready(Ok(0))
+        } else if msgflg & IPC_NOWAIT != 0 {
+            Poll::Ready(Err(AxError::WouldBlock))
+        } else {
+            queue.poll_send.register(cx.waker());
+            Poll::Pending
+        }
+    })))?
+}
+
+pub fn sys_msgrcv(
+    msqid: i32,
+    msgp: *mut u8,
+    msgsz: usize,
+    msgtyp: c_long,
+    msgflg: i32,
+) -> AxResult<isize> {
+    debug!("sys_msgrcv <= msqid: {msqid}, msgsz: {msgsz}, msgtyp: {msgtyp}, msgflg: {msgflg:#o}");
+
+    if (msgsz as isize) < 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let queue = get_queue(msqid)?;
+    let (uid, gid) = credentials()?;
+    queue.inner.lock().ds.msg_perm.check(uid, gid, 0o4)?;
+
+    let selector = match msgtyp {
+        0 => MsgSelector::First,
+        ty if ty > 0 && msgflg & MSG_EXCEPT != 0 => MsgSelector::Except(ty),
+        ty if ty > 0 => MsgSelector::Exact(ty),
+        ty => MsgSelector::LowestUpTo(ty.saturating_neg()),
+    };
+    let truncate = msgflg & MSG_NOERROR != 0;
+
+    let pid = current().as_thread().proc_data.proc.pid();
+    let (mtype, data) = block_on(interruptible(poll_fn(|cx| {
+        let mut inner = queue.inner.lock();
+        if inner.removed {
+            return Poll::Ready(Err(AxError::from(LinuxError::EIDRM)));
+        }
+        if let Some(result) = inner.pop(selector, msgsz, truncate, pid, now()) {
+            drop(inner);
+            if result.is_ok() {
+                queue.poll_send.wake();
+            }
+            Poll::Ready(result)
+        } else if msgflg & IPC_NOWAIT != 0 {
+            Poll::Ready(Err(AxError::from(LinuxError::ENOMSG)))
+        } else {
+            queue.poll_recv.register(cx.waker());
+            Poll::Pending
+        }
+    })))??;
+
+    msgp.cast::<c_long>().vm_write(mtype)?;
+    vm_write_slice(msgp.wrapping_add(size_of::<c_long>()), &data)?;
+    Ok(data.len() as isize)
+}
+
+pub fn sys_msgctl(msqid: i32, cmd: u32, buf: *mut MsqidDs) -> AxResult<isize> {
+    debug!("sys_msgctl <= msqid: {msqid}, cmd: {cmd}");
+
+    let cmd = cmd & !IPC_64;
+    let queue = get_queue(msqid)?;
+    let (uid, gid) = credentials()?;
+
+    match cmd {
+        IPC_STAT => {
+            let ds = {
+                let inner = queue.inner.lock();
+                inner.ds.msg_perm.check(uid, gid, 0o4)?;
+                inner.ds
+            };
+            buf.vm_write(ds)?;
+        }
+        IPC_SET => {
+            // FIXME: AnyBitPattern
+            let new = unsafe { buf.cast_const().vm_read_uninit()?.assume_init() };
+            let mut inner = queue.inner.lock();
+            let perm = &mut inner.ds.msg_perm;
+            if uid != 0 && uid != perm.uid && uid != perm.cuid {
+                return Err(AxError::OperationNotPermitted);
+            }
+            if new.msg_qbytes == 0 {
+                return Err(AxError::InvalidInput);
+            }
+            // Only root may grow a queue past the `kernel.msgmnb` default.
+            if uid != 0 && new.msg_qbytes as usize > msgmnb() {
+                return Err(AxError::OperationNotPermitted);
+            }
+            perm.uid = new.msg_perm.uid;
+            perm.gid = new.msg_perm.gid;
+            perm.mode = (perm.mode & !0o777) | (new.msg_perm.mode & 0o777);
+            inner.ds.msg_qbytes = new.msg_qbytes;
+            inner.ds.msg_ctime = now();
+            drop(inner);
+            // A larger queue may let blocked senders through.
+            queue.poll_send.wake();
+        }
+        IPC_RMID => {
+            {
+                let inner = queue.inner.lock();
+                let perm = &inner.ds.msg_perm;
+                if uid != 0 && uid != perm.uid && uid != perm.cuid {
+                    return Err(AxError::OperationNotPermitted);
+                }
+            }
+            MSG_MANAGER.lock().remove(msqid);
+        }
+        _ => return Err(AxError::InvalidInput),
+    }
+    Ok(0)
+}
@@ -1,7 +1,10 @@
 use axerrno::AxResult;
 use axtask::current;
+use memory_addr::{VirtAddr, VirtAddrRange, align_up_4k};
 use starry_core::task::AsThread;
 
+use crate::oom;
+
 pub fn sys_brk(addr: usize) -> AxResult<isize> {
     let curr = current();
     let proc_data = &curr.as_thread().proc_data;
@@ -9,6 +12,23 @@ pub fn sys_brk(addr: usize) -> AxResult<isize> {
     let heap_bottom = proc_data.get_heap_bottom() as usize;
     if addr != 0 && addr >= heap_bottom && addr <= heap_bottom + starry_core::config::USER_HEAP_SIZE
     {
+        // The heap is committed to up to the new break.
+        let top = VirtAddr::from(align_up_4k(addr));
+        let end = VirtAddr::from(heap_bottom + starry_core::config::USER_HEAP_SIZE);
+        let committed = oom::commit(
+            proc_data,
+            VirtAddrRange::new(VirtAddr::from(heap_bottom), top),
+            false,
+        );
+        if committed.is_err() {
+            return Ok(return_val);
+        }
+        if top < end {
+            proc_data
+                .commitments
+                .lock()
+                .remove(VirtAddrRange::new(top, end));
+        }
         proc_data.set_heap_top(addr);
         return_val = addr as isize;
     }
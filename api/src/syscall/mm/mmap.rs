@@ -1,19 +1,27 @@
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 
 use axerrno::{AxError, AxResult};
 use axfs::FileBackend;
+use axfs_ng_vfs::MetadataUpdate;
 use axhal::paging::{MappingFlags, PageSize};
 use axmm::backend::{Backend, SharedPages};
 use axtask::current;
 use linux_raw_sys::general::*;
-use memory_addr::{MemoryAddr, VirtAddr, VirtAddrRange, align_up_4k};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr, VirtAddrRange, align_up_4k};
 use starry_core::{
+    mm::FileMapping,
     task::AsThread,
-    vfs::{Device, DeviceMmap},
+    time::realtime,
+    vfs::{Device, DeviceMmap, MountUse},
 };
 use starry_vm::{vm_load, vm_write_slice};
 
-use crate::file::{File, FileLike};
+use crate::{
+    file::{File, FileLike},
+    mm::mapping_readahead,
+    oom,
+    vfs::{readahead::offset_to_page, writeback::writeback},
+};
 
 bitflags::bitflags! {
     /// `PROT_*` flags for use with [`sys_mmap`].
@@ -81,6 +89,9 @@ bitflags::bitflags! {
         const HUGE_1GB = MAP_HUGETLB | MAP_HUGE_1GB;
         /// Deprecated flag
         const DENYWRITE = MAP_DENYWRITE;
+        /// Place the mapping in the first 2GB of the address space.
+        #[cfg(target_arch = "x86_64")]
+        const BIT32 = MAP_32BIT;
 
         /// Mask for type of mapping
         const TYPE = MAP_TYPE;
@@ -152,23 +163,29 @@ pub fn sys_mmap(
         let dst_addr = VirtAddr::from(start);
         if !map_flags.contains(MmapFlags::FIXED_NOREPLACE) {
             aspace.unmap(dst_addr, length)?;
+            let range = VirtAddrRange::from_start_size(dst_addr, length);
+            let proc_data = &curr.as_thread().proc_data;
+            proc_data.file_mappings.lock().remove(range);
+            proc_data.commitments.lock().remove(range);
         }
         dst_addr
     } else {
-        let align = page_size as usize;
-        aspace
-            .find_free_area(
+        #[allow(unused_mut)]
+        let mut limit = VirtAddrRange::new(aspace.base(), aspace.end());
+        #[cfg(target_arch = "x86_64")]
+        if map_flags.contains(MmapFlags::BIT32) {
+            limit.end = limit.end.min(VirtAddr::from_usize(0x8000_0000));
+        }
+        curr.as_thread()
+            .proc_data
+            .mmap_layout()
+            .find_area(
+                &aspace,
                 VirtAddr::from(start),
                 length,
-                VirtAddrRange::new(aspace.base(), aspace.end()),
-                align,
+                page_size as usize,
+                limit,
             )
-            .or(aspace.find_free_area(
-                aspace.base(),
-                length,
-                VirtAddrRange::new(aspace.base(), aspace.end()),
-                align,
-            ))
             .ok_or(AxError::NoMemory)?
     };
 
@@ -178,6 +195,8 @@ pub fn sys_mmap(
         None
     };
 
+    // The regular file mapped, if any.
+    let mut mapped_file = None;
     let backend = match map_type {
         MmapFlags::SHARED | MmapFlags::SHARED_VALIDATE => {
             if let Some(file) = file {
@@ -185,6 +204,7 @@ pub fn sys_mmap(
                 let backend = file.backend()?.clone();
                 match file.backend()?.clone() {
                     FileBackend::Cached(cache) => {
+                        mapped_file = Some((file.location().clone(), backend, true));
                         // TODO(mivik): file mmap page size
                         Backend::new_file(
                             start,
@@ -234,7 +254,11 @@ pub fn sys_mmap(
         MmapFlags::PRIVATE => {
             if let Some(file) = file {
                 // Private mapping from a file
-                let backend = file.inner().backend()?.clone();
+                let file = file.inner();
+                let backend = file.backend()?.clone();
+                if matches!(backend, FileBackend::Cached(_)) {
+                    mapped_file = Some((file.location().clone(), backend.clone(), false));
+                }
                 Backend::new_cow(start, page_size, backend, offset as u64, None)
             } else {
                 Backend::new_alloc(start, page_size)
@@ -243,8 +267,42 @@ pub fn sys_mmap(
         _ => return Err(AxError::InvalidInput),
     };
 
+    // Private writable and shared anonymous memory is committed to.
+    let accountable = match map_type {
+        MmapFlags::PRIVATE => permission_flags.contains(MmapProt::WRITE),
+        _ => fd <= 0,
+    };
+    let range = VirtAddrRange::from_start_size(start, length);
+    let proc_data = &curr.as_thread().proc_data;
+    if accountable {
+        oom::commit(proc_data, range, map_flags.contains(MmapFlags::NORESERVE))?;
+    }
+
     let populate = map_flags.contains(MmapFlags::POPULATE);
-    aspace.map(start, length, permission_flags.into(), populate, backend)?;
+    if let Err(err) = aspace.map(start, length, permission_flags.into(), populate, backend) {
+        if accountable {
+            proc_data.commitments.lock().remove(range);
+        }
+        return Err(err);
+    }
+    if let Some((loc, backend, shared)) = mapped_file {
+        if shared {
+            writeback(&loc, &backend).set_mapped_shared();
+        }
+        let readahead = mapping_readahead(&backend, &loc);
+        proc_data.file_mappings.lock().insert(
+            start,
+            FileMapping {
+                end: start + length,
+                mount: MountUse::new(&loc),
+                loc,
+                offset: offset as u64,
+                backend,
+                shared,
+                readahead,
+            },
+        );
+    }
 
     Ok(start.as_usize() as _)
 }
@@ -256,6 +314,10 @@ pub fn sys_munmap(addr: usize, length: usize) -> AxResult<isize> {
     let length = align_up_4k(length);
     let start_addr = VirtAddr::from(addr);
     aspace.unmap(start_addr, length)?;
+    let range = VirtAddrRange::from_start_size(start_addr, length);
+    let proc_data = &curr.as_thread().proc_data;
+    proc_data.file_mappings.lock().remove(range);
+    proc_data.commitments.lock().remove(range);
     Ok(0)
 }
 
@@ -325,6 +387,108 @@ pub fn sys_madvise(addr: usize, length: usize, advice: i32) -> AxResult<isize> {
 pub fn sys_msync(addr: usize, length: usize, flags: u32) -> AxResult<isize> {
     debug!("sys_msync <= addr: {addr:#x}, length: {length:x}, flags: {flags:#x}");
 
+    if !PageSize::Size4K.is_aligned(addr)
+        || flags & !(MS_ASYNC | MS_INVALIDATE | MS_SYNC) != 0
+        || flags & (MS_ASYNC | MS_SYNC) == MS_ASYNC | MS_SYNC
+    {
+        return Err(AxError::InvalidInput);
+    }
+    let range = VirtAddrRange::from_start_size(VirtAddr::from(addr), align_up_4k(length));
+
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    let aspace = proc_data.aspace.lock();
+    let mut pos = range.start;
+    while pos < range.end {
+        pos = aspace.find_area(pos).ok_or(AxError::NoMemory)?.end();
+    }
+    drop(aspace);
+
+    // Stores through the mappings reach the page cache directly, unseen
+    // but for the first write fault on a page, so every page mapped
+    // writable is taken as dirty. It is written back with `MS_SYNC`, and
+    // left to the flusher with `MS_ASYNC`. The mappings share the pages of
+    // the page cache, so there are no copies for `MS_INVALIDATE` to drop.
+    let now = realtime();
+    let mappings = proc_data
+        .file_mappings
+        .lock()
+        .overlapping(range)
+        .filter(|(_, mapping)| mapping.shared)
+        .map(|(start, mapping)| {
+            let from = start.max(range.start);
+            let offset = mapping.offset + (from - start) as u64;
+            (
+                mapping.loc.clone(),
+                mapping.backend.clone(),
+                offset,
+                from,
+                mapping.end.min(range.end),
+            )
+        })
+        .collect::<Vec<_>>();
+    for (loc, backend, offset, from, to) in mappings {
+        loc.update_metadata(MetadataUpdate {
+            mtime: Some(now),
+            ..Default::default()
+        })?;
+        let writeback = writeback(&loc, &backend);
+        let start_page = offset_to_page(offset);
+        let num_pages = ((to - from) / PAGE_SIZE_4K) as u32;
+        {
+            let aspace = proc_data.aspace.lock();
+            for i in 0..num_pages {
+                let vaddr = from + i as usize * PAGE_SIZE_4K;
+                if let Ok((_, pte_flags, _)) = aspace.page_table().query(vaddr)
+                    && pte_flags.contains(MappingFlags::WRITE)
+                {
+                    writeback.mark_dirty(start_page + i, 1);
+                }
+            }
+        }
+        if flags & MS_SYNC != 0 {
+            writeback.write_back(start_page, start_page + num_pages)?;
+            loc.sync(false)?;
+        }
+    }
+    Ok(0)
+}
+
+pub fn sys_mincore(addr: usize, length: usize, vec: *mut u8) -> AxResult<isize> {
+    debug!("sys_mincore <= addr: {addr:#x}, length: {length:x}, vec: {vec:p}");
+
+    if !PageSize::Size4K.is_aligned(addr) {
+        return Err(AxError::InvalidInput);
+    }
+    let end = addr
+        .checked_add(align_up_4k(length))
+        .ok_or(AxError::NoMemory)?;
+    let range = VirtAddrRange::new(VirtAddr::from(addr), VirtAddr::from(end));
+
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    let mut resident = Vec::with_capacity(range.size() / PAGE_SIZE_4K);
+    {
+        let aspace = proc_data.aspace.lock();
+        let mappings = proc_data.file_mappings.lock();
+        let mut pos = range.start;
+        while pos < range.end {
+            // Holes fail the whole call, as on Linux.
+            let area_end = aspace.find_area(pos).ok_or(AxError::NoMemory)?.end();
+            while pos < area_end.min(range.end) {
+                // A file page counts if it is in the page cache, mapped or
+                // not; anything else if it is mapped.
+                let cached = mappings.find(pos).is_some_and(|(mapping, offset)| {
+                    mapping
+                        .backend
+                        .is_page_cached((offset / PAGE_SIZE_4K as u64) as u32)
+                });
+                resident.push((cached || aspace.page_table().query(pos).is_ok()) as u8);
+                pos += PAGE_SIZE_4K;
+            }
+        }
+    }
+    vm_write_slice(vec, &resident)?;
     Ok(0)
 }
 
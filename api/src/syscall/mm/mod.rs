@@ -1,4 +1,5 @@
 mod brk;
 mod mmap;
+mod swap;
 
-pub use self::{brk::*, mmap::*};
+pub use self::{brk::*, mmap::*, swap::*};
@@ -0,0 +1,69 @@
+use alloc::string::ToString;
+use core::ffi::c_char;
+
+use axerrno::{AxError, AxResult};
+use axfs::{FS_CONTEXT, OpenOptions};
+use starry_core::swap::{self, SwapArea};
+
+use crate::{mm::vm_load_string, syscall::sys::sys_geteuid};
+
+const SWAP_FLAG_PREFER: i32 = 0x8000;
+const SWAP_FLAG_PRIO_MASK: i32 = 0x7fff;
+const SWAP_FLAG_DISCARD: i32 = 0x10000;
+const SWAP_FLAG_DISCARD_ONCE: i32 = 0x20000;
+const SWAP_FLAG_DISCARD_PAGES: i32 = 0x40000;
+
+pub fn sys_swapon(path: *const c_char, swapflags: i32) -> AxResult<isize> {
+    let path = vm_load_string(path)?;
+    debug!("sys_swapon <= path: {path:?}, swapflags: {swapflags:#x}");
+
+    // Needs CAP_SYS_ADMIN, which only root has.
+    if sys_geteuid()? != 0 {
+        return Err(AxError::OperationNotPermitted);
+    }
+
+    let known = SWAP_FLAG_PREFER
+        | SWAP_FLAG_PRIO_MASK
+        | SWAP_FLAG_DISCARD
+        | SWAP_FLAG_DISCARD_ONCE
+        | SWAP_FLAG_DISCARD_PAGES;
+    if swapflags & !known != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    // Areas without an explicit priority are used after all prioritized ones.
+    let priority = if swapflags & SWAP_FLAG_PREFER != 0 {
+        (swapflags & SWAP_FLAG_PRIO_MASK) as i16
+    } else {
+        -1
+    };
+
+    let (path, file) = {
+        let fs = FS_CONTEXT.lock();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&fs, path.as_str())?
+            .into_file()?;
+        let path = file.backend()?.location().absolute_path()?.to_string();
+        (path, file.backend()?.clone())
+    };
+    swap::enable(SwapArea::new(path, file, priority)?)?;
+    Ok(0)
+}
+
+pub fn sys_swapoff(path: *const c_char) -> AxResult<isize> {
+    let path = vm_load_string(path)?;
+    debug!("sys_swapoff <= path: {path:?}");
+
+    if sys_geteuid()? != 0 {
+        return Err(AxError::OperationNotPermitted);
+    }
+
+    let path = FS_CONTEXT
+        .lock()
+        .resolve(path.as_str())?
+        .absolute_path()?
+        .to_string();
+    swap::disable(&path)?;
+    Ok(0)
+}
@@ -5,7 +5,8 @@ use axerrno::{AxError, AxResult};
 use axio::{Buf, BufMut};
 use axnet::{CMsgData, RecvFlags, RecvOptions, SendFlags, SendOptions, SocketAddrEx, SocketOps};
 use linux_raw_sys::net::{
-    MSG_PEEK, MSG_TRUNC, SCM_RIGHTS, SOL_SOCKET, cmsghdr, msghdr, sockaddr, socklen_t,
+    MSG_CMSG_CLOEXEC, MSG_PEEK, MSG_TRUNC, SCM_RIGHTS, SOL_SOCKET, cmsghdr, msghdr, sockaddr,
+    socklen_t,
 };
 
 use crate::{
@@ -127,8 +128,10 @@ fn recv_impl(
             let pushed = match *cmsg {
                 CMsg::Rights { fds } => builder.push(SOL_SOCKET, SCM_RIGHTS, |data| {
                     let mut written = 0;
+                    // The receiver gets the sender's open file descriptions
+                    // themselves, offset and readahead state included.
                     for (f, chunk) in fds.into_iter().zip(data.chunks_exact_mut(size_of::<i32>())) {
-                        let fd = add_file_like(f, false)?;
+                        let fd = add_file_like(f, flags & MSG_CMSG_CLOEXEC != 0)?;
                         chunk.copy_from_slice(&fd.to_ne_bytes());
                         written += size_of::<i32>();
                     }
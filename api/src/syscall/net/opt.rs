@@ -1,6 +1,6 @@
 use axerrno::{AxError, AxResult, LinuxError};
 use axnet::options::{Configurable, GetSocketOption, SetSocketOption};
-use linux_raw_sys::net::socklen_t;
+use linux_raw_sys::net::{SO_REUSEPORT, SOL_SOCKET, socklen_t};
 
 use crate::{
     file::{FileLike, Socket},
@@ -138,6 +138,11 @@ pub fn sys_getsockopt(
     }
 
     let socket = Socket::from_fd(fd)?;
+    // Kept by the socket itself; the stack never sees it.
+    if (level, optname) == (SOL_SOCKET, SO_REUSEPORT) {
+        *get(optval, optlen)? = conv::IntBool::rust_to_sys(socket.reuse_port()?)?;
+        return Ok(0);
+    }
     macro_rules! dispatch {
         ($which:ident) => {
             socket.get_option(GetSocketOption::$which(get(optval, optlen)?))?;
@@ -177,6 +182,10 @@ pub fn sys_setsockopt(
     }
 
     let socket = Socket::from_fd(fd)?;
+    if (level, optname) == (SOL_SOCKET, SO_REUSEPORT) {
+        socket.set_reuse_port(conv::IntBool::sys_to_rust(*get(optval, optlen)?)?)?;
+        return Ok(0);
+    }
     macro_rules! dispatch {
         ($which:ident) => {
             socket.set_option(SetSocketOption::$which(get(optval, optlen)?))?;
@@ -1,18 +1,19 @@
 use core::{future::poll_fn, task::Poll};
 
 use axerrno::{AxError, AxResult, LinuxError};
-use axhal::uspace::UserContext;
-use axtask::{
-    current,
-    future::{self, block_on},
-};
+use axhal::{time::monotonic_time, uspace::UserContext};
+use axtask::{current, future::block_on};
 use linux_raw_sys::general::{
     MINSIGSTKSZ, SI_TKILL, SI_USER, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK, kernel_sigaction, siginfo,
     timespec,
 };
-use starry_core::task::{
-    AsThread, processes, send_signal_to_process, send_signal_to_process_group,
-    send_signal_to_thread,
+use starry_core::{
+    park::{TaskParker, WakeReason},
+    task::{
+        AsThread, processes, send_signal_to_process, send_signal_to_process_group,
+        send_signal_to_thread,
+    },
+    timer,
 };
 use starry_process::Pid;
 use starry_signal::{SignalInfo, SignalSet, SignalStack, Signo};
@@ -239,23 +240,34 @@ pub fn sys_rt_sigtimedwait(
     signal.set_blocked(old_blocked & !set);
 
     uctx.set_retval(-LinuxError::EINTR.code() as usize);
-    let fut = poll_fn(|cx| {
+    let parker = TaskParker::new();
+    let timer = timeout.map(|timeout| {
+        timer::register_waker(monotonic_time() + timeout, parker.waker(WakeReason::TIMER))
+    });
+    let mut timed_out = false;
+    let sig = loop {
+        parker.watch_signals();
+        // A signal that came with the deadline is still taken.
         if let Some(sig) = signal.dequeue_signal(&set) {
             signal.set_blocked(old_blocked);
-            Poll::Ready(Some(sig))
+            break Some(sig);
         } else if check_signals(thr, uctx, Some(old_blocked)) {
-            Poll::Ready(None)
-        } else {
-            let _ = curr.poll_interrupt(cx);
-            Poll::Pending
+            break None;
+        } else if timed_out {
+            signal.set_blocked(old_blocked);
+            return Err(AxError::WouldBlock);
         }
-    });
-
-    let Ok(sig) = block_on(future::timeout(timeout, fut)) else {
-        // Timeout
-        signal.set_blocked(old_blocked);
-        return Err(AxError::WouldBlock);
+        let reasons = parker.park();
+        if reasons.contains(WakeReason::SIGNAL) {
+            // The interrupt stays pending until cleared; the signal behind
+            // it is looked for right after.
+            curr.clear_interrupt();
+        }
+        timed_out = reasons.contains(WakeReason::TIMER);
     };
+    if let Some(timer) = timer {
+        timer.cancel();
+    }
     let Some(sig) = sig else {
         // Interrupted
         return Ok(0);
@@ -291,7 +303,28 @@ pub fn sys_rt_sigsuspend(
         Poll::Pending
     }));
 
-    Ok(0)
+    // The handler is set up to run; keep its first argument.
+    Ok(uctx.retval() as isize)
+}
+
+/// Sleeps until a signal runs a handler or ends the process, then fails with
+/// `EINTR`. Ignored signals do not wake it.
+#[cfg(target_arch = "x86_64")]
+pub fn sys_pause(uctx: &mut UserContext) -> AxResult<isize> {
+    let curr = current();
+    let thr = curr.as_thread();
+
+    uctx.set_retval(-LinuxError::EINTR.code() as usize);
+
+    block_on(poll_fn(|cx| {
+        if check_signals(thr, uctx, None) {
+            return Poll::Ready(());
+        }
+        let _ = curr.poll_interrupt(cx);
+        Poll::Pending
+    }));
+
+    Ok(uctx.retval() as isize)
 }
 
 pub fn sys_sigaltstack(ss: *const SignalStack, old_ss: *mut SignalStack) -> AxResult<isize> {
@@ -1,6 +1,4 @@
-use core::sync::atomic::Ordering;
-
-use axerrno::{AxError, AxResult, LinuxError};
+use axerrno::{AxError, AxResult};
 use axtask::current;
 use linux_raw_sys::general::{
     FUTEX_CMD_MASK, FUTEX_CMP_REQUEUE, FUTEX_REQUEUE, FUTEX_WAIT, FUTEX_WAIT_BITSET, FUTEX_WAKE,
@@ -73,11 +71,7 @@ pub fn sys_futex(
                 return Err(AxError::WouldBlock);
             }
 
-            if futex.owner_dead.swap(false, Ordering::SeqCst) {
-                Err(AxError::from(LinuxError::EOWNERDEAD))
-            } else {
-                Ok(0)
-            }
+            Ok(0)
         }
         FUTEX_WAKE | FUTEX_WAKE_BITSET => {
             let futex = futex_table.get(&key);
@@ -4,39 +4,134 @@ use core::ffi::c_char;
 use axconfig::ARCH;
 use axerrno::{AxError, AxResult};
 use axfs::FS_CONTEXT;
+use axtask::current;
 use linux_raw_sys::{
     general::{GRND_INSECURE, GRND_NONBLOCK, GRND_RANDOM},
     system::{new_utsname, sysinfo},
 };
-use starry_core::task::processes;
+use starry_core::task::{AsThread, Credentials, processes};
 use starry_vm::{VmMutPtr, vm_write_slice};
 
+fn cred() -> Credentials {
+    *current().as_thread().proc_data.cred.read()
+}
+
 pub fn sys_getuid() -> AxResult<isize> {
-    Ok(0)
+    Ok(cred().uid as _)
 }
 
 pub fn sys_geteuid() -> AxResult<isize> {
-    Ok(0)
+    Ok(cred().euid as _)
 }
 
 pub fn sys_getgid() -> AxResult<isize> {
-    Ok(0)
+    Ok(cred().gid as _)
 }
 
 pub fn sys_getegid() -> AxResult<isize> {
-    Ok(0)
+    Ok(cred().egid as _)
 }
 
-pub fn sys_setuid(_uid: u32) -> AxResult<isize> {
-    debug!("sys_setuid <= uid: {_uid}");
-    Ok(0)
+/// Applies `setres[ug]id` to the real, effective and saved ids in `ids`,
+/// where `u32::MAX` (-1) leaves an id as it is. Without root each new id
+/// must be one of the current three.
+fn set_res_ids(ids: [&mut u32; 3], new: [u32; 3], root: bool) -> AxResult<()> {
+    let old = [*ids[0], *ids[1], *ids[2]];
+    if !root && new.iter().any(|id| *id != u32::MAX && !old.contains(id)) {
+        return Err(AxError::OperationNotPermitted);
+    }
+    for (id, new) in ids.into_iter().zip(new) {
+        if new != u32::MAX {
+            *id = new;
+        }
+    }
+    Ok(())
+}
+
+/// Applies `setre[ug]id`: without root the real id may become the real or
+/// effective one, and the effective id any of the three. The saved id
+/// follows the effective one when the real id changes, or the effective
+/// one is set to something else than the real one.
+fn set_re_ids(ids: [&mut u32; 3], new: [u32; 2], root: bool) -> AxResult<()> {
+    let [real, effective, saved] = ids;
+    let old = [*real, *effective, *saved];
+    if !root
+        && ((new[0] != u32::MAX && !old[..2].contains(&new[0]))
+            || (new[1] != u32::MAX && !old.contains(&new[1])))
+    {
+        return Err(AxError::OperationNotPermitted);
+    }
+    if new[0] != u32::MAX {
+        *real = new[0];
+    }
+    if new[1] != u32::MAX {
+        *effective = new[1];
+    }
+    if new[0] != u32::MAX || (new[1] != u32::MAX && new[1] != old[0]) {
+        *saved = *effective;
+    }
+    Ok(())
 }
 
-pub fn sys_setgid(_gid: u32) -> AxResult<isize> {
-    debug!("sys_setgid <= gid: {_gid}");
+/// Applies `set[ug]id`: root sets all three ids, others only the effective
+/// one, to the real or saved id.
+fn set_id(ids: [&mut u32; 3], new: u32, root: bool) -> AxResult<()> {
+    let [real, effective, saved] = ids;
+    if root {
+        (*real, *effective, *saved) = (new, new, new);
+    } else if new == *real || new == *saved {
+        *effective = new;
+    } else {
+        return Err(AxError::OperationNotPermitted);
+    }
+    Ok(())
+}
+
+fn update_cred(f: impl FnOnce(&mut Credentials, bool) -> AxResult<()>) -> AxResult<isize> {
+    let curr = current();
+    let mut cred = curr.as_thread().proc_data.cred.write();
+    let root = cred.euid == 0;
+    f(&mut cred, root)?;
     Ok(0)
 }
 
+pub fn sys_setuid(uid: u32) -> AxResult<isize> {
+    debug!("sys_setuid <= uid: {uid}");
+    update_cred(|c, root| set_id([&mut c.uid, &mut c.euid, &mut c.suid], uid, root))
+}
+
+pub fn sys_setgid(gid: u32) -> AxResult<isize> {
+    debug!("sys_setgid <= gid: {gid}");
+    update_cred(|c, root| set_id([&mut c.gid, &mut c.egid, &mut c.sgid], gid, root))
+}
+
+pub fn sys_setreuid(ruid: u32, euid: u32) -> AxResult<isize> {
+    debug!("sys_setreuid <= ruid: {ruid}, euid: {euid}");
+    update_cred(|c, root| set_re_ids([&mut c.uid, &mut c.euid, &mut c.suid], [ruid, euid], root))
+}
+
+pub fn sys_setresuid(ruid: u32, euid: u32, suid: u32) -> AxResult<isize> {
+    debug!("sys_setresuid <= ruid: {ruid}, euid: {euid}, suid: {suid}");
+    update_cred(|c, root| {
+        set_res_ids(
+            [&mut c.uid, &mut c.euid, &mut c.suid],
+            [ruid, euid, suid],
+            root,
+        )
+    })
+}
+
+pub fn sys_setresgid(rgid: u32, egid: u32, sgid: u32) -> AxResult<isize> {
+    debug!("sys_setresgid <= rgid: {rgid}, egid: {egid}, sgid: {sgid}");
+    update_cred(|c, root| {
+        set_res_ids(
+            [&mut c.gid, &mut c.egid, &mut c.sgid],
+            [rgid, egid, sgid],
+            root,
+        )
+    })
+}
+
 pub fn sys_getgroups(size: usize, list: *mut u32) -> AxResult<isize> {
     debug!("sys_getgroups <= size: {size}");
     if size < 1 {
@@ -17,7 +17,9 @@ use starry_signal::Signo;
 use crate::{
     file::{FD_TABLE, FileLike, PidFd},
     mm::UserPtr,
+    oom,
     task::new_user_task,
+    vfs::mounts::FS_MOUNTS,
 };
 
 bitflags! {
@@ -149,6 +151,7 @@ pub fn sys_clone(
             .set_page_table_root(old_proc_data.aspace.lock().page_table_root());
         old_proc_data.clone()
     } else {
+        let commitments = oom::commit_fork(&old_proc_data)?;
         let proc = if flags.contains(CloneFlags::PARENT) {
             old_proc_data.proc.parent().ok_or(AxError::InvalidInput)?
         } else {
@@ -182,6 +185,12 @@ pub fn sys_clone(
             exit_signal,
         );
         proc_data.set_umask(old_proc_data.umask());
+        *proc_data.cred.write() = *old_proc_data.cred.read();
+        proc_data.set_initial_sp(old_proc_data.initial_sp());
+        proc_data.replace_personality(old_proc_data.personality());
+        proc_data.set_mmap_layout(old_proc_data.mmap_layout());
+        *proc_data.file_mappings.lock() = old_proc_data.file_mappings.lock().clone();
+        *proc_data.commitments.lock() = commitments;
 
         {
             let mut scope = proc_data.scope.write();
@@ -196,11 +205,16 @@ pub fn sys_clone(
 
             if flags.contains(CloneFlags::FS) {
                 FS_CONTEXT.scope_mut(&mut scope).clone_from(&FS_CONTEXT);
+                FS_MOUNTS.scope_mut(&mut scope).clone_from(&FS_MOUNTS);
             } else {
                 FS_CONTEXT
                     .scope_mut(&mut scope)
                     .lock()
                     .clone_from(&FS_CONTEXT.lock());
+                FS_MOUNTS
+                    .scope_mut(&mut scope)
+                    .lock()
+                    .clone_from(&FS_MOUNTS.lock());
             }
         }
 
@@ -215,6 +229,8 @@ pub fn sys_clone(
     }
 
     let thr = Thread::new(tid, new_proc_data);
+    thr.set_ioprio(curr.as_thread().ioprio());
+    thr.set_timer_slack_ns(curr.as_thread().timer_slack_ns());
     if flags.contains(CloneFlags::CHILD_CLEARTID) {
         thr.set_clear_child_tid(child_tid);
     }
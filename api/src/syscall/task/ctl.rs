@@ -51,16 +51,16 @@ pub fn sys_umask(mask: u32) -> AxResult<isize> {
     Ok(old as isize)
 }
 
-pub fn sys_setreuid(_ruid: u32, _euid: u32) -> AxResult<isize> {
-    Ok(0)
-}
-
-pub fn sys_setresuid(_ruid: u32, _euid: u32, _suid: u32) -> AxResult<isize> {
-    Ok(0)
-}
-
-pub fn sys_setresgid(_rgid: u32, _egid: u32, _sgid: u32) -> AxResult<isize> {
-    Ok(0)
+pub fn sys_personality(persona: u32) -> AxResult<isize> {
+    let curr = current();
+    let proc_data = &curr.as_thread().proc_data;
+    // 0xffffffff only queries.
+    let old = if persona == u32::MAX {
+        proc_data.personality()
+    } else {
+        proc_data.replace_personality(persona)
+    };
+    Ok(old as isize)
 }
 
 pub fn sys_get_mempolicy(
@@ -97,6 +97,8 @@ pub fn sys_prctl(
             buf[..len].copy_from_slice(&name.as_bytes()[..len]);
             vm_write_slice(arg2 as _, &buf)?;
         }
+        PR_SET_TIMERSLACK => current().as_thread().set_timer_slack_ns(arg2 as _),
+        PR_GET_TIMERSLACK => return Ok(current().as_thread().timer_slack_ns() as _),
         PR_SET_SECCOMP => {}
         PR_MCE_KILL => {}
         PR_SET_MM_START_CODE
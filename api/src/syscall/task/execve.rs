@@ -1,14 +1,28 @@
-use alloc::{string::ToString, sync::Arc, vec::Vec};
-use core::ffi::c_char;
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::ffi::{c_char, c_int};
 
-use axerrno::{AxError, AxResult};
+use axerrno::{AxError, AxResult, LinuxError};
 use axfs::FS_CONTEXT;
+use axfs_ng_vfs::NodeType;
 use axhal::uspace::UserContext;
 use axtask::current;
-use starry_core::{mm::load_user_app, task::AsThread};
-use starry_vm::vm_load_until_nul;
+use linux_raw_sys::general::{AT_EMPTY_PATH, AT_SYMLINK_NOFOLLOW};
+use starry_core::{
+    mm::{FileMappings, MmapLayout, load_user_app},
+    task::AsThread,
+};
+use starry_vm::{VmPtr, vm_load_until_nul};
 
-use crate::{file::FD_TABLE, mm::vm_load_string};
+use crate::{
+    file::{FD_TABLE, resolve_at},
+    mm::vm_load_string,
+    syscall::clear_posix_timers,
+    task::release_robust_list,
+};
 
 pub fn sys_execve(
     uctx: &mut UserContext,
@@ -17,7 +31,41 @@ pub fn sys_execve(
     envp: *const *const c_char,
 ) -> AxResult<isize> {
     let path = vm_load_string(path)?;
+    execve(uctx, path, argv, envp)
+}
+
+/// Like `execve`, with the program given relative to `dirfd` or, with
+/// `AT_EMPTY_PATH` and an empty `path`, by `dirfd` itself.
+pub fn sys_execveat(
+    uctx: &mut UserContext,
+    dirfd: c_int,
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+    flags: u32,
+) -> AxResult<isize> {
+    let path = path.nullable().map(vm_load_string).transpose()?;
+    debug!("sys_execveat <= dirfd: {dirfd}, path: {path:?}, flags: {flags}");
+
+    if flags & !(AT_EMPTY_PATH | AT_SYMLINK_NOFOLLOW) != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let loc = resolve_at(dirfd, path.as_deref(), flags)?
+        .into_file()
+        .ok_or(AxError::PermissionDenied)?;
+    if loc.node_type() == NodeType::Symlink {
+        return Err(AxError::from(LinuxError::ELOOP));
+    }
+    // The loader goes by path, so hand it one that needs no `dirfd`.
+    execve(uctx, loc.absolute_path()?.to_string(), argv, envp)
+}
 
+fn execve(
+    uctx: &mut UserContext,
+    path: String,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> AxResult<isize> {
     let args = if argv.is_null() {
         // Handle NULL argv (treat as empty array)
         Vec::new()
@@ -49,10 +97,17 @@ pub fn sys_execve(
         return Err(AxError::WouldBlock);
     }
 
+    // The robust list points into the address space about to be replaced.
+    release_robust_list(curr.as_thread(), curr.id().as_u64() as _);
+
     let mut aspace = proc_data.aspace.lock();
     let (entry_point, user_stack_base) =
         load_user_app(&mut aspace, Some(path.as_str()), &args, &envs)?;
     drop(aspace);
+    proc_data.set_initial_sp(user_stack_base.as_usize());
+    proc_data.set_mmap_layout(MmapLayout::new(proc_data.personality()));
+    *proc_data.file_mappings.lock() = FileMappings::default();
+    proc_data.commitments.lock().clear();
 
     let loc = FS_CONTEXT.lock().resolve(&path)?;
     curr.set_name(loc.name());
@@ -61,6 +116,7 @@ pub fn sys_execve(
     *proc_data.cmdline.write() = Arc::new(args);
 
     *proc_data.signal.actions.lock() = Default::default();
+    clear_posix_timers();
 
     // Close CLOEXEC file descriptors
     let mut fd_table = FD_TABLE.write();
@@ -0,0 +1,79 @@
+use alloc::{vec, vec::Vec};
+
+use axerrno::{AxError, AxResult};
+use axtask::{AxTaskRef, current};
+use starry_core::{
+    ioprio::{IoPrio, IoPrioClass},
+    task::{AsThread, get_process_group, get_task, tasks},
+};
+
+use crate::syscall::sys::sys_geteuid;
+
+const IOPRIO_WHO_PROCESS: i32 = 1;
+const IOPRIO_WHO_PGRP: i32 = 2;
+const IOPRIO_WHO_USER: i32 = 3;
+
+/// Collects the tasks selected by an `ioprio_get`/`ioprio_set` target.
+fn targets(which: i32, who: i32) -> AxResult<Vec<AxTaskRef>> {
+    let who = u32::try_from(who).map_err(|_| AxError::NoSuchProcess)?;
+    let tasks = match which {
+        // `who` is a thread ID here, 0 meaning the calling thread.
+        IOPRIO_WHO_PROCESS => vec![get_task(who)?],
+        IOPRIO_WHO_PGRP => {
+            let pgid = if who == 0 {
+                current().as_thread().proc_data.proc.group().pgid()
+            } else {
+                who
+            };
+            get_process_group(pgid)?
+                .processes()
+                .iter()
+                .flat_map(|proc| proc.threads())
+                .filter_map(|tid| get_task(tid).ok())
+                .collect()
+        }
+        // Every task runs as root.
+        IOPRIO_WHO_USER if who == 0 => tasks()
+            .into_iter()
+            .filter(|task| task.try_as_thread().is_some())
+            .collect(),
+        IOPRIO_WHO_USER => Vec::new(),
+        _ => return Err(AxError::InvalidInput),
+    };
+    if tasks.is_empty() {
+        return Err(AxError::NoSuchProcess);
+    }
+    Ok(tasks)
+}
+
+pub fn sys_ioprio_get(which: i32, who: i32) -> AxResult<isize> {
+    debug!("sys_ioprio_get <= which: {which}, who: {who}");
+
+    let best = targets(which, who)?
+        .iter()
+        .map(|task| task.as_thread().ioprio())
+        .reduce(|best, prio| {
+            if prio.is_higher_than(best) {
+                prio
+            } else {
+                best
+            }
+        })
+        .unwrap_or(IoPrio::DEFAULT);
+    Ok(best.raw() as isize)
+}
+
+pub fn sys_ioprio_set(which: i32, who: i32, ioprio: u32) -> AxResult<isize> {
+    debug!("sys_ioprio_set <= which: {which}, who: {who}, ioprio: {ioprio:#x}");
+
+    let ioprio = IoPrio::from_raw(ioprio)?;
+    // The real-time class needs CAP_SYS_ADMIN (or CAP_SYS_NICE), which only
+    // root has for now.
+    if ioprio.class() == IoPrioClass::RealTime && sys_geteuid()? != 0 {
+        return Err(AxError::OperationNotPermitted);
+    }
+    for task in targets(which, who)? {
+        task.as_thread().set_ioprio(ioprio);
+    }
+    Ok(0)
+}
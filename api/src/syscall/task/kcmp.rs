@@ -0,0 +1,110 @@
+use alloc::sync::Arc;
+use core::cmp::Ordering;
+
+use axerrno::{AxError, AxResult};
+use axfs::FS_CONTEXT;
+use axhal::time::wall_time;
+use axtask::current;
+use lazy_static::lazy_static;
+use rand::{RngCore, SeedableRng, rngs::SmallRng};
+use starry_core::task::{AsThread, Credentials, ProcessData, get_process_data};
+
+use crate::file::FD_TABLE;
+
+const KCMP_FILE: u32 = 0;
+const KCMP_VM: u32 = 1;
+const KCMP_FILES: u32 = 2;
+const KCMP_FS: u32 = 3;
+const KCMP_SIGHAND: u32 = 4;
+const KCMP_IO: u32 = 5;
+const KCMP_SYSVSEM: u32 = 6;
+const KCMP_TYPES: usize = 8;
+
+lazy_static! {
+    /// Per-boot cookies so that the ordering reported does not leak kernel
+    /// addresses.
+    static ref COOKIES: [[usize; 2]; KCMP_TYPES] = {
+        let mut rng = SmallRng::seed_from_u64(wall_time().as_nanos() as u64);
+        core::array::from_fn(|_| {
+            [
+                rng.next_u64() as usize,
+                // odd and large, so that the multiplication is a bijection
+                rng.next_u64() as usize | !(usize::MAX >> 1) | 1,
+            ]
+        })
+    };
+}
+
+fn obfuscate(ptr: *const (), ty: u32) -> usize {
+    let [xor, mul] = COOKIES[ty as usize];
+    (ptr as usize ^ xor).wrapping_mul(mul)
+}
+
+fn kcmp_ptr(a: *const (), b: *const (), ty: u32) -> isize {
+    match obfuscate(a, ty).cmp(&obfuscate(b, ty)) {
+        Ordering::Equal => 0,
+        Ordering::Less => 1,
+        Ordering::Greater => 2,
+    }
+}
+
+fn file_ptr(proc_data: &ProcessData, fd: usize) -> AxResult<*const ()> {
+    let scope = proc_data.scope.read();
+    let table = FD_TABLE.scope(&scope).read();
+    let fd = table.get(fd).ok_or(AxError::BadFileDescriptor)?;
+    Ok(Arc::as_ptr(&fd.inner).cast())
+}
+
+/// Whether `caller` may look into `target`, as `PTRACE_MODE_READ_REALCREDS`
+/// has it: root may look into anything, others only into processes whose
+/// ids all are their real ones.
+fn may_inspect(caller: &Credentials, target: &ProcessData) -> bool {
+    let target = *target.cred.read();
+    caller.euid == 0
+        || ([target.uid, target.euid, target.suid] == [caller.uid; 3]
+            && [target.gid, target.egid, target.sgid] == [caller.gid; 3])
+}
+
+pub fn sys_kcmp(pid1: u32, pid2: u32, ty: u32, idx1: usize, idx2: usize) -> AxResult<isize> {
+    debug!("sys_kcmp <= pid1: {pid1}, pid2: {pid2}, type: {ty}, idx1: {idx1}, idx2: {idx2}");
+
+    let proc1 = get_process_data(pid1)?;
+    let proc2 = get_process_data(pid2)?;
+    let caller = *current().as_thread().proc_data.cred.read();
+    if !may_inspect(&caller, &proc1) || !may_inspect(&caller, &proc2) {
+        return Err(AxError::OperationNotPermitted);
+    }
+
+    let scopes = || (proc1.scope.read(), proc2.scope.read());
+    let (a, b): (*const (), *const ()) = match ty {
+        KCMP_FILE => (file_ptr(&proc1, idx1)?, file_ptr(&proc2, idx2)?),
+        KCMP_VM => (
+            Arc::as_ptr(&proc1.aspace).cast(),
+            Arc::as_ptr(&proc2.aspace).cast(),
+        ),
+        KCMP_FILES => {
+            let (s1, s2) = scopes();
+            (
+                Arc::as_ptr(&*FD_TABLE.scope(&s1)).cast(),
+                Arc::as_ptr(&*FD_TABLE.scope(&s2)).cast(),
+            )
+        }
+        KCMP_FS => {
+            let (s1, s2) = scopes();
+            (
+                Arc::as_ptr(&*FS_CONTEXT.scope(&s1)).cast(),
+                Arc::as_ptr(&*FS_CONTEXT.scope(&s2)).cast(),
+            )
+        }
+        KCMP_SIGHAND => (
+            Arc::as_ptr(&proc1.signal.actions).cast(),
+            Arc::as_ptr(&proc2.signal.actions).cast(),
+        ),
+        // There is no per-process I/O context; every process has its own.
+        KCMP_IO => (Arc::as_ptr(&proc1).cast(), Arc::as_ptr(&proc2).cast()),
+        // No process has System V semaphore undo lists.
+        KCMP_SYSVSEM => (core::ptr::null(), core::ptr::null()),
+        _ => return Err(AxError::InvalidInput),
+    };
+    Ok(kcmp_ptr(a, b, ty))
+}
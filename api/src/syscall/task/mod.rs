@@ -2,9 +2,14 @@ mod clone;
 mod ctl;
 mod execve;
 mod exit;
+mod ioprio;
 mod job;
+mod kcmp;
 mod schedule;
 mod thread;
 mod wait;
 
-pub use self::{clone::*, ctl::*, execve::*, exit::*, job::*, schedule::*, thread::*, wait::*};
+pub use self::{
+    clone::*, ctl::*, execve::*, exit::*, ioprio::*, job::*, kcmp::*, schedule::*, thread::*,
+    wait::*,
+};
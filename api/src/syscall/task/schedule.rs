@@ -1,33 +1,43 @@
 use axerrno::{AxError, AxResult};
-use axhal::time::TimeValue;
+use axhal::time::{TimeValue, monotonic_time};
 use axtask::{
     AxCpuMask, current,
-    future::{block_on, interruptible, sleep},
+    future::{block_on, interruptible},
 };
 use linux_raw_sys::general::{
-    __kernel_clockid_t, CLOCK_MONOTONIC, CLOCK_REALTIME, PRIO_PGRP, PRIO_PROCESS, PRIO_USER,
-    SCHED_RR, TIMER_ABSTIME, timespec,
+    __kernel_clockid_t, CLOCK_MONOTONIC_COARSE, CLOCK_MONOTONIC_RAW, CLOCK_PROCESS_CPUTIME_ID,
+    CLOCK_REALTIME, CLOCK_REALTIME_ALARM, CLOCK_REALTIME_COARSE, PRIO_PGRP, PRIO_PROCESS,
+    PRIO_USER, SCHED_RR, TIMER_ABSTIME, timespec,
+};
+use starry_core::{
+    task::{get_process_data, get_process_group},
+    time::sleep_until_realtime,
+    timer,
 };
-use starry_core::task::{get_process_data, get_process_group};
 use starry_vm::{VmMutPtr, VmPtr, vm_load, vm_write_slice};
 
-use crate::time::TimeValueLike;
+use crate::time::{TimeValueLike, timer_clock_time};
 
 pub fn sys_sched_yield() -> AxResult<isize> {
     axtask::yield_now();
     Ok(0)
 }
 
-fn sleep_impl(clock: impl Fn() -> TimeValue, dur: TimeValue) -> TimeValue {
-    debug!("sleep_impl <= {dur:?}");
-
-    let start = clock();
-
-    // TODO: currently ignoring concrete clock type
-    // We detect EINTR manually if the slept time is not enough.
-    let _ = block_on(interruptible(sleep(dur)));
-
-    clock() - start
+/// Sleeps for `req` on the monotonic clock, which no step of the wall
+/// clock moves, give or take the timer slack of the thread. If a signal cuts the sleep short, the time left is written
+/// to `rem` and it fails with `EINTR`.
+fn sleep_relative(req: TimeValue, rem: *mut timespec) -> AxResult<isize> {
+    let deadline = monotonic_time() + req;
+    let slacked = timer::apply_slack(deadline, timer::current_timer_slack());
+    if block_on(interruptible(timer::sleep_until(slacked))).is_ok() {
+        return Ok(0);
+    }
+    let diff = deadline.saturating_sub(monotonic_time());
+    debug!("sleep_relative => rem: {diff:?}");
+    if let Some(rem) = rem.nullable() {
+        rem.vm_write(timespec::from_time_value(diff))?;
+    }
+    Err(AxError::Interrupted)
 }
 
 /// Sleep some nanoseconds
@@ -36,17 +46,7 @@ pub fn sys_nanosleep(req: *const timespec, rem: *mut timespec) -> AxResult<isize
     let req = unsafe { req.vm_read_uninit()?.assume_init() }.try_into_time_value()?;
     debug!("sys_nanosleep <= req: {req:?}");
 
-    let actual = sleep_impl(axhal::time::monotonic_time, req);
-
-    if let Some(diff) = req.checked_sub(actual) {
-        debug!("sys_nanosleep => rem: {diff:?}");
-        if let Some(rem) = rem.nullable() {
-            rem.vm_write(timespec::from_time_value(diff))?;
-        }
-        Err(AxError::Interrupted)
-    } else {
-        Ok(0)
-    }
+    sleep_relative(req, rem)
 }
 
 pub fn sys_clock_nanosleep(
@@ -55,35 +55,33 @@ pub fn sys_clock_nanosleep(
     req: *const timespec,
     rem: *mut timespec,
 ) -> AxResult<isize> {
-    let clock = match clock_id as u32 {
-        CLOCK_REALTIME => axhal::time::wall_time,
-        CLOCK_MONOTONIC => axhal::time::monotonic_time,
-        _ => {
-            warn!("Unsupported clock_id: {clock_id}");
-            return Err(AxError::InvalidInput);
-        }
+    let clock = clock_id as u32;
+    let Some(now) = timer_clock_time(clock) else {
+        warn!("Unsupported clock_id: {clock_id}");
+        return Err(match clock {
+            CLOCK_PROCESS_CPUTIME_ID
+            | CLOCK_MONOTONIC_RAW
+            | CLOCK_REALTIME_COARSE
+            | CLOCK_MONOTONIC_COARSE => AxError::OperationNotSupported,
+            _ => AxError::InvalidInput,
+        });
     };
 
+    // FIXME: AnyBitPattern
     let req = unsafe { req.vm_read_uninit()?.assume_init() }.try_into_time_value()?;
     debug!("sys_clock_nanosleep <= clock_id: {clock_id}, flags: {flags}, req: {req:?}");
 
-    let dur = if flags & TIMER_ABSTIME != 0 {
-        req.saturating_sub(clock())
-    } else {
-        req
-    };
-
-    let actual = sleep_impl(clock, dur);
-
-    if let Some(diff) = dur.checked_sub(actual) {
-        debug!("sys_clock_nanosleep => rem: {diff:?}");
-        if let Some(rem) = rem.nullable() {
-            rem.vm_write(timespec::from_time_value(diff))?;
-        }
-        Err(AxError::Interrupted)
+    if flags & TIMER_ABSTIME == 0 {
+        return sleep_relative(req, rem);
+    }
+    // An absolute sleep leaves `rem` alone: restarting it needs nothing.
+    if matches!(clock, CLOCK_REALTIME | CLOCK_REALTIME_ALARM) {
+        block_on(interruptible(sleep_until_realtime(req)))?;
     } else {
-        Ok(0)
+        let deadline = monotonic_time() + req.saturating_sub(now);
+        block_on(interruptible(timer::sleep_until(deadline)))?;
     }
+    Ok(0)
 }
 
 pub fn sys_sched_getaffinity(pid: i32, cpusetsize: usize, user_mask: *mut u8) -> AxResult<isize> {
@@ -1,29 +1,48 @@
-use axerrno::{AxError, AxResult};
-use axhal::time::{TimeValue, monotonic_time, monotonic_time_nanos, nanos_to_ticks, wall_time};
+use alloc::collections::btree_map::BTreeMap;
+use core::{mem, time::Duration};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axhal::time::{TimeValue, monotonic_time, monotonic_time_nanos, nanos_to_ticks};
+use axsync::Mutex;
 use axtask::current;
+use bytemuck::AnyBitPattern;
 use linux_raw_sys::general::{
     __kernel_clockid_t, CLOCK_BOOTTIME, CLOCK_MONOTONIC, CLOCK_MONOTONIC_COARSE,
     CLOCK_MONOTONIC_RAW, CLOCK_PROCESS_CPUTIME_ID, CLOCK_REALTIME, CLOCK_REALTIME_COARSE,
-    CLOCK_THREAD_CPUTIME_ID, itimerval, timespec, timeval,
+    CLOCK_THREAD_CPUTIME_ID, SI_TIMER, SIGEV_NONE, SIGEV_SIGNAL, TIMER_ABSTIME, itimerspec,
+    itimerval, timespec, timeval,
+};
+use starry_core::{
+    task::{
+        AsThread, get_task, send_cpu_timer_signal, send_signal_to_process, send_signal_to_thread,
+    },
+    time::{CpuClock, CpuTimer, ITimerType, WallTimer, boottime, realtime, set_realtime},
 };
-use starry_core::{task::AsThread, time::ITimerType};
+use starry_process::Pid;
+use starry_signal::{SignalInfo, Signo};
 use starry_vm::{VmMutPtr, VmPtr};
 
-use crate::time::TimeValueLike;
+use crate::{
+    syscall::sys::sys_geteuid,
+    time::{ITimerValueLike, TimeValueLike, timer_clock_time},
+};
 
 pub fn sys_clock_gettime(clock_id: __kernel_clockid_t, ts: *mut timespec) -> AxResult<isize> {
     let now = match clock_id as u32 {
-        CLOCK_REALTIME | CLOCK_REALTIME_COARSE => wall_time(),
-        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => {
-            monotonic_time()
+        CLOCK_REALTIME | CLOCK_REALTIME_COARSE => realtime(),
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE => monotonic_time(),
+        CLOCK_BOOTTIME => boottime(),
+        CLOCK_PROCESS_CPUTIME_ID => {
+            let (utime, stime) = current().as_thread().proc_data.cpu_timers.lock().output();
+            utime + stime
         }
-        CLOCK_PROCESS_CPUTIME_ID | CLOCK_THREAD_CPUTIME_ID => {
+        CLOCK_THREAD_CPUTIME_ID => {
             let (utime, stime) = current().as_thread().time.borrow().output();
             utime + stime
         }
         _ => {
             warn!("Called sys_clock_gettime for unsupported clock {clock_id}");
-            wall_time()
+            realtime()
             // return Err(AxError::EINVAL);
         }
     };
@@ -32,16 +51,51 @@ pub fn sys_clock_gettime(clock_id: __kernel_clockid_t, ts: *mut timespec) -> AxR
 }
 
 pub fn sys_gettimeofday(ts: *mut timeval) -> AxResult<isize> {
-    ts.vm_write(timeval::from_time_value(wall_time()))?;
+    ts.vm_write(timeval::from_time_value(realtime()))?;
     Ok(0)
 }
 
+pub fn sys_clock_settime(clock_id: __kernel_clockid_t, ts: *const timespec) -> AxResult<isize> {
+    // Only the wall clock may be stepped.
+    if clock_id as u32 != CLOCK_REALTIME {
+        return Err(AxError::InvalidInput);
+    }
+    // FIXME: AnyBitPattern
+    let now = unsafe { ts.vm_read_uninit()?.assume_init() }.try_into_time_value()?;
+    debug!("sys_clock_settime <= {now:?}");
+    check_settime()?;
+    set_realtime(now);
+    Ok(0)
+}
+
+pub fn sys_settimeofday(tv: *const timeval, _tz: *const u8) -> AxResult<isize> {
+    let now = match tv.nullable() {
+        // FIXME: AnyBitPattern
+        Some(tv) => Some(unsafe { tv.vm_read_uninit()?.assume_init() }.try_into_time_value()?),
+        None => None,
+    };
+    check_settime()?;
+    if let Some(now) = now {
+        set_realtime(now);
+    }
+    Ok(0)
+}
+
+/// Fails with `EPERM` unless the caller may set the system time, which moves
+/// every absolute realtime deadline in the system.
+fn check_settime() -> AxResult<()> {
+    if sys_geteuid()? != 0 {
+        return Err(AxError::OperationNotPermitted);
+    }
+    Ok(())
+}
+
 pub fn sys_clock_getres(clock_id: __kernel_clockid_t, res: *mut timespec) -> AxResult<isize> {
     if clock_id as u32 != CLOCK_MONOTONIC && clock_id as u32 != CLOCK_REALTIME {
         warn!("Called sys_clock_getres for unsupported clock {clock_id}");
     }
     if let Some(res) = res.nullable() {
-        res.vm_write(timespec::from_time_value(TimeValue::from_micros(1)))?;
+        res.vm_write(timespec::from_time_value(starry_core::timer::RESOLUTION))?;
     }
     Ok(0)
 }
@@ -73,7 +127,12 @@ pub fn sys_times(tms: *mut Tms) -> AxResult<isize> {
 
 pub fn sys_getitimer(which: i32, value: *mut itimerval) -> AxResult<isize> {
     let ty = ITimerType::from_repr(which).ok_or(AxError::InvalidInput)?;
-    let (it_interval, it_value) = current().as_thread().time.borrow().get_itimer(ty);
+    let curr = current();
+    let thr = curr.as_thread();
+    let (it_interval, it_value) = match ty {
+        ITimerType::Real => thr.proc_data.real_timer.get(),
+        _ => thr.proc_data.cpu_timers.lock().get_itimer(ty),
+    };
 
     value.vm_write(itimerval {
         it_interval: timeval::from_time_value(it_interval),
@@ -94,21 +153,31 @@ pub fn sys_setitimer(
         Some(new_value) => {
             // FIXME: AnyBitPattern
             let new_value = unsafe { new_value.vm_read_uninit()?.assume_init() };
-            (
-                new_value.it_interval.try_into_time_value()?.as_nanos() as usize,
-                new_value.it_value.try_into_time_value()?.as_nanos() as usize,
-            )
+            let (interval, value) = new_value.try_into_time_values()?;
+            (interval.as_nanos() as usize, value.as_nanos() as usize)
         }
         None => (0, 0),
     };
 
     debug!("sys_setitimer <= type: {ty:?}, interval: {interval:?}, remained: {remained:?}");
 
-    let old = curr
-        .as_thread()
-        .time
-        .borrow_mut()
-        .set_itimer(ty, interval, remained);
+    let thr = curr.as_thread();
+    let old = match ty {
+        ITimerType::Real => thr.proc_data.real_timer.set(
+            Duration::from_nanos(interval as _),
+            Duration::from_nanos(remained as _),
+        ),
+        // These count the CPU time of the whole process.
+        _ => {
+            let signo = ty.signo();
+            thr.proc_data.cpu_timers.lock().set_itimer(
+                ty,
+                interval as _,
+                remained as _,
+                move |_| send_cpu_timer_signal(None, SignalInfo::new_kernel(signo)),
+            )
+        }
+    };
 
     if let Some(old_value) = old_value.nullable() {
         old_value.vm_write(itimerval {
@@ -118,3 +187,268 @@ pub fn sys_setitimer(
     }
     Ok(0)
 }
+
+/// Arms `ITIMER_REAL` to send `SIGALRM` in `seconds`, or disarms it if zero.
+/// Returns the seconds left on the previous alarm, rounded to the nearest
+/// and at least 1 if one was armed.
+#[cfg(target_arch = "x86_64")]
+pub fn sys_alarm(seconds: u32) -> AxResult<isize> {
+    debug!("sys_alarm <= seconds: {seconds}");
+    let (_, remaining) = current()
+        .as_thread()
+        .proc_data
+        .real_timer
+        .set(Duration::ZERO, Duration::from_secs(seconds as u64));
+    let secs = (remaining + Duration::from_millis(500)).as_secs();
+    Ok(secs.max(u64::from(!remaining.is_zero())) as _)
+}
+
+const SIGEV_THREAD_ID: i32 = 4;
+
+/// `struct sigevent`.
+#[repr(C)]
+#[derive(Clone, Copy, AnyBitPattern)]
+pub struct SigEvent {
+    sigev_value: usize,
+    sigev_signo: i32,
+    sigev_notify: i32,
+    sigev_notify_thread_id: i32,
+    _pad: [i32; 11],
+}
+
+/// A POSIX timer of the process
+enum PosixTimer {
+    /// A timer on CPU time, by its id in the process' CPU timers
+    Cpu(u32),
+    /// A timer on a wall clock, armed on the timer wheel
+    Wall { clockid: u32, timer: WallTimer },
+}
+
+scope_local::scope_local! {
+    /// The POSIX timers of the current process, by timer id.
+    static POSIX_TIMERS: Mutex<BTreeMap<i32, PosixTimer>> = Mutex::new(BTreeMap::new());
+}
+
+/// Deletes the POSIX timers of the current process, as `execve` and the exit
+/// of its last thread do.
+pub fn clear_posix_timers() {
+    let timers = mem::take(&mut *POSIX_TIMERS.lock());
+    let mut cpu_timers = current().as_thread().proc_data.cpu_timers.lock();
+    for timer in timers.into_values() {
+        if let PosixTimer::Cpu(cpu_id) = timer {
+            cpu_timers.remove(cpu_id);
+        }
+    }
+}
+
+/// Builds the `SI_TIMER` siginfo of an expiry.
+fn timer_signal_info(signo: Signo, timer_id: i32, overrun: u64, value: usize) -> SignalInfo {
+    let mut sig = SignalInfo::new_user(signo, SI_TIMER, 0);
+    // `_sifields._timer` holds si_tid, si_overrun and si_sigval from
+    // offset 16.
+    unsafe {
+        let base = (&raw mut sig.0).cast::<u8>();
+        base.add(16).cast::<i32>().write_unaligned(timer_id);
+        base.add(20)
+            .cast::<i32>()
+            .write_unaligned(overrun.min(i32::MAX as u64) as i32);
+        base.add(24).cast::<usize>().write_unaligned(value);
+    }
+    sig
+}
+
+pub fn sys_timer_create(
+    clock_id: __kernel_clockid_t,
+    sevp: *const SigEvent,
+    timer_id: *mut i32,
+) -> AxResult<isize> {
+    debug!("sys_timer_create <= clock_id: {clock_id}");
+    let curr = current();
+    let tid = curr.id().as_u64() as Pid;
+    let pid = curr.as_thread().proc_data.proc.pid();
+    let clockid = clock_id as u32;
+    // The thread whose CPU time a CPU timer counts, or `None` for the process
+    let cpu_thread = match clockid {
+        CLOCK_PROCESS_CPUTIME_ID => Some(None),
+        CLOCK_THREAD_CPUTIME_ID => Some(Some(tid)),
+        _ if clock_id < 0 => return Err(AxError::InvalidInput),
+        _ if timer_clock_time(clockid).is_some() => None,
+        _ => return Err(AxError::InvalidInput),
+    };
+
+    let mut timers = POSIX_TIMERS.lock();
+    let id = (0..)
+        .find(|id| !timers.contains_key(id))
+        .ok_or(AxError::from(LinuxError::EAGAIN))?;
+    let event = match sevp.nullable() {
+        Some(sevp) => sevp.vm_read()?,
+        None => SigEvent {
+            sigev_value: id as usize,
+            sigev_signo: Signo::SIGALRM as i32,
+            sigev_notify: SIGEV_SIGNAL as i32,
+            sigev_notify_thread_id: 0,
+            _pad: [0; 11],
+        },
+    };
+    let target = match event.sigev_notify as u32 {
+        SIGEV_NONE => None,
+        SIGEV_SIGNAL => Some(None),
+        _ if event.sigev_notify == SIGEV_THREAD_ID => {
+            let target = event.sigev_notify_thread_id as Pid;
+            let task = get_task(target).map_err(|_| AxError::InvalidInput)?;
+            if task.as_thread().proc_data.proc.pid() != pid {
+                return Err(AxError::InvalidInput);
+            }
+            Some(Some(target))
+        }
+        _ => return Err(AxError::InvalidInput),
+    };
+    let signo = match target {
+        Some(_) => Signo::from_repr(event.sigev_signo as u8).ok_or(AxError::InvalidInput)?,
+        None => Signo::SIGALRM,
+    };
+    timer_id.vm_write(id)?;
+
+    let value = event.sigev_value;
+    let timer = match cpu_thread {
+        Some(thread) => {
+            let timer = CpuTimer::new(CpuClock::Total, thread, move |overrun| {
+                if let Some(target) = target {
+                    send_cpu_timer_signal(target, timer_signal_info(signo, id, overrun, value));
+                }
+            });
+            PosixTimer::Cpu(curr.as_thread().proc_data.cpu_timers.lock().insert(timer))
+        }
+        // Expiries run on the timer task, so the process is looked up by id.
+        None => PosixTimer::Wall {
+            clockid,
+            timer: WallTimer::new(move |overrun| {
+                let sig = Some(timer_signal_info(signo, id, overrun, value));
+                let _ = match target {
+                    Some(Some(tid)) => send_signal_to_thread(Some(pid), tid, sig),
+                    Some(None) => send_signal_to_process(pid, sig),
+                    None => Ok(()),
+                };
+            }),
+        },
+    };
+    timers.insert(id, timer);
+    Ok(0)
+}
+
+fn write_itimerspec(ptr: *mut itimerspec, (interval, remaining): (u64, u64)) -> AxResult<()> {
+    ptr.vm_write(itimerspec {
+        it_interval: timespec::from_time_value(Duration::from_nanos(interval)),
+        it_value: timespec::from_time_value(Duration::from_nanos(remaining)),
+    })
+}
+
+pub fn sys_timer_settime(
+    timer_id: i32,
+    flags: i32,
+    new_value: *const itimerspec,
+    old_value: *mut itimerspec,
+) -> AxResult<isize> {
+    // FIXME: AnyBitPattern
+    let new_value = unsafe { new_value.vm_read_uninit()?.assume_init() };
+    let (interval, value) = new_value.try_into_time_values()?;
+    debug!(
+        "sys_timer_settime <= id: {timer_id}, flags: {flags}, interval: {interval:?}, value: \
+         {value:?}"
+    );
+    let absolute = flags as u32 & TIMER_ABSTIME != 0 && !value.is_zero();
+
+    let curr = current();
+    let thr = curr.as_thread();
+    let timers = POSIX_TIMERS.lock();
+    let old = match timers.get(&timer_id).ok_or(AxError::InvalidInput)? {
+        PosixTimer::Cpu(cpu_id) => {
+            let (interval, mut value) = (interval.as_nanos() as u64, value.as_nanos() as u64);
+            let mut cpu_timers = thr.proc_data.cpu_timers.lock();
+            if absolute {
+                // The target is a reading of the timer's clock; an expired one
+                // fires at the next tick.
+                let timer = cpu_timers.get_mut(*cpu_id).ok_or(AxError::InvalidInput)?;
+                let (utime, stime) = if timer.thread().is_some() {
+                    thr.time.borrow().output()
+                } else {
+                    cpu_timers.output()
+                };
+                value = value
+                    .saturating_sub((utime + stime).as_nanos() as u64)
+                    .max(1);
+            }
+            let timer = cpu_timers.get_mut(*cpu_id).ok_or(AxError::InvalidInput)?;
+            timer.set(interval, value)
+        }
+        PosixTimer::Wall { clockid, timer } => {
+            let value = if absolute {
+                // Converted once: a later step of the clock does not move it.
+                let now = timer_clock_time(*clockid).unwrap_or_default();
+                value.saturating_sub(now).max(Duration::from_nanos(1))
+            } else {
+                value
+            };
+            let (interval, remaining) = timer.set(interval, value);
+            (interval.as_nanos() as u64, remaining.as_nanos() as u64)
+        }
+    };
+    drop(timers);
+
+    if let Some(old_value) = old_value.nullable() {
+        write_itimerspec(old_value, old)?;
+    }
+    Ok(0)
+}
+
+pub fn sys_timer_gettime(timer_id: i32, curr_value: *mut itimerspec) -> AxResult<isize> {
+    let timers = POSIX_TIMERS.lock();
+    let value = match timers.get(&timer_id).ok_or(AxError::InvalidInput)? {
+        PosixTimer::Cpu(cpu_id) => current()
+            .as_thread()
+            .proc_data
+            .cpu_timers
+            .lock()
+            .get_mut(*cpu_id)
+            .ok_or(AxError::InvalidInput)?
+            .get(),
+        PosixTimer::Wall { timer, .. } => {
+            let (interval, remaining) = timer.get();
+            (interval.as_nanos() as u64, remaining.as_nanos() as u64)
+        }
+    };
+    write_itimerspec(curr_value, value)?;
+    Ok(0)
+}
+
+pub fn sys_timer_getoverrun(timer_id: i32) -> AxResult<isize> {
+    let timers = POSIX_TIMERS.lock();
+    let overrun = match timers.get(&timer_id).ok_or(AxError::InvalidInput)? {
+        PosixTimer::Cpu(cpu_id) => current()
+            .as_thread()
+            .proc_data
+            .cpu_timers
+            .lock()
+            .get_mut(*cpu_id)
+            .ok_or(AxError::InvalidInput)?
+            .overrun(),
+        PosixTimer::Wall { timer, .. } => timer.overrun(),
+    };
+    Ok(overrun.min(i32::MAX as u64) as _)
+}
+
+pub fn sys_timer_delete(timer_id: i32) -> AxResult<isize> {
+    let timer = POSIX_TIMERS
+        .lock()
+        .remove(&timer_id)
+        .ok_or(AxError::InvalidInput)?;
+    if let PosixTimer::Cpu(cpu_id) = timer {
+        current()
+            .as_thread()
+            .proc_data
+            .cpu_timers
+            .lock()
+            .remove(cpu_id);
+    }
+    Ok(0)
+}
@@ -1,4 +1,8 @@
-use core::{ffi::c_long, sync::atomic::Ordering};
+use core::{
+    ffi::c_long,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
 
 use axerrno::{AxError, AxResult};
 use axhal::uspace::{ExceptionKind, ReturnReason, UserContext};
@@ -10,8 +14,8 @@ use starry_core::{
     mm::access_user_memory,
     shm::SHM_MANAGER,
     task::{
-        AsThread, get_process_data, get_task, send_signal_to_process, send_signal_to_thread,
-        set_timer_state,
+        AsThread, Thread, get_process_data, get_task, send_signal_to_process,
+        send_signal_to_thread, set_timer_state,
     },
     time::TimerState,
 };
@@ -20,8 +24,11 @@ use starry_signal::{SignalInfo, Signo};
 use starry_vm::{VmMutPtr, VmPtr};
 
 use crate::{
-    signal::{check_signals, unblock_next_signal},
-    syscall::handle_syscall,
+    coredump::freeze_if_dumping,
+    mm::{check_file_fault, fault_readahead_at, map_around},
+    oom::handle_user_fault,
+    signal::{deliver_signals, unblock_next_signal},
+    syscall::{clear_posix_timers, handle_syscall},
 };
 
 /// Create a new user task.
@@ -50,13 +57,25 @@ pub fn new_user_task(
                 match reason {
                     ReturnReason::Syscall => handle_syscall(&mut uctx),
                     ReturnReason::PageFault(addr, flags) => {
-                        if !thr.proc_data.aspace.lock().handle_page_fault(addr, flags) {
+                        if !check_file_fault(&thr.proc_data, addr, flags) {
                             info!(
-                                "{:?}: segmentation fault at {:#x} {:?}",
+                                "{:?}: bus error at {:#x} {:?}",
                                 thr.proc_data.proc, addr, flags
                             );
-                            raise_signal_fatal(SignalInfo::new_kernel(Signo::SIGSEGV))
-                                .expect("Failed to send SIGSEGV");
+                            raise_signal_fatal(SignalInfo::new_kernel(Signo::SIGBUS))
+                                .expect("Failed to send SIGBUS");
+                        } else {
+                            let around = fault_readahead_at(&thr.proc_data, addr);
+                            if let Some(signo) = handle_user_fault(&thr.proc_data, addr, flags) {
+                                info!(
+                                    "{:?}: {signo:?} at {:#x} {:?}",
+                                    thr.proc_data.proc, addr, flags
+                                );
+                                raise_signal_fatal(SignalInfo::new_kernel(signo))
+                                    .expect("Failed to send fault signal");
+                            } else if let Some(range) = around {
+                                map_around(&thr.proc_data, range);
+                            }
                         }
                     }
                     ReturnReason::Interrupt => {}
@@ -86,8 +105,9 @@ pub fn new_user_task(
                 }
 
                 if !unblock_next_signal() {
-                    while check_signals(thr, &mut uctx, None) {}
+                    deliver_signals(thr, &mut uctx);
                 }
+                freeze_if_dumping(thr, &uctx);
 
                 set_timer_state(&curr, TimerState::User);
                 curr.clear_interrupt();
@@ -112,58 +132,134 @@ pub struct RobustListHead {
     pub list_op_pending: *mut RobustList,
 }
 
-fn handle_futex_death(entry: *mut RobustList, offset: i64) -> AxResult<()> {
-    let address = (entry as u64)
-        .checked_add_signed(offset)
-        .ok_or(AxError::InvalidInput)?;
-    let address: usize = address.try_into().map_err(|_| AxError::InvalidInput)?;
-    let key = FutexKey::new_current(address);
+const FUTEX_WAITERS: u32 = 0x8000_0000;
+const FUTEX_OWNER_DIED: u32 = 0x4000_0000;
+const FUTEX_TID_MASK: u32 = 0x3fff_ffff;
 
-    let curr = current();
-    let futex_table = curr.as_thread().proc_data.futex_table_for(&key);
+/// Marks the futex word at `entry + offset` as abandoned if `tid` owns it,
+/// waking one waiter so that it can recover the lock.
+///
+/// `pending` is set for the `list_op_pending` entry, which may also be a lock
+/// the dying thread was about to take with waiters already queued.
+fn handle_futex_death(
+    entry: usize,
+    offset: c_long,
+    tid: Pid,
+    pi: bool,
+    pending: bool,
+) -> AxResult<()> {
+    let address = entry
+        .checked_add_signed(offset as isize)
+        .ok_or(AxError::InvalidInput)?;
+    if address % align_of::<u32>() != 0 {
+        return Err(AxError::InvalidInput);
+    }
+    let uaddr = address as *mut u32;
+    let wake = || {
+        let key = FutexKey::new_current(address);
+        let table = current().as_thread().proc_data.futex_table_for(&key);
+        if let Some(futex) = table.get(&key) {
+            futex.wq.wake(1, u32::MAX);
+        }
+    };
 
-    let Some(futex) = futex_table.get(&key) else {
+    // Faults in the page, or bails out if the word is not mapped.
+    let mut uval = uaddr.vm_read()?;
+    if pending && !pi && uval == 0 {
+        wake();
         return Ok(());
-    };
-    futex.owner_dead.store(true, Ordering::SeqCst);
-    futex.wq.wake(1, u32::MAX);
+    }
+    loop {
+        if uval & FUTEX_TID_MASK != tid {
+            return Ok(());
+        }
+        let mval = (uval & FUTEX_WAITERS) | FUTEX_OWNER_DIED;
+        // Other threads of the process may be racing for the lock.
+        let result = access_user_memory(|| unsafe {
+            AtomicU32::from_ptr(uaddr).compare_exchange(
+                uval,
+                mval,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+        });
+        match result {
+            Ok(_) => break,
+            Err(current) => uval = current,
+        }
+    }
+
+    // PI futexes are handed over by the PI code, not by a plain wakeup.
+    if !pi && uval & FUTEX_WAITERS != 0 {
+        wake();
+    }
     Ok(())
 }
 
-pub fn exit_robust_list(head: *const RobustListHead) -> AxResult<()> {
-    // Reference: https://elixir.bootlin.com/linux/v6.13.6/source/kernel/futex/core.c#L777
+/// Reads a robust list pointer, whose lowest bit flags a PI futex.
+fn fetch_robust_entry(ptr: *const usize) -> AxResult<(usize, bool)> {
+    let entry = ptr.vm_read()?;
+    Ok((entry & !1, entry & 1 != 0))
+}
 
-    let mut limit = ROBUST_LIST_LIMIT;
+/// Walks the robust list of the exiting thread `tid`, releasing every futex it
+/// still holds.
+///
+/// The list lives in user memory and may be corrupted: the walk stops at the
+/// first fault and after [`ROBUST_LIST_LIMIT`] entries.
+pub fn exit_robust_list(head: *const RobustListHead, tid: Pid) -> AxResult<()> {
+    // Reference: https://elixir.bootlin.com/linux/v6.13.6/source/kernel/futex/core.c#L777
 
-    let end_ptr = unsafe { &raw const (*head).list };
-    let head = head.vm_read()?;
-    let mut entry = head.list.next;
-    let offset = head.futex_offset;
-    let pending = head.list_op_pending;
+    let end = head.addr();
+    let (mut entry, mut pi) = fetch_robust_entry(head.cast())?;
+    let offset = head.vm_read()?.futex_offset;
+    let (pending, pending_pi) =
+        fetch_robust_entry(unsafe { &raw const (*head).list_op_pending }.cast())?;
 
-    while !core::ptr::eq(entry, end_ptr) {
-        let next_entry = entry.vm_read()?.next;
+    let mut limit = ROBUST_LIST_LIMIT;
+    while entry != end {
+        // Fetch the next entry first, as the lock may be freed and reused as
+        // soon as it is released.
+        let next = fetch_robust_entry(entry as *const usize);
         if entry != pending {
-            handle_futex_death(entry, offset)?;
+            handle_futex_death(entry, offset, tid, pi, false)?;
         }
-        entry = next_entry;
+        (entry, pi) = next?;
 
         limit -= 1;
         if limit == 0 {
-            return Err(AxError::FilesystemLoop);
+            break;
         }
         axtask::yield_now();
     }
 
+    if pending != 0 {
+        handle_futex_death(pending, offset, tid, pending_pi, true)?;
+    }
     Ok(())
 }
 
+/// Processes and forgets the robust list of `thr`, on exit or `execve`.
+pub fn release_robust_list(thr: &Thread, tid: Pid) {
+    let head = thr.robust_list_head() as *const RobustListHead;
+    thr.set_robust_list_head(0);
+    if !head.is_null()
+        && let Err(err) = exit_robust_list(head, tid)
+    {
+        warn!("exit robust list failed: {err:?}");
+    }
+}
+
 pub fn do_exit(exit_code: i32, group_exit: bool) {
     let curr = current();
     let thr = curr.as_thread();
 
     info!("{} exit with code: {}", curr.id_name(), exit_code);
 
+    // Robust futexes are released before clear_child_tid wakes up joiners,
+    // while the address space is still around.
+    release_robust_list(thr, curr.id().as_u64() as Pid);
+
     let clear_child_tid = thr.clear_child_tid() as *mut u32;
     if clear_child_tid.vm_write(0).is_ok() {
         let key = FutexKey::new_current(clear_child_tid as usize);
@@ -174,16 +270,12 @@ pub fn do_exit(exit_code: i32, group_exit: bool) {
         }
         axtask::yield_now();
     }
-    let head = thr.robust_list_head() as *const RobustListHead;
-    if !head.is_null()
-        && let Err(err) = exit_robust_list(head)
-    {
-        warn!("exit robust list failed: {err:?}");
-    }
 
     let process = &thr.proc_data.proc;
     if process.exit_thread(curr.id().as_u64() as Pid, exit_code) {
         process.exit();
+        thr.proc_data.real_timer.set(Duration::ZERO, Duration::ZERO);
+        clear_posix_timers();
         if let Some(parent) = process.parent() {
             if let Some(signo) = thr.proc_data.exit_signal {
                 let _ = send_signal_to_process(parent.pid(), Some(SignalInfo::new_kernel(signo)));
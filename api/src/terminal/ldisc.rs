@@ -8,7 +8,7 @@ use core::{
 
 use axerrno::{AxError, AxResult};
 use axpoll::{IoEvents, PollSet, Pollable};
-use axtask::future::{block_on, poll_io};
+use axtask::future::block_on;
 use linux_raw_sys::general::{
     ECHOCTL, ECHOK, ICRNL, IGNCR, ISIG, VEOF, VERASE, VKILL, VMIN, VTIME,
 };
@@ -16,7 +16,7 @@ use ringbuf::{
     CachingCons, CachingProd,
     traits::{Consumer, Observer, Producer, Split},
 };
-use starry_core::task::send_signal_to_process_group;
+use starry_core::{task::send_signal_to_process_group, timer::wait_for_io};
 use starry_signal::SignalInfo;
 
 use crate::terminal::{Terminal, termios::Termios2};
@@ -57,7 +57,23 @@ pub trait TtyRead: Send + Sync + 'static {
     fn read(&mut self, buf: &mut [u8]) -> usize;
 }
 pub trait TtyWrite: Send + Sync + 'static {
+    /// Writes all of `buf`, used for echo and output processing.
     fn write(&self, buf: &[u8]);
+
+    /// Queues as much of `buf` as fits without blocking and returns the
+    /// number of bytes taken.
+    fn try_write(&self, buf: &[u8]) -> usize {
+        self.write(buf);
+        buf.len()
+    }
+
+    /// Returns whether [`TtyWrite::try_write`] would take at least one byte.
+    fn writable(&self) -> bool {
+        true
+    }
+
+    /// Registers a waker to be woken when there is room for output.
+    fn register_tx_waker(&self, _waker: &Waker) {}
 }
 
 struct InputReader<R, W> {
@@ -215,13 +231,14 @@ pub struct LineDiscipline<R, W> {
     processor: Processor<R, W>,
 }
 
-struct WaitPollable<'a>(Option<&'a Arc<PollSet>>);
+struct WaitPollable<'a>(Option<&'a Arc<PollSet>>, &'a Terminal);
 impl Pollable for WaitPollable<'_> {
     fn poll(&self) -> IoEvents {
         unreachable!()
     }
 
     fn register(&self, context: &mut Context<'_>, _events: IoEvents) {
+        self.1.register_hangup(context.waker());
         if let Some(set) = self.0 {
             set.register(context.waker());
         } else {
@@ -359,13 +376,16 @@ impl<R: TtyRead, W: TtyWrite> LineDiscipline<R, W> {
             Processor::External(set) => Some(set),
             _ => unreachable!(),
         };
-        let pollable = WaitPollable(set);
-        block_on(poll_io(&pollable, IoEvents::IN, false, || {
+        let pollable = WaitPollable(set, &self.terminal);
+        wait_for_io(&pollable, IoEvents::IN, false, None, true, || {
             total_read += self.buf_rx.pop_slice(&mut buf[total_read..]);
             self.poll_tx.wake();
-            (total_read >= vmin)
-                .then_some(total_read)
-                .ok_or(AxError::WouldBlock)
-        }))
+            // Once hung up, whatever is left is all there will be.
+            if total_read >= vmin || self.terminal.is_hung_up() {
+                Ok(total_read)
+            } else {
+                Err(AxError::WouldBlock)
+            }
+        })
     }
 }
@@ -1,8 +1,12 @@
 //! Terminal module.
 
 use alloc::sync::Arc;
-use core::sync::atomic::AtomicU32;
+use core::{
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering},
+    task::Waker,
+};
 
+use axpoll::PollSet;
 use bytemuck::AnyBitPattern;
 use kspin::SpinNoPreempt;
 
@@ -24,6 +28,15 @@ pub struct Terminal {
     pub window_size: SpinNoPreempt<WindowSize>,
     pub termios: SpinNoPreempt<Arc<termios::Termios2>>,
     pub pty_number: AtomicU32,
+    /// Set once the master side of a pseudo terminal goes away.
+    hung_up: AtomicBool,
+    poll_hangup: PollSet,
+    /// Whether the master side of a pseudo terminal is in packet mode, set
+    /// by `TIOCPKT`.
+    packet: AtomicBool,
+    /// `TIOCPKT_*` events of the slave side not yet read by the master.
+    packet_status: AtomicU8,
+    poll_packet: PollSet,
 }
 impl Default for Terminal {
     fn default() -> Self {
@@ -37,6 +50,11 @@ impl Default for Terminal {
             }),
             termios: SpinNoPreempt::new(Arc::new(termios::Termios2::default())),
             pty_number: AtomicU32::new(0),
+            hung_up: AtomicBool::new(false),
+            poll_hangup: PollSet::new(),
+            packet: AtomicBool::new(false),
+            packet_status: AtomicU8::new(0),
+            poll_packet: PollSet::new(),
         }
     }
 }
@@ -44,4 +62,56 @@ impl Terminal {
     pub fn load_termios(&self) -> Arc<termios::Termios2> {
         self.termios.lock().clone()
     }
+
+    /// Hangs the terminal up, waking everyone waiting on it.
+    pub fn hang_up(&self) {
+        self.hung_up.store(true, Ordering::Release);
+        self.poll_hangup.wake();
+    }
+
+    pub fn is_hung_up(&self) -> bool {
+        self.hung_up.load(Ordering::Acquire)
+    }
+
+    pub fn register_hangup(&self, waker: &Waker) {
+        self.poll_hangup.register(waker);
+    }
+
+    pub fn set_packet_mode(&self, enabled: bool) {
+        self.packet.store(enabled, Ordering::Release);
+        if !enabled {
+            self.packet_status.store(0, Ordering::Release);
+        }
+    }
+
+    pub fn packet_mode(&self) -> bool {
+        self.packet.load(Ordering::Acquire)
+    }
+
+    /// Posts a `TIOCPKT_*` event to the master, if it is in packet mode,
+    /// replacing the pending events in `clear`.
+    pub fn post_packet_status(&self, clear: u8, status: u8) {
+        if !self.packet_mode() {
+            return;
+        }
+        let _ = self
+            .packet_status
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |old| {
+                Some(old & !clear | status)
+            });
+        self.poll_packet.wake();
+    }
+
+    pub fn has_packet_status(&self) -> bool {
+        self.packet_status.load(Ordering::Acquire) != 0
+    }
+
+    /// Takes the pending `TIOCPKT_*` events, 0 if there are none.
+    pub fn take_packet_status(&self) -> u8 {
+        self.packet_status.swap(0, Ordering::AcqRel)
+    }
+
+    pub fn register_packet(&self, waker: &Waker) {
+        self.poll_packet.register(waker);
+    }
 }
@@ -4,7 +4,7 @@ use bytemuck::AnyBitPattern;
 use linux_raw_sys::general::{
     B38400, CREAD, CS8, ECHO, ECHOCTL, ECHOE, ECHOK, ECHOKE, ICANON, ICRNL, IEXTEN, ISIG, IXON,
     ONLCR, OPOST, VDISCARD, VEOF, VEOL, VEOL2, VERASE, VINTR, VKILL, VLNEXT, VQUIT, VREPRINT,
-    VWERASE, speed_t, tcflag_t,
+    VSTART, VSTOP, VWERASE, speed_t, tcflag_t,
 };
 use starry_signal::Signo;
 
@@ -86,6 +86,13 @@ impl Termios {
         self.has_lflag(IEXTEN)
     }
 
+    /// Whether output is stopped and started with the usual `^S` and `^Q`.
+    pub fn stops_with_ctrl_s(&self) -> bool {
+        self.has_iflag(IXON)
+            && self.special_char(VSTOP) == 0o23
+            && self.special_char(VSTART) == 0o21
+    }
+
     pub fn is_eol(&self, ch: u8) -> bool {
         if ch == b'\n' || ch == self.special_char(VEOL) {
             return true;
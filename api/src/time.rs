@@ -1,11 +1,13 @@
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use axerrno::{AxError, AxResult};
-use axhal::time::TimeValue;
+use axhal::time::{TimeValue, monotonic_time};
 use linux_raw_sys::general::{
     __kernel_old_timespec, __kernel_old_timeval, __kernel_sock_timeval, __kernel_timespec,
-    timespec, timeval,
+    CLOCK_BOOTTIME, CLOCK_BOOTTIME_ALARM, CLOCK_MONOTONIC, CLOCK_REALTIME, CLOCK_REALTIME_ALARM,
+    itimerspec, itimerval, timespec, timeval,
 };
+use starry_core::time::{boottime, realtime};
 
 /// A helper trait for converting from and to `TimeValue`.
 pub trait TimeValueLike {
@@ -131,6 +133,61 @@ impl TimeValueLike for __kernel_sock_timeval {
     }
 }
 
+/// A helper trait for converting interval timer values from and to
+/// `(interval, value)` pairs of `TimeValue`.
+pub trait ITimerValueLike {
+    /// Converts from `(interval, value)`.
+    fn from_time_values(interval: TimeValue, value: TimeValue) -> Self;
+
+    /// Tries to convert into `(interval, value)`, failing with `EINVAL` if
+    /// either is out of range.
+    fn try_into_time_values(self) -> AxResult<(TimeValue, TimeValue)>;
+}
+
+impl ITimerValueLike for itimerspec {
+    fn from_time_values(interval: TimeValue, value: TimeValue) -> Self {
+        Self {
+            it_interval: timespec::from_time_value(interval),
+            it_value: timespec::from_time_value(value),
+        }
+    }
+
+    fn try_into_time_values(self) -> AxResult<(TimeValue, TimeValue)> {
+        Ok((
+            self.it_interval.try_into_time_value()?,
+            self.it_value.try_into_time_value()?,
+        ))
+    }
+}
+
+impl ITimerValueLike for itimerval {
+    fn from_time_values(interval: TimeValue, value: TimeValue) -> Self {
+        Self {
+            it_interval: timeval::from_time_value(interval),
+            it_value: timeval::from_time_value(value),
+        }
+    }
+
+    fn try_into_time_values(self) -> AxResult<(TimeValue, TimeValue)> {
+        Ok((
+            self.it_interval.try_into_time_value()?,
+            self.it_value.try_into_time_value()?,
+        ))
+    }
+}
+
+/// Returns the time on `clockid` if timerfds and POSIX timers may be armed
+/// on it, or `None`. The `_ALARM` clocks read as theirs, as nothing
+/// suspends for them to wake from.
+pub fn timer_clock_time(clockid: u32) -> Option<TimeValue> {
+    match clockid {
+        CLOCK_REALTIME | CLOCK_REALTIME_ALARM => Some(realtime()),
+        CLOCK_MONOTONIC => Some(monotonic_time()),
+        CLOCK_BOOTTIME | CLOCK_BOOTTIME_ALARM => Some(boottime()),
+        _ => None,
+    }
+}
+
 static IRQ_CNT: AtomicUsize = AtomicUsize::new(0);
 
 pub(crate) fn inc_irq_cnt() {
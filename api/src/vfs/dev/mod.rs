@@ -227,6 +227,15 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
             tty::N_TTY.clone(),
         ),
     );
+    root.add(
+        "ttyS0",
+        Device::new(
+            fs.clone(),
+            NodeType::CharacterDevice,
+            DeviceId::new(4, 64),
+            tty::N_TTY.clone(),
+        ),
+    );
 
     root.add(
         "ptmx",
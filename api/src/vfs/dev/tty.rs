@@ -1,15 +1,12 @@
 use alloc::sync::{Arc, Weak};
-use core::{any::Any, ops::Deref, sync::atomic::Ordering, task::Context};
+use core::{any::Any, mem, ops::Deref, sync::atomic::Ordering, task::Context};
 
 use axerrno::{AxError, AxResult};
 use axfs_ng_vfs::NodeFlags;
 use axpoll::{IoEvents, Pollable};
 use axsync::Mutex;
-use axtask::{
-    current,
-    future::{block_on, poll_io},
-};
-use starry_core::{task::AsThread, vfs::SimpleFs};
+use axtask::current;
+use starry_core::{task::AsThread, timer::wait_for_io, vfs::SimpleFs};
 use starry_process::Process;
 use starry_vm::{VmMutPtr, VmPtr};
 
@@ -32,6 +29,12 @@ pub use ptm::Ptmx;
 pub use pts::PtsDir;
 pub use pty::PtyDriver;
 
+/// Events a packet mode master reads ahead of the data, see `TIOCPKT`.
+const TIOCPKT_DATA: u8 = 0;
+const TIOCPKT_FLUSHREAD: u8 = 1;
+const TIOCPKT_NOSTOP: u8 = 16;
+const TIOCPKT_DOSTOP: u8 = 32;
+
 pub fn create_pty_master(fs: Arc<SimpleFs>) -> AxResult<Arc<PtyDriver>> {
     let (master, slave) = pty::create_pty_pair();
     pts::add_slave(fs, slave)?;
@@ -80,14 +83,65 @@ impl<R: TtyRead, W: TtyWrite> Tty<R, W> {
     pub fn pty_number(&self) -> u32 {
         self.terminal.pty_number.load(Ordering::Acquire)
     }
+
+    /// Replaces the termios, discarding the pending input first if `flush`.
+    /// Either is posted to a packet mode master when done on the slave.
+    fn set_termios(&self, termios: Termios2, flush: bool) {
+        let stops = termios.stops_with_ctrl_s();
+        let old = mem::replace(&mut *self.terminal.termios.lock(), Arc::new(termios));
+        if flush {
+            self.ldisc.lock().drain_input();
+        }
+        if self.is_ptm {
+            return;
+        }
+        if flush {
+            self.terminal.post_packet_status(0, TIOCPKT_FLUSHREAD);
+        }
+        if old.stops_with_ctrl_s() != stops {
+            let status = if stops {
+                TIOCPKT_DOSTOP
+            } else {
+                TIOCPKT_NOSTOP
+            };
+            self.terminal
+                .post_packet_status(TIOCPKT_DOSTOP | TIOCPKT_NOSTOP, status);
+        }
+    }
+
+    /// Reads the master in packet mode: the pending events alone in a byte,
+    /// or else the data behind a `TIOCPKT_DATA` byte.
+    fn read_packet(&self, buf: &mut [u8]) -> AxResult<usize> {
+        wait_for_io(self, IoEvents::IN, false, None, true, || {
+            match self.terminal.take_packet_status() {
+                0 => {
+                    let mut ldisc = self.ldisc.lock();
+                    if !ldisc.poll_read() {
+                        return Err(AxError::WouldBlock);
+                    }
+                    buf[0] = TIOCPKT_DATA;
+                    Ok(1 + ldisc.read(&mut buf[1..])?)
+                }
+                status => {
+                    buf[0] = status;
+                    Ok(1)
+                }
+            }
+        })
+    }
 }
 
 impl<R: TtyRead, W: TtyWrite> DeviceOps for Tty<R, W> {
     fn read_at(&self, buf: &mut [u8], _offset: u64) -> AxResult<usize> {
-        block_on(poll_io(
+        if self.is_ptm && self.terminal.packet_mode() && !buf.is_empty() {
+            return self.read_packet(buf);
+        }
+        wait_for_io(
             &self.terminal.job_control,
             IoEvents::IN,
             false,
+            None,
+            true,
             || {
                 if self.is_ptm || self.terminal.job_control.current_in_foreground() {
                     self.ldisc.lock().read(buf)
@@ -95,12 +149,17 @@ impl<R: TtyRead, W: TtyWrite> DeviceOps for Tty<R, W> {
                     Err(AxError::WouldBlock)
                 }
             },
-        ))
+        )
     }
 
     fn write_at(&self, buf: &[u8], _offset: u64) -> AxResult<usize> {
-        self.writer.write(buf);
-        Ok(buf.len())
+        if !self.is_ptm && self.terminal.is_hung_up() {
+            return Err(AxError::Io);
+        }
+        match self.writer.try_write(buf) {
+            0 if !buf.is_empty() => Err(AxError::WouldBlock),
+            written => Ok(written),
+        }
     }
 
     fn ioctl(&self, cmd: u32, arg: usize) -> AxResult<usize> {
@@ -114,18 +173,12 @@ impl<R: TtyRead, W: TtyWrite> DeviceOps for Tty<R, W> {
             }
             TCSETS | TCSETSF | TCSETSW => {
                 // TODO: drain output?
-                *self.terminal.termios.lock() =
-                    Arc::new(Termios2::new((arg as *const Termios).vm_read()?));
-                if cmd == TCSETSF {
-                    self.ldisc.lock().drain_input();
-                }
+                let termios = Termios2::new((arg as *const Termios).vm_read()?);
+                self.set_termios(termios, cmd == TCSETSF);
             }
             TCSETS2 | TCSETSF2 | TCSETSW2 => {
                 // TODO: drain output?
-                *self.terminal.termios.lock() = Arc::new((arg as *const Termios2).vm_read()?);
-                if cmd == TCSETSF2 {
-                    self.ldisc.lock().drain_input();
-                }
+                self.set_termios((arg as *const Termios2).vm_read()?, cmd == TCSETSF2);
             }
             TIOCGPGRP => {
                 let foreground = self
@@ -148,6 +201,13 @@ impl<R: TtyRead, W: TtyWrite> DeviceOps for Tty<R, W> {
                 *self.terminal.window_size.lock() = (arg as *const WindowSize).vm_read()?;
             }
             TIOCSPTLCK => {}
+            TIOCPKT if self.is_ptm => {
+                self.terminal
+                    .set_packet_mode((arg as *const i32).vm_read()? != 0);
+            }
+            TIOCGPKT if self.is_ptm => {
+                (arg as *mut i32).vm_write(self.terminal.packet_mode() as i32)?;
+            }
             TIOCGPTN => {
                 (arg as *mut u32).vm_write(self.pty_number())?;
             }
@@ -195,20 +255,44 @@ impl<R: TtyRead, W: TtyWrite> DeviceOps for Tty<R, W> {
 
 impl<R: TtyRead, W: TtyWrite> Pollable for Tty<R, W> {
     fn poll(&self) -> IoEvents {
-        let mut events = IoEvents::OUT | self.terminal.job_control.poll();
+        let mut events = self.terminal.job_control.poll();
+        events.set(IoEvents::OUT, self.writer.writable());
         if self.is_ptm || events.contains(IoEvents::IN) {
             events.set(IoEvents::IN, self.ldisc.lock().poll_read());
         }
+        if self.is_ptm && self.terminal.has_packet_status() {
+            events |= IoEvents::IN | IoEvents::PRI;
+        }
+        if !self.is_ptm && self.terminal.is_hung_up() {
+            // End of file for readers, an error for writers.
+            events |= IoEvents::IN | IoEvents::OUT | IoEvents::HUP;
+        }
         events
     }
 
     fn register(&self, context: &mut Context<'_>, events: IoEvents) {
         if !self.is_ptm {
             self.terminal.job_control.register(context, events);
+            self.terminal.register_hangup(context.waker());
         }
         if events.contains(IoEvents::IN) {
             self.ldisc.lock().register_rx_waker(context.waker());
         }
+        if self.is_ptm && events.intersects(IoEvents::IN | IoEvents::PRI) {
+            self.terminal.register_packet(context.waker());
+        }
+        if events.contains(IoEvents::OUT) {
+            self.writer.register_tx_waker(context.waker());
+        }
+    }
+}
+
+impl<R, W> Drop for Tty<R, W> {
+    fn drop(&mut self) {
+        // The last reference to a master goes with its last open file.
+        if self.is_ptm {
+            self.terminal.hang_up();
+        }
     }
 }
 
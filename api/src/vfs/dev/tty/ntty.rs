@@ -1,13 +1,66 @@
 use alloc::{boxed::Box, sync::Arc};
+use core::{
+    future::poll_fn,
+    task::{Poll, Waker},
+};
 
-use axtask::future::register_irq_waker;
+use axpoll::PollSet;
+use axsync::Mutex;
+use axtask::future::{block_on, register_irq_waker};
 use lazy_static::lazy_static;
+use ringbuf::{
+    HeapRb,
+    traits::{Consumer, Observer, Producer},
+};
 
 use super::Tty;
 use crate::terminal::ldisc::{ProcessMode, TtyConfig, TtyRead, TtyWrite};
 
+/// Size of the console output ring.
+const OUTPUT_BUF_SIZE: usize = 16384;
+/// Bytes handed to the UART at a time.
+const DRAIN_CHUNK: usize = 256;
+
 pub type NTtyDriver = Tty<Console, Console>;
 
+/// Output queued for the UART. Writers only copy into the ring; the
+/// `console-tx` task drains it, so a slow UART blocks writers (or fails them
+/// with `EAGAIN`) instead of stalling them inside the driver.
+struct ConsoleOutput {
+    ring: Mutex<HeapRb<u8>>,
+    /// Woken when there is output to drain.
+    poll_drain: PollSet,
+    /// Woken when the ring has room again.
+    poll_tx: PollSet,
+}
+
+lazy_static! {
+    static ref OUTPUT: ConsoleOutput = ConsoleOutput {
+        ring: Mutex::new(HeapRb::new(OUTPUT_BUF_SIZE)),
+        poll_drain: PollSet::new(),
+        poll_tx: PollSet::new(),
+    };
+}
+
+fn drain_output() {
+    let mut chunk = [0; DRAIN_CHUNK];
+    block_on(poll_fn(|cx| {
+        loop {
+            let mut ring = OUTPUT.ring.lock();
+            let len = ring.pop_slice(&mut chunk);
+            if len == 0 {
+                OUTPUT.poll_drain.register(cx.waker());
+                return Poll::Pending;
+            }
+            // Written with the ring locked so that a synchronous flush in
+            // `Console::write` cannot overtake it.
+            axhal::console::write_bytes(&chunk[..len]);
+            drop(ring);
+            OUTPUT.poll_tx.wake();
+        }
+    }))
+}
+
 #[derive(Clone, Copy)]
 pub struct Console;
 impl TtyRead for Console {
@@ -17,7 +70,38 @@ impl TtyRead for Console {
 }
 impl TtyWrite for Console {
     fn write(&self, buf: &[u8]) {
-        axhal::console::write_bytes(buf);
+        let mut ring = OUTPUT.ring.lock();
+        let queued = ring.push_slice(buf);
+        if queued < buf.len() {
+            // Echo must not be dropped; flush in order and write the rest
+            // directly.
+            let (left, right) = ring.as_slices();
+            axhal::console::write_bytes(left);
+            axhal::console::write_bytes(right);
+            ring.clear();
+            axhal::console::write_bytes(&buf[queued..]);
+            drop(ring);
+            OUTPUT.poll_tx.wake();
+        } else {
+            drop(ring);
+            OUTPUT.poll_drain.wake();
+        }
+    }
+
+    fn try_write(&self, buf: &[u8]) -> usize {
+        let queued = OUTPUT.ring.lock().push_slice(buf);
+        if queued > 0 {
+            OUTPUT.poll_drain.wake();
+        }
+        queued
+    }
+
+    fn writable(&self) -> bool {
+        !OUTPUT.ring.lock().is_full()
+    }
+
+    fn register_tx_waker(&self, waker: &Waker) {
+        OUTPUT.poll_tx.register(waker);
     }
 }
 
@@ -27,6 +111,7 @@ lazy_static! {
 }
 
 fn new_n_tty() -> Arc<NTtyDriver> {
+    axtask::spawn_with_name(drain_output, "console-tx".into());
     Tty::new(
         Arc::default(),
         TtyConfig {
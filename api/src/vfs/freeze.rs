@@ -0,0 +1,178 @@
+//! Filesystem freezing for `FIFREEZE` and `FITHAW`.
+//!
+//! Every operation that modifies a filesystem runs inside
+//! [`FreezeLock::write`] of its device. Freezing waits for the operations in
+//! progress to drain, writes back the dirty pages of the filesystem and
+//! flushes it, and then holds new ones off until the last thaw; reads are
+//! never held up. The writeback flusher passes over frozen filesystems. Freezes nest, and writing
+//! a nonzero value to `/proc/sys/fs/emergency_thaw` thaws everything.
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    task::Poll,
+};
+
+use axerrno::{AxError, AxResult};
+use axfs::FsContext;
+use axfs_ng_vfs::{Location, path::Path};
+use axpoll::PollSet;
+use axsync::Mutex;
+use axtask::future::{block_on, interruptible};
+use linkme::distributed_slice;
+use starry_core::sysctl::{SYSCTLS, Sysctl, SysctlKind};
+
+/// The freeze state of one device.
+pub struct FreezeLock {
+    /// Number of freezes not yet thawed.
+    frozen: AtomicU32,
+    /// Modifying operations in progress.
+    writers: AtomicUsize,
+    /// Woken when the last writer leaves.
+    drained: PollSet,
+    /// Woken on the last thaw.
+    thawed: PollSet,
+}
+
+impl FreezeLock {
+    fn new() -> Self {
+        Self {
+            frozen: AtomicU32::new(0),
+            writers: AtomicUsize::new(0),
+            drained: PollSet::new(),
+            thawed: PollSet::new(),
+        }
+    }
+
+    fn leave(&self) {
+        if self.writers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.drained.wake();
+        }
+    }
+
+    /// Runs `f`, which modifies the filesystem, while it is not frozen.
+    ///
+    /// Waiting for a thaw is interrupted by signals.
+    pub fn write<R>(&self, f: impl FnOnce() -> AxResult<R>) -> AxResult<R> {
+        block_on(interruptible(poll_fn(|cx| {
+            // Sequentially consistent against `freeze`, so that either it
+            // sees this writer or this writer sees the freeze.
+            if self.frozen.load(Ordering::SeqCst) == 0 {
+                self.writers.fetch_add(1, Ordering::SeqCst);
+                // A freeze that started meanwhile waits for us to leave.
+                if self.frozen.load(Ordering::SeqCst) == 0 {
+                    return Poll::Ready(());
+                }
+                self.leave();
+            }
+            self.thawed.register(cx.waker());
+            if self.frozen.load(Ordering::Acquire) == 0 {
+                // Thawed while registering; try again right away.
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        })))?;
+        let result = f();
+        self.leave();
+        result
+    }
+
+    /// Runs `f`, which modifies the filesystem, if it is not frozen, or
+    /// returns `None` right away if it is.
+    pub fn try_write<R>(&self, f: impl FnOnce() -> R) -> Option<R> {
+        if self.frozen.load(Ordering::SeqCst) != 0 {
+            return None;
+        }
+        self.writers.fetch_add(1, Ordering::SeqCst);
+        if self.frozen.load(Ordering::SeqCst) != 0 {
+            self.leave();
+            return None;
+        }
+        let result = f();
+        self.leave();
+        Some(result)
+    }
+
+    /// Freezes the filesystem once the writes in progress have finished,
+    /// then calls `flush`. A nested freeze returns right away.
+    pub fn freeze(&self, flush: impl FnOnce() -> AxResult<()>) -> AxResult<()> {
+        if self.frozen.fetch_add(1, Ordering::SeqCst) > 0 {
+            return Ok(());
+        }
+        block_on(poll_fn(|cx| {
+            if self.writers.load(Ordering::SeqCst) == 0 {
+                return Poll::Ready(());
+            }
+            self.drained.register(cx.waker());
+            if self.writers.load(Ordering::SeqCst) == 0 {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }));
+        flush().inspect_err(|_| self.thaw_all())
+    }
+
+    /// Undoes one freeze. Fails with `EINVAL` if the filesystem is not
+    /// frozen.
+    pub fn thaw(&self) -> AxResult<()> {
+        self.frozen
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |frozen| {
+                frozen.checked_sub(1)
+            })
+            .map_err(|_| AxError::InvalidInput)?;
+        if self.frozen.load(Ordering::Acquire) == 0 {
+            self.thawed.wake();
+        }
+        Ok(())
+    }
+
+    fn thaw_all(&self) {
+        self.frozen.store(0, Ordering::Release);
+        self.thawed.wake();
+    }
+}
+
+/// Locks by device. They are few and must outlive any freeze, so they are
+/// never dropped.
+static FREEZE_LOCKS: Mutex<BTreeMap<u64, Arc<FreezeLock>>> = Mutex::new(BTreeMap::new());
+
+/// Returns the freeze lock of the filesystem `loc` is on.
+pub fn freeze_lock(loc: &Location) -> Arc<FreezeLock> {
+    FREEZE_LOCKS
+        .lock()
+        .entry(loc.mountpoint().device() as u64)
+        .or_insert_with(|| Arc::new(FreezeLock::new()))
+        .clone()
+}
+
+/// Runs `f`, which modifies the directory holding `path`, while its
+/// filesystem is not frozen.
+pub fn write_in<R>(fs: &FsContext, path: &str, f: impl FnOnce() -> AxResult<R>) -> AxResult<R> {
+    let (dir, _) = fs.resolve_parent(Path::new(path))?;
+    freeze_lock(&dir).write(f)
+}
+
+fn emergency_thaw() {
+    let locks = FREEZE_LOCKS.lock().values().cloned().collect::<Vec<_>>();
+    for lock in locks {
+        lock.thaw_all();
+    }
+}
+
+#[distributed_slice(SYSCTLS)]
+static EMERGENCY_THAW_SYSCTL: Sysctl = Sysctl {
+    path: "fs/emergency_thaw",
+    mode: 0o200,
+    kind: SysctlKind::Uint {
+        get: || 0,
+        set: |value| {
+            if value != 0 {
+                emergency_thaw();
+            }
+        },
+        min: 0,
+        max: 1,
+    },
+};
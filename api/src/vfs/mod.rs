@@ -1,9 +1,16 @@
 //! Virtual filesystems
 
 pub mod dev;
+pub mod freeze;
+pub mod mounts;
+pub mod page_cache;
 mod proc;
+pub mod ra_worker;
 pub mod readahead;
+pub mod size_lock;
 mod tmp;
+pub mod tunables;
+pub mod writeback;
 
 use axerrno::LinuxResult;
 use axfs::{FS_CONTEXT, FsContext};
@@ -20,7 +27,9 @@ fn mount_at(fs: &FsContext, path: &str, mount_fs: Filesystem) -> LinuxResult<()>
     if fs.resolve(path).is_err() {
         fs.create_dir(path, DIR_PERMISSION)?;
     }
-    fs.resolve(path)?.mount(&mount_fs)?;
+    let target = fs.resolve(path)?;
+    target.mount(&mount_fs)?;
+    mounts::record_mount(&target, &fs.resolve(path)?, mount_fs.name(), "");
     info!("Mounted {} at {}", mount_fs.name(), path);
     Ok(())
 }
@@ -45,6 +54,8 @@ pub fn mount_all() -> LinuxResult<()> {
     fs.symlink("whatever", &path)?;
     drop(fs);
 
+    writeback::spawn_flusher();
+
     #[cfg(feature = "dev-log")]
     dev::bind_dev_log().expect("Failed to bind /dev/log");
 
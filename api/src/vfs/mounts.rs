@@ -0,0 +1,273 @@
+//! The mount table.
+//!
+//! Every mount gets a 64-bit id the first time it is seen. Ids come from a
+//! counter and are never reused. An entry stays in the table until the last
+//! location on its mount is gone, so the id reported for an open file keeps
+//! referring to the same mount even after a lazy unmount; unmounted entries
+//! are just no longer visible to [`lookup`], [`children`] and [`mounts`].
+//!
+//! The working and root directories of a process keep their mounts busy
+//! through [`FS_MOUNTS`], which follows what `FS_CONTEXT` points at.
+//!
+//! A mount may also set the readahead size of the files opened on it, as a
+//! backing device setting would, for a store faster or slower than most.
+
+use alloc::{
+    string::{String, ToString},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+
+use axerrno::{AxError, AxResult};
+use axfs::FsContext;
+use axfs_ng_vfs::{Location, Mountpoint};
+use axsync::Mutex;
+use starry_core::vfs::MountUse;
+
+use super::readahead::{RA_HARD_MAX_PAGES, RA_MIN_PAGES};
+
+/// Unique mount ids start above the range of the old 32-bit ids, as on
+/// Linux.
+const FIRST_MOUNT_ID: u64 = 1 << 31;
+
+/// A mount in the table.
+#[derive(Clone)]
+pub struct MountEntry {
+    /// The unique mount id.
+    pub id: u64,
+    /// Id of the mount this one is mounted on. The root mount is its own
+    /// parent.
+    pub parent: u64,
+    /// The mount source, e.g. the device path.
+    pub source: String,
+    /// Name of the filesystem type.
+    pub fs_type: String,
+    /// Absolute path of the mount point at the time of mounting.
+    pub path: String,
+    /// Filesystem specific options passed to `mount`.
+    pub options: String,
+    /// Readahead limit in pages of the files opened on the mount, or `None`
+    /// to follow `vm/max_readahead_kb`.
+    pub ra_pages: Option<u32>,
+    mountpoint: Weak<Mountpoint>,
+    attached: bool,
+}
+
+impl MountEntry {
+    /// The old 32-bit style id, as shown in `/proc/<pid>/mountinfo`.
+    pub fn old_id(&self) -> u32 {
+        old_mount_id(self.id)
+    }
+
+    /// Returns the mount, unless it is already gone.
+    pub fn mountpoint(&self) -> Option<Arc<Mountpoint>> {
+        self.mountpoint.upgrade()
+    }
+}
+
+struct MountTable {
+    entries: Vec<MountEntry>,
+    next_id: u64,
+}
+
+impl MountTable {
+    /// Drops the entries of mounts that are gone. Holding the `Weak` keeps
+    /// the allocation, so a new mount never shares a pointer with a live
+    /// entry.
+    fn prune(&mut self) {
+        self.entries
+            .retain(|entry| entry.mountpoint.strong_count() > 0);
+    }
+
+    fn find(&self, mountpoint: &Arc<Mountpoint>) -> Option<&MountEntry> {
+        self.entries
+            .iter()
+            .find(|entry| Weak::as_ptr(&entry.mountpoint) == Arc::as_ptr(mountpoint))
+    }
+
+    fn insert(
+        &mut self,
+        mountpoint: &Arc<Mountpoint>,
+        parent: Option<u64>,
+        source: String,
+        path: String,
+        options: String,
+    ) -> u64 {
+        self.prune();
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push(MountEntry {
+            id,
+            parent: parent.unwrap_or(id),
+            source,
+            fs_type: mountpoint.root_location().filesystem().name().to_string(),
+            path,
+            options,
+            ra_pages: None,
+            mountpoint: Arc::downgrade(mountpoint),
+            attached: true,
+        });
+        id
+    }
+
+    fn id_of(&mut self, mountpoint: &Arc<Mountpoint>) -> u64 {
+        if let Some(entry) = self.find(mountpoint) {
+            return entry.id;
+        }
+        // Mounts made before the table saw them, i.e. the root filesystem.
+        let root = mountpoint.root_location();
+        let path = root
+            .absolute_path()
+            .map_or_else(|_| "/".into(), |path| path.to_string());
+        let fs_type = root.filesystem().name().to_string();
+        self.insert(mountpoint, None, fs_type, path, String::new())
+    }
+}
+
+static MOUNTS: Mutex<MountTable> = Mutex::new(MountTable {
+    entries: Vec::new(),
+    next_id: FIRST_MOUNT_ID,
+});
+
+/// Uses of the mounts of a working and a root directory.
+#[derive(Clone, Default)]
+pub struct FsMounts {
+    _cwd: Option<MountUse>,
+    _root: Option<MountUse>,
+}
+
+scope_local::scope_local! {
+    /// Uses of the mounts of the current working and root directories,
+    /// shared whenever `FS_CONTEXT` is.
+    pub static FS_MOUNTS: Arc<Mutex<FsMounts>> = Arc::default();
+}
+
+/// Takes uses of the mounts `fs` now has its working and root directories
+/// on, dropping those of its old ones.
+pub fn use_fs_mounts(fs: &FsContext) {
+    *FS_MOUNTS.lock() = FsMounts {
+        _cwd: Some(MountUse::new(fs.current_dir())),
+        _root: Some(MountUse::new(fs.root_dir())),
+    };
+}
+
+/// Converts a unique mount id to the old 32-bit style id.
+pub fn old_mount_id(id: u64) -> u32 {
+    (id - FIRST_MOUNT_ID + 1) as u32
+}
+
+/// Returns the id of `mountpoint`.
+pub fn mount_id(mountpoint: &Arc<Mountpoint>) -> u64 {
+    MOUNTS.lock().id_of(mountpoint)
+}
+
+/// Records a mount that was just made on `target`, whose root is now
+/// `mounted`.
+pub fn record_mount(target: &Location, mounted: &Location, source: &str, options: &str) -> u64 {
+    let mut table = MOUNTS.lock();
+    let parent = table.id_of(target.mountpoint());
+    let path = mounted
+        .absolute_path()
+        .map_or_else(|_| String::new(), |path| path.to_string());
+    table.insert(
+        mounted.mountpoint(),
+        Some(parent),
+        source.into(),
+        path,
+        options.into(),
+    )
+}
+
+/// Returns the readahead limit in pages for files opened on `mountpoint`,
+/// or `None` to follow `vm/max_readahead_kb`.
+pub fn mount_ra_pages(mountpoint: &Arc<Mountpoint>) -> Option<u32> {
+    MOUNTS
+        .lock()
+        .find(mountpoint)
+        .and_then(|entry| entry.ra_pages)
+}
+
+/// Checks a readahead limit for a mount, failing with `EINVAL` outside
+/// `RA_MIN_PAGES..=RA_HARD_MAX_PAGES`, but for 0 disabling readahead.
+pub fn check_ra_pages(ra_pages: Option<u32>) -> AxResult<()> {
+    match ra_pages {
+        Some(pages) if pages != 0 && !(RA_MIN_PAGES..=RA_HARD_MAX_PAGES).contains(&pages) => {
+            Err(AxError::InvalidInput)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Sets the readahead limit in pages for files opened on `mountpoint` from
+/// now on, for the mount options or the driver of its device. Files already
+/// open keep theirs. Fails as [`check_ra_pages`] does.
+pub fn set_mount_ra_pages(mountpoint: &Arc<Mountpoint>, ra_pages: Option<u32>) -> AxResult<()> {
+    check_ra_pages(ra_pages)?;
+    let mut table = MOUNTS.lock();
+    let id = table.id_of(mountpoint);
+    if let Some(entry) = table.entries.iter_mut().find(|entry| entry.id == id) {
+        entry.ra_pages = ra_pages;
+    }
+    Ok(())
+}
+
+/// Takes `mountpoint` and every mount below it out of the table.
+pub fn record_unmount(mountpoint: &Arc<Mountpoint>) {
+    let mut table = MOUNTS.lock();
+    let Some(id) = table.find(mountpoint).map(|entry| entry.id) else {
+        return;
+    };
+    let mut detached = Vec::from([id]);
+    while let Some(id) = detached.pop() {
+        for entry in &mut table.entries {
+            if entry.attached && (entry.id == id || entry.parent == id) {
+                entry.attached = false;
+                if entry.id != id {
+                    detached.push(entry.id);
+                }
+            }
+        }
+    }
+    table.prune();
+}
+
+/// Returns the mount with `id`, or `NotFound` if it has been unmounted.
+pub fn lookup(id: u64) -> AxResult<MountEntry> {
+    let table = MOUNTS.lock();
+    table
+        .entries
+        .iter()
+        .find(|entry| entry.attached && entry.id == id)
+        .cloned()
+        .ok_or(AxError::NotFound)
+}
+
+/// Returns the ids of the mounts directly below the mount with `id`, in
+/// mount order.
+pub fn children(id: u64) -> AxResult<Vec<u64>> {
+    let table = MOUNTS.lock();
+    if !table
+        .entries
+        .iter()
+        .any(|entry| entry.attached && entry.id == id)
+    {
+        return Err(AxError::NotFound);
+    }
+    Ok(table
+        .entries
+        .iter()
+        .filter(|entry| entry.attached && entry.parent == id && entry.id != id)
+        .map(|entry| entry.id)
+        .collect())
+}
+
+/// Returns all mounted filesystems, in mount order.
+pub fn mounts() -> Vec<MountEntry> {
+    MOUNTS
+        .lock()
+        .entries
+        .iter()
+        .filter(|entry| entry.attached)
+        .cloned()
+        .collect()
+}
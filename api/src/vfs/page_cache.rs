@@ -0,0 +1,68 @@
+//! Reclaim of the page cache under memory pressure.
+//!
+//! The page cache belongs to the filesystem layer, which alone knows which
+//! pages are cached and how recently each was used, so the choice of pages
+//! to drop is its own: it registers a [`Shrinker`], and the kernel calls
+//! [`shrink_page_cache`] when memory runs short, before any process is
+//! killed for it. Pages read ahead and never read should go first. Readahead
+//! needs no word of what was dropped: a read finding a page of its window
+//! gone counts toward thrashing by itself.
+//!
+//! The page cache of axfs does not register one yet, as it has no way to
+//! evict its pages on request, so its pages are neither reclaimed nor
+//! counted. What this tree keeps of files itself, the headers in the ELF
+//! cache, is: [`init`] registers a shrinker over that cache, which drops its
+//! least recently used entries.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kspin::SpinNoPreempt;
+
+/// What the page cache provides for reclaim
+#[derive(Clone, Copy)]
+pub struct Shrinker {
+    /// Returns the pages cached
+    pub cached_pages: fn() -> usize,
+    /// Drops up to the given number of clean pages, coldest first, and
+    /// returns how many were dropped. Dirty pages are skipped. It runs when
+    /// an allocation just failed, so it must not allocate.
+    pub shrink: fn(usize) -> usize,
+}
+
+static SHRINKER: SpinNoPreempt<Option<Shrinker>> = SpinNoPreempt::new(None);
+
+/// Pages dropped by reclaim since boot
+static RECLAIMED: AtomicU64 = AtomicU64::new(0);
+
+/// Set the shrinker of the page cache
+pub fn set_page_cache_shrinker(shrinker: Shrinker) {
+    *SHRINKER.lock() = Some(shrinker);
+}
+
+/// Registers the shrinker of the ELF cache.
+pub fn init() {
+    set_page_cache_shrinker(Shrinker {
+        cached_pages: starry_core::mm::elf_cache_pages,
+        shrink: starry_core::mm::shrink_elf_cache,
+    });
+}
+
+/// Returns the pages in the page cache, or 0 without a shrinker.
+pub fn cached_pages() -> usize {
+    let shrinker = *SHRINKER.lock();
+    shrinker.map_or(0, |shrinker| (shrinker.cached_pages)())
+}
+
+/// Returns the pages dropped from the page cache by reclaim since boot.
+pub fn reclaimed_pages() -> u64 {
+    RECLAIMED.load(Ordering::Relaxed)
+}
+
+/// Drops up to `nr_pages` of the coldest clean pages of the page cache.
+/// Returns how many were dropped.
+pub fn shrink_page_cache(nr_pages: usize) -> usize {
+    let shrinker = *SHRINKER.lock();
+    let dropped = shrinker.map_or(0, |shrinker| (shrinker.shrink)(nr_pages));
+    RECLAIMED.fetch_add(dropped as u64, Ordering::Relaxed);
+    dropped
+}
@@ -1,6 +1,7 @@
 use alloc::{
     borrow::Cow,
     boxed::Box,
+    collections::btree_set::BTreeSet,
     format,
     string::{String, ToString},
     sync::{Arc, Weak},
@@ -9,21 +10,33 @@ use alloc::{
 };
 use core::{ffi::CStr, iter};
 
-use axfs_ng_vfs::{Filesystem, NodeType, VfsError, VfsResult};
+use axfs_ng_vfs::{Filesystem, NodePermission, NodeType, VfsError, VfsResult};
 use axtask::{AxTaskRef, WeakAxTaskRef, current};
-use indoc::indoc;
+use indoc::{formatdoc, indoc};
+use linkme::distributed_slice;
+use linux_raw_sys::general::{O_CLOEXEC, O_NONBLOCK};
 use starry_core::{
-    task::{AsThread, TaskStat, get_task, tasks},
+    mm, swap,
+    sysctl::{SYSCTLS, Sysctl, SysctlKind},
+    task::{AsThread, TaskStat, get_task, tids_from},
     vfs::{
-        DirMaker, DirMapping, NodeOpsMux, RwFile, SimpleDir, SimpleDirOps, SimpleFile,
+        CHAIN_SPLIT, DirMaker, DirMapping, NodeOpsMux, RwFile, SimpleDir, SimpleDirOps, SimpleFile,
         SimpleFileOperation, SimpleFs,
     },
 };
-use starry_process::Process;
+use starry_process::{Pid, Process};
 
-use crate::file::FD_TABLE;
+use super::{mounts, writeback::writeback_stats};
+use crate::{
+    file::{FD_TABLE, File, FileDescriptor, Pipe, epoll::Epoll},
+    oom::{OOM_SCORE_ADJ_MAX, OOM_SCORE_ADJ_MIN, commit_limit, oom_score},
+};
 
-const DUMMY_MEMINFO: &str = indoc! {"
+fn meminfo() -> String {
+    let (commit_limit, committed) = (commit_limit() / 1024, mm::committed() / 1024);
+    let stats = writeback_stats();
+    let (dirty, writeback) = (stats.dirty * 4, stats.writeback * 4);
+    formatdoc! {"
     MemTotal:       32536204 kB
     MemFree:         5506524 kB
     MemAvailable:   18768344 kB
@@ -38,12 +51,12 @@ const DUMMY_MEMINFO: &str = indoc! {"
     Inactive(file):  6540624 kB
     Unevictable:      930088 kB
     Mlocked:            1136 kB
-    SwapTotal:       4194300 kB
-    SwapFree:        4194300 kB
+    SwapTotal:             0 kB
+    SwapFree:              0 kB
     Zswap:                 0 kB
     Zswapped:              0 kB
-    Dirty:             47952 kB
-    Writeback:             0 kB
+    Dirty:          {dirty:>8} kB
+    Writeback:      {writeback:>8} kB
     AnonPages:      10992512 kB
     Mapped:          1361184 kB
     Shmem:           1068056 kB
@@ -57,8 +70,8 @@ const DUMMY_MEMINFO: &str = indoc! {"
     NFS_Unstable:          0 kB
     Bounce:                0 kB
     WritebackTmp:          0 kB
-    CommitLimit:    20462400 kB
-    Committed_AS:   45105316 kB
+    CommitLimit:    {commit_limit:>8} kB
+    Committed_AS:   {committed:>8} kB
     VmallocTotal:   34359738367 kB
     VmallocUsed:      205924 kB
     VmallocChunk:          0 kB
@@ -81,12 +94,28 @@ const DUMMY_MEMINFO: &str = indoc! {"
     DirectMap4k:     1739900 kB
     DirectMap2M:    31492096 kB
     DirectMap1G:     1048576 kB
-"};
+"}
+}
 
 pub fn new_procfs() -> Filesystem {
     SimpleFs::new_with("proc".into(), 0x9fa0, builder)
 }
 
+/// Lists children named by number, such as pids or fds, from `cursor` on.
+/// Each child is positioned at its number, so a listing resumes correctly
+/// however the set changes between calls.
+fn numbered_from<'a>(
+    ids: impl IntoIterator<Item = u64>,
+    cursor: u64,
+) -> Box<dyn Iterator<Item = (u64, Cow<'a, str>)> + 'a> {
+    let mut ids = ids
+        .into_iter()
+        .filter(|id| *id >= cursor)
+        .collect::<Vec<_>>();
+    ids.sort_unstable();
+    Box::new(ids.into_iter().map(|id| (id, id.to_string().into())))
+}
+
 struct ProcessTaskDir {
     fs: Arc<SimpleFs>,
     process: Weak<Process>,
@@ -105,6 +134,16 @@ impl SimpleDirOps for ProcessTaskDir {
         )
     }
 
+    fn children_from<'a>(
+        &'a self,
+        cursor: u64,
+    ) -> Box<dyn Iterator<Item = (u64, Cow<'a, str>)> + 'a> {
+        let Some(process) = self.process.upgrade() else {
+            return Box::new(iter::empty());
+        };
+        numbered_from(process.threads().into_iter().map(u64::from), cursor)
+    }
+
     fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
         let process = self.process.upgrade().ok_or(VfsError::NotFound)?;
         let tid = name.parse::<u32>().map_err(|_| VfsError::NotFound)?;
@@ -127,26 +166,60 @@ impl SimpleDirOps for ProcessTaskDir {
     }
 }
 
+/// Open file descriptions, epoll watches and pipe buffer bytes held
+/// through the descriptors of the process of `task`
+fn fd_usage(task: &AxTaskRef) -> (usize, usize, usize) {
+    let scope = task.as_thread().proc_data.scope.read();
+    let table = FD_TABLE.scope(&scope).read();
+    let mut files = BTreeSet::new();
+    let mut pipes = BTreeSet::new();
+    let mut watches = 0;
+    let mut pipe_bytes = 0;
+    for fd in table.ids() {
+        let file = table.get(fd).unwrap().inner.clone();
+        // Descriptors sharing a description count once.
+        if !files.insert(Arc::as_ptr(&file).cast::<()>() as usize) {
+            continue;
+        }
+        let any = file.into_any();
+        if let Some(epoll) = any.downcast_ref::<Epoll>() {
+            watches += epoll.watches();
+        } else if let Some(pipe) = any.downcast_ref::<Pipe>()
+            && pipes.insert(pipe.buffer_id())
+        {
+            pipe_bytes += pipe.capacity();
+        }
+    }
+    (files.len(), watches, pipe_bytes)
+}
+
 #[rustfmt::skip]
 fn task_status(task: &AxTaskRef) -> String {
+    let (files, watches, pipe_bytes) = fd_usage(task);
     format!(
         "Tgid:\t{}\n\
         Pid:\t{}\n\
         Uid:\t0 0 0 0\n\
         Gid:\t0 0 0 0\n\
+        Files:\t{files}\n\
+        EpollWatches:\t{watches}\n\
+        PipeBufs:\t{} kB\n\
         Cpus_allowed:\t1\n\
         Cpus_allowed_list:\t0\n\
         Mems_allowed:\t1\n\
-        Mems_allowed_list:\t0",
+        Mems_allowed_list:\t0\n\
+        VmSwap:\t0 kB",
         task.as_thread().proc_data.proc.pid(),
-        task.id().as_u64()
+        task.id().as_u64(),
+        pipe_bytes / 1024
     )
 }
 
-/// The /proc/[pid]/fd directory
+/// The /proc/[pid]/fd directory, or /proc/[pid]/fdinfo with `info` set
 struct ThreadFdDir {
     fs: Arc<SimpleFs>,
     task: WeakAxTaskRef,
+    info: bool,
 }
 
 impl SimpleDirOps for ThreadFdDir {
@@ -163,18 +236,36 @@ impl SimpleDirOps for ThreadFdDir {
         Box::new(ids.into_iter())
     }
 
+    fn children_from<'a>(
+        &'a self,
+        cursor: u64,
+    ) -> Box<dyn Iterator<Item = (u64, Cow<'a, str>)> + 'a> {
+        let Some(task) = self.task.upgrade() else {
+            return Box::new(iter::empty());
+        };
+        let ids = FD_TABLE
+            .scope(&task.as_thread().proc_data.scope.read())
+            .read()
+            .ids()
+            .map(|id| id as u64)
+            .collect::<Vec<_>>();
+        numbered_from(ids, cursor)
+    }
+
     fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
         let fs = self.fs.clone();
         let task = self.task.upgrade().ok_or(VfsError::NotFound)?;
         let fd = name.parse::<u32>().map_err(|_| VfsError::NotFound)?;
-        let path = FD_TABLE
+        let desc = FD_TABLE
             .scope(&task.as_thread().proc_data.scope.read())
             .read()
             .get(fd as _)
             .ok_or(VfsError::NotFound)?
-            .inner
-            .path()
-            .into_owned();
+            .clone();
+        if self.info {
+            return Ok(SimpleFile::new_regular(fs, move || fd_info(&desc)).into());
+        }
+        let path = desc.inner.path().into_owned();
         Ok(SimpleFile::new(fs, NodeType::Symlink, move || Ok(path.clone())).into())
     }
 
@@ -183,6 +274,66 @@ impl SimpleDirOps for ThreadFdDir {
     }
 }
 
+fn fd_info(desc: &FileDescriptor) -> VfsResult<String> {
+    let stat = desc.inner.stat()?;
+    let any = desc.inner.clone().into_any();
+    let pos = any
+        .downcast_ref::<File>()
+        .map_or(0, |file| file.inner().position());
+    let mut flags = 0;
+    if desc.cloexec {
+        flags |= O_CLOEXEC;
+    }
+    if desc.inner.nonblocking() {
+        flags |= O_NONBLOCK;
+    }
+    Ok(format!(
+        "pos:\t{pos}\nflags:\t0{flags:o}\nmnt_id:\t{}\nino:\t{}\n{}",
+        stat.mnt_id,
+        stat.ino,
+        desc.inner.fdinfo()
+    ))
+}
+
+fn proc_mounts() -> String {
+    let mut out = String::new();
+    for entry in mounts::mounts() {
+        let mut options = "rw".to_string();
+        if !entry.options.is_empty() {
+            options += ",";
+            options += &entry.options;
+        }
+        out += &format!(
+            "{} {} {} {options} 0 0\n",
+            entry.source, entry.path, entry.fs_type
+        );
+    }
+    out
+}
+
+fn proc_mountinfo() -> String {
+    let mut out = String::new();
+    for entry in mounts::mounts() {
+        let parent = mounts::lookup(entry.parent).map_or(0, |parent| parent.old_id());
+        let dev = entry.mountpoint().map_or(0, |mp| mp.device() as u64);
+        let mut options = "rw".to_string();
+        if !entry.options.is_empty() {
+            options += ",";
+            options += &entry.options;
+        }
+        out += &format!(
+            "{} {parent} {}:{} / {} rw - {} {} {options}\n",
+            entry.old_id(),
+            dev >> 32,
+            dev as u32,
+            entry.path,
+            entry.fs_type,
+            entry.source
+        );
+    }
+    out
+}
+
 /// The /proc/[pid] directory
 struct ThreadDir {
     fs: Arc<SimpleFs>,
@@ -195,14 +346,18 @@ impl SimpleDirOps for ThreadDir {
             [
                 "stat",
                 "status",
+                "oom_score",
                 "oom_score_adj",
+                "timerslack_ns",
                 "task",
                 "maps",
                 "mounts",
+                "mountinfo",
                 "cmdline",
                 "comm",
                 "exe",
                 "fd",
+                "fdinfo",
             ]
             .into_iter()
             .map(Cow::Borrowed),
@@ -218,6 +373,10 @@ impl SimpleDirOps for ThreadDir {
             })
             .into(),
             "status" => SimpleFile::new_regular(fs, move || Ok(task_status(&task))).into(),
+            "oom_score" => SimpleFile::new_regular(fs, move || {
+                Ok(format!("{}\n", oom_score(&task.as_thread().proc_data)).into_bytes())
+            })
+            .into(),
             "oom_score_adj" => SimpleFile::new_regular(
                 fs,
                 RwFile::new(move |req| match req {
@@ -228,7 +387,8 @@ impl SimpleDirOps for ThreadDir {
                         if !data.is_empty() {
                             let value = str::from_utf8(data)
                                 .ok()
-                                .and_then(|it| it.parse::<i32>().ok())
+                                .and_then(|it| it.trim().parse::<i32>().ok())
+                                .filter(|it| (OOM_SCORE_ADJ_MIN..=OOM_SCORE_ADJ_MAX).contains(it))
                                 .ok_or(VfsError::InvalidInput)?;
                             task.as_thread().set_oom_score_adj(value);
                         }
@@ -237,6 +397,25 @@ impl SimpleDirOps for ThreadDir {
                 }),
             )
             .into(),
+            "timerslack_ns" => SimpleFile::new_regular(
+                fs,
+                RwFile::new(move |req| match req {
+                    SimpleFileOperation::Read => Ok(Some(
+                        format!("{}\n", task.as_thread().timer_slack_ns()).into_bytes(),
+                    )),
+                    SimpleFileOperation::Write(data) => {
+                        if !data.is_empty() {
+                            let value = str::from_utf8(data)
+                                .ok()
+                                .and_then(|it| it.trim().parse::<u64>().ok())
+                                .ok_or(VfsError::InvalidInput)?;
+                            task.as_thread().set_timer_slack_ns(value);
+                        }
+                        Ok(None)
+                    }
+                }),
+            )
+            .into(),
             "task" => SimpleDir::new_maker(
                 fs.clone(),
                 Arc::new(ProcessTaskDir {
@@ -254,10 +433,8 @@ impl SimpleDirOps for ThreadDir {
                 "})
             })
             .into(),
-            "mounts" => SimpleFile::new_regular(fs, move || {
-                Ok("proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n")
-            })
-            .into(),
+            "mounts" => SimpleFile::new_regular(fs, || Ok(proc_mounts())).into(),
+            "mountinfo" => SimpleFile::new_regular(fs, || Ok(proc_mountinfo())).into(),
             "cmdline" => SimpleFile::new_regular(fs, move || {
                 let cmdline = task.as_thread().proc_data.cmdline.read();
                 let mut buf = Vec::new();
@@ -305,6 +482,16 @@ impl SimpleDirOps for ThreadDir {
                 Arc::new(ThreadFdDir {
                     fs,
                     task: Arc::downgrade(&task),
+                    info: false,
+                }),
+            )
+            .into(),
+            "fdinfo" => SimpleDir::new_maker(
+                fs.clone(),
+                Arc::new(ThreadFdDir {
+                    fs,
+                    task: Arc::downgrade(&task),
+                    info: true,
                 }),
             )
             .into(),
@@ -320,14 +507,24 @@ impl SimpleDirOps for ThreadDir {
 /// Handles /proc/[pid] & /proc/self
 struct ProcFsHandler(Arc<SimpleFs>);
 
+/// Position of `self`, after every pid and before the entries chained after
+/// the handler.
+const SELF_POS: u64 = CHAIN_SPLIT - 1;
+
 impl SimpleDirOps for ProcFsHandler {
     fn child_names<'a>(&'a self) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
-        Box::new(
-            tasks()
-                .into_iter()
-                .map(|task| task.id().as_u64().to_string().into())
-                .chain([Cow::Borrowed("self")]),
-        )
+        Box::new(self.children_from(0).map(|(_, name)| name))
+    }
+
+    fn children_from<'a>(
+        &'a self,
+        cursor: u64,
+    ) -> Box<dyn Iterator<Item = (u64, Cow<'a, str>)> + 'a> {
+        // Walks the live task table from the last pid returned, holding no
+        // references to tasks between calls. `self` comes last.
+        let tids = Pid::try_from(cursor).map_or_else(|_| Vec::new(), tids_from);
+        let this = (cursor < SELF_POS).then_some((SELF_POS, Cow::Borrowed("self")));
+        Box::new(numbered_from(tids.into_iter().map(u64::from), cursor).chain(this))
     }
 
     fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
@@ -352,17 +549,78 @@ impl SimpleDirOps for ProcFsHandler {
     }
 }
 
+#[distributed_slice(SYSCTLS)]
+static PID_MAX_SYSCTL: Sysctl = Sysctl {
+    path: "kernel/pid_max",
+    mode: 0o444,
+    kind: SysctlKind::Uint {
+        get: || 32768,
+        set: |_| {},
+        min: 0,
+        max: 0,
+    },
+};
+
+/// Builds the `/proc/sys` directory at `prefix` from the registered
+/// tunables.
+fn sysctl_dir(fs: Arc<SimpleFs>, prefix: &str) -> DirMaker {
+    let mut dir = DirMapping::new();
+    let mut subdirs = BTreeSet::new();
+    for entry in SYSCTLS.iter() {
+        let Some(rest) = entry.path.strip_prefix(prefix) else {
+            continue;
+        };
+        if let Some((subdir, _)) = rest.split_once('/') {
+            subdirs.insert(subdir);
+            continue;
+        }
+        dir.add(
+            rest,
+            SimpleFile::new_with_mode(
+                fs.clone(),
+                NodeType::RegularFile,
+                NodePermission::from_bits_truncate(entry.mode),
+                RwFile::new(move |req| match req {
+                    SimpleFileOperation::Read => Ok(Some(entry.read().into_bytes())),
+                    SimpleFileOperation::Write(data) => entry.write(data).map(|_| None),
+                }),
+            ),
+        );
+    }
+    for subdir in subdirs {
+        dir.add(
+            subdir,
+            sysctl_dir(fs.clone(), &format!("{prefix}{subdir}/")),
+        );
+    }
+    SimpleDir::new_maker(fs, Arc::new(dir))
+}
+
 fn builder(fs: Arc<SimpleFs>) -> DirMaker {
     let mut root = DirMapping::new();
     root.add(
         "mounts",
-        SimpleFile::new_regular(fs.clone(), || {
-            Ok("proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n")
-        }),
+        SimpleFile::new_regular(fs.clone(), || Ok(proc_mounts())),
     );
     root.add(
         "meminfo",
-        SimpleFile::new_regular(fs.clone(), || Ok(DUMMY_MEMINFO)),
+        SimpleFile::new_regular(fs.clone(), || Ok(meminfo())),
+    );
+    root.add(
+        "swaps",
+        SimpleFile::new_regular(fs.clone(), || {
+            let mut swaps = "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority\n".to_string();
+            for area in swap::swap_areas() {
+                swaps += &format!(
+                    "{:<40}file\t\t{}\t\t{}\t\t{}\n",
+                    area.path,
+                    area.pages() * 4,
+                    area.used() * 4,
+                    area.priority
+                );
+            }
+            Ok(swaps)
+        }),
     );
     root.add(
         "meminfo2",
@@ -389,22 +647,7 @@ fn builder(fs: Arc<SimpleFs>) -> DirMaker {
         SimpleFile::new_regular(fs.clone(), || Ok(format!("0: {}", crate::time::irq_cnt()))),
     );
 
-    root.add("sys", {
-        let mut sys = DirMapping::new();
-
-        sys.add("kernel", {
-            let mut kernel = DirMapping::new();
-
-            kernel.add(
-                "pid_max",
-                SimpleFile::new_regular(fs.clone(), || Ok("32768\n")),
-            );
-
-            SimpleDir::new_maker(fs.clone(), Arc::new(kernel))
-        });
-
-        SimpleDir::new_maker(fs.clone(), Arc::new(sys))
-    });
+    root.add("sys", sysctl_dir(fs.clone(), ""));
 
     let proc_dir = ProcFsHandler(fs.clone());
     SimpleDir::new_maker(fs, Arc::new(proc_dir.chain(root)))
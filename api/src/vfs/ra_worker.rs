@@ -0,0 +1,124 @@
+//! Background workers for asynchronous readahead
+//!
+//! Asynchronous readaheads are queued to `RA_WORKERS` worker tasks instead
+//! of each getting a task of its own, so that heavy load cannot spawn
+//! prefetch tasks without bound. A readahead overlapping one still queued
+//! for the same file is merged into it.
+//!
+//! The workers take the queued readahead of the highest I/O priority first,
+//! and the oldest among equals, so that readaheads of the idle class only
+//! run when no other is queued. When the queue is full the oldest of the
+//! lowest priority is dropped, as having waited that long its reader has
+//! likely caught up with it already.
+
+use alloc::collections::VecDeque;
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::Poll,
+};
+
+use axpoll::PollSet;
+use axtask::future::block_on;
+use kspin::SpinNoPreempt;
+use lazy_static::lazy_static;
+
+use super::readahead::AsyncReadahead;
+
+/// Number of worker tasks
+const RA_WORKERS: usize = 2;
+
+/// Maximum number of queued readaheads
+const RA_QUEUE_LEN: usize = 64;
+
+/// Snapshot of the counters of the readahead workers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// Readaheads submitted
+    pub submitted: u64,
+    /// Readaheads merged into one already queued
+    pub coalesced: u64,
+    /// Queued readaheads dropped to make room
+    pub dropped: u64,
+}
+
+struct Workers {
+    queue: SpinNoPreempt<VecDeque<AsyncReadahead>>,
+    /// Woken when a readahead is queued
+    poll_queue: PollSet,
+}
+
+lazy_static! {
+    static ref WORKERS: Workers = Workers {
+        queue: SpinNoPreempt::new(VecDeque::new()),
+        poll_queue: PollSet::new(),
+    };
+}
+
+static SPAWNED: AtomicBool = AtomicBool::new(false);
+
+static SUBMITTED: AtomicU64 = AtomicU64::new(0);
+static COALESCED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Counters of the readahead workers since boot
+pub fn worker_stats() -> WorkerStats {
+    WorkerStats {
+        submitted: SUBMITTED.load(Ordering::Relaxed),
+        coalesced: COALESCED.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+    }
+}
+
+fn worker() {
+    loop {
+        let readahead = block_on(poll_fn(|cx| {
+            let mut queue = WORKERS.queue.lock();
+            // The first of the lowest rank, as `min_by_key` keeps the first
+            let next = (0..queue.len()).min_by_key(|&i| queue[i].ioprio().rank());
+            match next.and_then(|i| queue.remove(i)) {
+                Some(readahead) => Poll::Ready(readahead),
+                None => {
+                    WORKERS.poll_queue.register(cx.waker());
+                    Poll::Pending
+                }
+            }
+        }));
+        readahead.run();
+    }
+}
+
+/// Queue `readahead` to be run by a worker
+///
+/// The workers are spawned on the first call.
+pub fn submit_async_readahead(readahead: AsyncReadahead) {
+    SUBMITTED.fetch_add(1, Ordering::Relaxed);
+    let mut queue = WORKERS.queue.lock();
+    if queue.iter_mut().any(|queued| queued.coalesce(&readahead)) {
+        COALESCED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    queue.push_back(readahead);
+    // Dropped once the queue is unlocked, as the last reference to a file
+    // may go with it. The first of the highest rank, as `max_by_key` would
+    // keep the last.
+    let dropped = if queue.len() > RA_QUEUE_LEN {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        let worst = queue.iter().map(|queued| queued.ioprio().rank()).max();
+        let oldest = queue
+            .iter()
+            .position(|queued| Some(queued.ioprio().rank()) == worst);
+        oldest.and_then(|i| queue.remove(i))
+    } else {
+        None
+    };
+    drop(queue);
+    drop(dropped);
+
+    if !SPAWNED.swap(true, Ordering::AcqRel) {
+        for _ in 0..RA_WORKERS {
+            axtask::spawn_with_name(worker, "readahead".into());
+        }
+    }
+    WORKERS.poll_queue.wake();
+}
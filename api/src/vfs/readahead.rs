@@ -4,25 +4,100 @@
 //! The algorithm detects sequential access patterns and prefetches pages ahead of the
 //! current read position to improve I/O performance.
 
-use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
+use axerrno::{AxError, AxResult};
 use axfs::FileBackend;
+use kspin::SpinNoPreempt;
+use memory_addr::PAGE_SIZE_4K;
+use starry_core::ioprio::{IoPrio, IoPrioClass, current_ioprio};
 
-/// Page size in bytes (4KB)
-pub const PAGE_SIZE: u64 = 4096;
+use super::{
+    ra_worker::submit_async_readahead,
+    tunables::{ra_init_pages, ra_max_pages, ra_seq_gap, ra_thrash_percent},
+};
 
-/// Initial readahead size in pages (128KB = 32 pages)
-const RA_INIT_PAGES: u32 = 32;
+/// Size in bytes of the pages the page cache indexes by, the base page size
+/// of the platform
+///
+/// Every limit below is set in bytes and turned into pages of this size.
+pub const PAGE_SIZE: u64 = PAGE_SIZE_4K as u64;
+
+/// Number of whole pages in `bytes`, at least one
+const fn pages(bytes: u64) -> u32 {
+    let pages = bytes / PAGE_SIZE;
+    if pages == 0 { 1 } else { pages as u32 }
+}
+
+/// Ceiling of any readahead limit in pages (64MB)
+pub const RA_HARD_MAX_PAGES: u32 = pages(64 * 1024 * 1024);
+
+/// Maximum readahead size in pages for tasks in the idle I/O class (32KB)
+pub const RA_IDLE_MAX_PAGES: u32 = pages(32 * 1024);
+
+/// Minimum readahead limit in pages, short of disabling readahead (8KB)
+pub const RA_MIN_PAGES: u32 = pages(8 * 1024);
+
+/// Per-file limit meaning "follow `vm/max_readahead_kb`"
+const RA_LIMIT_GLOBAL: u32 = u32::MAX;
+
+/// Marker page meaning "no async readahead pending"
+const NO_MARKER: u64 = u64::MAX;
+
+/// Reads starting below this offset (16KB) are taken as the start of a
+/// sequential scan
+const RA_HEAD: u64 = 16 * 1024;
+
+/// Pages an asynchronous readahead prefetches between checks for
+/// cancellation (64KB)
+const RA_CHUNK_PAGES: u32 = pages(64 * 1024);
 
-/// Maximum readahead size in pages (1MB = 256 pages)
-const RA_MAX_PAGES: u32 = 256;
+/// Number of issued windows remembered per stream
+const RA_HISTORY: usize = 4;
 
-/// Minimum readahead size in pages (reserved for future use)
-#[allow(dead_code)]
-const RA_MIN_PAGES: u32 = 2;
+/// Clean windows needed after thrashing before the window grows again
+const RA_THRASH_HOLD: u32 = 2;
+
+/// Sequential reads after which a stream is established, and outlasts
+/// reads elsewhere in the file
+const RA_SETTLED_READS: u32 = 4;
+
+/// Reads continuing no stream that an established stream outlasts before
+/// it may be replaced
+const RA_MISS_TOLERANCE: u32 = 2 * RA_STREAMS as u32;
+
+/// Reads within which resuming a replaced stream restores its window
+const RA_RESUME_READS: u64 = 4;
+
+/// How short the system is of free memory, as reported by the function set
+/// with [`set_memory_pressure_fn`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PressureLevel {
+    /// Plenty of free memory
+    None,
+    /// Windows are capped at the initial window size
+    Medium,
+    /// Only initial sync windows are issued, no async ones
+    High,
+    /// Readahead is disabled
+    Critical,
+}
+
+static MEMORY_PRESSURE_FN: SpinNoPreempt<Option<fn() -> PressureLevel>> = SpinNoPreempt::new(None);
+
+/// Set the function readahead asks for the memory pressure before each
+/// decision
+///
+/// Without one, readahead assumes there is no pressure.
+pub fn set_memory_pressure_fn(f: fn() -> PressureLevel) {
+    *MEMORY_PRESSURE_FN.lock() = Some(f);
+}
 
-/// Maximum allowed gap between reads to still be considered sequential (in pages)
-const RA_SEQ_GAP_PAGES: u64 = 2;
+fn memory_pressure() -> PressureLevel {
+    let f = *MEMORY_PRESSURE_FN.lock();
+    f.map_or(PressureLevel::None, |f| f())
+}
 
 /// Readahead access pattern
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -34,6 +109,8 @@ pub enum RaPattern {
     Sequential = 1,
     /// Random access detected
     Random = 2,
+    /// Sequential access from the end toward the beginning
+    Backward = 3,
 }
 
 impl From<u32> for RaPattern {
@@ -41,122 +118,802 @@ impl From<u32> for RaPattern {
         match value {
             1 => Self::Sequential,
             2 => Self::Random,
+            3 => Self::Backward,
             _ => Self::Initial,
         }
     }
 }
 
-/// Readahead state for a file (similar to Linux's `file_ra_state`)
+/// How a read moves on from the previous one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Forward,
+    Backward,
+    Random,
+}
+
+/// Access pattern hinted by `posix_fadvise`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum RaMode {
+    /// No hint; the pattern is detected automatically
+    Normal = 0,
+    /// Sequential access, with twice the maximum window
+    Sequential = 1,
+    /// Random access, no readahead at all
+    Random = 2,
+}
+
+impl From<u32> for RaMode {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => Self::Sequential,
+            2 => Self::Random,
+            _ => Self::Normal,
+        }
+    }
+}
+
+/// Snapshot of readahead counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RaStats {
+    /// Synchronous readaheads decided
+    pub sync_ra_issued: u64,
+    /// Asynchronous readaheads decided
+    pub async_ra_issued: u64,
+    /// Pages read by synchronous or asynchronous readahead
+    pub pages_prefetched: u64,
+    /// Reads starting on a cached page
+    pub cache_hits: u64,
+    /// Reads starting on a page not cached
+    pub cache_misses: u64,
+    /// Detected patterns given up on a random access or a turnaround
+    pub pattern_resets: u64,
+    /// Windows shrunk because pages read ahead were evicted before use
+    pub thrash_shrinks: u64,
+    /// Asynchronous readaheads cut short by a cancellation
+    pub async_ra_cancelled: u64,
+}
+
+struct RaCounters {
+    sync_ra_issued: AtomicU64,
+    async_ra_issued: AtomicU64,
+    pages_prefetched: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    pattern_resets: AtomicU64,
+    thrash_shrinks: AtomicU64,
+    async_ra_cancelled: AtomicU64,
+}
+
+impl RaCounters {
+    const fn new() -> Self {
+        Self {
+            sync_ra_issued: AtomicU64::new(0),
+            async_ra_issued: AtomicU64::new(0),
+            pages_prefetched: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            pattern_resets: AtomicU64::new(0),
+            thrash_shrinks: AtomicU64::new(0),
+            async_ra_cancelled: AtomicU64::new(0),
+        }
+    }
+
+    fn snapshot(&self) -> RaStats {
+        RaStats {
+            sync_ra_issued: self.sync_ra_issued.load(Ordering::Relaxed),
+            async_ra_issued: self.async_ra_issued.load(Ordering::Relaxed),
+            pages_prefetched: self.pages_prefetched.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            pattern_resets: self.pattern_resets.load(Ordering::Relaxed),
+            thrash_shrinks: self.thrash_shrinks.load(Ordering::Relaxed),
+            async_ra_cancelled: self.async_ra_cancelled.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counters summed over all files
+static RA_TOTALS: RaCounters = RaCounters::new();
+
+/// Readahead counters of all files since boot
+pub fn ra_stats() -> RaStats {
+    RA_TOTALS.snapshot()
+}
+
+/// What a file shares with its asynchronous readaheads in flight
+struct RaShared {
+    /// Bumped to cancel every asynchronous readahead queued before
+    epoch: AtomicU64,
+    stats: RaCounters,
+}
+
+impl RaShared {
+    /// Add `n` to a counter of the file and to the total
+    fn count(&self, counter: fn(&RaCounters) -> &AtomicU64, n: u64) {
+        counter(&self.stats).fetch_add(n, Ordering::Relaxed);
+        counter(&RA_TOTALS).fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+/// A window issued by readahead and what became of its pages
+#[derive(Clone, Copy)]
+struct IssuedWindow {
+    start_page: u64,
+    num_pages: u32,
+    /// Reads starting in the window
+    reads: u32,
+    /// Those of them finding their page no longer cached
+    lost: u32,
+}
+
+impl IssuedWindow {
+    const EMPTY: Self = Self {
+        start_page: 0,
+        num_pages: 0,
+        reads: 0,
+        lost: 0,
+    };
+
+    fn contains(&self, page: u64) -> bool {
+        (self.start_page..self.start_page + self.num_pages as u64).contains(&page)
+    }
+}
+
+/// Ring of the windows a stream issued last
+struct WindowHistory {
+    windows: [IssuedWindow; RA_HISTORY],
+    /// Index of the newest window
+    newest: usize,
+}
+
+impl WindowHistory {
+    const fn new() -> Self {
+        Self {
+            windows: [IssuedWindow::EMPTY; RA_HISTORY],
+            newest: 0,
+        }
+    }
+
+    fn push(&mut self, start_page: u64, num_pages: u32) {
+        self.newest = (self.newest + 1) % RA_HISTORY;
+        self.windows[self.newest] = IssuedWindow {
+            start_page,
+            num_pages,
+            reads: 0,
+            lost: 0,
+        };
+    }
+
+    /// Cut the newest window down to its first `num_pages` pages, the ones
+    /// that made it into the page cache
+    fn shrink_newest(&mut self, num_pages: u32) {
+        let newest = &mut self.windows[self.newest];
+        newest.num_pages = newest.num_pages.min(num_pages);
+    }
+
+    /// Account a read starting on `page`, which was `cached` or not
+    fn record_read(&mut self, page: u64, cached: bool) {
+        if let Some(window) = self.windows.iter_mut().find(|w| w.contains(page)) {
+            window.reads += 1;
+            if !cached {
+                window.lost += 1;
+            }
+        }
+    }
+
+    /// Whether the window before the newest lost too many of its pages
+    /// before they were read
+    fn previous_thrashed(&self) -> bool {
+        let previous = &self.windows[(self.newest + RA_HISTORY - 1) % RA_HISTORY];
+        previous.reads > 0 && previous.lost * 100 >= previous.reads * ra_thrash_percent()
+    }
+
+    fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+/// How far a read is from continuing a stream that last read
+/// `prev_start..prev_end`, in either direction, or `None` if it leaves a
+/// gap larger than `max_gap`
+fn continuation(
+    prev_start: u64,
+    prev_end: u64,
+    read_start: u64,
+    read_end: u64,
+    max_gap: u64,
+) -> Option<u64> {
+    let forward = read_start.abs_diff(prev_end);
+    let backward = read_end.abs_diff(prev_start);
+    if forward <= max_gap {
+        Some(forward)
+    } else if read_start < prev_start && backward <= max_gap {
+        Some(backward)
+    } else {
+        None
+    }
+}
+
+/// A stream replaced by another, remembered for a while in case it is
+/// resumed
+#[derive(Clone, Copy)]
+struct Demoted {
+    prev_start: u64,
+    prev_end: u64,
+    backward: bool,
+    seq_count: u32,
+    ra_size: u32,
+    /// When it was replaced, on the clock of its file
+    at: u64,
+}
+
+/// One sequential stream through a file
 ///
-/// This structure tracks the readahead window and access patterns for a file.
-/// It uses atomic operations to allow concurrent access without locks.
-pub struct ReadaheadState {
+/// Each stream keeps its own window, so readers scanning different parts
+/// of the same file do not break each other's pattern.
+struct RaStream {
     ra_start: AtomicU64,
     ra_size: AtomicU32,
-    /// Async trigger point: when reads reach (ra_start + ra_size - async_size),
-    /// trigger the next async readahead (in pages)
-    async_size: AtomicU32,
+    /// Page whose first read triggers the next async readahead, or
+    /// `NO_MARKER`. Only the read that clears it issues the readahead, so
+    /// concurrent readers do not both do so.
+    marker: AtomicU64,
     prev_end: AtomicU64,
+    prev_start: AtomicU64,
     pattern: AtomicU32,
-    /// Number of consecutive sequential reads
+    /// Number of consecutive sequential reads in the same direction
     seq_count: AtomicU32,
+    /// Whether the last sequential read went backward
+    backward: AtomicBool,
+    /// When the stream was last read, on the clock of its file; 0 if unused
+    last_used: AtomicU64,
+    history: SpinNoPreempt<WindowHistory>,
+    /// Windows left to issue before the window may grow again
+    hold: AtomicU32,
+    /// Reads to the file continuing no stream since this one was last read
+    misses: AtomicU32,
+    /// Size in pages of the next sync window, restored from a stream this
+    /// one resumes; 0 for the initial size
+    resume_size: AtomicU32,
 }
 
-impl Default for ReadaheadState {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
-impl ReadaheadState {
-    /// Create a new readahead state
-    pub const fn new() -> Self {
+impl RaStream {
+    const fn new() -> Self {
         Self {
             ra_start: AtomicU64::new(0),
             ra_size: AtomicU32::new(0),
-            async_size: AtomicU32::new(0),
+            marker: AtomicU64::new(NO_MARKER),
             prev_end: AtomicU64::new(0),
+            prev_start: AtomicU64::new(0),
             pattern: AtomicU32::new(RaPattern::Initial as u32),
             seq_count: AtomicU32::new(0),
+            backward: AtomicBool::new(false),
+            last_used: AtomicU64::new(0),
+            history: SpinNoPreempt::new(WindowHistory::new()),
+            hold: AtomicU32::new(0),
+            misses: AtomicU32::new(0),
+            resume_size: AtomicU32::new(0),
         }
     }
 
-    /// Get the current access pattern
+    /// Forget everything about the stream, starting with `pattern`
+    fn reset(&self, pattern: RaPattern) {
+        self.reset_window();
+        self.prev_end.store(0, Ordering::Relaxed);
+        self.prev_start.store(0, Ordering::Relaxed);
+        self.pattern.store(pattern as u32, Ordering::Relaxed);
+        self.seq_count.store(0, Ordering::Relaxed);
+        self.backward.store(false, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+
+    /// What to remember of the stream when it is replaced, if it had a
+    /// window worth restoring
+    fn demote(&self, now: u64) -> Option<Demoted> {
+        let ra_size = self.ra_size.load(Ordering::Relaxed);
+        (self.is_established() && ra_size > 0).then(|| Demoted {
+            prev_start: self.prev_start.load(Ordering::Relaxed),
+            prev_end: self.prev_end.load(Ordering::Relaxed),
+            backward: self.backward.load(Ordering::Relaxed),
+            seq_count: self.seq_count.load(Ordering::Relaxed),
+            ra_size,
+            at: now,
+        })
+    }
+
+    /// Carry on from `demoted`, which the read starting this stream resumes
+    fn resume(&self, demoted: &Demoted) {
+        let pattern = if demoted.backward {
+            RaPattern::Backward
+        } else {
+            RaPattern::Sequential
+        };
+        self.prev_end.store(demoted.prev_end, Ordering::Relaxed);
+        self.prev_start.store(demoted.prev_start, Ordering::Relaxed);
+        self.pattern.store(pattern as u32, Ordering::Relaxed);
+        self.seq_count.store(demoted.seq_count, Ordering::Relaxed);
+        self.backward.store(demoted.backward, Ordering::Relaxed);
+        self.resume_size.store(demoted.ra_size, Ordering::Relaxed);
+    }
+
     #[inline]
-    pub fn pattern(&self) -> RaPattern {
+    fn pattern(&self) -> RaPattern {
         self.pattern.load(Ordering::Relaxed).into()
     }
 
-    /// Check if the current read should trigger async readahead
-    fn should_trigger_async(&self, read_start: u64) -> bool {
-        let ra_start = self.ra_start.load(Ordering::Relaxed);
-        let ra_size = self.ra_size.load(Ordering::Relaxed) as u64 * PAGE_SIZE;
-        let async_size = self.async_size.load(Ordering::Relaxed) as u64 * PAGE_SIZE;
+    /// Whether a sequential pattern had been detected
+    fn is_streaming(&self) -> bool {
+        matches!(self.pattern(), RaPattern::Sequential | RaPattern::Backward)
+    }
 
-        if ra_size == 0 {
-            return false;
-        }
+    /// Whether a sequential pattern was held long enough for the stream to
+    /// outlast a few reads elsewhere in the file
+    fn is_established(&self) -> bool {
+        self.is_streaming()
+            && self.seq_count.load(Ordering::Relaxed) >= RA_SETTLED_READS
+            && self.misses.load(Ordering::Relaxed) <= RA_MISS_TOLERANCE
+    }
 
-        // Trigger when read position enters the async window
-        let trigger_point = ra_start + ra_size.saturating_sub(async_size);
-        read_start >= trigger_point && read_start < ra_start + ra_size
+    /// How far a read is from continuing this stream in either direction,
+    /// or `None` if it leaves a gap larger than `max_gap`
+    fn distance(&self, read_start: u64, read_end: u64, max_gap: u64) -> Option<u64> {
+        continuation(
+            self.prev_start.load(Ordering::Relaxed),
+            self.prev_end.load(Ordering::Relaxed),
+            read_start,
+            read_end,
+            max_gap,
+        )
     }
 
-    /// Update readahead window
-    fn update_window(&self, start: u64, size_pages: u32, async_pages: u32) {
+    /// Check if the current read should trigger async readahead, clearing
+    /// the marker if it touches the marked page
+    fn take_marker(&self, read_start: u64, read_len: usize) -> bool {
+        let first = read_start / PAGE_SIZE;
+        let last = (read_start + read_len as u64 - 1) / PAGE_SIZE;
+        let marker = self.marker.load(Ordering::Relaxed);
+        marker != NO_MARKER
+            && (first..=last).contains(&marker)
+            && self
+                .marker
+                .compare_exchange(marker, NO_MARKER, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    /// Update readahead window, marking `marker_page` to trigger the next
+    /// one
+    fn update_window(&self, start: u64, size_pages: u32, marker_page: u64) {
         self.ra_start.store(start, Ordering::Relaxed);
         self.ra_size.store(size_pages, Ordering::Relaxed);
-        self.async_size.store(async_pages, Ordering::Relaxed);
+        self.marker.store(marker_page, Ordering::Relaxed);
+        self.history.lock().push(start / PAGE_SIZE, size_pages);
+    }
+
+    /// Drop the readahead window
+    fn reset_window(&self) {
+        self.ra_size.store(0, Ordering::Relaxed);
+        self.marker.store(NO_MARKER, Ordering::Relaxed);
+        self.history.lock().clear();
+        self.hold.store(0, Ordering::Relaxed);
+        self.resume_size.store(0, Ordering::Relaxed);
+    }
+
+    /// Cut the window starting on `start_page` down to its first
+    /// `num_pages` pages, dropping it if that leaves none
+    ///
+    /// Returns whether the stream had such a window.
+    fn shrink_window(&self, start_page: u64, num_pages: u32) -> bool {
+        let ra_size = self.ra_size.load(Ordering::Relaxed);
+        if ra_size == 0 || self.ra_start.load(Ordering::Relaxed) / PAGE_SIZE != start_page {
+            return false;
+        }
+        if num_pages == 0 {
+            self.reset_window();
+            return true;
+        }
+        self.ra_size.store(ra_size.min(num_pages), Ordering::Relaxed);
+        // Keep the marker on a page that is cached, so that reading on
+        // issues the next window instead of missing past it
+        let last = start_page + num_pages as u64 - 1;
+        let _ = self
+            .marker
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |marker| {
+                (marker != NO_MARKER && marker > last).then_some(last)
+            });
+        self.history.lock().shrink_newest(num_pages);
+        true
+    }
+
+    /// Size in pages of a sync window started on a cache miss by a read of
+    /// `read_len` bytes
+    ///
+    /// The window covers twice the read rounded up to a power of two, so
+    /// that a large first read is not followed by several more misses while
+    /// the window ramps up.
+    fn initial_ra_size(&self, state: &ReadaheadState, read_len: usize) -> u32 {
+        let read_pages = (read_len as u64).div_ceil(PAGE_SIZE).min(u32::MAX as u64) as u32;
+        let scaled = read_pages
+            .checked_next_power_of_two()
+            .map_or(u32::MAX, |pages| pages.saturating_mul(2));
+        ra_init_pages()
+            .max(scaled)
+            .max(self.resume_size.swap(0, Ordering::Relaxed))
+            .min(state.max_pages())
     }
 
     /// Calculate next readahead size with exponential growth
-    fn next_ra_size(&self) -> u32 {
+    ///
+    /// If the pages of the previous window were evicted before the reader
+    /// got to them, the window is halved instead, and kept from growing for
+    /// the next `RA_THRASH_HOLD` windows.
+    fn next_ra_size(&self, state: &ReadaheadState) -> u32 {
+        let max_pages = state.max_pages();
         let current = self.ra_size.load(Ordering::Relaxed);
         if current == 0 {
-            RA_INIT_PAGES
-        } else {
-            // Double the size, but cap at maximum
-            (current * 2).min(RA_MAX_PAGES)
+            return ra_init_pages().min(max_pages);
         }
+        if self.history.lock().previous_thrashed() {
+            state.count(|c| &c.thrash_shrinks, 1);
+            self.hold.store(RA_THRASH_HOLD, Ordering::Relaxed);
+            return (current / 2).max(RA_MIN_PAGES).min(max_pages);
+        }
+        if self
+            .hold
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |hold| hold.checked_sub(1))
+            .is_ok()
+        {
+            return current.min(max_pages);
+        }
+        // Double the size, but cap at maximum
+        (current * 2).min(max_pages)
     }
 
     /// Detect access pattern and update state
     ///
-    /// Returns (is_sequential, is_cache_hit) tuple
-    fn detect_pattern(&self, read_start: u64, read_len: usize, cache_hit: bool) -> (bool, bool) {
-        let prev_end = self.prev_end.swap(read_start + read_len as u64, Ordering::Relaxed);
-        let pattern = self.pattern();
-
-        // Check if this is a sequential read
-        let gap = if read_start >= prev_end {
-            read_start - prev_end
-        } else {
-            prev_end - read_start
-        };
+    /// Returns how this read moves on from the previous one
+    fn detect_pattern(&self, state: &ReadaheadState, read_start: u64, read_len: usize) -> Step {
+        let read_end = read_start + read_len as u64;
+        let prev_end = self.prev_end.swap(read_end, Ordering::Relaxed);
+        let prev_start = self.prev_start.swap(read_start, Ordering::Relaxed);
+        let max_gap = ra_seq_gap();
 
-        let is_sequential = if prev_end == 0 {
+        let step = if state.mode() == RaMode::Sequential {
+            // Hinted by the application, whatever the gaps
+            Step::Forward
+        } else if prev_end == 0 {
             // First read - assume sequential if starting from beginning
-            read_start < PAGE_SIZE * 4
-        } else {
+            if read_start < RA_HEAD {
+                Step::Forward
+            } else {
+                Step::Random
+            }
+        } else if read_start < prev_start && read_end.abs_diff(prev_start) <= max_gap {
+            // Ends just below where the previous read started
+            Step::Backward
+        } else if read_start.abs_diff(prev_end) <= max_gap {
             // Allow small gaps for sequential detection
-            gap <= RA_SEQ_GAP_PAGES * PAGE_SIZE
+            Step::Forward
+        } else {
+            Step::Random
         };
 
-        if is_sequential {
-            let count = self.seq_count.fetch_add(1, Ordering::Relaxed);
-            if pattern != RaPattern::Sequential && count >= 2 {
-                self.pattern
-                    .store(RaPattern::Sequential as u32, Ordering::Relaxed);
+        if step == Step::Random {
+            if self.is_streaming() {
+                state.count(|c| &c.pattern_resets, 1);
             }
-        } else {
             self.seq_count.store(0, Ordering::Relaxed);
             self.pattern
                 .store(RaPattern::Random as u32, Ordering::Relaxed);
             // Reset readahead window on random access
-            self.ra_size.store(0, Ordering::Relaxed);
+            self.reset_window();
+            return step;
         }
 
-        (is_sequential, cache_hit)
+        let backward = step == Step::Backward;
+        if self.backward.swap(backward, Ordering::Relaxed) != backward {
+            // Turning around starts over, rather than chasing both ways
+            if self.is_streaming() {
+                state.count(|c| &c.pattern_resets, 1);
+            }
+            self.seq_count.store(0, Ordering::Relaxed);
+            self.pattern
+                .store(RaPattern::Initial as u32, Ordering::Relaxed);
+            self.reset_window();
+        }
+        let target = if backward {
+            RaPattern::Backward
+        } else {
+            RaPattern::Sequential
+        };
+        let count = self.seq_count.fetch_add(1, Ordering::Relaxed);
+        if self.pattern() != target && count >= 2 {
+            self.pattern.store(target as u32, Ordering::Relaxed);
+        }
+        step
+    }
+}
+
+/// Number of streams tracked per file
+const RA_STREAMS: usize = 4;
+
+/// Readahead state for a file (similar to Linux's `file_ra_state`)
+///
+/// This structure tracks the readahead windows and access patterns of up
+/// to `RA_STREAMS` interleaved streams through a file. A read continues the
+/// stream that ended or started closest to it; one continuing none starts a
+/// new stream in place of the least recently used, sparing established
+/// streams for up to `RA_MISS_TOLERANCE` such reads, so that a peek
+/// elsewhere does not cost a long scan its window. A stream replaced all
+/// the same is remembered, and gets its window back if resumed within
+/// `RA_RESUME_READS` reads. It uses atomic operations to allow concurrent
+/// access without locks.
+pub struct ReadaheadState {
+    streams: [RaStream; RA_STREAMS],
+    /// Ticks once per read, to find the least recently used stream
+    clock: AtomicU64,
+    /// Access pattern hinted by the application
+    mode: AtomicU32,
+    /// Maximum readahead size in pages set for this file
+    limit: AtomicU32,
+    shared: Arc<RaShared>,
+    /// The stream replaced last, if it was established
+    demoted: SpinNoPreempt<Option<Demoted>>,
+}
+
+impl Default for ReadaheadState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ReadaheadState {
+    fn drop(&mut self) {
+        // No one is left to read what is in flight.
+        self.shared.epoch.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl ReadaheadState {
+    /// Create a new readahead state
+    pub fn new() -> Self {
+        Self {
+            streams: [const { RaStream::new() }; RA_STREAMS],
+            clock: AtomicU64::new(0),
+            mode: AtomicU32::new(RaMode::Normal as u32),
+            limit: AtomicU32::new(RA_LIMIT_GLOBAL),
+            shared: Arc::new(RaShared {
+                epoch: AtomicU64::new(0),
+                stats: RaCounters::new(),
+            }),
+            demoted: SpinNoPreempt::new(None),
+        }
+    }
+
+    /// Create a readahead state whose maximum size is `limit` pages, as set
+    /// for the mount of the file, or `None` to follow `vm/max_readahead_kb`
+    pub fn with_default_limit(limit: Option<u32>) -> Self {
+        let state = Self::new();
+        if let Some(pages) = limit {
+            state.set_limit(pages);
+        }
+        state
+    }
+
+    /// Readahead counters of this file
+    pub fn snapshot(&self) -> RaStats {
+        self.shared.stats.snapshot()
+    }
+
+    /// Add `n` to a counter of this file and to the total
+    fn count(&self, counter: fn(&RaCounters) -> &AtomicU64, n: u64) {
+        self.shared.count(counter, n);
+    }
+
+    /// Cancel the asynchronous readaheads queued for this file, and forget
+    /// the windows they were to fill, so that reads into them miss and
+    /// start over instead of waiting for pages that never come
+    pub fn cancel(&self) {
+        self.shared.epoch.fetch_add(1, Ordering::Release);
+        for stream in &self.streams {
+            stream.reset_window();
+        }
+        *self.demoted.lock() = None;
+    }
+
+    /// Forget the windows overlapping `start_page..start_page + num_pages`,
+    /// whose pages are being dropped from the page cache, so that reads
+    /// into them start over instead of counting on the pages
+    pub fn invalidate(&self, start_page: u32, num_pages: u32) {
+        let range = start_page as u64..start_page as u64 + num_pages as u64;
+        for stream in &self.streams {
+            let start = stream.ra_start.load(Ordering::Relaxed) / PAGE_SIZE;
+            let size = stream.ra_size.load(Ordering::Relaxed) as u64;
+            if size > 0 && start < range.end && range.start < start + size {
+                stream.reset_window();
+            }
+        }
+        *self.demoted.lock() = None;
+    }
+
+    /// Prepare for reads from `new_offset`, where the file position was
+    /// just moved
+    ///
+    /// A move within the sequential gap of where the stream read last ended
+    /// changes nothing. Otherwise that stream drops its window, which no one
+    /// reads any more, and goes on from `new_offset`: the next read there
+    /// starts a sequential run instead of looking random, with a first
+    /// window as large as the one dropped.
+    pub fn reset_on_seek(&self, new_offset: u64) {
+        let Some(stream) = self
+            .streams
+            .iter()
+            .filter(|stream| stream.last_used.load(Ordering::Relaxed) != 0)
+            .max_by_key(|stream| stream.last_used.load(Ordering::Relaxed))
+        else {
+            return;
+        };
+        if new_offset.abs_diff(stream.prev_end.load(Ordering::Relaxed)) <= ra_seq_gap() {
+            return;
+        }
+        let ra_size = stream.ra_size.load(Ordering::Relaxed);
+        stream.reset_window();
+        stream.resume_size.store(ra_size, Ordering::Relaxed);
+        stream.prev_start.store(new_offset, Ordering::Relaxed);
+        stream.prev_end.store(new_offset, Ordering::Relaxed);
+        if stream.backward.swap(false, Ordering::Relaxed) || !stream.is_streaming() {
+            // Only a forward run is resumed.
+            stream.seq_count.store(0, Ordering::Relaxed);
+            stream
+                .pattern
+                .store(RaPattern::Initial as u32, Ordering::Relaxed);
+        }
+    }
+
+    /// Cut the window starting on `start_page` down to the `num_pages`
+    /// pages a prefetch got through
+    fn shrink_window(&self, start_page: u32, num_pages: u32) {
+        for stream in &self.streams {
+            if stream.shrink_window(start_page as u64, num_pages) {
+                break;
+            }
+        }
+    }
+
+    /// Whether `page` is the async marker of a window
+    fn is_marker(&self, page: u32) -> bool {
+        self.streams
+            .iter()
+            .any(|stream| stream.marker.load(Ordering::Relaxed) == page as u64)
+    }
+
+    /// Maximum readahead size in pages, before any hint; 0 if readahead
+    /// is disabled
+    pub fn limit(&self) -> u32 {
+        match self.limit.load(Ordering::Relaxed) {
+            RA_LIMIT_GLOBAL => ra_max_pages(),
+            limit => limit,
+        }
+    }
+
+    /// Set the maximum readahead size in pages, which must be 0 or within
+    /// `RA_MIN_PAGES..=RA_HARD_MAX_PAGES`
+    pub fn set_limit(&self, pages: u32) {
+        debug_assert!(pages == 0 || (RA_MIN_PAGES..=RA_HARD_MAX_PAGES).contains(&pages));
+        self.limit.store(pages, Ordering::Relaxed);
+    }
+
+    /// Get the hinted access pattern
+    #[inline]
+    pub fn mode(&self) -> RaMode {
+        self.mode.load(Ordering::Relaxed).into()
+    }
+
+    /// Apply an access pattern hint
+    pub fn set_mode(&self, mode: RaMode) {
+        self.mode.store(mode as u32, Ordering::Relaxed);
+        if mode == RaMode::Sequential {
+            for stream in &self.streams {
+                stream
+                    .pattern
+                    .store(RaPattern::Sequential as u32, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Maximum readahead size in pages under the current hint and memory
+    /// pressure
+    fn max_pages(&self) -> u32 {
+        let max_pages = match self.mode() {
+            RaMode::Sequential => self.limit().saturating_mul(2),
+            _ => self.limit(),
+        };
+        if memory_pressure() >= PressureLevel::Medium {
+            max_pages.min(ra_init_pages())
+        } else {
+            max_pages
+        }
+    }
+
+    /// Get the access pattern of the most recently read stream
+    pub fn pattern(&self) -> RaPattern {
+        self.streams
+            .iter()
+            .max_by_key(|stream| stream.last_used.load(Ordering::Relaxed))
+            .map_or(RaPattern::Initial, RaStream::pattern)
+    }
+
+    /// Find the stream a read continues, or start a new one
+    fn stream_for(&self, read_start: u64, read_len: usize) -> &RaStream {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        let read_end = read_start + read_len as u64;
+        // Gaps do not break a hinted stream, unless they are too large to
+        // tell it from another one
+        let max_gap = match self.mode() {
+            RaMode::Sequential => self.max_pages() as u64 * PAGE_SIZE,
+            _ => ra_seq_gap(),
+        };
+        let closest = self
+            .streams
+            .iter()
+            .filter(|stream| stream.last_used.load(Ordering::Relaxed) != 0)
+            .filter_map(|stream| Some((stream.distance(read_start, read_end, max_gap)?, stream)))
+            .min_by_key(|(distance, _)| *distance);
+        let stream = match closest {
+            Some((_, stream)) => stream,
+            None => {
+                for stream in &self.streams {
+                    let _ = stream
+                        .misses
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |misses| {
+                            misses.checked_add(1)
+                        });
+                }
+                // Unused streams come first, being last used at 0, and
+                // established ones last
+                let stream = self
+                    .streams
+                    .iter()
+                    .min_by_key(|stream| {
+                        (stream.is_established(), stream.last_used.load(Ordering::Relaxed))
+                    })
+                    .unwrap();
+                let demoted = stream.demote(now);
+                stream.reset(match self.mode() {
+                    RaMode::Sequential => RaPattern::Sequential,
+                    _ => RaPattern::Initial,
+                });
+                let mut slot = self.demoted.lock();
+                if let Some(resumed) = slot.take_if(|d| {
+                    now - d.at <= RA_RESUME_READS
+                        && continuation(d.prev_start, d.prev_end, read_start, read_end, max_gap)
+                            .is_some()
+                }) {
+                    stream.resume(&resumed);
+                }
+                if demoted.is_some() {
+                    *slot = demoted;
+                }
+                stream
+            }
+        };
+        stream.misses.store(0, Ordering::Relaxed);
+        stream.last_used.store(now, Ordering::Relaxed);
+        stream
+    }
+}
+
+/// What readahead sees of the pages it decides over: those of the page
+/// cache of a file, or whatever else a reader keeps ahead of its position
+pub trait RaPages {
+    /// Check whether `page` is already in memory
+    fn is_page_cached(&self, page: u32) -> bool;
+}
+
+impl RaPages for FileBackend {
+    fn is_page_cached(&self, page: u32) -> bool {
+        FileBackend::is_page_cached(self, page)
     }
 }
 
@@ -170,42 +927,111 @@ pub enum ReadaheadAction {
     Async { start_page: u32, num_pages: u32 },
 }
 
-/// Make a readahead decision based on current access
+impl ReadaheadAction {
+    /// Limit the number of pages prefetched by this action
+    pub fn capped(self, max_pages: u32) -> Self {
+        match self {
+            Self::None => Self::None,
+            Self::Sync { start_page, num_pages } => Self::Sync {
+                start_page,
+                num_pages: num_pages.min(max_pages),
+            },
+            Self::Async { start_page, num_pages } => Self::Async {
+                start_page,
+                num_pages: num_pages.min(max_pages),
+            },
+        }
+    }
+
+    /// Limit this action to pages below a file size of `size` bytes
+    fn clamped(self, size: u64) -> Self {
+        let end_page = size.div_ceil(PAGE_SIZE).min(u32::MAX as u64) as u32;
+        let (start_page, num_pages) = match self {
+            Self::None => return Self::None,
+            Self::Sync { start_page, num_pages } | Self::Async { start_page, num_pages } => {
+                (start_page, num_pages.min(end_page.saturating_sub(start_page)))
+            }
+        };
+        match self {
+            _ if num_pages == 0 => Self::None,
+            Self::Sync { .. } => Self::Sync { start_page, num_pages },
+            _ => Self::Async { start_page, num_pages },
+        }
+    }
+}
+
+/// Make a readahead decision based on current access to a file of `size`
+/// bytes
 ///
 /// This function should be called before each read operation.
-/// It returns the recommended readahead action.
+/// It returns the recommended readahead action, which never covers pages
+/// past the one holding the last byte.
 pub fn readahead_decide(
     state: &ReadaheadState,
-    backend: &FileBackend,
+    backend: &impl RaPages,
+    read_start: u64,
+    read_len: usize,
+    size: u64,
+) -> ReadaheadAction {
+    if read_start >= size || state.limit() == 0 || memory_pressure() == PressureLevel::Critical {
+        return ReadaheadAction::None;
+    }
+    let action = decide(state, backend, read_start, read_len).clamped(size);
+    match action {
+        ReadaheadAction::Sync { .. } => state.count(|c| &c.sync_ra_issued, 1),
+        ReadaheadAction::Async { .. } => state.count(|c| &c.async_ra_issued, 1),
+        ReadaheadAction::None => {}
+    }
+    action
+}
+
+fn decide(
+    state: &ReadaheadState,
+    backend: &impl RaPages,
     read_start: u64,
     read_len: usize,
 ) -> ReadaheadAction {
-    if read_len == 0 {
+    if read_len == 0 || state.mode() == RaMode::Random {
         return ReadaheadAction::None;
     }
 
     let start_page = (read_start / PAGE_SIZE) as u32;
     let cache_hit = backend.is_page_cached(start_page);
+    if cache_hit {
+        state.count(|c| &c.cache_hits, 1);
+    } else {
+        state.count(|c| &c.cache_misses, 1);
+    }
 
-    // Detect access pattern
-    let (is_sequential, _) = state.detect_pattern(read_start, read_len, cache_hit);
-
-    if !is_sequential {
-        return ReadaheadAction::None;
+    // Detect access pattern of the stream this read belongs to
+    let stream = state.stream_for(read_start, read_len);
+    // A read into a window issued earlier should find its page cached
+    stream.history.lock().record_read(start_page as u64, cache_hit);
+    match stream.detect_pattern(state, read_start, read_len) {
+        Step::Forward => {}
+        Step::Backward => {
+            return backward_readahead(state, stream, read_start, read_len, cache_hit);
+        }
+        Step::Random => return ReadaheadAction::None,
     }
 
     // Check if we should trigger async readahead
-    if state.should_trigger_async(read_start) {
-        let ra_start = state.ra_start.load(Ordering::Relaxed);
-        let ra_size = state.ra_size.load(Ordering::Relaxed);
+    if stream.take_marker(read_start, read_len) {
+        if memory_pressure() >= PressureLevel::High {
+            // The stream falls back to a sync window on its next miss
+            return ReadaheadAction::None;
+        }
+        let ra_start = stream.ra_start.load(Ordering::Relaxed);
+        let ra_size = stream.ra_size.load(Ordering::Relaxed);
 
         // Next window starts at current window end
         let next_start = ra_start + ra_size as u64 * PAGE_SIZE;
-        let next_size = state.next_ra_size();
-        let async_size = next_size / 4; // 25% of window for async trigger
+        let next_size = stream.next_ra_size(state);
+        let async_size = (next_size / 4).max(1); // 25% of window for async trigger
 
         // Update window for next iteration
-        state.update_window(next_start, next_size, async_size);
+        let marker = next_start / PAGE_SIZE + (next_size - async_size) as u64;
+        stream.update_window(next_start, next_size, marker);
 
         return ReadaheadAction::Async {
             start_page: (next_start / PAGE_SIZE) as u32,
@@ -214,13 +1040,14 @@ pub fn readahead_decide(
     }
 
     // Initial readahead on cache miss with sequential pattern
-    if !cache_hit && state.pattern() != RaPattern::Random {
-        let ra_size = RA_INIT_PAGES;
-        let async_size = ra_size / 4;
+    if !cache_hit && stream.pattern() != RaPattern::Random {
+        let ra_size = stream.initial_ra_size(state, read_len);
+        let async_size = (ra_size / 4).max(1);
 
         // Set initial window
         let window_start = (start_page as u64) * PAGE_SIZE;
-        state.update_window(window_start, ra_size, async_size.max(1));
+        let marker = start_page as u64 + (ra_size - async_size) as u64;
+        stream.update_window(window_start, ra_size, marker);
 
         return ReadaheadAction::Sync {
             start_page,
@@ -231,11 +1058,212 @@ pub fn readahead_decide(
     ReadaheadAction::None
 }
 
+/// Readahead decision for a backward scan, prefetching the window below
+/// the read position
+fn backward_readahead(
+    state: &ReadaheadState,
+    stream: &RaStream,
+    read_start: u64,
+    read_len: usize,
+    cache_hit: bool,
+) -> ReadaheadAction {
+    if stream.pattern() != RaPattern::Backward {
+        return ReadaheadAction::None;
+    }
+
+    // The window grows downward, so the marker sits on the highest of its
+    // lowest async_size pages
+    if stream.take_marker(read_start, read_len) {
+        if memory_pressure() >= PressureLevel::High {
+            return ReadaheadAction::None;
+        }
+        let ra_start = stream.ra_start.load(Ordering::Relaxed);
+        let next_size = stream.next_ra_size(state);
+        let next_start = ra_start.saturating_sub(next_size as u64 * PAGE_SIZE);
+        let num_pages = ((ra_start - next_start) / PAGE_SIZE) as u32;
+        if num_pages == 0 {
+            // Already at the beginning of the file
+            return ReadaheadAction::None;
+        }
+        set_backward_window(stream, next_start, num_pages);
+        return ReadaheadAction::Async {
+            start_page: (next_start / PAGE_SIZE) as u32,
+            num_pages,
+        };
+    }
+
+    if !cache_hit {
+        // The initial window ends with the page being read
+        let window_end = (read_start / PAGE_SIZE + 1) * PAGE_SIZE;
+        let ra_size = stream.initial_ra_size(state, read_len);
+        let window_start = window_end.saturating_sub(ra_size as u64 * PAGE_SIZE);
+        let num_pages = ((window_end - window_start) / PAGE_SIZE) as u32;
+        set_backward_window(stream, window_start, num_pages);
+        return ReadaheadAction::Sync {
+            start_page: (window_start / PAGE_SIZE) as u32,
+            num_pages,
+        };
+    }
+
+    ReadaheadAction::None
+}
+
+fn set_backward_window(stream: &RaStream, start: u64, num_pages: u32) {
+    let async_size = (num_pages / 4).max(1);
+    stream.update_window(start, num_pages, start / PAGE_SIZE + async_size as u64 - 1);
+}
+
+/// Read ahead for a page fault on a file mapping at `page` of a file of
+/// `size` bytes, before it is handled
+///
+/// A fault goes through the same detection as a read of its page. Returns
+/// how many pages from `page` on are cached and may be mapped along with
+/// it: the run of cached pages up to the next async marker, whose own fault
+/// then issues the next window, or just `page` while faults are random.
+pub fn fault_readahead(
+    state: &ReadaheadState,
+    backend: &FileBackend,
+    page: u32,
+    size: u64,
+) -> u32 {
+    match readahead_decide(state, backend, page_to_offset(page), PAGE_SIZE as usize, size) {
+        ReadaheadAction::Sync {
+            start_page,
+            num_pages,
+        } => {
+            // The fault reads the page itself if this fails.
+            let _ = do_sync_readahead(state, backend, start_page, num_pages);
+        }
+        ReadaheadAction::Async {
+            start_page,
+            num_pages,
+        } => {
+            submit_async_readahead(async_readahead(state, backend, start_page, num_pages));
+        }
+        ReadaheadAction::None => {}
+    }
+    if state.mode() == RaMode::Random || state.pattern() == RaPattern::Random {
+        return 1;
+    }
+    let end_page = size.div_ceil(PAGE_SIZE).min(u32::MAX as u64) as u32;
+    let limit = page.saturating_add(state.max_pages()).min(end_page);
+    let mut end = page + 1;
+    while end < limit && !state.is_marker(end) && backend.is_page_cached(end) {
+        end += 1;
+    }
+    end - page
+}
+
 /// Execute synchronous readahead
 ///
-/// This function prefetches pages synchronously into the page cache.
-pub fn do_sync_readahead(backend: &FileBackend, start_page: u32, num_pages: u32) -> usize {
-    backend.prefetch_pages(start_page, num_pages)
+/// This function prefetches pages synchronously into the page cache, and
+/// returns how many of them, from `start_page` on, made it there. The
+/// prefetch stops at the first page that fails to read; the window it was
+/// for is then cut down to the pages before, or dropped with an `EIO` if
+/// not even the first one was read, so that the next read demands the rest
+/// itself. Callers reading on should ignore the error, leaving the read to
+/// report its own.
+pub fn do_sync_readahead(
+    state: &ReadaheadState,
+    backend: &FileBackend,
+    start_page: u32,
+    num_pages: u32,
+) -> AxResult<usize> {
+    let pages = backend.prefetch_pages(start_page, num_pages);
+    state.count(|c| &c.pages_prefetched, pages as u64);
+    if pages < num_pages as usize {
+        state.shrink_window(start_page, pages as u32);
+        if pages == 0 {
+            return Err(AxError::Io);
+        }
+    }
+    Ok(pages)
+}
+
+/// An asynchronous readahead, to be run in the background
+///
+/// It prefetches `RA_CHUNK_PAGES` at a time and drops the rest once the
+/// readaheads of the file are cancelled. It carries the I/O priority of the
+/// task that prepared it, by which the workers order the queue. One in the
+/// idle class also yields before each chunk so that foreground reads go
+/// first.
+pub struct AsyncReadahead {
+    shared: Arc<RaShared>,
+    /// Epoch of the file when it was prepared
+    epoch: u64,
+    backend: FileBackend,
+    start_page: u32,
+    end_page: u32,
+    ioprio: IoPrio,
+}
+
+impl AsyncReadahead {
+    /// Merge `other` into this readahead if both are for the same file and
+    /// their pages overlap or adjoin
+    ///
+    /// The merged one takes the higher priority of the two.
+    pub fn coalesce(&mut self, other: &Self) -> bool {
+        if !Arc::ptr_eq(&self.shared, &other.shared)
+            || self.epoch != other.epoch
+            || other.start_page > self.end_page
+            || self.start_page > other.end_page
+        {
+            return false;
+        }
+        self.start_page = self.start_page.min(other.start_page);
+        self.end_page = self.end_page.max(other.end_page);
+        if other.ioprio.is_higher_than(self.ioprio) {
+            self.ioprio = other.ioprio;
+        }
+        true
+    }
+
+    /// I/O priority of the task that prepared this readahead
+    pub fn ioprio(&self) -> IoPrio {
+        self.ioprio
+    }
+
+    /// Prefetch the pages
+    pub fn run(self) {
+        let idle = self.ioprio.class() == IoPrioClass::Idle;
+        let mut page = self.start_page;
+        while page < self.end_page {
+            if idle {
+                axtask::yield_now();
+            }
+            if self.shared.epoch.load(Ordering::Acquire) != self.epoch {
+                self.shared.count(|c| &c.async_ra_cancelled, 1);
+                return;
+            }
+            let chunk = (self.end_page - page).min(RA_CHUNK_PAGES);
+            self.backend.try_prefetch_pages(page, chunk);
+            self.shared.count(|c| &c.pages_prefetched, chunk as u64);
+            page += chunk;
+        }
+    }
+}
+
+/// Prepare asynchronous readahead, at the I/O priority of the current task
+///
+/// This function returns the readahead for the caller to run in the
+/// background, usually through
+/// [`submit_async_readahead`](super::ra_worker::submit_async_readahead).
+pub fn async_readahead(
+    state: &ReadaheadState,
+    backend: &FileBackend,
+    start_page: u32,
+    num_pages: u32,
+) -> AsyncReadahead {
+    let shared = state.shared.clone();
+    let epoch = shared.epoch.load(Ordering::Acquire);
+    AsyncReadahead {
+        shared,
+        epoch,
+        backend: backend.clone(),
+        start_page,
+        end_page: start_page.saturating_add(num_pages),
+        ioprio: current_ioprio(),
+    }
 }
 
 /// Execute asynchronous readahead
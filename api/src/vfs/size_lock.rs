@@ -0,0 +1,113 @@
+//! Per-inode locks ordering reads against size changes.
+//!
+//! Reads of a regular file run with the inode's [`SizeLock`] held shared;
+//! truncation and writes that may move the end of file hold it exclusively.
+//! A read therefore sees one size for its whole duration, which gives:
+//!
+//! - the byte count returned never exceeds the size at some instant during
+//!   the call, and no byte comes from beyond it;
+//! - a shrinking truncate has dropped the pages past the new end before any
+//!   read can observe the smaller size;
+//! - a read starting below a size that only grows never returns 0, as an
+//!   append publishes its size and cached data together.
+//!
+//! Writes within the current size only take the lock shared, so they do not
+//! serialize with readers.
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    sync::{Arc, Weak},
+};
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::Poll,
+};
+
+use axfs_ng_vfs::Location;
+use axpoll::PollSet;
+use axsync::Mutex;
+use axtask::future::block_on;
+
+/// A sleeping reader-writer lock for the size of one inode. Writers are
+/// preferred: once one waits, new readers queue behind it.
+pub struct SizeLock {
+    /// The inode, by `(device, inode)`
+    key: (u64, u64),
+    /// Held by a writer for its whole operation, and by readers only to
+    /// register themselves.
+    writer: Mutex<()>,
+    readers: AtomicUsize,
+    /// Woken when the last reader leaves.
+    drained: PollSet,
+}
+
+impl SizeLock {
+    fn new(key: (u64, u64)) -> Self {
+        Self {
+            key,
+            writer: Mutex::new(()),
+            readers: AtomicUsize::new(0),
+            drained: PollSet::new(),
+        }
+    }
+
+    /// Runs `f` with the size held stable.
+    pub fn shared<R>(&self, f: impl FnOnce() -> R) -> R {
+        {
+            let _writer = self.writer.lock();
+            self.readers.fetch_add(1, Ordering::AcqRel);
+        }
+        let result = f();
+        if self.readers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.drained.wake();
+        }
+        result
+    }
+
+    /// Runs `f`, which may change the size, with no read in progress.
+    pub fn exclusive<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _writer = self.writer.lock();
+        block_on(poll_fn(|cx| {
+            if self.readers.load(Ordering::Acquire) == 0 {
+                return Poll::Ready(());
+            }
+            self.drained.register(cx.waker());
+            if self.readers.load(Ordering::Acquire) == 0 {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }));
+        f()
+    }
+}
+
+impl Drop for SizeLock {
+    fn drop(&mut self) {
+        let mut locks = SIZE_LOCKS.lock();
+        // A new lock may have taken the slot since the last reference went.
+        if locks
+            .get(&self.key)
+            .is_some_and(|lock| lock.strong_count() == 0)
+        {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+/// Locks by `(device, inode)`, so every mount and open file of an inode
+/// shares one. Each lock takes its entry out when it goes.
+static SIZE_LOCKS: Mutex<BTreeMap<(u64, u64), Weak<SizeLock>>> = Mutex::new(BTreeMap::new());
+
+/// Returns the size lock of the inode at `loc`.
+pub fn size_lock(loc: &Location) -> Arc<SizeLock> {
+    let key = (loc.mountpoint().device() as u64, loc.inode());
+    let mut locks = SIZE_LOCKS.lock();
+    if let Some(lock) = locks.get(&key).and_then(Weak::upgrade) {
+        return lock;
+    }
+    let lock = Arc::new(SizeLock::new(key));
+    locks.insert(key, Arc::downgrade(&lock));
+    lock
+}
@@ -1,10 +1,20 @@
-use alloc::{borrow::ToOwned, string::String, sync::Arc};
-use core::{any::Any, borrow::Borrow, cmp::Ordering, task::Context, time::Duration};
+use alloc::{
+    borrow::ToOwned, collections::btree_map::BTreeMap, string::String, sync::Arc, vec::Vec,
+};
+use core::{
+    any::Any,
+    borrow::Borrow,
+    cmp::Ordering,
+    sync::atomic::{AtomicU32, Ordering as AtomicOrdering},
+    task::Context,
+    time::Duration,
+};
 
+use axerrno::LinuxError;
 use axfs_ng_vfs::{
     DeviceId, DirEntry, DirEntrySink, DirNode, DirNodeOps, FileNode, FileNodeOps, Filesystem,
-    FilesystemOps, Metadata, MetadataUpdate, NodeFlags, NodeOps, NodePermission, NodeType,
-    Reference, StatFs, VfsError, VfsResult, WeakDirEntry,
+    FilesystemOps, Location, Metadata, MetadataUpdate, NodeFlags, NodeOps, NodePermission,
+    NodeType, Reference, StatFs, VfsError, VfsResult, WeakDirEntry,
 };
 use axpoll::{IoEvents, Pollable};
 use axsync::Mutex;
@@ -12,6 +22,8 @@ use hashbrown::HashMap;
 use slab::Slab;
 use starry_core::vfs::dummy_stat_fs;
 
+use super::readahead::PAGE_SIZE;
+
 #[derive(PartialEq, Eq, Hash, Clone)]
 struct FileName(String);
 
@@ -49,19 +61,37 @@ impl Borrow<str> for FileName {
     }
 }
 
+/// Generation of the next inode of any memory filesystem. Slots of freed
+/// inodes are reused, so a file handle is only valid while its generation
+/// matches; sharing the counter keeps a handle from matching an inode of
+/// another memory filesystem.
+static NEXT_GENERATION: AtomicU32 = AtomicU32::new(0);
+
 /// A simple in-memory filesystem that supports basic file operations.
 pub struct MemoryFs {
     inodes: Mutex<Slab<Arc<Inode>>>,
     root: Mutex<Option<DirEntry>>,
+    /// Maximum number of pages of file content, or 0 for no limit.
+    capacity: u64,
+    /// Pages of file content reserved by writes.
+    used_pages: Mutex<u64>,
 }
 
 impl MemoryFs {
     /// Creates a new empty memory filesystem.
     #[allow(clippy::new_ret_no_self)]
     pub fn new() -> Filesystem {
+        Self::with_capacity(0)
+    }
+
+    /// Creates a new empty memory filesystem holding at most `capacity`
+    /// pages of file content, or any amount if it is 0.
+    pub fn with_capacity(capacity: u64) -> Filesystem {
         let fs = Arc::new(Self {
             inodes: Mutex::new(Slab::new()),
             root: Mutex::default(),
+            capacity,
+            used_pages: Mutex::new(0),
         });
         let root_ino = Inode::new(
             &fs,
@@ -70,7 +100,10 @@ impl MemoryFs {
             NodePermission::from_bits_truncate(0o755),
         );
         *fs.root.lock() = Some(DirEntry::new_dir(
-            |this| DirNode::new(MemoryNode::new(fs.clone(), root_ino, Some(this))),
+            |this| {
+                *root_ino.entry.lock() = Some(this.clone());
+                DirNode::new(MemoryNode::new(fs.clone(), root_ino, Some(this)))
+            },
             Reference::root(),
         ));
         Filesystem::new(fs)
@@ -79,6 +112,92 @@ impl MemoryFs {
     fn get(&self, ino: u64) -> Arc<Inode> {
         self.inodes.lock()[ino as usize - 1].clone()
     }
+
+    /// Returns the inode number and generation identifying the file at
+    /// `loc` in a file handle, if it is on a memory filesystem.
+    pub fn encode_handle(loc: &Location) -> Option<(u64, u32)> {
+        let node = memory_node(loc.entry()).ok()?;
+        Some((node.inode.ino, node.inode.generation))
+    }
+
+    /// Finds the file identified by a file handle on the memory filesystem
+    /// whose root is `root`.
+    ///
+    /// Fails with `ESTALE` once the inode has been freed, even if its number
+    /// has been reused since.
+    pub fn decode_handle(root: &Location, ino: u64, generation: u32) -> VfsResult<DirEntry> {
+        let stale = || VfsError::from(LinuxError::ESTALE);
+        let fs = memory_node(root.entry()).map_err(|_| stale())?.fs.clone();
+        let inode = ino
+            .checked_sub(1)
+            .and_then(|slot| fs.inodes.lock().get(slot as usize).cloned())
+            .filter(|inode| inode.generation == generation)
+            .ok_or_else(stale)?;
+        let entry = inode.entry.lock().as_ref().and_then(WeakDirEntry::upgrade);
+        entry.ok_or_else(stale)
+    }
+
+    /// Returns whether `loc` is on a memory filesystem.
+    pub fn contains(loc: &Location) -> bool {
+        memory_node(loc.entry()).is_ok()
+    }
+
+    /// Sets the device number of the device node at `loc`, which must be on
+    /// a memory filesystem.
+    pub fn set_rdev(loc: &Location, rdev: DeviceId) -> VfsResult<()> {
+        memory_node(loc.entry())?.inode.metadata.lock().rdev = rdev;
+        Ok(())
+    }
+
+    /// Reserves the pages a write of `len` bytes at `offset` to the file at
+    /// `loc` is about to fill, failing with `ENOSPC` if they do not all fit.
+    ///
+    /// Holes take no space until written. Does nothing for files on other
+    /// filesystems.
+    pub fn reserve(loc: &Location, offset: u64, len: u64) -> VfsResult<()> {
+        let Ok(node) = memory_node(loc.entry()) else {
+            return Ok(());
+        };
+        let Ok(file) = node.inode.as_file() else {
+            return Ok(());
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        let (start, end) = (offset / PAGE_SIZE, (offset + len - 1) / PAGE_SIZE + 1);
+        let mut reserved = file.pages.lock();
+        let missing = end - start - reserved.count_in(start, end);
+        let mut used = node.fs.used_pages.lock();
+        if node.fs.capacity != 0 && *used + missing > node.fs.capacity {
+            return Err(VfsError::StorageFull);
+        }
+        *used += missing;
+        reserved.insert(start, end);
+        Ok(())
+    }
+
+    /// Gives back the space of the whole pages in `offset..offset + len` of
+    /// the file at `loc`, after they have been punched out.
+    pub fn release(loc: &Location, offset: u64, len: u64) {
+        if let Ok(node) = memory_node(loc.entry())
+            && let Ok(file) = node.inode.as_file()
+        {
+            let (start, end) = (offset.div_ceil(PAGE_SIZE), (offset + len) / PAGE_SIZE);
+            node.fs.release_pages(&mut file.pages.lock(), start, end);
+        }
+    }
+
+    /// Gives back the space of the pages in `start..end` of `reserved`.
+    fn release_pages(&self, reserved: &mut PageSet, start: u64, end: u64) {
+        *self.used_pages.lock() -= reserved.remove(start, end);
+    }
+}
+
+fn memory_node(entry: &DirEntry) -> VfsResult<Arc<MemoryNode>> {
+    match entry.as_dir() {
+        Ok(dir) => dir.downcast::<MemoryNode>(),
+        Err(_) => entry.as_file()?.downcast::<MemoryNode>(),
+    }
 }
 
 impl FilesystemOps for MemoryFs {
@@ -91,7 +210,15 @@ impl FilesystemOps for MemoryFs {
     }
 
     fn stat(&self) -> VfsResult<StatFs> {
-        Ok(dummy_stat_fs(0x01021994))
+        // Like Linux, an unlimited tmpfs reports no blocks at all.
+        let free = self.capacity.saturating_sub(*self.used_pages.lock());
+        Ok(StatFs {
+            block_size: PAGE_SIZE as _,
+            blocks: self.capacity,
+            blocks_free: free,
+            blocks_available: free,
+            ..dummy_stat_fs(0x01021994)
+        })
     }
 }
 
@@ -101,6 +228,9 @@ fn release_inode(fs: &MemoryFs, inode: &Arc<Inode>, nlink: u64) {
     metadata.nlink -= nlink;
     if metadata.nlink == 0 && Arc::strong_count(inode) == 2 {
         inodes.remove(metadata.inode as usize - 1);
+        if let Ok(file) = inode.as_file() {
+            fs.release_pages(&mut file.pages.lock(), 0, u64::MAX);
+        }
     }
 }
 
@@ -112,6 +242,73 @@ struct FileContent {
     /// content management to page cache.
     length: Mutex<u64>,
     symlink: Mutex<Option<String>>,
+    /// Pages reserved for content, charged to the filesystem.
+    pages: Mutex<PageSet>,
+}
+
+/// A set of page indices, kept as extents so that large writes cost no
+/// more than small ones.
+#[derive(Default)]
+struct PageSet {
+    /// Disjoint, non-adjacent `start..end` ranges, by start.
+    extents: BTreeMap<u64, u64>,
+    len: u64,
+}
+
+impl PageSet {
+    /// Returns the number of pages in the set.
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns the extents overlapping or touching `start..end`.
+    fn touching(&self, start: u64, end: u64) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.extents
+            .range(..=end)
+            .rev()
+            .take_while(move |(_, e)| **e >= start)
+            .map(|(s, e)| (*s, *e))
+    }
+
+    /// Returns how many pages of `start..end` are in the set.
+    fn count_in(&self, start: u64, end: u64) -> u64 {
+        self.touching(start, end)
+            .map(|(s, e)| e.min(end).saturating_sub(s.max(start)))
+            .sum()
+    }
+
+    /// Adds the pages in `start..end`.
+    fn insert(&mut self, mut start: u64, mut end: u64) {
+        let merged = self.touching(start, end).collect::<Vec<_>>();
+        for (s, e) in merged {
+            self.extents.remove(&s);
+            self.len -= e - s;
+            start = start.min(s);
+            end = end.max(e);
+        }
+        self.extents.insert(start, end);
+        self.len += end - start;
+    }
+
+    /// Removes the pages in `start..end`, returning how many there were.
+    fn remove(&mut self, start: u64, end: u64) -> u64 {
+        if start >= end {
+            return 0;
+        }
+        let before = self.len;
+        let cut = self.touching(start, end).collect::<Vec<_>>();
+        for (s, e) in cut {
+            self.extents.remove(&s);
+            self.len -= e - s;
+            for (s, e) in [(s, e.min(start)), (s.max(end), e)] {
+                if s < e {
+                    self.extents.insert(s, e);
+                    self.len += e - s;
+                }
+            }
+        }
+        before - self.len
+    }
 }
 
 #[derive(Default)]
@@ -126,8 +323,12 @@ enum NodeContent {
 
 struct Inode {
     ino: u64,
+    generation: u32,
     metadata: Mutex<Metadata>,
     content: NodeContent,
+    /// The entry the inode was last made visible through, for opening it by
+    /// file handle.
+    entry: Mutex<Option<WeakDirEntry>>,
 }
 
 impl Inode {
@@ -162,8 +363,10 @@ impl Inode {
         };
         let result = Arc::new(Self {
             ino,
+            generation: NEXT_GENERATION.fetch_add(1, AtomicOrdering::Relaxed),
             metadata: Mutex::new(metadata),
             content,
+            entry: Mutex::default(),
         });
         entry.insert(result.clone());
         drop(inodes);
@@ -234,15 +437,20 @@ impl MemoryNode {
         );
         Ok(if node_type == NodeType::Directory {
             DirEntry::new_dir(
-                |this| DirNode::new(MemoryNode::new(fs, inode, Some(this))),
+                |this| {
+                    *inode.entry.lock() = Some(this.clone());
+                    DirNode::new(MemoryNode::new(fs, inode, Some(this)))
+                },
                 reference,
             )
         } else {
-            DirEntry::new_file(
-                FileNode::new(MemoryNode::new(fs, inode, None)),
+            let entry = DirEntry::new_file(
+                FileNode::new(MemoryNode::new(fs, inode.clone(), None)),
                 node_type,
                 reference,
-            )
+            );
+            *inode.entry.lock() = Some(entry.downgrade());
+            entry
         })
     }
 }
@@ -257,6 +465,9 @@ impl NodeOps for MemoryNode {
         match &self.inode.content {
             NodeContent::File(content) => {
                 metadata.size = *content.length.lock();
+                // In 512-byte units, of the pages written or allocated.
+                metadata.blocks = content.pages.lock().len() * (PAGE_SIZE / 512);
+                metadata.block_size = PAGE_SIZE as _;
             }
             NodeContent::Dir(dir) => {
                 metadata.size = dir.entries.lock().len() as u64;
@@ -321,7 +532,14 @@ impl FileNodeOps for MemoryNode {
     }
 
     fn set_len(&self, len: u64) -> VfsResult<()> {
-        *self.inode.as_file()?.length.lock() = len;
+        let file = self.inode.as_file()?;
+        let mut length = file.length.lock();
+        if len < *length {
+            let start = len.div_ceil(PAGE_SIZE);
+            self.fs
+                .release_pages(&mut file.pages.lock(), start, u64::MAX);
+        }
+        *length = len;
         Ok(())
     }
 
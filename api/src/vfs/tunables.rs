@@ -0,0 +1,242 @@
+//! Runtime tunables of readahead and writeback.
+//!
+//! Each tunable is an atomic behind a named accessor, and writable under
+//! `/proc/sys/vm`. Readahead sizes are set in KiB and kept in pages. The
+//! initial window may not be larger than the maximum one: a write that
+//! would make it so fails with `EINVAL` and changes nothing.
+
+use core::{
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use axerrno::AxError;
+use linkme::distributed_slice;
+use starry_core::sysctl::{SYSCTLS, Sysctl, SysctlKind};
+
+use super::readahead::{PAGE_SIZE, RA_HARD_MAX_PAGES};
+
+/// Number of whole pages in `kb` KiB, at least one
+const fn kb_to_pages(kb: u64) -> u32 {
+    let pages = kb * 1024 / PAGE_SIZE;
+    if pages == 0 { 1 } else { pages as u32 }
+}
+
+const fn pages_to_kb(pages: u32) -> u64 {
+    pages as u64 * PAGE_SIZE / 1024
+}
+
+/// Initial readahead size in pages (128KB)
+static RA_INIT: AtomicU32 = AtomicU32::new(kb_to_pages(128));
+
+/// Default maximum readahead size in pages (1MB)
+static RA_MAX: AtomicU32 = AtomicU32::new(kb_to_pages(1024));
+
+/// Largest gap in bytes between reads still taken as sequential (8KB)
+static RA_SEQ_GAP: AtomicU64 = AtomicU64::new(8 * 1024);
+
+static RA_THRASH_PERCENT: AtomicU32 = AtomicU32::new(25);
+
+/// Initial readahead size in pages, tunable via
+/// `/proc/sys/vm/readahead_init_kb`
+pub fn ra_init_pages() -> u32 {
+    RA_INIT.load(Ordering::Relaxed)
+}
+
+/// Current maximum readahead size in pages, tunable via
+/// `/proc/sys/vm/max_readahead_kb`
+pub fn ra_max_pages() -> u32 {
+    RA_MAX.load(Ordering::Relaxed)
+}
+
+/// Largest gap in bytes between reads still taken as sequential, tunable
+/// via `/proc/sys/vm/readahead_seq_gap_kb`
+pub fn ra_seq_gap() -> u64 {
+    RA_SEQ_GAP.load(Ordering::Relaxed)
+}
+
+/// Share of the reads into a window, in percent, finding their page evicted
+/// that counts as thrashing, tunable via `/proc/sys/vm/readahead_thrash_percent`
+pub fn ra_thrash_percent() -> u32 {
+    RA_THRASH_PERCENT.load(Ordering::Relaxed)
+}
+
+#[distributed_slice(SYSCTLS)]
+static RA_INIT_SYSCTL: Sysctl = Sysctl {
+    path: "vm/readahead_init_kb",
+    mode: 0o644,
+    kind: SysctlKind::CheckedUint {
+        get: || pages_to_kb(ra_init_pages()),
+        set: |kb| {
+            let pages = kb_to_pages(kb);
+            if pages > ra_max_pages() {
+                return Err(AxError::InvalidInput);
+            }
+            RA_INIT.store(pages, Ordering::Relaxed);
+            Ok(())
+        },
+        min: PAGE_SIZE / 1024,
+        max: pages_to_kb(RA_HARD_MAX_PAGES),
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static RA_MAX_SYSCTL: Sysctl = Sysctl {
+    path: "vm/max_readahead_kb",
+    mode: 0o644,
+    kind: SysctlKind::CheckedUint {
+        get: || pages_to_kb(ra_max_pages()),
+        set: |kb| {
+            let pages = kb_to_pages(kb);
+            if pages < ra_init_pages() {
+                return Err(AxError::InvalidInput);
+            }
+            RA_MAX.store(pages, Ordering::Relaxed);
+            Ok(())
+        },
+        min: PAGE_SIZE / 1024,
+        max: pages_to_kb(RA_HARD_MAX_PAGES),
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static RA_SEQ_GAP_SYSCTL: Sysctl = Sysctl {
+    path: "vm/readahead_seq_gap_kb",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || ra_seq_gap() / 1024,
+        set: |kb| RA_SEQ_GAP.store(kb * 1024, Ordering::Relaxed),
+        min: 0,
+        max: pages_to_kb(RA_HARD_MAX_PAGES),
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static RA_THRASH_SYSCTL: Sysctl = Sysctl {
+    path: "vm/readahead_thrash_percent",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || ra_thrash_percent() as u64,
+        set: |percent| RA_THRASH_PERCENT.store(percent as u32, Ordering::Relaxed),
+        min: 1,
+        max: 100,
+    },
+};
+
+static DIRTY_BACKGROUND_RATIO: AtomicU32 = AtomicU32::new(10);
+static DIRTY_RATIO: AtomicU32 = AtomicU32::new(20);
+static DIRTY_BACKGROUND_BYTES: AtomicU64 = AtomicU64::new(0);
+static DIRTY_BYTES: AtomicU64 = AtomicU64::new(0);
+static DIRTY_WRITEBACK_CENTISECS: AtomicU32 = AtomicU32::new(500);
+static DIRTY_EXPIRE_CENTISECS: AtomicU32 = AtomicU32::new(3000);
+
+/// Percent of RAM that may be dirty before the flusher starts early,
+/// tunable via `/proc/sys/vm/dirty_background_ratio`
+pub fn dirty_background_ratio() -> u32 {
+    DIRTY_BACKGROUND_RATIO.load(Ordering::Relaxed)
+}
+
+/// Percent of RAM that may be dirty before writers are held back, tunable
+/// via `/proc/sys/vm/dirty_ratio`
+pub fn dirty_ratio() -> u32 {
+    DIRTY_RATIO.load(Ordering::Relaxed)
+}
+
+/// Bytes that may be dirty before the flusher starts early, 0 to go by
+/// the ratio, tunable via `/proc/sys/vm/dirty_background_bytes`
+pub fn dirty_background_bytes() -> u64 {
+    DIRTY_BACKGROUND_BYTES.load(Ordering::Relaxed)
+}
+
+/// Bytes that may be dirty before writers are held back, 0 to go by the
+/// ratio, tunable via `/proc/sys/vm/dirty_bytes`
+pub fn dirty_bytes() -> u64 {
+    DIRTY_BYTES.load(Ordering::Relaxed)
+}
+
+/// Interval of the flusher, or `None` if it only runs when woken, tunable
+/// via `/proc/sys/vm/dirty_writeback_centisecs`
+pub fn dirty_writeback_interval() -> Option<Duration> {
+    match DIRTY_WRITEBACK_CENTISECS.load(Ordering::Relaxed) {
+        0 => None,
+        centisecs => Some(Duration::from_millis(centisecs as u64 * 10)),
+    }
+}
+
+/// How long a page may stay dirty before the flusher writes it back,
+/// tunable via `/proc/sys/vm/dirty_expire_centisecs`
+pub fn dirty_expire() -> Duration {
+    Duration::from_millis(DIRTY_EXPIRE_CENTISECS.load(Ordering::Relaxed) as u64 * 10)
+}
+
+#[distributed_slice(SYSCTLS)]
+static DIRTY_BACKGROUND_RATIO_SYSCTL: Sysctl = Sysctl {
+    path: "vm/dirty_background_ratio",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || dirty_background_ratio() as _,
+        set: |value| DIRTY_BACKGROUND_RATIO.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: 100,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static DIRTY_RATIO_SYSCTL: Sysctl = Sysctl {
+    path: "vm/dirty_ratio",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || dirty_ratio() as _,
+        set: |value| DIRTY_RATIO.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: 100,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static DIRTY_BACKGROUND_BYTES_SYSCTL: Sysctl = Sysctl {
+    path: "vm/dirty_background_bytes",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: dirty_background_bytes,
+        set: |value| DIRTY_BACKGROUND_BYTES.store(value, Ordering::Relaxed),
+        min: 0,
+        max: u64::MAX,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static DIRTY_BYTES_SYSCTL: Sysctl = Sysctl {
+    path: "vm/dirty_bytes",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: dirty_bytes,
+        set: |value| DIRTY_BYTES.store(value, Ordering::Relaxed),
+        min: 0,
+        max: u64::MAX,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static DIRTY_WRITEBACK_CENTISECS_SYSCTL: Sysctl = Sysctl {
+    path: "vm/dirty_writeback_centisecs",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || DIRTY_WRITEBACK_CENTISECS.load(Ordering::Relaxed) as _,
+        set: |value| DIRTY_WRITEBACK_CENTISECS.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: u32::MAX as _,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static DIRTY_EXPIRE_CENTISECS_SYSCTL: Sysctl = Sysctl {
+    path: "vm/dirty_expire_centisecs",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || DIRTY_EXPIRE_CENTISECS.load(Ordering::Relaxed) as _,
+        set: |value| DIRTY_EXPIRE_CENTISECS.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: u32::MAX as _,
+    },
+};
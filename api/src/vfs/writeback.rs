@@ -0,0 +1,505 @@
+//! Dirty page tracking and writeback of regular files.
+//!
+//! The page cache does not tell the file layer which of its pages differ
+//! from the backend, so the file layer keeps its own record: buffered writes
+//! and write faults on shared mappings mark the pages they touch in the
+//! [`Writeback`] of the inode. Writing back reads each run of dirty pages
+//! from the page cache and writes it straight to the backend, in offset
+//! order and at most [`WB_BATCH_PAGES`] at a time, so that only what changed
+//! is written.
+//!
+//! Stores through a shared mapping fault at most on the first write to a
+//! page, and later ones go unseen. Once an inode is mapped shared, syncing
+//! it also writes out its whole page cache, as before; `msync` instead
+//! takes the pages its caller has mapped writable as dirty.
+//!
+//! A failed writeback is recorded in an error sequence, in the spirit of
+//! Linux's `errseq_t`: each open file reports the errors recorded since it
+//! last looked once, from `fsync` or `fdatasync`.
+//!
+//! Dirty pages do not wait for a sync forever. A flusher task wakes every
+//! `vm/dirty_writeback_centisecs` and writes back the inodes with pages
+//! dirty for longer than `vm/dirty_expire_centisecs`, and it is woken early
+//! once the dirty pages pass the background limit, to write back the
+//! inodes dirtied first until they are under it again. Past the hard limit,
+//! writers wait for it to catch up. The limits are set as a percent of RAM
+//! by `vm/dirty_background_ratio` and `vm/dirty_ratio`, or in bytes by
+//! `vm/dirty_background_bytes` and `vm/dirty_bytes` when those are not 0.
+//!
+//! The flusher goes by I/O priority first: each inode remembers the highest
+//! priority of the tasks that dirtied it since it was last clean, and
+//! inodes of a higher one are written back before those dirtied first.
+//! Writers in the idle class are held back from the background limit on,
+//! so that a background job does not fill the page cache with dirty pages.
+//!
+//! The flusher writes under the freeze lock of the filesystem and leaves
+//! frozen ones alone; freezing writes back their dirty pages first.
+
+use alloc::{
+    collections::btree_map::{BTreeMap, Entry},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    future::poll_fn,
+    ops::Deref,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    task::Poll,
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult};
+use axfs::FileBackend;
+use axfs_ng_vfs::Location;
+use axhal::time::monotonic_time;
+use axpoll::PollSet;
+use axsync::Mutex;
+use axtask::future::{block_on, interruptible};
+use kspin::SpinNoPreempt;
+use lazy_static::lazy_static;
+use starry_core::{
+    ioprio::{IoPrio, IoPrioClass, current_ioprio},
+    timer::timeout,
+};
+
+use super::{
+    freeze::{FreezeLock, freeze_lock},
+    readahead::PAGE_SIZE,
+    size_lock::size_lock,
+    tunables::{
+        dirty_background_bytes, dirty_background_ratio, dirty_bytes, dirty_expire, dirty_ratio,
+        dirty_writeback_interval,
+    },
+};
+use crate::oom::total_pages;
+
+/// Most pages written back with one write to the backend
+pub const WB_BATCH_PAGES: u32 = 16;
+
+/// Dirty pages of all inodes
+static DIRTY_PAGES: AtomicUsize = AtomicUsize::new(0);
+/// Pages being written back
+static WRITEBACK_PAGES: AtomicUsize = AtomicUsize::new(0);
+/// Pages written back since boot
+static WRITTEN: AtomicU64 = AtomicU64::new(0);
+/// Writers held back for the flusher to catch up since boot
+static THROTTLED: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the dirty pages of all inodes.
+pub fn dirty_pages() -> usize {
+    DIRTY_PAGES.load(Ordering::Relaxed)
+}
+
+/// Snapshot of the writeback counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WritebackStats {
+    /// Pages dirty now
+    pub dirty: usize,
+    /// Pages being written back now
+    pub writeback: usize,
+    /// Pages written back since boot
+    pub written: u64,
+    /// Writers held back past the hard limit since boot
+    pub throttled: u64,
+}
+
+/// Returns the writeback counters.
+pub fn writeback_stats() -> WritebackStats {
+    WritebackStats {
+        dirty: DIRTY_PAGES.load(Ordering::Relaxed),
+        writeback: WRITEBACK_PAGES.load(Ordering::Relaxed),
+        written: WRITTEN.load(Ordering::Relaxed),
+        throttled: THROTTLED.load(Ordering::Relaxed),
+    }
+}
+
+/// Returns a limit on the dirty pages, set in `bytes` or else as a `ratio`
+/// percent of RAM.
+fn limit(bytes: u64, ratio: u32) -> usize {
+    match bytes {
+        0 => total_pages().saturating_mul(ratio as usize) / 100,
+        bytes => bytes.div_ceil(PAGE_SIZE) as usize,
+    }
+}
+
+/// Returns the dirty pages past which writers are held back.
+fn hard_limit() -> usize {
+    limit(dirty_bytes(), dirty_ratio()).max(1)
+}
+
+/// Returns the dirty pages past which the flusher starts early, kept under
+/// the hard limit.
+fn background_limit() -> usize {
+    let hard = hard_limit();
+    match limit(dirty_background_bytes(), dirty_background_ratio()) {
+        background if background >= hard => hard / 2,
+        background => background,
+    }
+}
+
+/// The last writeback error of an inode and how many were recorded.
+struct ErrSeq {
+    seq: u32,
+    error: Option<AxError>,
+}
+
+/// The dirty pages and writeback errors of one inode.
+pub struct Writeback {
+    /// The inode, by `(device, inode)`
+    key: (u64, u64),
+    /// The page cache of the inode
+    backend: FileBackend,
+    /// The freeze lock of the filesystem of the inode
+    freeze: Arc<FreezeLock>,
+    /// Dirty pages, with when each was first dirtied
+    dirty: SpinNoPreempt<BTreeMap<u32, Duration>>,
+    /// Whether the inode was mapped shared, so stores may have gone unseen
+    mapped_write: AtomicBool,
+    /// Highest I/O priority of the tasks that dirtied pages since the inode
+    /// was last clean
+    ioprio: SpinNoPreempt<IoPrio>,
+    errseq: SpinNoPreempt<ErrSeq>,
+    /// Held for a whole writeback, so that a sync waits for the pages taken
+    /// by one in progress to reach the backend
+    running: Mutex<()>,
+}
+
+impl Writeback {
+    fn new(key: (u64, u64), backend: FileBackend) -> Self {
+        Self {
+            key,
+            freeze: freeze_lock(backend.location()),
+            backend,
+            dirty: SpinNoPreempt::new(BTreeMap::new()),
+            mapped_write: AtomicBool::new(false),
+            ioprio: SpinNoPreempt::new(IoPrio::DEFAULT),
+            errseq: SpinNoPreempt::new(ErrSeq {
+                seq: 0,
+                error: None,
+            }),
+            running: Mutex::new(()),
+        }
+    }
+
+    /// Marks `start_page..start_page + num_pages` dirty.
+    pub fn mark_dirty(&self, start_page: u32, num_pages: u32) {
+        let now = monotonic_time();
+        let end_page = start_page.saturating_add(num_pages);
+        let ioprio = current_ioprio();
+        let mut dirty = self.dirty.lock();
+        let mut prio = self.ioprio.lock();
+        if dirty.is_empty() || ioprio.is_higher_than(*prio) {
+            *prio = ioprio;
+        }
+        drop(prio);
+        let mut added = 0;
+        for page in start_page..end_page {
+            if let Entry::Vacant(entry) = dirty.entry(page) {
+                entry.insert(now);
+                added += 1;
+            }
+        }
+        drop(dirty);
+        if DIRTY_PAGES.fetch_add(added, Ordering::Relaxed) + added > background_limit() {
+            kick_flusher();
+        }
+    }
+
+    /// Records that the inode is mapped shared.
+    pub fn set_mapped_shared(&self) {
+        self.mapped_write.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether stores through shared mappings may have dirtied pages that
+    /// are not marked.
+    pub fn mapped_write(&self) -> bool {
+        self.mapped_write.load(Ordering::Relaxed)
+    }
+
+    /// Whether any page is dirty.
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.lock().is_empty()
+    }
+
+    /// Returns when the page dirty for longest was dirtied.
+    fn oldest(&self) -> Option<Duration> {
+        self.dirty.lock().values().min().copied()
+    }
+
+    /// Returns the highest I/O priority of the tasks that dirtied pages
+    /// since the inode was last clean.
+    fn ioprio(&self) -> IoPrio {
+        *self.ioprio.lock()
+    }
+
+    /// Forgets the dirty pages from `start_page` on, which a truncate
+    /// dropped.
+    pub fn forget(&self, start_page: u32) {
+        let dropped = self.dirty.lock().split_off(&start_page).len();
+        DIRTY_PAGES.fetch_sub(dropped, Ordering::Relaxed);
+    }
+
+    /// Returns the error sequence, for an open file to report the errors
+    /// recorded after it.
+    pub fn errseq(&self) -> u32 {
+        self.errseq.lock().seq
+    }
+
+    fn record_error(&self, error: AxError) {
+        let mut errseq = self.errseq.lock();
+        errseq.seq = errseq.seq.wrapping_add(1);
+        errseq.error = Some(error);
+    }
+
+    /// Fails with the last error if any was recorded since `seen`, which is
+    /// moved past it.
+    pub fn check_error(&self, seen: &AtomicU32) -> AxResult<()> {
+        let errseq = self.errseq.lock();
+        if seen.swap(errseq.seq, Ordering::Relaxed) == errseq.seq {
+            return Ok(());
+        }
+        Err(errseq.error.unwrap_or(AxError::Io))
+    }
+
+    /// Takes the first run of dirty pages in `start_page..end_page`, of at
+    /// most [`WB_BATCH_PAGES`] pages.
+    fn take_run(&self, start_page: u32, end_page: u32) -> Option<(u32, u32)> {
+        let mut dirty = self.dirty.lock();
+        let (&start, _) = dirty.range(start_page..end_page).next()?;
+        let mut count = 0;
+        while count < WB_BATCH_PAGES {
+            match start.checked_add(count) {
+                Some(page) if page < end_page && dirty.remove(&page).is_some() => count += 1,
+                _ => break,
+            }
+        }
+        DIRTY_PAGES.fetch_sub(count as usize, Ordering::Relaxed);
+        WRITEBACK_PAGES.fetch_add(count as usize, Ordering::Relaxed);
+        Some((start, count))
+    }
+
+    /// Writes back the dirty pages in `start_page..end_page`, in offset
+    /// order. The pages are clean afterwards even if writing them failed,
+    /// which is recorded in the error sequence and returned.
+    pub fn write_back(&self, start_page: u32, end_page: u32) -> AxResult<()> {
+        let _running = self.running.lock();
+        let loc = self.backend.location();
+        // A write past the end left by a truncate would extend the file.
+        size_lock(loc).shared(|| {
+            let size = loc.len()?;
+            let target = FileBackend::Direct(loc.clone());
+            let mut buf = Vec::new();
+            let mut result = Ok(());
+            while let Some((start, count)) = self.take_run(start_page, end_page) {
+                let offset = start as u64 * PAGE_SIZE;
+                let len = (count as u64 * PAGE_SIZE).min(size.saturating_sub(offset));
+                buf.resize(len as usize, 0);
+                let written = self.write_run(&target, &mut buf, offset);
+                WRITEBACK_PAGES.fetch_sub(count as usize, Ordering::Relaxed);
+                WRITTEN.fetch_add(count as u64, Ordering::Relaxed);
+                if let Err(err) = written {
+                    self.record_error(err);
+                    result = result.and(Err(err));
+                }
+            }
+            result
+        })
+    }
+
+    /// Waits for a writeback in progress to finish.
+    pub fn wait(&self) {
+        drop(self.running.lock());
+    }
+
+    /// Copies `buf.len()` bytes at `offset` from the page cache to `target`.
+    fn write_run(&self, target: &FileBackend, buf: &mut [u8], offset: u64) -> AxResult<()> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self
+                .backend
+                .read_at(&mut &mut buf[read..], offset + read as u64)?
+            {
+                0 => break,
+                n => read += n,
+            }
+        }
+        let mut written = 0;
+        while written < read {
+            match target.write_at(&mut &buf[written..read], offset + written as u64)? {
+                0 => return Err(AxError::Io),
+                n => written += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writeback state by `(device, inode)`, kept while an inode has dirty
+/// pages or a [`WritebackRef`] to it.
+static WRITEBACKS: Mutex<BTreeMap<(u64, u64), Arc<Writeback>>> = Mutex::new(BTreeMap::new());
+
+/// A reference to the writeback state of an inode. The last one to go drops
+/// the state from [`WRITEBACKS`] if the inode is clean; otherwise the
+/// flusher does once it has written the inode back.
+pub struct WritebackRef(Arc<Writeback>);
+
+impl Clone for WritebackRef {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl Deref for WritebackRef {
+    type Target = Writeback;
+
+    fn deref(&self) -> &Writeback {
+        &self.0
+    }
+}
+
+impl Drop for WritebackRef {
+    fn drop(&mut self) {
+        let mut writebacks = WRITEBACKS.lock();
+        // This reference and the table's are the last ones. Only holders
+        // dirty pages or take new references outside the table lock, so
+        // neither can happen after the check.
+        if Arc::strong_count(&self.0) == 2 && !self.0.is_dirty() {
+            writebacks.remove(&self.0.key);
+        }
+    }
+}
+
+/// Returns the writeback state of the inode at `loc`, whose page cache is
+/// `backend`.
+pub fn writeback(loc: &Location, backend: &FileBackend) -> WritebackRef {
+    let key = (loc.mountpoint().device() as u64, loc.inode());
+    let writeback = WRITEBACKS
+        .lock()
+        .entry(key)
+        .or_insert_with(|| Arc::new(Writeback::new(key, backend.clone())))
+        .clone();
+    WritebackRef(writeback)
+}
+
+/// Writes back every inode on `device`, for a freeze, which holds new
+/// writes off afterwards.
+pub fn write_back_device(device: u64) -> AxResult<()> {
+    let inodes = WRITEBACKS
+        .lock()
+        .iter()
+        .filter(|((dev, _), _)| *dev == device)
+        .map(|(_, writeback)| WritebackRef(writeback.clone()))
+        .collect::<Vec<_>>();
+    let mut result = Ok(());
+    for writeback in inodes {
+        result = result.and(writeback.write_back(0, u32::MAX));
+    }
+    result
+}
+
+struct Flusher {
+    /// Set to have the flusher run before its interval is up
+    kicked: AtomicBool,
+    /// Woken when the flusher is kicked
+    poll_kick: PollSet,
+    /// Woken after each pass of the flusher
+    poll_flushed: PollSet,
+}
+
+lazy_static! {
+    static ref FLUSHER: Flusher = Flusher {
+        kicked: AtomicBool::new(false),
+        poll_kick: PollSet::new(),
+        poll_flushed: PollSet::new(),
+    };
+}
+
+fn kick_flusher() {
+    if !FLUSHER.kicked.swap(true, Ordering::AcqRel) {
+        FLUSHER.poll_kick.wake();
+    }
+}
+
+/// Writes back inodes while the dirty pages are over the background limit,
+/// then those with pages dirty past the expiry, in order of the I/O
+/// priority of their dirtiers and then of when they were dirtied.
+fn flush() {
+    let now = monotonic_time();
+    let expire = dirty_expire();
+    let mut inodes = WRITEBACKS
+        .lock()
+        .values()
+        .filter_map(|writeback| {
+            let since = writeback.oldest()?;
+            Some((writeback.ioprio(), since, WritebackRef(writeback.clone())))
+        })
+        .collect::<Vec<_>>();
+    inodes.sort_by_key(|(ioprio, since, _)| (ioprio.rank(), *since));
+    for (_, since, writeback) in inodes {
+        if dirty_pages() <= background_limit() && now.saturating_sub(since) < expire {
+            continue;
+        }
+        // Failures land in the error sequence, for the next sync. A frozen
+        // filesystem was written back when it was frozen, and what got
+        // dirty since waits for the thaw.
+        let _ = writeback
+            .freeze
+            .try_write(|| writeback.write_back(0, u32::MAX));
+        FLUSHER.poll_flushed.wake();
+    }
+}
+
+fn flusher() {
+    loop {
+        let _ = block_on(timeout(
+            dirty_writeback_interval(),
+            poll_fn(|cx| {
+                if FLUSHER.kicked.swap(false, Ordering::AcqRel) {
+                    return Poll::Ready(());
+                }
+                FLUSHER.poll_kick.register(cx.waker());
+                if FLUSHER.kicked.swap(false, Ordering::AcqRel) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }),
+        ));
+        flush();
+        FLUSHER.poll_flushed.wake();
+    }
+}
+
+/// Spawns the flusher task.
+pub fn spawn_flusher() {
+    axtask::spawn_with_name(flusher, "writeback".into());
+}
+
+/// Blocks the current task while the dirty pages are over the hard limit,
+/// or the background one for a task in the idle I/O class, for the flusher
+/// to catch up. Called after dirtying pages, with no lock held.
+///
+/// A signal ends the wait early; the write is done by then, and the signal
+/// is handled on the way back to user space.
+pub fn throttle_dirtier() {
+    let limit = match current_ioprio().class() {
+        IoPrioClass::Idle => background_limit(),
+        _ => hard_limit(),
+    };
+    if dirty_pages() <= limit {
+        return;
+    }
+    THROTTLED.fetch_add(1, Ordering::Relaxed);
+    let _ = block_on(interruptible(poll_fn(|cx| {
+        if dirty_pages() <= limit {
+            return Poll::Ready(());
+        }
+        FLUSHER.poll_flushed.register(cx.waker());
+        if dirty_pages() <= limit {
+            Poll::Ready(())
+        } else {
+            kick_flusher();
+            Poll::Pending
+        }
+    })));
+}
@@ -0,0 +1,133 @@
+//! Kernel critical sections that hold off signal-driven work.
+//!
+//! File code that mutates state under a lock can reach code taking the same
+//! lock again through the side effects of that mutation: a wake inserting
+//! into an epoll ready list, a signal sent to the task itself, a fanotify
+//! event. Such code runs inside a [`CriticalSection`], and the side effects
+//! go through [`defer`]: while the current task holds a section they are
+//! queued, and they run when its outermost section ends, with the locks
+//! released. This is kernel-internal and unrelated to the signal mask seen
+//! by userspace.
+//!
+//! Sections carry a [`LockLevel`] and nest in strictly increasing order.
+//! Debug builds assert the order, and that nothing blocks inside a section,
+//! so that a misordered path fails loudly instead of deadlocking now and
+//! then.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use axtask::current;
+use kspin::SpinNoIrq;
+
+use crate::task::AsThread;
+
+/// The order critical sections nest in: a section may only be entered
+/// while holding sections of lower levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum LockLevel {
+    /// An epoll instance, which polls the files added to it.
+    Epoll = 0,
+    /// The state of a file: a pipe, socket, eventfd or timerfd.
+    File  = 1,
+    /// A wait queue or poll set, taken last.
+    Wake  = 2,
+}
+
+type Deferred = Box<dyn FnOnce() + Send>;
+
+/// The critical sections of a thread and the work they hold off.
+pub(crate) struct CriticalState {
+    /// Bit `n` is set while a section of level `n` is held.
+    held: AtomicU32,
+    deferred: SpinNoIrq<Vec<Deferred>>,
+}
+
+impl CriticalState {
+    pub(crate) const fn new() -> Self {
+        Self {
+            held: AtomicU32::new(0),
+            deferred: SpinNoIrq::new(Vec::new()),
+        }
+    }
+}
+
+/// A critical section of the current task, ended on drop.
+///
+/// On kernel tasks, which never run signal-driven work, this does nothing.
+pub struct CriticalSection {
+    level: Option<LockLevel>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl CriticalSection {
+    /// Enters a section of `level`.
+    pub fn enter(level: LockLevel) -> Self {
+        let curr = current();
+        let level = curr.try_as_thread().map(|thr| {
+            let bit = 1 << level as u32;
+            let held = thr.critical.held.fetch_or(bit, Ordering::Relaxed);
+            debug_assert!(
+                held < bit,
+                "critical section {level:?} entered out of order (held: {held:#b})"
+            );
+            level
+        });
+        Self {
+            level,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for CriticalSection {
+    fn drop(&mut self) {
+        let Some(level) = self.level else {
+            return;
+        };
+        let curr = current();
+        let state = &curr.as_thread().critical;
+        let held = state
+            .held
+            .fetch_and(!(1 << level as u32), Ordering::Relaxed);
+        if held != 1 << level as u32 {
+            return;
+        }
+        // The outermost section ended. The work may defer more, which then
+        // runs right away.
+        let deferred = core::mem::take(&mut *state.deferred.lock());
+        for f in deferred {
+            f();
+        }
+    }
+}
+
+/// Runs `f` once the current task holds no critical section: right away if
+/// it holds none now.
+pub fn defer(f: impl FnOnce() + Send + 'static) {
+    let curr = current();
+    match curr.try_as_thread() {
+        Some(thr) if thr.critical.held.load(Ordering::Relaxed) != 0 => {
+            thr.critical.deferred.lock().push(Box::new(f));
+        }
+        _ => f(),
+    }
+}
+
+/// Asserts in debug builds that the current task holds no critical section,
+/// before it does something that may block.
+pub fn assert_outside(what: &str) {
+    if cfg!(debug_assertions)
+        && let Some(thr) = current().try_as_thread()
+    {
+        let held = thr.critical.held.load(Ordering::Relaxed);
+        debug_assert!(
+            held == 0,
+            "{what} inside a critical section (held: {held:#b})"
+        );
+    }
+}
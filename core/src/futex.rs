@@ -5,29 +5,25 @@ use alloc::{
     sync::{Arc, Weak},
     vec::Vec,
 };
-use core::{
-    future::poll_fn,
-    ops::Deref,
-    sync::atomic::AtomicBool,
-    task::{Poll, Waker},
-    time::Duration,
-};
+use core::{ops::Deref, task::Waker, time::Duration};
 
-use axerrno::AxResult;
+use axerrno::{AxError, AxResult};
+use axhal::time::monotonic_time;
 use axmm::{
     AddrSpace,
     backend::{Backend, SharedPages},
 };
 use axsync::Mutex;
-use axtask::{
-    current,
-    future::{self, block_on, interruptible},
-};
+use axtask::current;
 use hashbrown::HashMap;
 use kspin::SpinNoIrq;
 use memory_addr::VirtAddr;
 
-use crate::task::AsThread;
+use crate::{
+    park::{TaskParker, WakeReason},
+    task::AsThread,
+    timer,
+};
 
 /// Wait queue used by futex.
 #[derive(Default)]
@@ -50,23 +46,37 @@ impl WaitQueue {
         timeout: Option<Duration>,
         condition: impl FnOnce() -> bool,
     ) -> AxResult<bool> {
-        let mut condition = Some(condition);
-        block_on(interruptible(future::timeout(
-            timeout,
-            poll_fn(|cx| {
-                if let Some(cond) = condition.take() {
-                    let mut queue = self.queue.lock();
-                    if !cond() {
-                        Poll::Ready(Ok(false))
-                    } else {
-                        queue.push_back((cx.waker().clone(), bitset));
-                        Poll::Pending
-                    }
-                } else {
-                    Poll::Ready(Ok(true))
-                }
-            }),
-        )))??
+        let parker = TaskParker::new();
+        let event = parker.waker(WakeReason::EVENT);
+        {
+            let mut queue = self.queue.lock();
+            if !condition() {
+                return Ok(false);
+            }
+            queue.push_back((event.clone(), bitset));
+        }
+        let timer = timeout.map(|timeout| {
+            timer::register_waker(monotonic_time() + timeout, parker.waker(WakeReason::TIMER))
+        });
+        parker.watch_signals();
+        // A wake beats the deadline, which beats a signal.
+        let reasons = parker.park();
+        if let Some(timer) = timer {
+            timer.cancel();
+        }
+        if reasons.contains(WakeReason::EVENT) {
+            return Ok(true);
+        }
+        // Not woken, so we are still queued and must not take a later wake
+        // meant for someone else.
+        self.queue
+            .lock()
+            .retain(|(waker, _)| !waker.will_wake(&event));
+        if reasons.contains(WakeReason::TIMER) {
+            Err(AxError::TimedOut)
+        } else {
+            Err(AxError::Interrupted)
+        }
     }
 
     /// Wakes up at most `count` tasks whose bitset intersects with the given
@@ -162,16 +172,12 @@ impl FutexKey {
 pub struct FutexEntry {
     /// The wait queue associated with this futex.
     pub wq: WaitQueue,
-
-    /// Used by robust list, indicates if the owner of this futex is dead.
-    pub owner_dead: AtomicBool,
 }
 
 impl FutexEntry {
     fn new() -> Self {
         Self {
             wq: WaitQueue::new(),
-            owner_dead: AtomicBool::new(false),
         }
     }
 }
@@ -0,0 +1,105 @@
+//! I/O priorities, as set by `ioprio_set`.
+//!
+//! There is no block request queue to order requests by priority: reads
+//! and writes reach the device in submission order. The priority orders
+//! the I/O done in the background instead: the readahead workers run the
+//! queued readaheads of higher priority first, and the flusher writes back
+//! the inodes dirtied by higher priority tasks first. Readers in the idle
+//! class also get a smaller readahead window, and writers in it are held
+//! back from the background dirty limit on, instead of the hard one.
+
+use axerrno::{AxError, AxResult};
+use axtask::current;
+
+use crate::task::AsThread;
+
+const IOPRIO_CLASS_SHIFT: u16 = 13;
+const IOPRIO_PRIO_MASK: u16 = (1 << IOPRIO_CLASS_SHIFT) - 1;
+
+/// Number of levels in the real-time and best-effort classes.
+pub const IOPRIO_NR_LEVELS: u16 = 8;
+
+/// An I/O scheduling class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u16)]
+pub enum IoPrioClass {
+    /// No priority set; behaves as best-effort derived from the CPU nice
+    /// value.
+    None       = 0,
+    /// Meant to be served before everything else.
+    RealTime   = 1,
+    /// The default class.
+    BestEffort = 2,
+    /// Meant to be served only when nobody else needs the disk; caps
+    /// readahead.
+    Idle       = 3,
+}
+
+/// An encoded I/O priority: the class in the top bits and the level within
+/// it in the low ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoPrio(pub(crate) u16);
+
+impl IoPrio {
+    /// The priority of tasks that never called `ioprio_set`.
+    pub const DEFAULT: Self = Self(0);
+
+    /// Decodes and validates a priority passed from user space.
+    pub fn from_raw(raw: u32) -> AxResult<Self> {
+        let raw = u16::try_from(raw).map_err(|_| AxError::InvalidInput)?;
+        let level = raw & IOPRIO_PRIO_MASK;
+        match raw >> IOPRIO_CLASS_SHIFT {
+            // Linux ignores the level of IDLE and NONE.
+            0 | 3 => {}
+            1 | 2 if level < IOPRIO_NR_LEVELS => {}
+            _ => return Err(AxError::InvalidInput),
+        }
+        Ok(Self(raw))
+    }
+
+    /// Returns the encoded value.
+    pub fn raw(self) -> u16 {
+        self.0
+    }
+
+    /// Returns the scheduling class.
+    pub fn class(self) -> IoPrioClass {
+        match self.0 >> IOPRIO_CLASS_SHIFT {
+            1 => IoPrioClass::RealTime,
+            2 => IoPrioClass::BestEffort,
+            3 => IoPrioClass::Idle,
+            _ => IoPrioClass::None,
+        }
+    }
+
+    /// Returns the level within the class, 0 being the highest.
+    pub fn level(self) -> u16 {
+        self.0 & IOPRIO_PRIO_MASK
+    }
+
+    /// Returns the rank of this priority, lower ranks being served first:
+    /// by class, then by level within it.
+    pub fn rank(self) -> (IoPrioClass, u16) {
+        match self.class() {
+            // Without an explicit priority, nice 0 maps to best-effort level 4.
+            IoPrioClass::None => (IoPrioClass::BestEffort, 4),
+            // The idle class has no levels.
+            IoPrioClass::Idle => (IoPrioClass::Idle, 0),
+            class => (class, self.level()),
+        }
+    }
+
+    /// Returns whether this priority is served before `other`, as used by
+    /// `ioprio_get` to report the best of several tasks.
+    pub fn is_higher_than(self, other: Self) -> bool {
+        self.rank() < other.rank()
+    }
+}
+
+/// Returns the I/O priority of the current task, or the default one for a
+/// kernel task.
+pub fn current_ioprio() -> IoPrio {
+    current()
+        .try_as_thread()
+        .map_or(IoPrio::DEFAULT, |thr| thr.ioprio())
+}
@@ -11,10 +11,17 @@ extern crate alloc;
 extern crate axlog;
 
 pub mod config;
+pub mod critical;
 pub mod futex;
+pub mod ioprio;
 pub mod mm;
+pub mod msg;
+pub mod park;
 pub mod resources;
 pub mod shm;
+pub mod swap;
+pub mod sysctl;
 pub mod task;
 pub mod time;
+pub mod timer;
 pub mod vfs;
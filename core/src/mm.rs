@@ -1,12 +1,14 @@
 //! User address space management.
 
-use alloc::{borrow::ToOwned, string::String, vec, vec::Vec};
+use alloc::{
+    borrow::ToOwned, collections::btree_map::BTreeMap, string::String, sync::Arc, vec, vec::Vec,
+};
 use core::{
     ffi::CStr,
     hint::unlikely,
     iter,
     mem::MaybeUninit,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
 };
 
 use axerrno::{AxError, AxResult};
@@ -22,12 +24,17 @@ use axsync::Mutex;
 use extern_trait::extern_trait;
 use kernel_elf_parser::{AuxEntry, ELFHeaders, ELFHeadersBuilder, ELFParser, app_stack_region};
 use kernel_guard::IrqSave;
-use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr};
+use linkme::distributed_slice;
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, VirtAddr, VirtAddrRange};
 use ouroboros::self_referencing;
 use starry_vm::{VmError, VmIo, VmResult};
 use uluru::LRUCache;
 
-use crate::config::{USER_SPACE_BASE, USER_SPACE_SIZE};
+use crate::{
+    config::{USER_SPACE_BASE, USER_SPACE_SIZE},
+    sysctl::{SYSCTLS, Sysctl, SysctlKind},
+    vfs::MountUse,
+};
 
 /// Creates a new empty user address space.
 pub fn new_user_aspace_empty() -> AxResult<AddrSpace> {
@@ -173,6 +180,11 @@ impl ElfCacheEntry {
             Err((_, heads)) => Ok(Err(heads.data)),
         }
     }
+
+    /// Returns the pages taken by the headers kept.
+    fn pages(&self) -> usize {
+        self.borrow_data().len().div_ceil(PAGE_SIZE_4K)
+    }
 }
 
 struct ElfLoader(LRUCache<ElfCacheEntry, 32>);
@@ -264,6 +276,27 @@ pub fn clear_elf_cache() {
     ELF_LOADER.lock().0.clear();
 }
 
+/// Returns the pages the ELF cache keeps.
+pub fn elf_cache_pages() -> usize {
+    ELF_LOADER.lock().0.iter().map(ElfCacheEntry::pages).sum()
+}
+
+/// Drops the least recently used entries of the ELF cache, with the
+/// references to the files they hold, until `nr_pages` pages are freed.
+/// Returns how many were, or 0 if an exec is using the cache.
+pub fn shrink_elf_cache(nr_pages: usize) -> usize {
+    let Some(mut loader) = ELF_LOADER.try_lock() else {
+        return 0;
+    };
+    let mut dropped = 0;
+    while dropped < nr_pages
+        && let Some(entry) = loader.0.pop()
+    {
+        dropped += entry.pages();
+    }
+    dropped
+}
+
 /// Load the user app to the user address space.
 ///
 /// # Arguments
@@ -350,6 +383,357 @@ pub fn load_user_app(
     Ok((entry, user_sp))
 }
 
+/// `personality` flag disabling address space randomization.
+pub const ADDR_NO_RANDOMIZE: u32 = 0x004_0000;
+/// `personality` flag selecting the legacy bottom-up mmap layout.
+pub const ADDR_COMPAT_LAYOUT: u32 = 0x020_0000;
+
+static RANDOMIZE_VA_SPACE: AtomicUsize = AtomicUsize::new(2);
+static LEGACY_VA_LAYOUT: AtomicBool = AtomicBool::new(false);
+
+#[distributed_slice(SYSCTLS)]
+static RANDOMIZE_VA_SPACE_SYSCTL: Sysctl = Sysctl {
+    path: "kernel/randomize_va_space",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || RANDOMIZE_VA_SPACE.load(Ordering::Relaxed) as _,
+        set: |value| RANDOMIZE_VA_SPACE.store(value as _, Ordering::Relaxed),
+        min: 0,
+        max: 2,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static LEGACY_VA_LAYOUT_SYSCTL: Sysctl = Sysctl {
+    path: "vm/legacy_va_layout",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || LEGACY_VA_LAYOUT.load(Ordering::Relaxed) as _,
+        set: |value| LEGACY_VA_LAYOUT.store(value != 0, Ordering::Relaxed),
+        min: 0,
+        max: 1,
+    },
+};
+
+/// Space left below the stack for it to grow into.
+const STACK_GAP: usize = 0x800_0000;
+
+/// Returns a page-aligned random offset, or 0 with randomization off.
+///
+/// This only needs to be unpredictable across execs, so mixing the clock
+/// into a splitmix64 sequence is good enough.
+fn mmap_rnd(randomize: bool) -> usize {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    if !randomize {
+        return 0;
+    }
+    let mut z = STATE.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+        ^ axhal::time::monotonic_time_nanos();
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    // 1/64 of the address space: 2TB on 47-bit spaces, 4GB on 38-bit ones.
+    (z as usize % (USER_SPACE_SIZE >> 6)).align_down_4k()
+}
+
+/// Readahead for the faults on a file mapping, kept by the file layer.
+pub trait FaultAround: Send + Sync {
+    /// Reads ahead for a fault at `offset` in the file, before it is
+    /// handled. Returns how many pages from the faulting one on are cached,
+    /// to be mapped along with it; 1 for just the faulting page.
+    fn fault_around(&self, offset: u64) -> usize;
+}
+
+/// A mapping of a file, tracked for the bookkeeping the page tables know
+/// nothing of.
+#[derive(Clone)]
+pub struct FileMapping {
+    /// The end of the mapping.
+    pub end: VirtAddr,
+    /// The file mapped.
+    pub loc: Location,
+    /// The offset in the file of the start of the mapping.
+    pub offset: u64,
+    /// The page cache of the file.
+    pub backend: FileBackend,
+    /// Whether stores reach the file, rather than private copies.
+    pub shared: bool,
+    /// The readahead for faults on the mapping, if the file has a page
+    /// cache.
+    pub readahead: Option<Arc<dyn FaultAround>>,
+    /// Keeps the mount of the file busy.
+    pub mount: MountUse,
+}
+
+/// The file mappings of a process, by start address.
+#[derive(Clone, Default)]
+pub struct FileMappings(BTreeMap<VirtAddr, FileMapping>);
+
+impl FileMappings {
+    /// Records `mapping` from `start` on, replacing whatever was recorded
+    /// there.
+    pub fn insert(&mut self, start: VirtAddr, mapping: FileMapping) {
+        self.remove(VirtAddrRange::new(start, mapping.end));
+        self.0.insert(start, mapping);
+    }
+
+    /// Forgets what is mapped in `range`, keeping the parts of mappings
+    /// outside it.
+    pub fn remove(&mut self, range: VirtAddrRange) {
+        let overlapping = self
+            .0
+            .range(..range.end)
+            .filter(|(_, mapping)| mapping.end > range.start)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+        for start in overlapping {
+            let mapping = self.0.remove(&start).unwrap();
+            if start < range.start {
+                self.0.insert(
+                    start,
+                    FileMapping {
+                        end: range.start,
+                        ..mapping.clone()
+                    },
+                );
+            }
+            if mapping.end > range.end {
+                let offset = mapping.offset + (range.end.as_usize() - start.as_usize()) as u64;
+                self.0.insert(range.end, FileMapping { offset, ..mapping });
+            }
+        }
+    }
+
+    /// Returns the mapping containing `addr`, along with the offset in the
+    /// file `addr` stands for.
+    pub fn find(&self, addr: VirtAddr) -> Option<(&FileMapping, u64)> {
+        let (start, mapping) = self.0.range(..=addr).next_back()?;
+        (addr < mapping.end).then(|| {
+            (
+                mapping,
+                mapping.offset + (addr.as_usize() - start.as_usize()) as u64,
+            )
+        })
+    }
+
+    /// Iterates over all the mappings, with their start.
+    pub fn iter(&self) -> impl Iterator<Item = (VirtAddr, &FileMapping)> {
+        self.0.iter().map(|(start, mapping)| (*start, mapping))
+    }
+
+    /// Iterates over the mappings overlapping `range`, with their start.
+    pub fn overlapping(
+        &self,
+        range: VirtAddrRange,
+    ) -> impl Iterator<Item = (VirtAddr, &FileMapping)> {
+        self.0
+            .range(..range.end)
+            .filter(move |(_, mapping)| mapping.end > range.start)
+            .map(|(start, mapping)| (*start, mapping))
+    }
+}
+
+static COMMITTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the bytes of memory all processes have committed to.
+pub fn committed() -> usize {
+    COMMITTED.load(Ordering::Relaxed)
+}
+
+/// The ranges of a process whose memory counts against the commit limit:
+/// private writable and shared anonymous mappings, and the heap. Dropping
+/// it gives back what it holds.
+#[derive(Default)]
+pub struct Commitments {
+    /// End of each range, by start address.
+    ranges: BTreeMap<VirtAddr, VirtAddr>,
+    size: usize,
+}
+
+impl Commitments {
+    /// Returns the bytes committed.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Commits `range`, in addition to whatever was committed there. Fails,
+    /// changing nothing, if the total of all processes would exceed `limit`.
+    pub fn insert(&mut self, range: VirtAddrRange, limit: Option<usize>) -> bool {
+        let overlap = self
+            .ranges
+            .range(..range.end)
+            .filter(|(_, end)| **end > range.start)
+            .map(|(start, end)| end.min(&range.end).as_usize() - start.max(&range.start).as_usize())
+            .sum::<usize>();
+        let grow = range.size() - overlap;
+        if !charge(grow, limit) {
+            return false;
+        }
+        self.cut(range);
+        self.ranges.insert(range.start, range.end);
+        self.size += grow;
+        true
+    }
+
+    /// Gives back what is committed in `range`.
+    pub fn remove(&mut self, range: VirtAddrRange) {
+        let removed = self.cut(range);
+        self.size -= removed;
+        COMMITTED.fetch_sub(removed, Ordering::Relaxed);
+    }
+
+    /// Gives back everything.
+    pub fn clear(&mut self) {
+        COMMITTED.fetch_sub(self.size, Ordering::Relaxed);
+        self.ranges.clear();
+        self.size = 0;
+    }
+
+    /// Commits the same ranges again for a copy of the address space, or
+    /// returns `None` if the total would exceed `limit`.
+    pub fn try_clone(&self, limit: Option<usize>) -> Option<Self> {
+        charge(self.size, limit).then(|| Self {
+            ranges: self.ranges.clone(),
+            size: self.size,
+        })
+    }
+
+    /// Forgets `range`, keeping the parts of ranges outside it. Returns the
+    /// bytes forgotten.
+    fn cut(&mut self, range: VirtAddrRange) -> usize {
+        let overlapping = self
+            .ranges
+            .range(..range.end)
+            .filter(|(_, end)| **end > range.start)
+            .map(|(start, end)| (*start, *end))
+            .collect::<Vec<_>>();
+        let mut removed = 0;
+        for (start, end) in overlapping {
+            self.ranges.remove(&start);
+            if start < range.start {
+                self.ranges.insert(start, range.start);
+            }
+            if end > range.end {
+                self.ranges.insert(range.end, end);
+            }
+            removed += end.min(range.end).as_usize() - start.max(range.start).as_usize();
+        }
+        removed
+    }
+}
+
+impl Drop for Commitments {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Adds `size` to the committed total unless it would exceed `limit`.
+fn charge(size: usize, limit: Option<usize>) -> bool {
+    COMMITTED
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |committed| {
+            let total = committed.checked_add(size)?;
+            limit.is_none_or(|limit| total <= limit).then_some(total)
+        })
+        .is_ok()
+}
+
+/// Where mappings without a fixed address are placed, chosen at exec.
+#[derive(Debug, Clone, Copy)]
+pub struct MmapLayout {
+    /// The highest address of top-down allocation, or the lowest of
+    /// bottom-up allocation.
+    pub base: VirtAddr,
+    /// Whether new mappings are placed below `base` going down, rather than
+    /// above it going up.
+    pub top_down: bool,
+}
+
+impl MmapLayout {
+    /// Chooses the layout for a process with the given `personality`.
+    pub fn new(personality: u32) -> Self {
+        let randomize =
+            RANDOMIZE_VA_SPACE.load(Ordering::Relaxed) != 0 && personality & ADDR_NO_RANDOMIZE == 0;
+        let rnd = mmap_rnd(randomize);
+        if personality & ADDR_COMPAT_LAYOUT != 0 || LEGACY_VA_LAYOUT.load(Ordering::Relaxed) {
+            Self {
+                base: VirtAddr::from_usize((USER_SPACE_SIZE / 3).align_down_4k() + rnd),
+                top_down: false,
+            }
+        } else {
+            let stack_bottom = crate::config::USER_STACK_TOP - crate::config::USER_STACK_SIZE;
+            Self {
+                base: VirtAddr::from_usize(stack_bottom - STACK_GAP - rnd),
+                top_down: true,
+            }
+        }
+    }
+
+    /// Finds an unmapped range of `size` bytes aligned to `align` within
+    /// `limit`. `hint` is used as is if the range there is free; otherwise
+    /// the search follows the layout, then falls back to the rest of
+    /// `limit`. Returns `None` only if no room is left in `limit`.
+    pub fn find_area(
+        &self,
+        aspace: &AddrSpace,
+        hint: VirtAddr,
+        size: usize,
+        align: usize,
+        limit: VirtAddrRange,
+    ) -> Option<VirtAddr> {
+        if hint.as_usize() != 0
+            && hint.is_aligned(align)
+            && hint >= limit.start
+            && hint
+                .as_usize()
+                .checked_add(size)
+                .is_some_and(|end| end <= limit.end.as_usize())
+            && aspace.find_free_area(hint, size, limit, align) == Some(hint)
+        {
+            return Some(hint);
+        }
+
+        let base = self.base.clamp(limit.start, limit.end);
+        if self.top_down {
+            find_area_top_down(aspace, VirtAddrRange::new(limit.start, base), size, align)
+                .or_else(|| aspace.find_free_area(base, size, limit, align))
+        } else {
+            aspace.find_free_area(base, size, limit, align).or_else(|| {
+                find_area_top_down(aspace, VirtAddrRange::new(limit.start, base), size, align)
+            })
+        }
+    }
+}
+
+/// Finds the highest unmapped range of `size` bytes aligned to `align`
+/// within `limit`.
+fn find_area_top_down(
+    aspace: &AddrSpace,
+    limit: VirtAddrRange,
+    size: usize,
+    align: usize,
+) -> Option<VirtAddr> {
+    let fits_below = |end: VirtAddr, floor: VirtAddr| {
+        let start = end.as_usize().checked_sub(size)?.align_down(align);
+        (start >= floor.as_usize()).then(|| VirtAddr::from_usize(start))
+    };
+    let areas = aspace
+        .areas()
+        .filter(|area| area.end() > limit.start && area.start() < limit.end)
+        .map(|area| (area.start(), area.end()))
+        .collect::<Vec<_>>();
+    let mut ceiling = limit.end;
+    for (start, end) in areas.into_iter().rev() {
+        if end < ceiling
+            && let Some(addr) = fits_below(ceiling, end)
+        {
+            return Some(addr);
+        }
+        ceiling = ceiling.min(start);
+    }
+    fits_below(ceiling, limit.start)
+}
+
 static ACCESSING_USER_MEM: AtomicBool = AtomicBool::new(false);
 
 /// Enables scoped access into user memory, allowing page faults to occur inside
@@ -0,0 +1,272 @@
+//! System V message queues.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use axerrno::{AxError, AxResult, LinuxError};
+use axpoll::PollSet;
+use axsync::Mutex;
+use linkme::distributed_slice;
+use linux_raw_sys::{
+    ctypes::{c_long, c_ulong},
+    general::*,
+};
+use starry_process::Pid;
+
+use crate::{
+    shm::IpcPerm,
+    sysctl::{SYSCTLS, Sysctl, SysctlKind},
+};
+
+/// Default maximum number of bytes in a queue.
+pub const MSGMNB: usize = 16384;
+/// Default maximum size of a single message.
+pub const MSGMAX: usize = 8192;
+
+static MSG_MNB: AtomicUsize = AtomicUsize::new(MSGMNB);
+static MSG_MAX: AtomicUsize = AtomicUsize::new(MSGMAX);
+
+/// Returns the default `msg_qbytes` of newly created queues.
+pub fn msgmnb() -> usize {
+    MSG_MNB.load(Ordering::Relaxed)
+}
+
+/// Sets the default `msg_qbytes` of newly created queues.
+pub fn set_msgmnb(value: usize) {
+    MSG_MNB.store(value, Ordering::Relaxed);
+}
+
+/// Returns the maximum size of a single message.
+pub fn msgmax() -> usize {
+    MSG_MAX.load(Ordering::Relaxed)
+}
+
+/// Sets the maximum size of a single message.
+pub fn set_msgmax(value: usize) {
+    MSG_MAX.store(value, Ordering::Relaxed);
+}
+
+#[distributed_slice(SYSCTLS)]
+static MSGMNB_SYSCTL: Sysctl = Sysctl {
+    path: "kernel/msgmnb",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || msgmnb() as _,
+        set: |value| set_msgmnb(value as _),
+        min: 0,
+        max: i32::MAX as _,
+    },
+};
+
+#[distributed_slice(SYSCTLS)]
+static MSGMAX_SYSCTL: Sysctl = Sysctl {
+    path: "kernel/msgmax",
+    mode: 0o644,
+    kind: SysctlKind::Uint {
+        get: || msgmax() as _,
+        set: |value| set_msgmax(value as _),
+        min: 0,
+        max: i32::MAX as _,
+    },
+};
+
+/// Data structure describing a message queue.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MsqidDs {
+    /// operation permission struct
+    pub msg_perm: IpcPerm,
+    /// time of last msgsnd()
+    pub msg_stime: __kernel_time_t,
+    /// time of last msgrcv()
+    pub msg_rtime: __kernel_time_t,
+    /// time of last change by msgctl()
+    pub msg_ctime: __kernel_time_t,
+    /// number of bytes currently in the queue
+    pub msg_cbytes: c_ulong,
+    /// number of messages currently in the queue
+    pub msg_qnum: c_ulong,
+    /// maximum number of bytes allowed in the queue
+    pub msg_qbytes: c_ulong,
+    /// pid of last msgsnd()
+    pub msg_lspid: __kernel_pid_t,
+    /// pid of last msgrcv()
+    pub msg_lrpid: __kernel_pid_t,
+    unused4: c_ulong,
+    unused5: c_ulong,
+}
+
+struct Message {
+    mtype: c_long,
+    data: Vec<u8>,
+}
+
+/// How `msgrcv` picks a message from the queue.
+#[derive(Debug, Clone, Copy)]
+pub enum MsgSelector {
+    /// The first message in the queue.
+    First,
+    /// The first message of exactly this type.
+    Exact(c_long),
+    /// The first message whose type differs from this one (`MSG_EXCEPT`).
+    Except(c_long),
+    /// The first message of the lowest type not greater than this one.
+    LowestUpTo(c_long),
+}
+
+/// The mutable state of a message queue.
+pub struct MsgQueueInner {
+    /// c type struct, used in msgctl
+    pub ds: MsqidDs,
+    messages: VecDeque<Message>,
+    /// Whether the queue has been removed with `IPC_RMID`.
+    pub removed: bool,
+}
+
+impl MsgQueueInner {
+    /// Returns whether a message of `len` bytes fits into the queue.
+    pub fn has_room(&self, len: usize) -> bool {
+        let qbytes = self.ds.msg_qbytes as usize;
+        self.ds.msg_cbytes as usize + len <= qbytes && self.messages.len() < qbytes
+    }
+
+    /// Appends a message to the queue.
+    pub fn push(&mut self, mtype: c_long, data: Vec<u8>, pid: Pid, now: __kernel_time_t) {
+        self.ds.msg_cbytes += data.len() as c_ulong;
+        self.ds.msg_qnum += 1;
+        self.ds.msg_lspid = pid as _;
+        self.ds.msg_stime = now;
+        self.messages.push_back(Message { mtype, data });
+    }
+
+    fn find(&self, selector: MsgSelector) -> Option<usize> {
+        let mut iter = self.messages.iter().enumerate();
+        match selector {
+            MsgSelector::First => (!self.messages.is_empty()).then_some(0),
+            MsgSelector::Exact(ty) => iter.find(|(_, m)| m.mtype == ty).map(|(i, _)| i),
+            MsgSelector::Except(ty) => iter.find(|(_, m)| m.mtype != ty).map(|(i, _)| i),
+            MsgSelector::LowestUpTo(ty) => iter
+                .filter(|(_, m)| m.mtype <= ty)
+                // `min_by_key` keeps the first minimum, preserving FIFO order.
+                .min_by_key(|(_, m)| m.mtype)
+                .map(|(i, _)| i),
+        }
+    }
+
+    /// Removes the message picked by `selector`.
+    ///
+    /// Messages longer than `max_len` are left in the queue and `E2BIG` is
+    /// returned, unless `truncate` is set.
+    pub fn pop(
+        &mut self,
+        selector: MsgSelector,
+        max_len: usize,
+        truncate: bool,
+        pid: Pid,
+        now: __kernel_time_t,
+    ) -> Option<AxResult<(c_long, Vec<u8>)>> {
+        let index = self.find(selector)?;
+        if self.messages[index].data.len() > max_len && !truncate {
+            return Some(Err(AxError::from(LinuxError::E2BIG)));
+        }
+        let Message { mtype, mut data } = self.messages.remove(index)?;
+        self.ds.msg_cbytes -= data.len() as c_ulong;
+        self.ds.msg_qnum -= 1;
+        self.ds.msg_lrpid = pid as _;
+        self.ds.msg_rtime = now;
+        data.truncate(max_len);
+        Some(Ok((mtype, data)))
+    }
+}
+
+/// A System V message queue.
+pub struct MsgQueue {
+    /// Message queue identifier.
+    pub msqid: i32,
+    /// The queue state.
+    pub inner: Mutex<MsgQueueInner>,
+    /// Woken when space becomes available or the queue is removed.
+    pub poll_send: PollSet,
+    /// Woken when a message arrives or the queue is removed.
+    pub poll_recv: PollSet,
+}
+
+impl MsgQueue {
+    /// Creates a new empty [`MsgQueue`].
+    pub fn new(msqid: i32, perm: IpcPerm, now: __kernel_time_t) -> Self {
+        Self {
+            msqid,
+            inner: Mutex::new(MsgQueueInner {
+                ds: MsqidDs {
+                    msg_perm: perm,
+                    msg_stime: 0,
+                    msg_rtime: 0,
+                    msg_ctime: now,
+                    msg_cbytes: 0,
+                    msg_qnum: 0,
+                    msg_qbytes: msgmnb() as _,
+                    msg_lspid: 0,
+                    msg_lrpid: 0,
+                    unused4: 0,
+                    unused5: 0,
+                },
+                messages: VecDeque::new(),
+                removed: false,
+            }),
+            poll_send: PollSet::new(),
+            poll_recv: PollSet::new(),
+        }
+    }
+}
+
+/// Keeps track of all message queues in the system.
+pub struct MsgManager {
+    /// key -> msqid
+    key_msqid: BTreeMap<i32, i32>,
+    /// msqid -> queue
+    queues: BTreeMap<i32, Arc<MsgQueue>>,
+}
+
+impl MsgManager {
+    const fn new() -> Self {
+        Self {
+            key_msqid: BTreeMap::new(),
+            queues: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the message queue ID associated with the given key.
+    pub fn get_msqid_by_key(&self, key: i32) -> Option<i32> {
+        self.key_msqid.get(&key).cloned()
+    }
+
+    /// Returns the message queue with the given ID.
+    pub fn get(&self, msqid: i32) -> Option<Arc<MsgQueue>> {
+        self.queues.get(&msqid).cloned()
+    }
+
+    /// Registers a new message queue, optionally under `key`.
+    pub fn insert(&mut self, key: Option<i32>, queue: Arc<MsgQueue>) {
+        if let Some(key) = key {
+            self.key_msqid.insert(key, queue.msqid);
+        }
+        self.queues.insert(queue.msqid, queue);
+    }
+
+    /// Removes the message queue, waking up everyone blocked on it.
+    pub fn remove(&mut self, msqid: i32) -> Option<Arc<MsgQueue>> {
+        self.key_msqid.retain(|_, id| *id != msqid);
+        let queue = self.queues.remove(&msqid)?;
+        queue.inner.lock().removed = true;
+        queue.poll_send.wake();
+        queue.poll_recv.wake();
+        Some(queue)
+    }
+}
+
+/// Global message queue manager.
+pub static MSG_MANAGER: Mutex<MsgManager> = Mutex::new(MsgManager::new());
@@ -0,0 +1,123 @@
+//! Parking a task on several wake sources at once.
+//!
+//! A blocking wait usually has more than one way to end: an event (I/O
+//! readiness or an explicit wake such as a futex), its deadline and a
+//! signal. Each source gets a waker of its own from one [`TaskParker`],
+//! tagged with a [`WakeReason`]. Firing it records the reason and wakes the
+//! parked task, and [`TaskParker::park`] hands back every reason recorded
+//! since it last returned, so the waiter sees which sources fired instead of
+//! re-checking each of them and none fired in between is lost.
+//!
+//! A parker lives for one wait. Sources may keep a waker after the wait is
+//! over (a poll set is only drained when it is woken), and those stale
+//! wakers then record into a parker no one parks on any more.
+
+use alloc::{sync::Arc, task::Wake};
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use axtask::{current, future::block_on};
+use bitflags::bitflags;
+use kspin::SpinNoIrq;
+
+use crate::critical::assert_outside;
+
+bitflags! {
+    /// Why a parked task was woken.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WakeReason: u32 {
+        /// The event waited for: I/O readiness or an explicit wake.
+        const EVENT = 1 << 0;
+        /// The deadline of the wait passed.
+        const TIMER = 1 << 1;
+        /// A signal interrupted the task.
+        const SIGNAL = 1 << 2;
+    }
+}
+
+/// The wake sources of one wait.
+pub struct TaskParker {
+    reasons: AtomicU32,
+    /// The waker of the task while it is parked.
+    task: SpinNoIrq<Option<Waker>>,
+}
+
+struct ReasonWaker {
+    parker: Arc<TaskParker>,
+    reason: WakeReason,
+}
+
+impl Wake for ReasonWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.parker.record(self.reason);
+    }
+}
+
+impl TaskParker {
+    /// Creates a parker with nothing recorded.
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            reasons: AtomicU32::new(0),
+            task: SpinNoIrq::new(None),
+        })
+    }
+
+    /// Returns a waker that records `reason` and wakes the parked task.
+    pub fn waker(self: &Arc<Self>, reason: WakeReason) -> Waker {
+        Waker::from(Arc::new(ReasonWaker {
+            parker: self.clone(),
+            reason,
+        }))
+    }
+
+    /// Records `reason` and wakes the parked task.
+    pub fn record(&self, reason: WakeReason) {
+        self.reasons.fetch_or(reason.bits(), Ordering::AcqRel);
+        if let Some(task) = self.task.lock().as_ref() {
+            task.wake_by_ref();
+        }
+    }
+
+    /// Makes a signal sent to the current task wake it. A signal already
+    /// pending is recorded right away.
+    pub fn watch_signals(self: &Arc<Self>) {
+        let waker = self.waker(WakeReason::SIGNAL);
+        if current()
+            .poll_interrupt(&mut Context::from_waker(&waker))
+            .is_ready()
+        {
+            self.record(WakeReason::SIGNAL);
+        }
+    }
+
+    /// Takes the reasons recorded so far without blocking.
+    pub fn take(&self) -> WakeReason {
+        WakeReason::from_bits_truncate(self.reasons.swap(0, Ordering::AcqRel))
+    }
+
+    /// Blocks the current task until some reason is recorded, then takes
+    /// the reasons. Returns right away if one was recorded already.
+    pub fn park(&self) -> WakeReason {
+        assert_outside("parking");
+        let reasons = block_on(poll_fn(|cx| {
+            // The waker is in place before the check, so a reason recorded
+            // meanwhile still wakes us.
+            *self.task.lock() = Some(cx.waker().clone());
+            let reasons = self.take();
+            if reasons.is_empty() {
+                Poll::Pending
+            } else {
+                Poll::Ready(reasons)
+            }
+        }));
+        *self.task.lock() = None;
+        reasons
+    }
+}
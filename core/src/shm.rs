@@ -17,18 +17,63 @@ use starry_process::Pid;
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct IpcPerm {
-    key: __kernel_key_t,
-    uid: __kernel_uid_t,
-    gid: __kernel_gid_t,
-    cuid: __kernel_uid_t,
-    cgid: __kernel_gid_t,
-    mode: __kernel_mode_t,
+    /// key supplied to the get call
+    pub key: __kernel_key_t,
+    /// effective UID of owner
+    pub uid: __kernel_uid_t,
+    /// effective GID of owner
+    pub gid: __kernel_gid_t,
+    /// effective UID of creator
+    pub cuid: __kernel_uid_t,
+    /// effective GID of creator
+    pub cgid: __kernel_gid_t,
+    /// permissions
+    pub mode: __kernel_mode_t,
     seq: c_ushort,
     pad: c_ushort,
     unused0: c_long,
     unused1: c_long,
 }
 
+impl IpcPerm {
+    /// Creates a new [`IpcPerm`] owned and created by `uid`/`gid`.
+    pub fn new(key: i32, mode: __kernel_mode_t, uid: u32, gid: u32) -> Self {
+        Self {
+            key,
+            uid,
+            gid,
+            cuid: uid,
+            cgid: gid,
+            mode,
+            seq: 0,
+            pad: 0,
+            unused0: 0,
+            unused1: 0,
+        }
+    }
+
+    /// Checks whether `uid`/`gid` is granted all bits of `access` (a
+    /// combination of `0o4` for read and `0o2` for write).
+    pub fn check(&self, uid: u32, gid: u32, access: u32) -> AxResult<()> {
+        if uid == 0 {
+            return Ok(());
+        }
+        let mode = self.mode as u32;
+        let granted = if uid == self.uid || uid == self.cuid {
+            mode >> 6
+        } else if gid == self.gid || gid == self.cgid {
+            mode >> 3
+        } else {
+            mode
+        };
+        if granted & access & 0o7 == access {
+            Ok(())
+        } else {
+            Err(AxError::PermissionDenied)
+        }
+    }
+}
+
 /// Data structure describing a shared memory segment.
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -54,18 +99,7 @@ pub struct ShmidDs {
 impl ShmidDs {
     fn new(key: i32, size: usize, mode: __kernel_mode_t, pid: __kernel_pid_t) -> Self {
         Self {
-            shm_perm: IpcPerm {
-                key,
-                uid: 0,
-                gid: 0,
-                cuid: 0,
-                cgid: 0,
-                mode,
-                seq: 0,
-                pad: 0,
-                unused0: 0,
-                unused1: 0,
-            },
+            shm_perm: IpcPerm::new(key, mode, 0, 0),
             shm_segsz: size as __kernel_size_t,
             shm_atime: 0,
             shm_dtime: 0,
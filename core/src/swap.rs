@@ -0,0 +1,126 @@
+//! Swap areas.
+//!
+//! A swap area is a file or block device set up with `mkswap`. `swapon`
+//! validates its header and adds it to the swap list shown in `/proc/swaps`;
+//! `swapoff` takes it out.
+//!
+//! This is only the bookkeeping: nothing reclaims pages to an area yet, so
+//! no page is ever swapped out, every area reads as unused, and `swapoff`
+//! has nothing to bring back in. As an area can back no memory, it counts
+//! towards neither the swap space in `/proc/meminfo` nor the commit limit.
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+
+use axerrno::{AxError, AxResult};
+use axfs::FileBackend;
+use axsync::Mutex;
+use memory_addr::PAGE_SIZE_4K;
+
+const SWAP_MAGIC: &[u8] = b"SWAPSPACE2";
+/// Offsets into `union swap_header`.
+const VERSION_OFFSET: usize = 1024;
+const LAST_PAGE_OFFSET: usize = 1028;
+const NR_BADPAGES_OFFSET: usize = 1032;
+const BADPAGES_OFFSET: usize = 1536;
+
+/// An active swap area.
+pub struct SwapArea {
+    /// The path it was enabled with, as shown in `/proc/swaps`.
+    pub path: String,
+    /// The backing file or device, held open while the area is active.
+    _file: FileBackend,
+    /// Allocation priority; higher is used first.
+    pub priority: i16,
+    /// Number of usable pages, past the header and the bad pages.
+    pages: usize,
+}
+
+impl SwapArea {
+    /// Validates the swap header of `file` and creates an area for it.
+    pub fn new(path: String, file: FileBackend, priority: i16) -> AxResult<Self> {
+        let mut header = vec![0u8; PAGE_SIZE_4K];
+        let read = file.read_at(&mut header.as_mut_slice(), 0)?;
+        if read < PAGE_SIZE_4K || &header[PAGE_SIZE_4K - SWAP_MAGIC.len()..] != SWAP_MAGIC {
+            return Err(AxError::InvalidInput);
+        }
+        let word = |offset: usize| {
+            u32::from_ne_bytes(header[offset..offset + 4].try_into().unwrap()) as usize
+        };
+        if word(VERSION_OFFSET) != 1 {
+            return Err(AxError::InvalidInput);
+        }
+
+        let file_pages = file.location().len()? as usize / PAGE_SIZE_4K;
+        let last_page = word(LAST_PAGE_OFFSET).min(file_pages.saturating_sub(1));
+        if last_page == 0 {
+            return Err(AxError::InvalidInput);
+        }
+        let nr_badpages = word(NR_BADPAGES_OFFSET);
+        if BADPAGES_OFFSET + nr_badpages * 4 > PAGE_SIZE_4K - SWAP_MAGIC.len() {
+            return Err(AxError::InvalidInput);
+        }
+
+        let mut bad = vec![false; last_page + 1];
+        let mut pages = last_page;
+        for i in 0..nr_badpages {
+            let page = word(BADPAGES_OFFSET + i * 4);
+            if (1..=last_page).contains(&page) && !bad[page] {
+                bad[page] = true;
+                pages -= 1;
+            }
+        }
+        if pages == 0 {
+            return Err(AxError::InvalidInput);
+        }
+
+        Ok(Self {
+            path,
+            _file: file,
+            priority,
+            pages,
+        })
+    }
+
+    /// Returns the number of usable pages.
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Returns the number of pages in use, always 0 as nothing is swapped
+    /// out.
+    pub fn used(&self) -> usize {
+        0
+    }
+}
+
+static SWAP_AREAS: Mutex<Vec<Option<Arc<SwapArea>>>> = Mutex::new(Vec::new());
+
+/// Returns all active swap areas.
+pub fn swap_areas() -> Vec<Arc<SwapArea>> {
+    SWAP_AREAS.lock().iter().flatten().cloned().collect()
+}
+
+/// Activates a swap area.
+pub fn enable(area: SwapArea) -> AxResult<()> {
+    let mut areas = SWAP_AREAS.lock();
+    if areas.iter().flatten().any(|it| it.path == area.path) {
+        return Err(AxError::ResourceBusy);
+    }
+    let area = Some(Arc::new(area));
+    match areas.iter_mut().find(|it| it.is_none()) {
+        Some(free) => *free = area,
+        None => areas.push(area),
+    }
+    Ok(())
+}
+
+/// Deactivates the swap area enabled with `path`.
+pub fn disable(path: &str) -> AxResult<()> {
+    let mut areas = SWAP_AREAS.lock();
+    let area = areas
+        .iter_mut()
+        .find(|it| it.as_ref().is_some_and(|it| it.path == path))
+        .ok_or(AxError::InvalidInput)?;
+    *area = None;
+    Ok(())
+}
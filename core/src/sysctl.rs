@@ -0,0 +1,144 @@
+//! Runtime tunables exposed under `/proc/sys`.
+//!
+//! A subsystem declares a tunable by adding a [`Sysctl`] to [`SYSCTLS`]:
+//!
+//! ```ignore
+//! #[linkme::distributed_slice(SYSCTLS)]
+//! static MSGMAX: Sysctl = Sysctl {
+//!     path: "kernel/msgmax",
+//!     mode: 0o644,
+//!     kind: SysctlKind::Uint {
+//!         get: || msgmax() as _,
+//!         set: |value| set_msgmax(value as _),
+//!         min: 0,
+//!         max: i32::MAX as _,
+//!     },
+//! };
+//! ```
+//!
+//! procfs builds the directory tree from the paths. A write is parsed and
+//! validated as a whole before the setter is called once, so a rejected
+//! write leaves the old value in place.
+
+use alloc::{format, string::String};
+
+use axerrno::{AxError, AxResult};
+use linkme::distributed_slice;
+
+/// The value type of a [`Sysctl`] and its accessors.
+pub enum SysctlKind {
+    /// A signed integer within `min..=max`.
+    Int {
+        /// Returns the current value.
+        get: fn() -> i64,
+        /// Applies a validated value.
+        set: fn(i64),
+        /// Smallest accepted value.
+        min: i64,
+        /// Largest accepted value.
+        max: i64,
+    },
+    /// An unsigned integer within `min..=max`.
+    Uint {
+        /// Returns the current value.
+        get: fn() -> u64,
+        /// Applies a validated value.
+        set: fn(u64),
+        /// Smallest accepted value.
+        min: u64,
+        /// Largest accepted value.
+        max: u64,
+    },
+    /// An unsigned integer within `min..=max`, which the setter checks
+    /// further, e.g. against other tunables.
+    CheckedUint {
+        /// Returns the current value.
+        get: fn() -> u64,
+        /// Applies a value within bounds, or rejects it.
+        set: fn(u64) -> AxResult<()>,
+        /// Smallest accepted value.
+        min: u64,
+        /// Largest accepted value.
+        max: u64,
+    },
+    /// A string. The setter does its own validation.
+    Str {
+        /// Returns the current value.
+        get: fn() -> String,
+        /// Applies a value, without the trailing newline.
+        set: fn(&str) -> AxResult<()>,
+    },
+}
+
+/// A `/proc/sys` entry.
+pub struct Sysctl {
+    /// The path below `/proc/sys`, e.g. `"fs/pipe-max-size"`.
+    pub path: &'static str,
+    /// Permission bits of the file. Entries without write bits are read-only.
+    pub mode: u16,
+    /// The value type and accessors.
+    pub kind: SysctlKind,
+}
+
+impl Sysctl {
+    /// Formats the current value as read from the file.
+    pub fn read(&self) -> String {
+        match &self.kind {
+            SysctlKind::Int { get, .. } => format!("{}\n", get()),
+            SysctlKind::Uint { get, .. } | SysctlKind::CheckedUint { get, .. } => {
+                format!("{}\n", get())
+            }
+            SysctlKind::Str { get, .. } => format!("{}\n", get()),
+        }
+    }
+
+    /// Parses and applies a write to the file.
+    pub fn write(&self, data: &[u8]) -> AxResult<()> {
+        if self.mode & 0o222 == 0 {
+            return Err(AxError::PermissionDenied);
+        }
+        // Opening with `O_TRUNC` writes nothing.
+        if data.is_empty() {
+            return Ok(());
+        }
+        let text = str::from_utf8(data).map_err(|_| AxError::InvalidInput)?;
+        match &self.kind {
+            SysctlKind::Int { set, min, max, .. } => {
+                let value = text
+                    .trim()
+                    .parse::<i64>()
+                    .map_err(|_| AxError::InvalidInput)?;
+                if !(*min..=*max).contains(&value) {
+                    return Err(AxError::InvalidInput);
+                }
+                set(value);
+            }
+            SysctlKind::Uint { set, min, max, .. } => {
+                let value = text
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| AxError::InvalidInput)?;
+                if !(*min..=*max).contains(&value) {
+                    return Err(AxError::InvalidInput);
+                }
+                set(value);
+            }
+            SysctlKind::CheckedUint { set, min, max, .. } => {
+                let value = text
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|_| AxError::InvalidInput)?;
+                if !(*min..=*max).contains(&value) {
+                    return Err(AxError::InvalidInput);
+                }
+                set(value)?;
+            }
+            SysctlKind::Str { set, .. } => set(text.strip_suffix('\n').unwrap_or(text))?,
+        }
+        Ok(())
+    }
+}
+
+/// All registered tunables.
+#[distributed_slice]
+pub static SYSCTLS: [Sysctl];
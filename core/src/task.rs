@@ -11,7 +11,7 @@ use alloc::{
 use core::{
     cell::RefCell,
     ops::Deref,
-    sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicI32, AtomicU16, AtomicU32, AtomicU64, AtomicUsize, Ordering},
 };
 
 use axerrno::{AxError, AxResult};
@@ -26,16 +26,20 @@ use scope_local::{ActiveScope, Scope};
 use spin::RwLock;
 use starry_process::{Pid, Process, ProcessGroup, Session};
 use starry_signal::{
-    SignalInfo, Signo,
+    SignalInfo, SignalSet, Signo,
     api::{ProcessSignalManager, SignalActions, ThreadSignalManager},
 };
 use weak_map::WeakMap;
 
 pub use self::stat::TaskStat;
 use crate::{
+    critical::CriticalState,
     futex::{FutexKey, FutexTable},
+    ioprio::IoPrio,
+    mm::{Commitments, FileMappings, MmapLayout},
     resources::Rlimits,
-    time::{TimeManager, TimerState},
+    time::{CpuTimers, ITimerType, TimeManager, TimerState, WallTimer},
+    timer::{DEFAULT_TIMER_SLACK_NS, MAX_TIMER_SLACK_NS},
 };
 
 ///  A wrapper type that assumes the inner type is `Sync`.
@@ -80,6 +84,19 @@ pub struct Thread {
     /// The OOM score adjustment value.
     oom_score_adj: AtomicI32,
 
+    /// The I/O priority, encoded as for `ioprio_set`.
+    ioprio: AtomicU16,
+
+    /// How late relative timeouts of the thread may fire, in nanoseconds.
+    timer_slack_ns: AtomicU64,
+
+    /// The signal mask to restore once the signal that interrupted a wait
+    /// under a temporary mask is delivered.
+    saved_blocked: SpinNoIrq<Option<SignalSet>>,
+
+    /// The kernel critical sections held and the work they defer.
+    pub(crate) critical: CriticalState,
+
     /// Ready to exit
     exit: AtomicBool,
 }
@@ -94,6 +111,10 @@ impl Thread {
             robust_list_head: AtomicUsize::new(0),
             time: AssumeSync(RefCell::new(TimeManager::new())),
             oom_score_adj: AtomicI32::new(200),
+            ioprio: AtomicU16::new(IoPrio::DEFAULT.raw()),
+            timer_slack_ns: AtomicU64::new(DEFAULT_TIMER_SLACK_NS),
+            saved_blocked: SpinNoIrq::new(None),
+            critical: CriticalState::new(),
             exit: AtomicBool::new(false),
         })
     }
@@ -130,6 +151,41 @@ impl Thread {
         self.oom_score_adj.store(value, Ordering::SeqCst);
     }
 
+    /// Get the I/O priority.
+    pub fn ioprio(&self) -> IoPrio {
+        IoPrio(self.ioprio.load(Ordering::Relaxed))
+    }
+
+    /// Set the I/O priority.
+    pub fn set_ioprio(&self, ioprio: IoPrio) {
+        self.ioprio.store(ioprio.raw(), Ordering::Relaxed);
+    }
+
+    /// Get the timer slack in nanoseconds.
+    pub fn timer_slack_ns(&self) -> u64 {
+        self.timer_slack_ns.load(Ordering::Relaxed)
+    }
+
+    /// Set the timer slack in nanoseconds, clamped to
+    /// [`MAX_TIMER_SLACK_NS`]. Zero restores the default.
+    pub fn set_timer_slack_ns(&self, ns: u64) {
+        let ns = match ns {
+            0 => DEFAULT_TIMER_SLACK_NS,
+            ns => ns.min(MAX_TIMER_SLACK_NS),
+        };
+        self.timer_slack_ns.store(ns, Ordering::Relaxed);
+    }
+
+    /// Save the signal mask to restore once the pending signal is delivered.
+    pub fn set_saved_blocked(&self, set: SignalSet) {
+        *self.saved_blocked.lock() = Some(set);
+    }
+
+    /// Take the signal mask saved by [`Thread::set_saved_blocked`].
+    pub fn take_saved_blocked(&self) -> Option<SignalSet> {
+        self.saved_blocked.lock().take()
+    }
+
     /// Check if the thread is ready to exit.
     pub fn pending_exit(&self) -> bool {
         self.exit.load(Ordering::Acquire)
@@ -144,12 +200,18 @@ impl Thread {
 #[extern_trait]
 unsafe impl TaskExt for Box<Thread> {
     fn on_enter(&self) {
+        if let Ok(mut time) = self.time.try_borrow_mut() {
+            time.reset();
+        }
         let scope = self.proc_data.scope.read();
         unsafe { ActiveScope::set(&scope) };
         core::mem::forget(scope);
     }
 
     fn on_leave(&self) {
+        if let Ok(mut time) = self.time.try_borrow_mut() {
+            time.reset();
+        }
         ActiveScope::set_global();
         unsafe { self.proc_data.scope.force_read_decrement() };
     }
@@ -173,6 +235,25 @@ impl AsThread for TaskInner {
     }
 }
 
+/// The user and group ids of a process.
+///
+/// Everything starts out as root.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Credentials {
+    /// The real user id.
+    pub uid: u32,
+    /// The effective user id, checked for permissions.
+    pub euid: u32,
+    /// The saved set-user-id.
+    pub suid: u32,
+    /// The real group id.
+    pub gid: u32,
+    /// The effective group id, checked for permissions.
+    pub egid: u32,
+    /// The saved set-group-id.
+    pub sgid: u32,
+}
+
 /// [`Process`]-shared data.
 pub struct ProcessData {
     /// The process.
@@ -190,9 +271,14 @@ pub struct ProcessData {
     heap_bottom: AtomicUsize,
     /// The user heap top
     heap_top: AtomicUsize,
+    /// The initial user stack pointer, which points at `argc` followed by the
+    /// argument, environment and auxiliary vectors.
+    initial_sp: AtomicUsize,
 
     /// The resource limits
     pub rlim: RwLock<Rlimits>,
+    /// The user and group ids.
+    pub cred: RwLock<Credentials>,
 
     /// The child exit wait event
     pub child_exit_event: Arc<PollSet>,
@@ -209,6 +295,20 @@ pub struct ProcessData {
 
     /// The default mask for file permissions.
     umask: AtomicU32,
+
+    /// The CPU time of all threads and the timers armed against it.
+    pub cpu_timers: SpinNoIrq<CpuTimers>,
+    /// `ITIMER_REAL`, sending the process `SIGALRM`.
+    pub real_timer: WallTimer,
+
+    /// The execution domain and flags set by `personality`.
+    personality: AtomicU32,
+    /// Where mappings without a fixed address are placed.
+    mmap_layout: SpinNoIrq<MmapLayout>,
+    /// The file mappings in the address space.
+    pub file_mappings: Mutex<FileMappings>,
+    /// The memory committed to by the address space.
+    pub commitments: Mutex<Commitments>,
 }
 
 impl ProcessData {
@@ -221,6 +321,7 @@ impl ProcessData {
         signal_actions: Arc<SpinNoIrq<SignalActions>>,
         exit_signal: Option<Signo>,
     ) -> Arc<Self> {
+        let pid = proc.pid();
         Arc::new(Self {
             proc,
             exe_path: RwLock::new(exe_path),
@@ -229,8 +330,10 @@ impl ProcessData {
             scope: RwLock::new(Scope::new()),
             heap_bottom: AtomicUsize::new(crate::config::USER_HEAP_BASE),
             heap_top: AtomicUsize::new(crate::config::USER_HEAP_BASE),
+            initial_sp: AtomicUsize::new(0),
 
             rlim: RwLock::default(),
+            cred: RwLock::default(),
 
             child_exit_event: Arc::default(),
             exit_event: Arc::default(),
@@ -244,6 +347,17 @@ impl ProcessData {
             futex_table: Arc::new(FutexTable::new()),
 
             umask: AtomicU32::new(0o022),
+
+            cpu_timers: SpinNoIrq::new(CpuTimers::default()),
+            real_timer: WallTimer::new(move |_| {
+                let signo = ITimerType::Real.signo();
+                let _ = send_signal_to_process(pid, Some(SignalInfo::new_kernel(signo)));
+            }),
+
+            personality: AtomicU32::new(0),
+            mmap_layout: SpinNoIrq::new(MmapLayout::new(0)),
+            file_mappings: Mutex::default(),
+            commitments: Mutex::default(),
         })
     }
 
@@ -267,6 +381,16 @@ impl ProcessData {
         self.heap_top.store(top, Ordering::Release)
     }
 
+    /// Get the initial user stack pointer.
+    pub fn initial_sp(&self) -> usize {
+        self.initial_sp.load(Ordering::Acquire)
+    }
+
+    /// Set the initial user stack pointer.
+    pub fn set_initial_sp(&self, sp: usize) {
+        self.initial_sp.store(sp, Ordering::Release)
+    }
+
     /// Linux manual: A "clone" child is one which delivers no signal, or a
     /// signal other than SIGCHLD to its parent upon termination.
     pub fn is_clone_child(&self) -> bool {
@@ -301,6 +425,26 @@ impl ProcessData {
     pub fn replace_umask(&self, umask: u32) -> u32 {
         self.umask.swap(umask, Ordering::SeqCst)
     }
+
+    /// Get the personality.
+    pub fn personality(&self) -> u32 {
+        self.personality.load(Ordering::SeqCst)
+    }
+
+    /// Set the personality, returning the old one.
+    pub fn replace_personality(&self, personality: u32) -> u32 {
+        self.personality.swap(personality, Ordering::SeqCst)
+    }
+
+    /// Get the mmap layout.
+    pub fn mmap_layout(&self) -> MmapLayout {
+        *self.mmap_layout.lock()
+    }
+
+    /// Set the mmap layout.
+    pub fn set_mmap_layout(&self, layout: MmapLayout) {
+        *self.mmap_layout.lock() = layout;
+    }
 }
 
 struct FutexTables {
@@ -389,6 +533,19 @@ pub fn tasks() -> Vec<AxTaskRef> {
     TASK_TABLE.read().values().collect()
 }
 
+/// Lists the TIDs of live tasks from `start` on, in increasing order.
+///
+/// Unlike [`tasks`], this holds no references to the tasks, so callers can
+/// walk the table across calls without keeping exited tasks around.
+pub fn tids_from(start: Pid) -> Vec<Pid> {
+    TASK_TABLE
+        .read()
+        .iter()
+        .map(|(tid, _)| *tid)
+        .filter(|tid| *tid >= start)
+        .collect()
+}
+
 /// Finds the task with the given TID.
 pub fn get_task(tid: Pid) -> AxResult<AxTaskRef> {
     if tid == 0 {
@@ -402,6 +559,13 @@ pub fn processes() -> Vec<Arc<ProcessData>> {
     PROCESS_TABLE.read().values().collect()
 }
 
+/// Calls `f` on every process, without allocating.
+pub fn for_each_process(mut f: impl FnMut(Arc<ProcessData>)) {
+    for proc_data in PROCESS_TABLE.read().values() {
+        f(proc_data);
+    }
+}
+
 /// Finds the process with the given PID.
 pub fn get_process_data(pid: Pid) -> AxResult<Arc<ProcessData>> {
     if pid == 0 {
@@ -424,6 +588,9 @@ pub fn get_session(sid: Pid) -> AxResult<Arc<Session>> {
 }
 
 /// Poll the timer
+///
+/// CPU time is only charged if `task` is the one running here; the timer
+/// tick polls the current task so that CPU timers advance while it runs.
 pub fn poll_timer(task: &TaskInner) {
     let Some(thr) = task.try_as_thread() else {
         return;
@@ -432,9 +599,9 @@ pub fn poll_timer(task: &TaskInner) {
         // reentrant borrow, likely IRQ
         return;
     };
-    time.poll(|signo| {
-        send_signal_thread_inner(task, thr, SignalInfo::new_kernel(signo));
-    });
+    let charged = time.poll(task.id() == current().id());
+    drop(time);
+    charge_process(task, thr, charged);
 }
 
 /// Sets the timer state.
@@ -446,10 +613,62 @@ pub fn set_timer_state(task: &TaskInner, state: TimerState) {
         // reentrant borrow, likely IRQ
         return;
     };
-    time.poll(|signo| {
-        send_signal_thread_inner(task, thr, SignalInfo::new_kernel(signo));
-    });
+    let charged = time.poll(true);
     time.set_state(state);
+    drop(time);
+    charge_process(task, thr, charged);
+}
+
+fn charge_process(task: &TaskInner, thr: &Thread, (utime_ns, stime_ns): (u64, u64)) {
+    if utime_ns == 0 && stime_ns == 0 {
+        return;
+    }
+    let tid = task.id().as_u64() as Pid;
+    let expired = thr
+        .proc_data
+        .cpu_timers
+        .lock()
+        .charge(tid, utime_ns, stime_ns);
+    for (action, overrun) in expired {
+        action(overrun);
+    }
+}
+
+/// Sends a signal from a CPU timer, to thread `thread` or, if `None`, to
+/// the process of the current task.
+///
+/// This may run in interrupt context, where the task table must not be
+/// waited for. If a thread cannot be looked up, a thread-directed signal is
+/// dropped, while a process-directed one interrupts the current task, which
+/// belongs to the process, instead of the thread chosen to handle it.
+pub fn send_cpu_timer_signal(thread: Option<Pid>, sig: SignalInfo) {
+    let curr = current();
+    let lookup = |tid: Pid| {
+        if curr.id().as_u64() as Pid == tid {
+            return Some(curr.clone());
+        }
+        TASK_TABLE.try_read().and_then(|table| table.get(&tid))
+    };
+    match thread {
+        Some(tid) => {
+            if let Some(task) = lookup(tid)
+                && let Some(thr) = task.try_as_thread()
+            {
+                send_signal_thread_inner(&task, thr, sig);
+            }
+        }
+        None => {
+            let Some(thr) = curr.try_as_thread() else {
+                return;
+            };
+            if let Some(tid) = thr.proc_data.signal.send_signal(sig) {
+                match lookup(tid) {
+                    Some(task) => task.interrupt(),
+                    None => curr.interrupt(),
+                }
+            }
+        }
+    }
 }
 
 fn send_signal_thread_inner(task: &TaskInner, thr: &Thread, sig: SignalInfo) {
@@ -1,20 +1,24 @@
 //! Time management module.
 
-use alloc::{borrow::ToOwned, collections::binary_heap::BinaryHeap, sync::Arc};
-use core::{mem, time::Duration};
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    task::Poll,
+    time::Duration,
+};
 
-use axhal::time::{NANOS_PER_SEC, TimeValue, monotonic_time_nanos, wall_time};
-use axtask::{
-    WeakAxTaskRef, current,
-    future::{block_on, timeout_at},
+use axhal::time::{
+    NANOS_PER_SEC, TimeValue, monotonic_time, monotonic_time_nanos, wall_time_nanos,
 };
-use event_listener::{Event, listener};
+use axpoll::PollSet;
+use kspin::SpinNoIrq;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use starry_process::Pid;
 use starry_signal::Signo;
 use strum::FromRepr;
 
-use crate::task::poll_timer;
+use crate::timer::{self, TimerHandle};
 
 fn time_value_from_nanos(nanos: usize) -> TimeValue {
     let secs = nanos as u64 / NANOS_PER_SEC;
@@ -22,30 +26,80 @@ fn time_value_from_nanos(nanos: usize) -> TimeValue {
     TimeValue::new(secs, nsecs as u32)
 }
 
-struct Entry {
-    deadline: Duration,
-    task: WeakAxTaskRef,
+/// Nanoseconds `CLOCK_REALTIME` is ahead of the wall time of the platform,
+/// moved whenever the clock is set.
+static REALTIME_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+static CLOCK_WAS_SET_FN: SpinNoIrq<Option<fn()>> = SpinNoIrq::new(None);
+
+/// Steps of `CLOCK_REALTIME` so far.
+static REALTIME_STEPS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Woken on each step of `CLOCK_REALTIME`.
+    static ref REALTIME_STEPPED: PollSet = PollSet::new();
 }
-impl PartialEq for Entry {
-    fn eq(&self, other: &Self) -> bool {
-        self.deadline == other.deadline
-    }
+
+/// Returns the time of `CLOCK_REALTIME`.
+pub fn realtime() -> TimeValue {
+    let nanos = wall_time_nanos() as i64 + REALTIME_OFFSET.load(Ordering::Relaxed);
+    Duration::from_nanos(nanos.max(0) as u64)
 }
-impl Eq for Entry {}
-impl PartialOrd for Entry {
-    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        Some(other.deadline.cmp(&self.deadline))
+
+/// Steps `CLOCK_REALTIME` to `now`, then calls the function set with
+/// [`set_clock_was_set_fn`], for the timers on the clock to catch up.
+pub fn set_realtime(now: TimeValue) {
+    let offset = now.as_nanos() as i64 - wall_time_nanos() as i64;
+    REALTIME_OFFSET.store(offset, Ordering::Relaxed);
+    REALTIME_STEPS.fetch_add(1, Ordering::Release);
+    REALTIME_STEPPED.wake();
+    let clock_was_set = *CLOCK_WAS_SET_FN.lock();
+    if let Some(clock_was_set) = clock_was_set {
+        clock_was_set();
     }
 }
-impl Ord for Entry {
-    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        other.deadline.cmp(&self.deadline)
+
+/// Sets the function called after `CLOCK_REALTIME` is stepped.
+pub fn set_clock_was_set_fn(f: fn()) {
+    *CLOCK_WAS_SET_FN.lock() = Some(f);
+}
+
+/// Sleeps until `CLOCK_REALTIME` reads `deadline`.
+///
+/// The deadline is checked again on each step of the clock: stepping past
+/// it ends the sleep, and stepping back makes it longer.
+pub async fn sleep_until_realtime(deadline: TimeValue) {
+    loop {
+        let now = realtime();
+        if now >= deadline {
+            return;
+        }
+        let steps = REALTIME_STEPS.load(Ordering::Acquire);
+        let stepped = poll_fn(|cx| {
+            REALTIME_STEPPED.register(cx.waker());
+            if REALTIME_STEPS.load(Ordering::Acquire) != steps {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        });
+        let _ = timer::timeout(Some(deadline - now), stepped).await;
     }
 }
 
-lazy_static! {
-    static ref ALARM_LIST: Mutex<BinaryHeap<Entry>> = Mutex::new(BinaryHeap::new());
-    static ref EVENT_NEW_TIMER: Event = Event::new();
+/// Nanoseconds spent suspended since boot.
+static SUSPENDED: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the time of `CLOCK_BOOTTIME`: the monotonic time plus the time
+/// spent suspended. Nothing suspends yet, so for now it equals the
+/// monotonic time.
+pub fn boottime() -> TimeValue {
+    monotonic_time() + Duration::from_nanos(SUSPENDED.load(Ordering::Relaxed))
+}
+
+/// Counts `duration` spent suspended into `CLOCK_BOOTTIME`, on resume.
+pub fn add_suspended_time(duration: Duration) {
+    SUSPENDED.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
 }
 
 /// The type of interval timer.
@@ -72,50 +126,306 @@ impl ITimerType {
     }
 }
 
-#[derive(Default)]
-struct ITimer {
-    interval_ns: usize,
-    remained_ns: usize,
+type WallTimerAction = Arc<dyn Fn(u64) + Send + Sync>;
+
+struct WallTimerState {
+    interval: Duration,
+    deadline: Option<TimeValue>,
+    overrun: u64,
+    handle: Option<TimerHandle>,
+    action: WallTimerAction,
 }
 
-impl ITimer {
-    pub fn new(interval_ns: usize, remained_ns: usize) -> Self {
-        let result = Self {
-            interval_ns,
-            remained_ns,
+/// An interval timer on the monotonic clock, armed on the timer wheel.
+///
+/// Each expiry runs the action with the expirations missed since the last
+/// one, and a periodic timer re-arms from its previous deadline, so it keeps
+/// to its period however late the callback runs.
+pub struct WallTimer {
+    state: Arc<SpinNoIrq<WallTimerState>>,
+}
+
+impl WallTimer {
+    /// Creates a disarmed timer running `action` on each expiry.
+    pub fn new(action: impl Fn(u64) + Send + Sync + 'static) -> Self {
+        Self {
+            state: Arc::new(SpinNoIrq::new(WallTimerState {
+                interval: Duration::ZERO,
+                deadline: None,
+                overrun: 0,
+                handle: None,
+                action: Arc::new(action),
+            })),
+        }
+    }
+
+    /// Arms the timer to expire in `value`, then every `interval` if that is
+    /// not zero, or disarms it if `value` is zero. Returns the previous
+    /// interval and remaining time.
+    pub fn set(&self, interval: Duration, value: Duration) -> (Duration, Duration) {
+        let mut state = self.state.lock();
+        let old = Self::remaining(&state);
+        state.interval = interval;
+        state.overrun = 0;
+        state.deadline = (!value.is_zero()).then(|| monotonic_time() + value);
+        Self::arm(&self.state, &mut state);
+        old
+    }
+
+    /// Returns the interval and the remaining time, zero if disarmed.
+    pub fn get(&self) -> (Duration, Duration) {
+        Self::remaining(&self.state.lock())
+    }
+
+    /// Returns the expirations missed before the last one ran the action.
+    pub fn overrun(&self) -> u64 {
+        self.state.lock().overrun
+    }
+
+    fn remaining(state: &WallTimerState) -> (Duration, Duration) {
+        let remaining = state.deadline.map_or(Duration::ZERO, |deadline| {
+            deadline
+                .saturating_sub(monotonic_time())
+                .max(Duration::from_nanos(1))
+        });
+        (state.interval, remaining)
+    }
+
+    fn arm(this: &Arc<SpinNoIrq<WallTimerState>>, state: &mut WallTimerState) {
+        if let Some(handle) = state.handle.take() {
+            handle.cancel();
+        }
+        let Some(deadline) = state.deadline else {
+            return;
         };
-        result.renew_timer();
-        result
+        let weak = Arc::downgrade(this);
+        state.handle = Some(timer::register(deadline, move || {
+            if let Some(this) = weak.upgrade() {
+                Self::expire(&this, deadline);
+            }
+        }));
     }
 
-    pub fn update(&mut self, delta: usize) -> bool {
-        if self.remained_ns == 0 {
-            return false;
+    fn expire(this: &Arc<SpinNoIrq<WallTimerState>>, deadline: TimeValue) {
+        let mut state = this.lock();
+        // Re-armed or disarmed after the callback was taken
+        if state.deadline != Some(deadline) {
+            return;
         }
-        if self.remained_ns > delta {
-            self.remained_ns -= delta;
-            false
+        state.handle = None;
+        state.overrun = 0;
+        if state.interval.is_zero() {
+            state.deadline = None;
         } else {
-            self.remained_ns = self.interval_ns;
-            self.renew_timer();
-            true
+            let late = monotonic_time().saturating_sub(deadline);
+            let missed = (late.as_nanos() / state.interval.as_nanos()) as u64;
+            let advance = state.interval.as_nanos().saturating_mul(missed as u128 + 1);
+            let advance = Duration::from_nanos(advance.min(u64::MAX as u128) as u64);
+            state.overrun = missed;
+            state.deadline = Some(deadline.saturating_add(advance));
+            Self::arm(this, &mut state);
         }
+        let (action, overrun) = (state.action.clone(), state.overrun);
+        drop(state);
+        action(overrun);
     }
+}
 
-    pub fn renew_timer(&self) {
-        if self.remained_ns > 0 {
-            let deadline = wall_time() + Duration::from_nanos(self.remained_ns as u64);
-            let mut guard = ALARM_LIST.lock();
-            let should_wake = guard.peek().is_none_or(|it| it.deadline > deadline);
-            guard.push(Entry {
-                deadline,
-                task: Arc::downgrade(&current()),
-            });
-            drop(guard);
-            if should_wake {
-                EVENT_NEW_TIMER.notify(1);
-            }
+impl Drop for WallTimer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.state.lock().handle.take() {
+            handle.cancel();
+        }
+    }
+}
+
+/// Nanoseconds between two timer ticks. Polls of a running task are never
+/// further apart, so a poll charges at most this much CPU time.
+const TICK_NS: usize = NANOS_PER_SEC as usize / axconfig::TICKS_PER_SEC;
+
+/// The CPU time a [`CpuTimer`] counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuClock {
+    /// User time only, as for `ITIMER_VIRTUAL`.
+    User,
+    /// User and system time, as for `ITIMER_PROF` and the CPU-time clocks.
+    Total,
+}
+
+/// Called with the overrun count when a [`CpuTimer`] expires.
+pub type CpuTimerAction = Arc<dyn Fn(u64) + Send + Sync>;
+
+/// A timer that expires once its clock has advanced by the armed amount.
+pub struct CpuTimer {
+    clock: CpuClock,
+    /// Only the CPU time of this thread counts, as for
+    /// `CLOCK_THREAD_CPUTIME_ID`; otherwise that of the whole process.
+    thread: Option<Pid>,
+    interval_ns: u64,
+    /// Zero when disarmed.
+    remaining_ns: u64,
+    /// Expirations missed within the last one signalled.
+    overrun: u64,
+    action: CpuTimerAction,
+}
+
+impl CpuTimer {
+    /// Creates a disarmed timer.
+    pub fn new(
+        clock: CpuClock,
+        thread: Option<Pid>,
+        action: impl Fn(u64) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            clock,
+            thread,
+            interval_ns: 0,
+            remaining_ns: 0,
+            overrun: 0,
+            action: Arc::new(action),
+        }
+    }
+
+    /// Arms the timer to expire after `value_ns` of CPU time and every
+    /// `interval_ns` after that, or disarms it if `value_ns` is zero.
+    /// Returns the old interval and remaining time.
+    pub fn set(&mut self, interval_ns: u64, value_ns: u64) -> (u64, u64) {
+        let old = self.get();
+        self.interval_ns = interval_ns;
+        self.remaining_ns = value_ns;
+        old
+    }
+
+    /// Returns the interval and the remaining CPU time until expiry.
+    pub fn get(&self) -> (u64, u64) {
+        (self.interval_ns, self.remaining_ns)
+    }
+
+    /// Returns the thread whose CPU time this timer counts, if not the
+    /// whole process.
+    pub fn thread(&self) -> Option<Pid> {
+        self.thread
+    }
+
+    /// Returns the overrun count of the last expiry.
+    pub fn overrun(&self) -> u64 {
+        self.overrun
+    }
+
+    fn charge(&mut self, tid: Pid, utime_ns: u64, stime_ns: u64) -> Option<(CpuTimerAction, u64)> {
+        if self.thread.is_some_and(|thread| thread != tid) {
+            return None;
+        }
+        let delta = match self.clock {
+            CpuClock::User => utime_ns,
+            CpuClock::Total => utime_ns + stime_ns,
+        };
+        if self.remaining_ns == 0 || delta == 0 {
+            return None;
+        }
+        if delta < self.remaining_ns {
+            self.remaining_ns -= delta;
+            return None;
         }
+        let late = delta - self.remaining_ns;
+        if self.interval_ns == 0 {
+            self.remaining_ns = 0;
+            self.overrun = 0;
+        } else {
+            self.remaining_ns = self.interval_ns - late % self.interval_ns;
+            self.overrun = late / self.interval_ns;
+        }
+        Some((self.action.clone(), self.overrun))
+    }
+}
+
+/// The CPU time of a process and the timers armed against it or against
+/// the CPU time of its threads.
+#[derive(Default)]
+pub struct CpuTimers {
+    utime_ns: u64,
+    stime_ns: u64,
+    /// `ITIMER_VIRTUAL` and `ITIMER_PROF`.
+    itimers: [Option<CpuTimer>; 2],
+    timers: BTreeMap<u32, CpuTimer>,
+    next_id: u32,
+}
+
+impl CpuTimers {
+    /// Returns the user and system time consumed.
+    pub fn output(&self) -> (TimeValue, TimeValue) {
+        (
+            time_value_from_nanos(self.utime_ns as usize),
+            time_value_from_nanos(self.stime_ns as usize),
+        )
+    }
+
+    /// Adds a timer, returning its id.
+    pub fn insert(&mut self, timer: CpuTimer) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.timers.insert(id, timer);
+        id
+    }
+
+    /// Returns the timer with `id`.
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut CpuTimer> {
+        self.timers.get_mut(&id)
+    }
+
+    /// Removes the timer with `id`.
+    pub fn remove(&mut self, id: u32) -> Option<CpuTimer> {
+        self.timers.remove(&id)
+    }
+
+    /// Sets `ITIMER_VIRTUAL` or `ITIMER_PROF`, calling `action` on expiry.
+    /// Returns the old interval and remaining time.
+    pub fn set_itimer(
+        &mut self,
+        ty: ITimerType,
+        interval_ns: u64,
+        value_ns: u64,
+        action: impl Fn(u64) + Send + Sync + 'static,
+    ) -> (TimeValue, TimeValue) {
+        let (slot, clock) = match ty {
+            ITimerType::Virtual => (0, CpuClock::User),
+            ITimerType::Prof => (1, CpuClock::Total),
+            ITimerType::Real => unreachable!("ITIMER_REAL counts wall time"),
+        };
+        let mut timer = CpuTimer::new(clock, None, action);
+        timer.set(interval_ns, value_ns);
+        let old = self.itimers[slot]
+            .replace(timer)
+            .map_or((0, 0), |old| old.get());
+        (
+            time_value_from_nanos(old.0 as usize),
+            time_value_from_nanos(old.1 as usize),
+        )
+    }
+
+    /// Gets the interval and remaining time of `ITIMER_VIRTUAL` or
+    /// `ITIMER_PROF`.
+    pub fn get_itimer(&self, ty: ITimerType) -> (TimeValue, TimeValue) {
+        let slot = if ty == ITimerType::Virtual { 0 } else { 1 };
+        let (interval, remaining) = self.itimers[slot].as_ref().map_or((0, 0), CpuTimer::get);
+        (
+            time_value_from_nanos(interval as usize),
+            time_value_from_nanos(remaining as usize),
+        )
+    }
+
+    /// Charges CPU time consumed by thread `tid`, returning the actions of
+    /// the timers that expired with their overrun counts. The caller runs
+    /// them once it has released its locks.
+    pub fn charge(&mut self, tid: Pid, utime_ns: u64, stime_ns: u64) -> Vec<(CpuTimerAction, u64)> {
+        self.utime_ns += utime_ns;
+        self.stime_ns += stime_ns;
+        self.itimers
+            .iter_mut()
+            .flatten()
+            .chain(self.timers.values_mut())
+            .filter_map(|timer| timer.charge(tid, utime_ns, stime_ns))
+            .collect()
     }
 }
 
@@ -135,9 +445,8 @@ pub enum TimerState {
 pub struct TimeManager {
     utime_ns: usize,
     stime_ns: usize,
-    last_wall_ns: usize,
+    last_cpu_ns: usize,
     state: TimerState,
-    itimers: [ITimer; 3],
 }
 
 impl Default for TimeManager {
@@ -151,9 +460,8 @@ impl TimeManager {
         Self {
             utime_ns: 0,
             stime_ns: 0,
-            last_wall_ns: 0,
+            last_cpu_ns: 0,
             state: TimerState::None,
-            itimers: Default::default(),
         }
     }
 
@@ -164,25 +472,26 @@ impl TimeManager {
         (utime, stime)
     }
 
-    /// Polls the time manager to update the timers and emit signals if
-    /// necessary.
-    pub fn poll(&mut self, emitter: impl Fn(Signo)) {
+    /// Polls the time manager to charge CPU time.
+    ///
+    /// CPU time is only charged if the thread is `running`, i.e. polled by
+    /// itself or by the tick that interrupted it. Returns the user and system
+    /// time charged, for the process' [`CpuTimers`].
+    pub fn poll(&mut self, running: bool) -> (u64, u64) {
         let now_ns = monotonic_time_nanos() as usize;
-        let delta = now_ns - self.last_wall_ns;
-        match self.state {
-            TimerState::User => {
-                self.utime_ns += delta;
-                self.update_itimer(ITimerType::Virtual, delta, &emitter);
-                self.update_itimer(ITimerType::Prof, delta, &emitter);
-            }
-            TimerState::Kernel => {
-                self.stime_ns += delta;
-                self.update_itimer(ITimerType::Prof, delta, &emitter);
-            }
-            TimerState::None => {}
+        let mut charged = (0, 0);
+        if running {
+            let cpu_delta = (now_ns - self.last_cpu_ns).min(TICK_NS) as u64;
+            charged = match self.state {
+                TimerState::User => (cpu_delta, 0),
+                TimerState::Kernel => (0, cpu_delta),
+                TimerState::None => (0, 0),
+            };
+            self.utime_ns += charged.0 as usize;
+            self.stime_ns += charged.1 as usize;
+            self.last_cpu_ns = now_ns;
         }
-        self.update_itimer(ITimerType::Real, delta, &emitter);
-        self.last_wall_ns = now_ns;
+        charged
     }
 
     /// Updates the timer state.
@@ -190,87 +499,11 @@ impl TimeManager {
         self.state = state;
     }
 
-    /// Sets the interval timer of the specified type with the given interval
-    /// and remaining time.
-    pub fn set_itimer(
-        &mut self,
-        ty: ITimerType,
-        interval_ns: usize,
-        remained_ns: usize,
-    ) -> (TimeValue, TimeValue) {
-        let old = mem::replace(
-            &mut self.itimers[ty as usize],
-            ITimer::new(interval_ns, remained_ns),
-        );
-        (
-            time_value_from_nanos(old.interval_ns),
-            time_value_from_nanos(old.remained_ns),
-        )
-    }
-
-    /// Gets the current interval and remaining time.
-    pub fn get_itimer(&self, ty: ITimerType) -> (TimeValue, TimeValue) {
-        let itimer = &self.itimers[ty as usize];
-        (
-            time_value_from_nanos(itimer.interval_ns),
-            time_value_from_nanos(itimer.remained_ns),
-        )
-    }
-
-    fn update_itimer(&mut self, ty: ITimerType, delta: usize, emitter: impl Fn(Signo)) {
-        if self.itimers[ty as usize].update(delta) {
-            emitter(ty.signo());
-        }
-    }
-}
-
-async fn alarm_task() {
-    loop {
-        let guard = ALARM_LIST.lock();
-        let Some(entry) = guard.peek() else {
-            drop(guard);
-            listener!(EVENT_NEW_TIMER => listener);
-
-            if !ALARM_LIST.lock().is_empty() {
-                continue;
-            }
-            listener.await;
-
-            continue;
-        };
-
-        let now = wall_time();
-        if entry.deadline <= now {
-            let entry_deadline = entry.deadline;
-            if let Some(task) = entry.task.upgrade() {
-                drop(guard);
-                poll_timer(&task);
-            } else {
-                drop(guard);
-            }
-            let mut guard = ALARM_LIST.lock();
-            assert!(guard.pop().is_some_and(|it| it.deadline == entry_deadline));
-        } else {
-            let deadline = entry.deadline;
-            drop(guard);
-            listener!(EVENT_NEW_TIMER => listener);
-            if ALARM_LIST
-                .lock()
-                .peek()
-                .is_none_or(|it| it.deadline != deadline)
-            {
-                continue;
-            }
-            let _ = timeout_at(Some(deadline), listener).await;
-        }
+    /// Restarts CPU time accounting from now without charging anything.
+    ///
+    /// Called when the thread is switched out and back in, so that the time
+    /// it spent blocked or waiting for a CPU is not charged by its next poll.
+    pub fn reset(&mut self) {
+        self.last_cpu_ns = monotonic_time_nanos() as usize;
     }
 }
-
-/// Spawns the alarm task.
-pub fn spawn_alarm_task() {
-    axtask::spawn_raw(
-        || block_on(alarm_task()),
-        "alarm_task".to_owned(),
-        axconfig::TASK_STACK_SIZE,
-    );
-}
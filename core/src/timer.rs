@@ -0,0 +1,537 @@
+//! The kernel timer wheel.
+//!
+//! Every kernel timer (timerfd expirations, `poll` timeouts, interval timers)
+//! is an entry in one hashed wheel of [`WHEEL_SIZE`] one-millisecond buckets.
+//! Arming and cancelling a timer are constant time, and a cancelled timer
+//! leaves its bucket at once, so timers re-armed over and over leave nothing
+//! behind for the dispatcher to scan. Deadlines further away than one
+//! revolution stay in their bucket until a later pass.
+//!
+//! The timer interrupt only checks the occupancy bitmap of the buckets that
+//! became due and wakes the dispatcher task, which runs the expired callbacks
+//! in task context. Timers cost nothing between the passes over their
+//! bucket, but a timer due more than one revolution ahead is not free: every
+//! revolution its bucket comes due, the dispatcher is woken and scans past
+//! it. There is no coarser level to cascade such timers from.
+//!
+//! Timers due within 16 milliseconds of arming are kept in deadline order
+//! instead and fire at their exact deadline: the hardware timer is
+//! programmed one-shot for the earliest of them whenever it comes before the
+//! next periodic tick. Long timeouts stay on the wheel and never reprogram
+//! the hardware.
+//!
+//! [`wait_for_io`] builds blocking I/O with a deadline on top of it.
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, vec::Vec};
+use core::{
+    future::{IntoFuture, poll_fn},
+    pin::pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use axerrno::{AxError, AxResult};
+use axhal::time::{
+    NANOS_PER_MILLIS, NANOS_PER_SEC, TimeValue, monotonic_time, monotonic_time_nanos,
+    set_oneshot_timer,
+};
+use axpoll::{IoEvents, Pollable};
+use axtask::{current, future::block_on};
+use kspin::SpinNoIrq;
+use lazy_static::lazy_static;
+use slab::Slab;
+
+use crate::{
+    park::{TaskParker, WakeReason},
+    task::AsThread,
+    time::sleep_until_realtime,
+};
+
+/// Number of buckets in the wheel.
+const WHEEL_SIZE: usize = 512;
+/// Width of a bucket.
+const TICK_NANOS: u64 = NANOS_PER_MILLIS;
+/// Timers due sooner than this when armed bypass the wheel and fire at their
+/// exact deadline.
+const PRECISE_RANGE_NANOS: u64 = 16 * TICK_NANOS;
+/// Interval of the periodic timer interrupt.
+const PERIOD_NANOS: u64 = NANOS_PER_SEC / axconfig::TICKS_PER_SEC as u64;
+
+/// The resolution of timer deadlines, as reported by `clock_getres`.
+pub const RESOLUTION: Duration = Duration::from_nanos(1);
+
+/// The timer slack of a thread that never set one, in nanoseconds.
+pub const DEFAULT_TIMER_SLACK_NS: u64 = 50_000;
+/// The largest timer slack, in nanoseconds; larger ones are clamped to it.
+pub const MAX_TIMER_SLACK_NS: u64 = NANOS_PER_SEC;
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+struct Timer {
+    id: u64,
+    tick: u64,
+    /// The index of the timer in its bucket.
+    slot: usize,
+    /// The deadline in nanoseconds, for a timer kept in `Wheel::precise`.
+    precise: Option<u64>,
+    callback: Callback,
+}
+
+struct Wheel {
+    timers: Slab<Timer>,
+    /// Slab keys of the timers on the wheel.
+    buckets: [Vec<usize>; WHEEL_SIZE],
+    /// Slab keys of precise timers by `(deadline, timer id)`.
+    precise: BTreeMap<(u64, u64), usize>,
+    next_id: u64,
+}
+
+impl Wheel {
+    /// Puts the timer at `key` into the bucket of its tick.
+    fn link(&mut self, key: usize) {
+        let index = self.timers[key].tick as usize % WHEEL_SIZE;
+        let bucket = &mut self.buckets[index];
+        self.timers[key].slot = bucket.len();
+        bucket.push(key);
+        OCCUPIED[index / 64].fetch_or(1 << (index % 64), Ordering::Release);
+    }
+
+    /// Takes the timer at `key` out of its bucket. The slab entry stays.
+    fn unlink(&mut self, key: usize) {
+        let Timer { tick, slot, .. } = self.timers[key];
+        let index = tick as usize % WHEEL_SIZE;
+        let bucket = &mut self.buckets[index];
+        bucket.swap_remove(slot);
+        if let Some(&moved) = bucket.get(slot) {
+            self.timers[moved].slot = slot;
+        }
+        if bucket.is_empty() {
+            OCCUPIED[index / 64].fetch_and(!(1 << (index % 64)), Ordering::Release);
+        }
+    }
+
+    fn expire_bucket(&mut self, index: usize, now: u64, expired: &mut Vec<Callback>) {
+        let mut i = 0;
+        while let Some(&key) = self.buckets[index].get(i) {
+            if self.timers[key].tick > now {
+                i += 1;
+                continue;
+            }
+            // The last timer of the bucket moves into slot `i`.
+            self.unlink(key);
+            expired.push(self.timers.remove(key).callback);
+        }
+    }
+
+    fn expire_precise(&mut self, now_nanos: u64, expired: &mut Vec<Callback>) {
+        while let Some(entry) = self.precise.first_entry() {
+            if entry.key().0 > now_nanos {
+                break;
+            }
+            let key = entry.remove();
+            expired.push(self.timers.remove(key).callback);
+        }
+        self.update_next_precise();
+    }
+
+    fn update_next_precise(&self) {
+        let next = self
+            .precise
+            .first_key_value()
+            .map_or(u64::MAX, |((deadline, _), _)| *deadline);
+        NEXT_PRECISE.store(next, Ordering::Release);
+    }
+}
+
+lazy_static! {
+    static ref WHEEL: SpinNoIrq<Wheel> = SpinNoIrq::new(Wheel {
+        timers: Slab::new(),
+        buckets: [const { Vec::new() }; WHEEL_SIZE],
+        precise: BTreeMap::new(),
+        next_id: 0,
+    });
+}
+
+/// One bit per bucket, set while the bucket holds entries.
+static OCCUPIED: [AtomicU64; WHEEL_SIZE / 64] = [const { AtomicU64::new(0) }; WHEEL_SIZE / 64];
+/// The last tick processed by the dispatcher. Only written with the wheel
+/// locked.
+static CURSOR: AtomicU64 = AtomicU64::new(0);
+/// The earliest deadline of a precise timer, or `u64::MAX` if there is none.
+/// Only written with the wheel locked.
+static NEXT_PRECISE: AtomicU64 = AtomicU64::new(u64::MAX);
+static PENDING: AtomicBool = AtomicBool::new(false);
+static DISPATCHER: SpinNoIrq<Option<Waker>> = SpinNoIrq::new(None);
+
+fn now_tick() -> u64 {
+    monotonic_time_nanos() / TICK_NANOS
+}
+
+fn occupied(tick: u64) -> bool {
+    let index = tick as usize % WHEEL_SIZE;
+    OCCUPIED[index / 64].load(Ordering::Acquire) & (1 << (index % 64)) != 0
+}
+
+/// Programs the timer interrupt of this CPU for `deadline` if it comes
+/// before the next periodic tick, which the handler reprograms afterwards.
+fn program(deadline: u64, now_nanos: u64) {
+    if deadline < now_nanos + PERIOD_NANOS {
+        set_oneshot_timer(deadline);
+    }
+}
+
+/// A handle to an armed timer.
+#[derive(Debug)]
+pub struct TimerHandle {
+    key: usize,
+    id: u64,
+}
+
+impl TimerHandle {
+    /// Cancels the timer.
+    ///
+    /// Returns `true` if the callback had not run yet and never will, or
+    /// `false` if it has already been taken for expiry.
+    pub fn cancel(self) -> bool {
+        let mut wheel = WHEEL.lock();
+        if wheel
+            .timers
+            .get(self.key)
+            .is_some_and(|timer| timer.id == self.id)
+        {
+            match wheel.timers[self.key].precise {
+                Some(deadline) => {
+                    // An interrupt left programmed for it finds nothing due.
+                    wheel.precise.remove(&(deadline, self.id));
+                    wheel.update_next_precise();
+                }
+                None => wheel.unlink(self.key),
+            }
+            wheel.timers.remove(self.key);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Arms a timer that runs `callback` once the monotonic clock reaches
+/// `deadline`.
+///
+/// The callback runs at most once, in the dispatcher task, and must not
+/// block. A deadline in the past fires right away.
+pub fn register(deadline: TimeValue, callback: impl FnOnce() + Send + 'static) -> TimerHandle {
+    let nanos = deadline.as_nanos().min(u64::MAX as u128) as u64;
+    let mut wheel = WHEEL.lock();
+    let id = wheel.next_id;
+    wheel.next_id += 1;
+
+    let now_nanos = monotonic_time_nanos();
+    if nanos < now_nanos.saturating_add(PRECISE_RANGE_NANOS) {
+        let key = wheel.timers.insert(Timer {
+            id,
+            tick: 0,
+            slot: 0,
+            precise: Some(nanos),
+            callback: Box::new(callback),
+        });
+        wheel.precise.insert((nanos, id), key);
+        if nanos < NEXT_PRECISE.load(Ordering::Relaxed) {
+            NEXT_PRECISE.store(nanos, Ordering::Release);
+            program(nanos, now_nanos);
+        }
+        return TimerHandle { key, id };
+    }
+
+    let tick = nanos
+        .div_ceil(TICK_NANOS)
+        .max(CURSOR.load(Ordering::Relaxed) + 1);
+    let key = wheel.timers.insert(Timer {
+        id,
+        tick,
+        slot: 0,
+        precise: None,
+        callback: Box::new(callback),
+    });
+    wheel.link(key);
+    TimerHandle { key, id }
+}
+
+/// Delays `deadline` by less than `slack`, onto a multiple of the largest
+/// power of two within it, so that nearby deadlines fall on the same instant
+/// and fire in one interrupt.
+pub fn apply_slack(deadline: TimeValue, slack: Duration) -> TimeValue {
+    let slack = slack.as_nanos().min(MAX_TIMER_SLACK_NS as u128) as u64;
+    if slack == 0 {
+        return deadline;
+    }
+    let granule = 1 << slack.ilog2();
+    let nanos = deadline.as_nanos().min(u64::MAX as u128) as u64;
+    nanos
+        .checked_next_multiple_of(granule)
+        .map_or(deadline, Duration::from_nanos)
+}
+
+/// Returns the timer slack of the current thread, or zero outside of one.
+pub fn current_timer_slack() -> Duration {
+    let slack = current()
+        .try_as_thread()
+        .map_or(0, |thr| thr.timer_slack_ns());
+    Duration::from_nanos(slack)
+}
+
+/// Returns the monotonic deadline `timeout` from now, delayed by the timer
+/// slack of the current thread.
+pub fn deadline_after(timeout: Duration) -> TimeValue {
+    apply_slack(monotonic_time() + timeout, current_timer_slack())
+}
+
+/// Arms a timer that wakes `waker` at `deadline`.
+pub fn register_waker(deadline: TimeValue, waker: Waker) -> TimerHandle {
+    register(deadline, move || waker.wake())
+}
+
+/// Checks for due timers. Called from the timer interrupt, after the
+/// periodic tick has been programmed again.
+pub fn check_expiry() {
+    if PENDING.load(Ordering::Acquire) {
+        return;
+    }
+    let now_nanos = monotonic_time_nanos();
+    let next_precise = NEXT_PRECISE.load(Ordering::Acquire);
+    let now = now_nanos / TICK_NANOS;
+    let cursor = CURSOR.load(Ordering::Acquire);
+    let due = if next_precise <= now_nanos {
+        true
+    } else if now <= cursor {
+        false
+    } else if now - cursor >= WHEEL_SIZE as u64 {
+        OCCUPIED
+            .iter()
+            .any(|word| word.load(Ordering::Acquire) != 0)
+    } else {
+        (cursor + 1..=now).any(occupied)
+    };
+    if !due {
+        // The periodic tick may have been programmed over the deadline.
+        if next_precise != u64::MAX {
+            program(next_precise, now_nanos);
+        }
+        return;
+    }
+    PENDING.store(true, Ordering::Release);
+    if let Some(waker) = DISPATCHER.lock().take() {
+        waker.wake();
+    }
+}
+
+fn run_expired() {
+    let mut expired = Vec::new();
+    {
+        let mut wheel = WHEEL.lock();
+        let now_nanos = monotonic_time_nanos();
+        let now = now_nanos / TICK_NANOS;
+        let cursor = CURSOR.load(Ordering::Relaxed);
+        let count = now.saturating_sub(cursor).min(WHEEL_SIZE as u64);
+        for tick in now + 1 - count..=now {
+            wheel.expire_bucket(tick as usize % WHEEL_SIZE, now, &mut expired);
+        }
+        CURSOR.store(now.max(cursor), Ordering::Release);
+        wheel.expire_precise(now_nanos, &mut expired);
+        let next_precise = NEXT_PRECISE.load(Ordering::Relaxed);
+        if next_precise != u64::MAX {
+            program(next_precise, now_nanos);
+        }
+    }
+    for callback in expired {
+        callback();
+    }
+}
+
+async fn timer_task() {
+    loop {
+        poll_fn(|cx| {
+            if PENDING.swap(false, Ordering::AcqRel) {
+                return Poll::Ready(());
+            }
+            *DISPATCHER.lock() = Some(cx.waker().clone());
+            if PENDING.swap(false, Ordering::AcqRel) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+        run_expired();
+    }
+}
+
+/// Spawns the dispatcher task.
+pub fn spawn_timer_task() {
+    CURSOR.store(now_tick(), Ordering::Release);
+    axtask::spawn_raw(
+        || block_on(timer_task()),
+        "timer_task".into(),
+        axconfig::TASK_STACK_SIZE,
+    );
+}
+
+struct Sleep {
+    deadline: TimeValue,
+    timer: Option<(TimerHandle, Waker)>,
+}
+
+impl Sleep {
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if monotonic_time() >= self.deadline {
+            if let Some((timer, _)) = self.timer.take() {
+                timer.cancel();
+            }
+            return Poll::Ready(());
+        }
+        if !self
+            .timer
+            .as_ref()
+            .is_some_and(|(_, waker)| waker.will_wake(cx.waker()))
+        {
+            if let Some((timer, _)) = self.timer.take() {
+                timer.cancel();
+            }
+            let timer = register_waker(self.deadline, cx.waker().clone());
+            self.timer = Some((timer, cx.waker().clone()));
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some((timer, _)) = self.timer.take() {
+            timer.cancel();
+        }
+    }
+}
+
+/// The error of [`timeout`] and [`timeout_at`] when the deadline passes
+/// first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// Runs `f` until it completes or the monotonic clock reaches `deadline`.
+pub async fn timeout_at<F: IntoFuture>(deadline: TimeValue, f: F) -> Result<F::Output, TimedOut> {
+    let mut fut = pin!(f.into_future());
+    let mut sleep = Sleep {
+        deadline,
+        timer: None,
+    };
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = fut.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        sleep.poll(cx).map(|_| Err(TimedOut))
+    })
+    .await
+}
+
+/// Sleeps until the monotonic clock reaches `deadline`.
+pub async fn sleep_until(deadline: TimeValue) {
+    let mut sleep = Sleep {
+        deadline,
+        timer: None,
+    };
+    poll_fn(|cx| sleep.poll(cx)).await
+}
+
+/// Runs `f` until it completes or `duration` elapses, give or take the timer
+/// slack of the current thread. `None` waits forever.
+pub async fn timeout<F: IntoFuture>(
+    duration: Option<Duration>,
+    f: F,
+) -> Result<F::Output, TimedOut> {
+    match duration {
+        Some(duration) => timeout_at(deadline_after(duration), f).await,
+        None => Ok(f.await),
+    }
+}
+
+/// Runs `f` until it completes or `CLOCK_REALTIME` reaches `deadline`, for
+/// the absolute timeouts POSIX keeps on that clock. The deadline is checked
+/// again on each step of the clock. `None` waits forever.
+pub async fn timeout_at_realtime<F: IntoFuture>(
+    deadline: Option<TimeValue>,
+    f: F,
+) -> Result<F::Output, TimedOut> {
+    let Some(deadline) = deadline else {
+        return Ok(f.await);
+    };
+    let mut fut = pin!(f.into_future());
+    let mut sleep = pin!(sleep_until_realtime(deadline));
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = fut.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        sleep.as_mut().poll(cx).map(|_| Err(TimedOut))
+    })
+    .await
+}
+
+/// Retries `f` until it stops failing with `WouldBlock`, sleeping on
+/// `pollable` becoming ready for `events` in between.
+///
+/// This is the one way for blocking file operations to wait with a
+/// deadline and a signal in play. When several of them happen at once,
+/// readiness beats the deadline, which beats a signal: `f` gets a last try
+/// before the wait fails with `TimedOut`, and an interrupted wait checks the
+/// deadline before failing with `Interrupted`. With `nonblocking` it fails
+/// with `WouldBlock` right after the first try.
+pub fn wait_for_io<T>(
+    pollable: &(impl Pollable + ?Sized),
+    events: IoEvents,
+    nonblocking: bool,
+    deadline: Option<TimeValue>,
+    interruptible: bool,
+    mut f: impl FnMut() -> AxResult<T>,
+) -> AxResult<T> {
+    match f() {
+        Err(AxError::WouldBlock) if !nonblocking => {}
+        result => return result,
+    }
+    let parker = TaskParker::new();
+    let event = parker.waker(WakeReason::EVENT);
+    let timer = deadline.map(|deadline| register_waker(deadline, parker.waker(WakeReason::TIMER)));
+    let result = loop {
+        // Every source is armed before the check below, so none of them can
+        // fire unnoticed.
+        pollable.register(&mut Context::from_waker(&event), events);
+        if interruptible {
+            parker.watch_signals();
+        }
+        match f() {
+            Err(AxError::WouldBlock) => {}
+            result => break result,
+        }
+        let reasons = parker.park();
+        if reasons.contains(WakeReason::TIMER) {
+            break match f() {
+                Err(AxError::WouldBlock) => Err(AxError::TimedOut),
+                result => result,
+            };
+        }
+        if reasons.contains(WakeReason::SIGNAL) {
+            break match f() {
+                Err(AxError::WouldBlock)
+                    if deadline.is_some_and(|deadline| monotonic_time() >= deadline) =>
+                {
+                    Err(AxError::TimedOut)
+                }
+                Err(AxError::WouldBlock) => Err(AxError::Interrupted),
+                result => result,
+            };
+        }
+    };
+    if let Some(timer) = timer {
+        timer.cancel();
+    }
+    result
+}
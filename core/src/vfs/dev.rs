@@ -1,4 +1,7 @@
-use alloc::sync::Arc;
+use alloc::{
+    collections::btree_map::BTreeMap,
+    sync::{Arc, Weak},
+};
 use core::{any::Any, task::Context};
 
 use axfs::CachedFile;
@@ -7,6 +10,7 @@ use axfs_ng_vfs::{
     NodePermission, NodeType, VfsError, VfsResult,
 };
 use axpoll::{IoEvents, Pollable};
+use axsync::Mutex;
 use inherit_methods_macro::inherit_methods;
 use memory_addr::PhysAddrRange;
 
@@ -54,6 +58,25 @@ pub trait DeviceOps: Send + Sync {
     }
 }
 
+/// Live devices by ID, for opening device nodes made by `mknod` elsewhere.
+static DEVICES: Mutex<BTreeMap<u64, Weak<Device>>> = Mutex::new(BTreeMap::new());
+
+fn register(device_id: DeviceId, device: Weak<Device>) {
+    // Not assigned yet.
+    if device_id.0 == 0 {
+        return;
+    }
+    let mut devices = DEVICES.lock();
+    devices.retain(|_, device| device.strong_count() > 0);
+    devices.insert(device_id.0, device);
+}
+
+/// Finds the device with ID `device_id`, i.e. the driver backing device
+/// nodes with that `st_rdev`.
+pub fn find_device(device_id: DeviceId) -> Option<Arc<Device>> {
+    DEVICES.lock().get(&device_id.0).and_then(Weak::upgrade)
+}
+
 /// A device node in the filesystem.
 pub struct Device {
     node: SimpleFsNode,
@@ -70,7 +93,9 @@ impl Device {
     ) -> Arc<Self> {
         let node = SimpleFsNode::new(fs, node_type, NodePermission::default());
         node.metadata.lock().rdev = device_id;
-        Arc::new(Self { node, ops })
+        let device = Arc::new(Self { node, ops });
+        register(device_id, Arc::downgrade(&device));
+        device
     }
 
     /// Returns the inner device operations.
@@ -79,8 +104,9 @@ impl Device {
     }
 
     /// Updates the device ID.
-    pub fn set_device_id(&self, device_id: DeviceId) {
+    pub fn set_device_id(self: &Arc<Self>, device_id: DeviceId) {
         self.node.metadata.lock().rdev = device_id;
+        register(device_id, Arc::downgrade(self));
     }
 
     /// Returns the memory mapping behavior of the device.
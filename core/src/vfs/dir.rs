@@ -23,6 +23,25 @@ pub trait SimpleDirOps: Send + Sync + 'static {
     /// Look up a child directory or file by name.
     fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux>;
 
+    /// Get the children from position `cursor` on, each with its position.
+    ///
+    /// Positions must increase but need not be contiguous. Directories whose
+    /// contents change between calls, like the process list, use stable keys
+    /// such as the pid, so that a listing resumes after the last child
+    /// returned however many have come or gone since. The default numbers
+    /// [`child_names`](Self::child_names) in order.
+    fn children_from<'a>(
+        &'a self,
+        cursor: u64,
+    ) -> Box<dyn Iterator<Item = (u64, Cow<'a, str>)> + 'a> {
+        Box::new(
+            self.child_names()
+                .enumerate()
+                .skip(cursor as usize)
+                .map(|(i, name)| (i as u64, name)),
+        )
+    }
+
     /// Check if the directory is cacheable.
     ///
     /// See [`DirNodeOps::is_cacheable`].
@@ -73,11 +92,27 @@ impl Default for DirMapping {
 /// Directory created by [`SimpleDirOps::chain`].
 pub struct ChainedDirOps<A, B>(A, B);
 
+/// Positions of the second directory of a [`ChainedDirOps`] start here; the
+/// first must keep its positions below.
+pub const CHAIN_SPLIT: u64 = 1 << 32;
+
 impl<A: SimpleDirOps, B: SimpleDirOps> SimpleDirOps for ChainedDirOps<A, B> {
     fn child_names<'a>(&'a self) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
         Box::new(self.0.child_names().chain(self.1.child_names()))
     }
 
+    fn children_from<'a>(
+        &'a self,
+        cursor: u64,
+    ) -> Box<dyn Iterator<Item = (u64, Cow<'a, str>)> + 'a> {
+        let first = (cursor < CHAIN_SPLIT).then(|| self.0.children_from(cursor));
+        let second = self
+            .1
+            .children_from(cursor.saturating_sub(CHAIN_SPLIT))
+            .map(|(pos, name)| (pos + CHAIN_SPLIT, name));
+        Box::new(first.into_iter().flatten().chain(second))
+    }
+
     fn lookup_child(&self, name: &str) -> VfsResult<NodeOpsMux> {
         match self.0.lookup_child(name) {
             Ok(ops) => Ok(ops),
@@ -140,27 +175,36 @@ impl<O: SimpleDirOps> NodeOps for SimpleDir<O> {
 
 impl<O: SimpleDirOps> DirNodeOps for SimpleDir<O> {
     fn read_dir(&self, offset: u64, sink: &mut dyn DirEntrySink) -> VfsResult<usize> {
-        let children = [DOT, DOTDOT]
+        // Offsets 0 and 1 are `.` and `..`; a child at position `pos` is at
+        // offset `pos + 2`. Only the offset is kept between calls.
+        let children = [(0, Cow::Borrowed(DOT)), (1, Cow::Borrowed(DOTDOT))]
             .into_iter()
-            .map(Cow::Borrowed)
-            .chain(self.ops.child_names());
+            .skip(offset as usize)
+            .chain(
+                self.ops
+                    .children_from(offset.saturating_sub(2))
+                    .map(|(pos, name)| (pos + 2, name)),
+            );
 
         let this_entry = self.this.upgrade().unwrap();
         let this_dir = this_entry.as_dir()?;
 
         let mut count = 0;
-        for (i, name) in children.enumerate().skip(offset as usize) {
+        for (offset, name) in children {
             let metadata = match name.as_ref() {
                 DOT => this_entry.metadata(),
                 DOTDOT => this_entry
                     .parent()
                     .map_or_else(|| this_entry.metadata(), |parent| parent.metadata()),
-                other => {
-                    let entry = this_dir.lookup(other)?;
-                    entry.metadata()
-                }
+                // Children may vanish after being listed, e.g. exited
+                // processes; leave them out rather than fail the listing.
+                other => match this_dir.lookup(other) {
+                    Ok(entry) => entry.metadata(),
+                    Err(VfsError::NotFound) => continue,
+                    Err(err) => Err(err),
+                },
             }?;
-            if !sink.accept(&name, metadata.inode, metadata.node_type, i as u64 + 1) {
+            if !sink.accept(&name, metadata.inode, metadata.node_type, offset + 1) {
                 break;
             }
             count += 1;
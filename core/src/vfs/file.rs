@@ -78,7 +78,17 @@ pub struct SimpleFile {
 impl SimpleFile {
     /// Creates a simple file from given file operations.
     pub fn new(fs: Arc<SimpleFs>, ty: NodeType, ops: impl SimpleFileOps) -> Arc<Self> {
-        let node = SimpleFsNode::new(fs, ty, NodePermission::default());
+        Self::new_with_mode(fs, ty, NodePermission::default(), ops)
+    }
+
+    /// Creates a simple file with the given permission bits.
+    pub fn new_with_mode(
+        fs: Arc<SimpleFs>,
+        ty: NodeType,
+        mode: NodePermission,
+        ops: impl SimpleFileOps,
+    ) -> Arc<Self> {
+        let node = SimpleFsNode::new(fs, ty, mode);
         Arc::new(Self {
             node,
             ops: Arc::new(ops),
@@ -4,6 +4,7 @@ mod dev;
 mod dir;
 mod file;
 mod fs;
+mod mount;
 
 use alloc::sync::Arc;
 
@@ -12,6 +13,7 @@ pub use dev::*;
 pub use dir::*;
 pub use file::*;
 pub use fs::*;
+pub use mount::*;
 
 /// A callback that builds a `Arc<dyn DirNodeOps>` for a given
 /// `WeakDirEntry`.
@@ -0,0 +1,89 @@
+//! Users of mounts, which keep them from being unmounted.
+//!
+//! Open files, the working and root directories of processes and file
+//! mappings each hold a [`MountUse`] of the mount they are on. A mount with
+//! users is busy and cannot be unmounted, but it can be detached: it is then
+//! pinned, keeping its filesystem alive, until its last user goes.
+
+use alloc::{
+    collections::{BTreeMap, btree_map::Entry},
+    sync::Arc,
+};
+
+use axfs_ng_vfs::{Location, Mountpoint};
+use axsync::Mutex;
+
+struct MountUsers {
+    count: usize,
+    /// Set once the mount is detached.
+    pinned: Option<Arc<Mountpoint>>,
+}
+
+/// Users of every mount that has any, by the address of its mountpoint. Each
+/// [`MountUse`] holds its mountpoint, so an address is not reused while it
+/// is a key here.
+static USERS: Mutex<BTreeMap<usize, MountUsers>> = Mutex::new(BTreeMap::new());
+
+fn key(mountpoint: &Arc<Mountpoint>) -> usize {
+    Arc::as_ptr(mountpoint) as usize
+}
+
+/// A user of a mount, keeping it busy until dropped.
+pub struct MountUse(Arc<Mountpoint>);
+
+impl MountUse {
+    /// Takes a use of the mount `loc` is on.
+    pub fn new(loc: &Location) -> Self {
+        Self::of(loc.mountpoint().clone())
+    }
+
+    fn of(mountpoint: Arc<Mountpoint>) -> Self {
+        USERS
+            .lock()
+            .entry(key(&mountpoint))
+            .or_insert(MountUsers {
+                count: 0,
+                pinned: None,
+            })
+            .count += 1;
+        Self(mountpoint)
+    }
+}
+
+impl Clone for MountUse {
+    fn clone(&self) -> Self {
+        Self::of(self.0.clone())
+    }
+}
+
+impl Drop for MountUse {
+    fn drop(&mut self) {
+        let mut users = USERS.lock();
+        let Entry::Occupied(mut entry) = users.entry(key(&self.0)) else {
+            unreachable!("mount use not counted");
+        };
+        entry.get_mut().count -= 1;
+        if entry.get().count == 0 {
+            let pinned = entry.remove().pinned;
+            drop(users);
+            // The filesystem of a detached mount may go with this.
+            drop(pinned);
+        }
+    }
+}
+
+/// Returns how many users `mountpoint` has.
+pub fn mount_users(mountpoint: &Arc<Mountpoint>) -> usize {
+    USERS
+        .lock()
+        .get(&key(mountpoint))
+        .map_or(0, |users| users.count)
+}
+
+/// Pins `mountpoint`, which is being detached, until its last user goes.
+/// Does nothing if it has no users.
+pub fn pin_detached(mountpoint: &Arc<Mountpoint>) {
+    if let Some(users) = USERS.lock().get_mut(&key(mountpoint)) {
+        users.pinned = Some(mountpoint.clone());
+    }
+}
@@ -0,0 +1,37 @@
+#!/bin/sh
+# Builds the user-space tests in tests/ with the musl cross compiler for
+# ARCH and copies them, with tests/run.sh, into /tests on arceos/disk.img.
+#
+# Usage: scripts/build-tests.sh <arch>
+
+set -e
+
+ARCH=${1:?usage: $0 <arch>}
+ROOT=$(cd "$(dirname "$0")/.." && pwd)
+SRC=$ROOT/tests
+OUT=$ROOT/target/tests/$ARCH
+IMG=$ROOT/arceos/disk.img
+CC=${CC:-$ARCH-linux-musl-gcc}
+
+mkdir -p "$OUT"
+for src in "$SRC"/*.c; do
+	name=$(basename "$src" .c)
+	echo "CC $name"
+	"$CC" -static -O2 -Wall -pthread -o "$OUT/$name" "$src"
+done
+
+cmds=$(mktemp)
+trap 'rm -f "$cmds"' EXIT
+{
+	echo "mkdir /tests"
+	echo "mkdir /tests/bin"
+	echo "mkdir /tests/work"
+	echo "write $SRC/run.sh /tests/run.sh"
+	echo "sif /tests/run.sh mode 0100755"
+	for bin in "$OUT"/*; do
+		name=$(basename "$bin")
+		echo "write $bin /tests/bin/$name"
+		echo "sif /tests/bin/$name mode 0100755"
+	done
+} >"$cmds"
+debugfs -w -f "$cmds" "$IMG" >/dev/null
@@ -2,6 +2,7 @@
 
 import argparse
 import datetime
+import re
 import socket
 import subprocess
 import sys
@@ -9,6 +10,11 @@ import threading
 
 parser = argparse.ArgumentParser()
 parser.add_argument("arch")
+parser.add_argument(
+    "--tests",
+    action="store_true",
+    help="run the user-space tests installed by `make tests`",
+)
 
 args = parser.parse_args()
 arch = args.arch
@@ -47,10 +53,16 @@ try:
         raise Exception("QEMU exited prematurely")
 
     PROMPT = "starry:~#"
+    SUMMARY = re.compile(r"TESTS DONE: passed=(\d+) failed=(\d+) skipped=(\d+)")
+    timeout = datetime.timedelta(seconds=900 if args.tests else 10)
 
     s = socket.create_connection(("localhost", 4444), timeout=5)
+    if args.tests:
+        # A test may run quietly for up to the two minutes run.sh allows it.
+        s.settimeout(150)
     buffer = ""
     sent = False
+    summary = None
     start = datetime.datetime.now()
 
     while True:
@@ -66,10 +78,18 @@ try:
         buffer += b
 
         if PROMPT in buffer and not sent:
-            s.sendall(b"exit\r\n")
+            if args.tests:
+                s.sendall(b"sh /tests/run.sh\r\n")
+            else:
+                s.sendall(b"exit\r\n")
             sent = True
 
-        if datetime.datetime.now() - start > datetime.timedelta(seconds=10):
+        if args.tests and summary is None:
+            summary = SUMMARY.search(buffer)
+            if summary:
+                s.sendall(b"exit\r\n")
+
+        if datetime.datetime.now() - start > timeout:
             raise Exception("Timeout waiting for exit")
 
     if PROMPT not in buffer:
@@ -77,8 +97,16 @@ try:
 
     print()
     print("\x1b[32m✔ Boot into BusyBox shell\x1b[0m")
+
+    if args.tests:
+        if summary is None:
+            raise Exception("Tests did not finish")
+        passed, failed, skipped = map(int, summary.groups())
+        if failed:
+            raise Exception(f"{failed} tests failed")
+        print(f"\x1b[32m✔ {passed} tests passed, {skipped} skipped\x1b[0m")
 except Exception:
-    print("\x1b[31m❌ Boot failed or timed out\x1b[0m")
+    print("\x1b[31m❌ Boot failed, timed out or tests failed\x1b[0m")
     raise
 finally:
     try:
@@ -53,6 +53,7 @@ pub fn run_initproc(args: &[String], envs: &[String]) -> i32 {
         Arc::default(),
         None,
     );
+    proc_data.set_initial_sp(ustack_top.as_usize());
     {
         let mut scope = proc_data.scope.write();
         starry_api::file::add_stdio(&mut FD_TABLE.scope_mut(&mut scope).write())
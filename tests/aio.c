@@ -0,0 +1,144 @@
+/* io_setup/io_submit/io_getevents: a batch of reads and writes, eventfd
+ * ticks per completion, timeouts, and io_destroy with work in flight. */
+
+#include "common.h"
+
+#include <sys/eventfd.h>
+
+#define IOCB_CMD_PREAD 0
+#define IOCB_CMD_PWRITE 1
+#define IOCB_FLAG_RESFD 1
+
+struct iocb {
+	uint64_t aio_data;
+	uint32_t aio_key;
+	int32_t aio_rw_flags;
+	uint16_t aio_lio_opcode;
+	int16_t aio_reqprio;
+	uint32_t aio_fildes;
+	uint64_t aio_buf;
+	uint64_t aio_nbytes;
+	int64_t aio_offset;
+	uint64_t aio_reserved2;
+	uint32_t aio_flags;
+	uint32_t aio_resfd;
+};
+
+struct io_event {
+	uint64_t data;
+	uint64_t obj;
+	int64_t res;
+	int64_t res2;
+};
+
+#define NR 8
+#define LEN 16384
+
+static char bufs[NR][LEN];
+
+static void prep(struct iocb *cb, int op, int fd, int resfd, int i, off_t off)
+{
+	memset(cb, 0, sizeof(*cb));
+	cb->aio_data = 1000 + i;
+	cb->aio_lio_opcode = op;
+	cb->aio_fildes = fd;
+	cb->aio_buf = (uintptr_t)bufs[i];
+	cb->aio_nbytes = LEN;
+	cb->aio_offset = off;
+	if (resfd >= 0) {
+		cb->aio_flags = IOCB_FLAG_RESFD;
+		cb->aio_resfd = resfd;
+	}
+}
+
+int main(void)
+{
+	unsigned long ctx = 0;
+	struct iocb cbs[NR], *ptrs[NR];
+	struct io_event events[NR];
+	int seen[NR] = { 0 };
+	int fd, efd, reaped = 0;
+	uint64_t ticks = 0, n;
+	struct timespec ts = { 0, 50 * 1000000 };
+	int64_t start;
+
+	fd = open("aio.dat", O_RDWR | O_CREAT | O_TRUNC, 0644);
+	CHECK(fd >= 0);
+	efd = eventfd(0, EFD_NONBLOCK);
+	CHECK(efd >= 0);
+	/* The first half of the file is read back by the batch; the second
+	 * half is written by it. */
+	fill(fd, 0, NR / 2 * LEN, 'r');
+
+	CHECK(syscall(SYS_io_setup, NR, &ctx) == 0);
+	CHECK(ctx != 0);
+	CHECK_ERR(syscall(SYS_io_setup, NR, &ctx), EINVAL);
+
+	for (int i = 0; i < NR; i++) {
+		if (i % 2 == 0) {
+			prep(&cbs[i], IOCB_CMD_PREAD, fd, efd, i, i / 2 * LEN);
+		} else {
+			off_t off = (NR / 2 + i / 2) * LEN;
+			for (int j = 0; j < LEN; j++)
+				bufs[i][j] = (char)('w' + (off + j) % 251);
+			prep(&cbs[i], IOCB_CMD_PWRITE, fd, efd, i, off);
+		}
+		ptrs[i] = &cbs[i];
+	}
+	CHECK_EQ(syscall(SYS_io_submit, ctx, NR, ptrs), NR);
+
+	/* Reap in small bites, whatever order the completions come in. */
+	while (reaped < NR) {
+		long got = syscall(SYS_io_getevents, ctx, 1, 3, events, NULL);
+		CHECK(got >= 1 && got <= 3);
+		for (long k = 0; k < got; k++) {
+			int i = (int)(events[k].data - 1000);
+			CHECK(i >= 0 && i < NR);
+			CHECK_EQ(events[k].obj, (uintptr_t)&cbs[i]);
+			CHECK_EQ(events[k].res, LEN);
+			CHECK_EQ(seen[i], 0);
+			seen[i] = 1;
+			if (cbs[i].aio_lio_opcode == IOCB_CMD_PREAD)
+				CHECK(filled(bufs[i], cbs[i].aio_offset, LEN, 'r'));
+		}
+		reaped += got;
+	}
+	/* One tick per completion. */
+	while (read(efd, &n, sizeof(n)) == sizeof(n))
+		ticks += n;
+	CHECK_EQ(ticks, NR);
+
+	for (int i = 1; i < NR; i += 2) {
+		static char check[LEN];
+		CHECK_EQ(pread(fd, check, LEN, cbs[i].aio_offset), LEN);
+		CHECK(filled(check, cbs[i].aio_offset, LEN, 'w'));
+	}
+
+	/* Nothing left to reap: the timeout is honoured. */
+	start = mono_ms();
+	CHECK_EQ(syscall(SYS_io_getevents, ctx, 1, NR, events, &ts), 0);
+	CHECK(mono_ms() - start >= 40);
+	CHECK_EQ(syscall(SYS_io_getevents, ctx, 0, NR, events, NULL), 0);
+	CHECK_ERR(syscall(SYS_io_getevents, ctx, 2, 1, events, NULL), EINVAL);
+
+	/* Destroying the context waits for the requests still running. */
+	for (int i = 0; i < NR; i++) {
+		off_t off = (NR + i) * LEN;
+		for (int j = 0; j < LEN; j++)
+			bufs[i][j] = (char)('d' + (off + j) % 251);
+		prep(&cbs[i], IOCB_CMD_PWRITE, fd, -1, i, off);
+	}
+	CHECK_EQ(syscall(SYS_io_submit, ctx, NR, ptrs), NR);
+	CHECK_EQ(syscall(SYS_io_destroy, ctx), 0);
+	for (int i = 0; i < NR; i++) {
+		static char check[LEN];
+		CHECK_EQ(pread(fd, check, LEN, cbs[i].aio_offset), LEN);
+		CHECK(filled(check, cbs[i].aio_offset, LEN, 'd'));
+	}
+	CHECK_ERR(syscall(SYS_io_getevents, ctx, 0, NR, events, NULL), EINVAL);
+
+	close(efd);
+	close(fd);
+	unlink("aio.dat");
+	return 0;
+}
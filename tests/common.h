@@ -0,0 +1,189 @@
+/* Helpers shared by the user-space tests.
+ *
+ * A test is a static program that exits 0 when it passes, SKIP when what
+ * it tests cannot run here (e.g. it needs root), and anything else when it
+ * fails. Failed checks print where and why before exiting. */
+
+#ifndef STARRY_TESTS_COMMON_H
+#define STARRY_TESTS_COMMON_H
+
+#define _GNU_SOURCE
+#include <errno.h>
+#include <fcntl.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <sys/syscall.h>
+#include <sys/types.h>
+#include <sys/wait.h>
+#include <time.h>
+#include <unistd.h>
+
+#define SKIP 77
+
+#define FAIL(...)                                                              \
+	do {                                                                   \
+		fprintf(stderr, "%s:%d: ", __FILE__, __LINE__);                \
+		fprintf(stderr, __VA_ARGS__);                                  \
+		fprintf(stderr, "\n");                                         \
+		exit(1);                                                       \
+	} while (0)
+
+/* Check that `cond` holds; errno is printed as a hint. */
+#define CHECK(cond)                                                            \
+	do {                                                                   \
+		if (!(cond))                                                   \
+			FAIL("CHECK(%s) failed (errno %d: %s)", #cond, errno,  \
+			     strerror(errno));                                 \
+	} while (0)
+
+/* Check that `expr` fails with `err`. */
+#define CHECK_ERR(expr, err)                                                   \
+	do {                                                                   \
+		errno = 0;                                                     \
+		long _ret = (long)(expr);                                      \
+		if (_ret != -1 || errno != (err))                              \
+			FAIL("%s returned %ld, errno %d (%s), want %s", #expr, \
+			     _ret, errno, strerror(errno), #err);              \
+	} while (0)
+
+/* Check that `a == b`, printing both if not. */
+#define CHECK_EQ(a, b)                                                         \
+	do {                                                                   \
+		long long _a = (long long)(a), _b = (long long)(b);            \
+		if (_a != _b)                                                  \
+			FAIL("CHECK_EQ(%s, %s) failed: %lld != %lld", #a, #b,  \
+			     _a, _b);                                          \
+	} while (0)
+
+static inline int64_t now_ns(clockid_t clock)
+{
+	struct timespec ts;
+	clock_gettime(clock, &ts);
+	return (int64_t)ts.tv_sec * 1000000000 + ts.tv_nsec;
+}
+
+static inline int64_t mono_ms(void)
+{
+	return now_ns(CLOCK_MONOTONIC) / 1000000;
+}
+
+static inline void sleep_ms(long ms)
+{
+	struct timespec ts = { ms / 1000, (ms % 1000) * 1000000 };
+	while (nanosleep(&ts, &ts) < 0 && errno == EINTR)
+		;
+}
+
+/* Returns the value of `key` in /proc/meminfo, in kB, or -1. */
+static inline long meminfo_kb(const char *key)
+{
+	FILE *f = fopen("/proc/meminfo", "r");
+	char line[128];
+	size_t len = strlen(key);
+	long value = -1;
+
+	if (!f)
+		return -1;
+	while (fgets(line, sizeof(line), f)) {
+		if (!strncmp(line, key, len) && line[len] == ':') {
+			value = strtol(line + len + 1, NULL, 10);
+			break;
+		}
+	}
+	fclose(f);
+	return value;
+}
+
+/* Returns the number in the file at `path`, such as a sysctl, or -1. */
+static inline long read_long(const char *path)
+{
+	FILE *f = fopen(path, "r");
+	long value = -1;
+
+	if (!f)
+		return -1;
+	if (fscanf(f, "%ld", &value) != 1)
+		value = -1;
+	fclose(f);
+	return value;
+}
+
+/* Writes `value` to the file at `path`; returns 0 or -1 with errno set. */
+static inline int write_long(const char *path, long value)
+{
+	char buf[32];
+	int fd = open(path, O_WRONLY);
+	int len = snprintf(buf, sizeof(buf), "%ld\n", value);
+	int ret = 0;
+
+	if (fd < 0)
+		return -1;
+	if (write(fd, buf, len) != len)
+		ret = -1;
+	close(fd);
+	return ret;
+}
+
+/* Writes `len` bytes of a pattern keyed by `seed` and the file offset at
+ * `offset`, or fails the test. */
+static inline void fill(int fd, off_t offset, size_t len, char seed)
+{
+	char buf[4096];
+	size_t done = 0;
+
+	while (done < len) {
+		size_t n = len - done < sizeof(buf) ? len - done : sizeof(buf);
+		for (size_t i = 0; i < n; i++)
+			buf[i] = (char)(seed + (offset + done + i) % 251);
+		CHECK(pwrite(fd, buf, n, offset + done) == (ssize_t)n);
+		done += n;
+	}
+}
+
+/* Checks that `buf` holds what `fill` wrote at `offset`. */
+static inline int filled(const char *buf, off_t offset, size_t len, char seed)
+{
+	for (size_t i = 0; i < len; i++)
+		if (buf[i] != (char)(seed + (offset + i) % 251))
+			return 0;
+	return 1;
+}
+
+/* Waits for `pid` and returns its exit status, or 128 + signal. */
+static inline int wait_child(pid_t pid)
+{
+	int status;
+
+	CHECK(waitpid(pid, &status, 0) == pid);
+	if (WIFSIGNALED(status))
+		return 128 + WTERMSIG(status);
+	return WEXITSTATUS(status);
+}
+
+/* Runs `fn` in a child which drops to an unprivileged uid and gid first,
+ * and returns its exit status. */
+static inline int as_nobody(int (*fn)(void *), void *arg)
+{
+	pid_t pid = fork();
+
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		if (setgid(65534) < 0 || setuid(65534) < 0)
+			_exit(100);
+		_exit(fn(arg));
+	}
+	return wait_child(pid);
+}
+
+static inline void require_root(void)
+{
+	if (geteuid() != 0) {
+		fprintf(stderr, "needs root\n");
+		exit(SKIP);
+	}
+}
+
+#endif
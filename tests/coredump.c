@@ -0,0 +1,175 @@
+/* Core dumps: a process that jumps to an unmapped address leaves an ELF
+ * core whose NT_PRSTATUS has its pid and that address as the pc, and whose
+ * NT_FILE lists its file mapping with the path and page offset. */
+
+#include "common.h"
+
+#include <elf.h>
+#include <limits.h>
+#include <signal.h>
+#include <sys/mman.h>
+#include <sys/resource.h>
+
+#define PAGE 4096
+#define BAD_PC 0x1230000UL
+#define PATTERN "/proc/sys/kernel/core_pattern"
+
+/* Index of the pc in elf_gregset_t. */
+#if defined(__x86_64__)
+#define PC_REG 16
+#elif defined(__aarch64__)
+#define PC_REG 32
+#elif defined(__riscv)
+#define PC_REG 0
+#elif defined(__loongarch64)
+#define PC_REG 33
+#endif
+
+/* Offsets in struct elf_prstatus. */
+#define PR_PID 32
+#define PR_REG 112
+
+static char mapped_path[PATH_MAX];
+static uintptr_t mapped_at;
+
+/* Maps pages 2 and 3 of the file, tells the parent where through `out`,
+ * and jumps to nowhere. */
+static void crash(int out)
+{
+	struct rlimit unlimited = { RLIM_INFINITY, RLIM_INFINITY };
+	int fd = open("mapped.dat", O_RDONLY);
+	void *map;
+
+	if (fd < 0 || setrlimit(RLIMIT_CORE, &unlimited) < 0)
+		_exit(1);
+	map = mmap(NULL, 2 * PAGE, PROT_READ, MAP_SHARED, fd, 2 * PAGE);
+	if (map == MAP_FAILED)
+		_exit(2);
+	mapped_at = (uintptr_t)map;
+	if (write(out, &mapped_at, sizeof(mapped_at)) != sizeof(mapped_at))
+		_exit(3);
+	((void (*)(void))BAD_PC)();
+	_exit(4);
+}
+
+static char *read_file(const char *path, size_t *len)
+{
+	int fd = open(path, O_RDONLY);
+	struct stat st;
+	char *buf;
+
+	CHECK(fd >= 0 && fstat(fd, &st) == 0);
+	buf = malloc(st.st_size);
+	CHECK(buf);
+	CHECK_EQ(read(fd, buf, st.st_size), st.st_size);
+	close(fd);
+	*len = st.st_size;
+	return buf;
+}
+
+static void check_prstatus(const char *desc, pid_t pid)
+{
+	const uint64_t *regs = (const uint64_t *)(desc + PR_REG);
+
+	CHECK_EQ(*(const int32_t *)(desc + PR_PID), pid);
+	if (regs[PC_REG] != BAD_PC)
+		FAIL("pc in the core is %#llx, want %#lx",
+		     (unsigned long long)regs[PC_REG], BAD_PC);
+}
+
+/* Returns whether the NT_FILE note lists the mapping made in crash(). */
+static int lists_mapping(const char *desc, uintptr_t at)
+{
+	const uint64_t *words = (const uint64_t *)desc;
+	uint64_t count = words[0];
+	const char *name = (const char *)&words[2 + 3 * count];
+
+	CHECK_EQ(words[1], PAGE);
+	for (uint64_t i = 0; i < count; i++) {
+		const uint64_t *entry = &words[2 + 3 * i];
+
+		if (entry[0] == at && !strcmp(name, mapped_path)) {
+			CHECK_EQ(entry[1], at + 2 * PAGE);
+			CHECK_EQ(entry[2], 2);
+			return 1;
+		}
+		name += strlen(name) + 1;
+	}
+	return 0;
+}
+
+static void check_core(const char *path, pid_t pid, uintptr_t at)
+{
+	size_t len, off;
+	char *core = read_file(path, &len);
+	Elf64_Ehdr *eh = (Elf64_Ehdr *)core;
+	Elf64_Phdr *note = NULL;
+	int prstatus = 0, file = 0;
+
+	CHECK(len >= sizeof(*eh) && !memcmp(eh->e_ident, ELFMAG, SELFMAG));
+	CHECK_EQ(eh->e_type, ET_CORE);
+	for (int i = 0; i < eh->e_phnum; i++) {
+		Elf64_Phdr *ph = (Elf64_Phdr *)(core + eh->e_phoff) + i;
+
+		if (ph->p_type == PT_NOTE)
+			note = ph;
+	}
+	CHECK(note && note->p_offset + note->p_filesz <= len);
+
+	for (off = note->p_offset; off < note->p_offset + note->p_filesz;) {
+		Elf64_Nhdr *nh = (Elf64_Nhdr *)(core + off);
+		const char *desc = (const char *)(nh + 1) +
+				   ((nh->n_namesz + 3) & ~3u);
+
+		/* The faulting thread is the first. */
+		if (nh->n_type == NT_PRSTATUS && !prstatus++)
+			check_prstatus(desc, pid);
+		if (nh->n_type == NT_FILE)
+			file = lists_mapping(desc, at);
+		off = desc + ((nh->n_descsz + 3) & ~3u) - core;
+	}
+	CHECK(prstatus >= 1);
+	CHECK(file);
+	free(core);
+}
+
+int main(void)
+{
+	char saved[128] = { 0 }, core[64];
+	int fd, pipefd[2];
+	FILE *f;
+	pid_t pid;
+
+	require_root();
+	f = fopen(PATTERN, "r");
+	CHECK(f && fgets(saved, sizeof(saved), f));
+	fclose(f);
+	f = fopen(PATTERN, "w");
+	CHECK(f && fputs("core.%p", f) >= 0 && fclose(f) == 0);
+
+	fd = open("mapped.dat", O_RDWR | O_CREAT | O_TRUNC, 0644);
+	CHECK(fd >= 0);
+	fill(fd, 0, 4 * PAGE, 'c');
+	close(fd);
+	CHECK(realpath("mapped.dat", mapped_path));
+
+	CHECK(pipe(pipefd) == 0);
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0)
+		crash(pipefd[1]);
+	close(pipefd[1]);
+	CHECK_EQ(read(pipefd[0], &mapped_at, sizeof(mapped_at)),
+		 sizeof(mapped_at));
+	close(pipefd[0]);
+	CHECK_EQ(wait_child(pid), 128 + SIGSEGV);
+
+	snprintf(core, sizeof(core), "core.%d", pid);
+	check_core(core, pid, mapped_at);
+
+	unlink(core);
+	unlink("mapped.dat");
+	f = fopen(PATTERN, "w");
+	CHECK(f && fputs(saved, f) >= 0 && fclose(f) == 0);
+	return 0;
+}
@@ -0,0 +1,57 @@
+/* User and group ids: root may take any ids, others only move between the
+ * real, effective and saved ones they have, and children inherit them. */
+
+#include "common.h"
+
+static void check_ids(uid_t uid, uid_t euid, gid_t gid, gid_t egid)
+{
+	CHECK_EQ(getuid(), uid);
+	CHECK_EQ(geteuid(), euid);
+	CHECK_EQ(getgid(), gid);
+	CHECK_EQ(getegid(), egid);
+}
+
+static void in_child(void)
+{
+	pid_t pid;
+
+	CHECK(setresgid(100, 101, 102) == 0);
+	CHECK(setresuid(1000, 1001, 1002) == 0);
+	check_ids(1000, 1001, 100, 101);
+
+	/* Without root only the three current ids can be taken. */
+	CHECK_ERR(setuid(0), EPERM);
+	CHECK_ERR(setresuid(5, -1, -1), EPERM);
+	CHECK_ERR(setgid(0), EPERM);
+	CHECK(setuid(1002) == 0);
+	check_ids(1000, 1002, 100, 101);
+	CHECK(setreuid(-1, 1000) == 0);
+	check_ids(1000, 1000, 100, 101);
+	CHECK(setgid(102) == 0);
+	check_ids(1000, 1000, 100, 102);
+
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		check_ids(1000, 1000, 100, 102);
+		_exit(0);
+	}
+	CHECK_EQ(wait_child(pid), 0);
+}
+
+int main(void)
+{
+	pid_t pid;
+
+	require_root();
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		in_child();
+		_exit(0);
+	}
+	CHECK_EQ(wait_child(pid), 0);
+	/* None of it reached the parent. */
+	check_ids(0, 0, 0, 0);
+	return 0;
+}
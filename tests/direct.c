@@ -0,0 +1,63 @@
+/* O_DIRECT: alignment is enforced, and direct and buffered I/O on the same
+ * file see each other's writes. */
+
+#include "common.h"
+
+#define PAGE 4096
+
+int main(void)
+{
+	char *buf, small[PAGE];
+	int fd, dfd;
+
+	CHECK(posix_memalign((void **)&buf, PAGE, 4 * PAGE) == 0);
+	fd = open("direct.dat", O_RDWR | O_CREAT | O_TRUNC, 0644);
+	CHECK(fd >= 0);
+	fill(fd, 0, 8 * PAGE, 'a');
+	dfd = open("direct.dat", O_RDWR | O_DIRECT);
+	CHECK(dfd >= 0);
+	CHECK(fcntl(dfd, F_GETFL) & O_DIRECT);
+
+	/* Misaligned buffers, offsets and lengths are refused. */
+	CHECK_ERR(pread(dfd, buf + 1, PAGE, 0), EINVAL);
+	CHECK_ERR(pread(dfd, buf, PAGE, 100), EINVAL);
+	CHECK_ERR(pread(dfd, buf, 100, 0), EINVAL);
+	CHECK_ERR(pwrite(dfd, buf, PAGE, 7), EINVAL);
+
+	/* Buffered writes still dirty in the cache are seen by direct reads. */
+	fill(fd, 2 * PAGE, PAGE, 'b');
+	CHECK_EQ(pread(dfd, buf, 4 * PAGE, 0), 4 * PAGE);
+	CHECK(filled(buf, 0, 2 * PAGE, 'a'));
+	CHECK(filled(buf + 2 * PAGE, 2 * PAGE, PAGE, 'b'));
+	CHECK(filled(buf + 3 * PAGE, 3 * PAGE, PAGE, 'a'));
+
+	/* Direct writes over cached pages are seen by buffered reads. */
+	CHECK_EQ(pread(fd, small, PAGE, 5 * PAGE), PAGE);
+	for (int i = 0; i < 2 * PAGE; i++)
+		buf[i] = (char)('c' + (4 * PAGE + i) % 251);
+	CHECK_EQ(pwrite(dfd, buf, 2 * PAGE, 4 * PAGE), 2 * PAGE);
+	CHECK_EQ(pread(fd, small, PAGE, 5 * PAGE), PAGE);
+	CHECK(filled(small, 5 * PAGE, PAGE, 'c'));
+	CHECK_EQ(pread(fd, small, PAGE, 4 * PAGE), PAGE);
+	CHECK(filled(small, 4 * PAGE, PAGE, 'c'));
+
+	/* Extending the file directly. */
+	for (int i = 0; i < PAGE; i++)
+		buf[i] = (char)('e' + (8 * PAGE + i) % 251);
+	CHECK_EQ(pwrite(dfd, buf, PAGE, 8 * PAGE), PAGE);
+	CHECK_EQ(lseek(fd, 0, SEEK_END), 9 * PAGE);
+	CHECK_EQ(pread(fd, small, PAGE, 8 * PAGE), PAGE);
+	CHECK(filled(small, 8 * PAGE, PAGE, 'e'));
+
+	/* O_DIRECT can be turned off and on again. */
+	CHECK(fcntl(dfd, F_SETFL, 0) == 0);
+	CHECK_EQ(pread(dfd, small + 1, 100, 100), 100);
+	CHECK(fcntl(dfd, F_SETFL, O_DIRECT) == 0);
+	CHECK_ERR(pread(dfd, small + 1, 100, 100), EINVAL);
+
+	close(dfd);
+	close(fd);
+	free(buf);
+	unlink("direct.dat");
+	return 0;
+}
@@ -0,0 +1,77 @@
+/* Dirty page tracking: writes dirty only the pages they touch, fsync and
+ * fdatasync write them all out, sync_file_range only those in its range,
+ * and the flusher writes them out past the background limit without any
+ * sync. */
+
+#include "common.h"
+
+#define PAGE 4096
+#define PAGES 256
+
+#define BG_BYTES "/proc/sys/vm/dirty_background_bytes"
+#define INTERVAL "/proc/sys/vm/dirty_writeback_centisecs"
+
+static long dirty_since(long base)
+{
+	return meminfo_kb("Dirty") - base;
+}
+
+int main(void)
+{
+	long base, bg_bytes, interval;
+	int64_t deadline;
+	int fd;
+
+	fd = open("dirty.dat", O_RDWR | O_CREAT | O_TRUNC, 0644);
+	CHECK(fd >= 0);
+	fill(fd, 0, PAGES * PAGE, 'a');
+	CHECK(fsync(fd) == 0);
+	sync();
+	base = meminfo_kb("Dirty");
+	CHECK(base >= 0);
+
+	/* Three scattered pages, one of them written twice. */
+	fill(fd, 3 * PAGE, 100, 'b');
+	fill(fd, 3 * PAGE + 2000, 100, 'b');
+	fill(fd, 100 * PAGE, PAGE, 'b');
+	fill(fd, 200 * PAGE + PAGE - 1, 1, 'b');
+	CHECK_EQ(dirty_since(base), 3 * PAGE / 1024);
+	CHECK(fsync(fd) == 0);
+	CHECK_EQ(dirty_since(base), 0);
+
+	fill(fd, 10 * PAGE, 2 * PAGE, 'c');
+	CHECK_EQ(dirty_since(base), 2 * PAGE / 1024);
+	CHECK(fdatasync(fd) == 0);
+	CHECK_EQ(dirty_since(base), 0);
+
+	/* Pages outside the range stay dirty. */
+	fill(fd, 20 * PAGE, 4 * PAGE, 'd');
+	CHECK(sync_file_range(fd, 21 * PAGE, 2 * PAGE,
+			      SYNC_FILE_RANGE_WAIT_BEFORE |
+				      SYNC_FILE_RANGE_WRITE |
+				      SYNC_FILE_RANGE_WAIT_AFTER) == 0);
+	CHECK_EQ(dirty_since(base), 2 * PAGE / 1024);
+	CHECK_ERR(sync_file_range(fd, -1, PAGE, SYNC_FILE_RANGE_WRITE), EINVAL);
+	CHECK_ERR(sync_file_range(fd, 0, PAGE, 8), EINVAL);
+	CHECK(fsync(fd) == 0);
+	CHECK_EQ(dirty_since(base), 0);
+
+	/* The flusher starts on its own past the background limit. */
+	bg_bytes = read_long(BG_BYTES);
+	interval = read_long(INTERVAL);
+	CHECK(bg_bytes >= 0 && interval >= 0);
+	CHECK(write_long(BG_BYTES, 16 * PAGE) == 0);
+	CHECK(write_long(INTERVAL, 20) == 0);
+	CHECK_EQ(read_long(BG_BYTES), 16 * PAGE);
+	fill(fd, 0, PAGES * PAGE, 'e');
+	deadline = mono_ms() + 10000;
+	while (dirty_since(base) > 16 * PAGE / 1024 && mono_ms() < deadline)
+		sleep_ms(50);
+	CHECK(dirty_since(base) <= 16 * PAGE / 1024);
+	CHECK(write_long(BG_BYTES, bg_bytes) == 0);
+	CHECK(write_long(INTERVAL, interval) == 0);
+
+	close(fd);
+	unlink("dirty.dat");
+	return 0;
+}
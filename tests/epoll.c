@@ -0,0 +1,233 @@
+/* epoll: edge-triggered and one-shot interest, EPOLLEXCLUSIVE, nesting and
+ * loops, EPOLL_CTL_MOD re-evaluating readiness, interest keyed by fd and
+ * file across dup and close, and the nanosecond timeout of epoll_pwait2. */
+
+#include "common.h"
+
+#include <pthread.h>
+#include <stdatomic.h>
+#include <sys/epoll.h>
+#include <sys/socket.h>
+
+#ifndef SYS_epoll_pwait2
+#define SYS_epoll_pwait2 441
+#endif
+
+static int add(int ep, int fd, uint32_t events, uint64_t data)
+{
+	struct epoll_event ev = { .events = events, .data.u64 = data };
+
+	return epoll_ctl(ep, EPOLL_CTL_ADD, fd, &ev);
+}
+
+static int wait_one(int ep, int ms, struct epoll_event *ev)
+{
+	return epoll_wait(ep, ev, 1, ms);
+}
+
+static void test_edge(void)
+{
+	int ep = epoll_create1(0), p[2];
+	struct epoll_event ev;
+	char c;
+
+	CHECK(ep >= 0 && pipe(p) == 0);
+	CHECK(add(ep, p[0], EPOLLIN | EPOLLET, 1) == 0);
+	CHECK_EQ(write(p[1], "ab", 2), 2);
+	CHECK_EQ(wait_one(ep, 0, &ev), 1);
+	CHECK_EQ(ev.data.u64, 1);
+	/* Unread data is not reported again until more arrives. */
+	CHECK_EQ(wait_one(ep, 50, &ev), 0);
+	CHECK_EQ(read(p[0], &c, 1), 1);
+	CHECK_EQ(wait_one(ep, 0, &ev), 0);
+	CHECK_EQ(write(p[1], "c", 1), 1);
+	CHECK_EQ(wait_one(ep, 0, &ev), 1);
+	CHECK_EQ(wait_one(ep, 0, &ev), 0);
+	close(p[0]);
+	close(p[1]);
+	close(ep);
+}
+
+static atomic_int woken;
+
+/* Waits on the epoll fd `arg` for half a second, counting a wakeup. */
+static void *waiter(void *arg)
+{
+	struct epoll_event ev;
+
+	if (epoll_wait((int)(intptr_t)arg, &ev, 1, 500) == 1)
+		atomic_fetch_add(&woken, 1);
+	return NULL;
+}
+
+static void test_oneshot(void)
+{
+	int ep = epoll_create1(0), p[2];
+	struct epoll_event ev = { .events = EPOLLIN | EPOLLONESHOT };
+	pthread_t threads[2];
+
+	CHECK(ep >= 0 && pipe(p) == 0);
+	CHECK(add(ep, p[0], EPOLLIN | EPOLLONESHOT, 2) == 0);
+	atomic_store(&woken, 0);
+	for (int i = 0; i < 2; i++)
+		CHECK(pthread_create(&threads[i], NULL, waiter,
+				     (void *)(intptr_t)ep) == 0);
+	sleep_ms(50);
+	CHECK_EQ(write(p[1], "x", 1), 1);
+	for (int i = 0; i < 2; i++)
+		pthread_join(threads[i], NULL);
+	CHECK_EQ(atomic_load(&woken), 1);
+
+	/* Disabled until re-armed, even though still readable. */
+	CHECK_EQ(wait_one(ep, 0, &ev), 0);
+	ev.data.u64 = 3;
+	CHECK(epoll_ctl(ep, EPOLL_CTL_MOD, p[0], &ev) == 0);
+	CHECK_EQ(wait_one(ep, 0, &ev), 1);
+	CHECK_EQ(ev.data.u64, 3);
+	close(p[0]);
+	close(p[1]);
+	close(ep);
+}
+
+static void test_exclusive(void)
+{
+	int p[2], eps[4];
+	pthread_t threads[4];
+	struct epoll_event ev;
+	char c;
+
+	CHECK(pipe(p) == 0);
+	for (int i = 0; i < 4; i++) {
+		eps[i] = epoll_create1(0);
+		CHECK(eps[i] >= 0);
+		CHECK(add(eps[i], p[0], EPOLLIN | EPOLLEXCLUSIVE, i) == 0);
+	}
+	/* Not allowed with MOD, nor on an epoll fd. */
+	ev.events = EPOLLIN;
+	CHECK_ERR(epoll_ctl(eps[0], EPOLL_CTL_MOD, p[0], &ev), EINVAL);
+	CHECK_ERR(add(eps[0], eps[1], EPOLLIN | EPOLLEXCLUSIVE, 0), EINVAL);
+
+	/* One write wakes one of four waiters, each on its own instance. */
+	atomic_store(&woken, 0);
+	for (int i = 0; i < 4; i++)
+		CHECK(pthread_create(&threads[i], NULL, waiter,
+				     (void *)(intptr_t)eps[i]) == 0);
+	sleep_ms(50);
+	CHECK_EQ(write(p[1], "x", 1), 1);
+	sleep_ms(100);
+	CHECK_EQ(read(p[0], &c, 1), 1);
+	for (int i = 0; i < 4; i++)
+		pthread_join(threads[i], NULL);
+	CHECK_EQ(atomic_load(&woken), 1);
+	for (int i = 0; i < 4; i++)
+		close(eps[i]);
+	close(p[0]);
+	close(p[1]);
+}
+
+static void test_nesting(void)
+{
+	int outer = epoll_create1(0), inner = epoll_create1(0), p[2];
+	int a = epoll_create1(0), b = epoll_create1(0);
+	struct epoll_event ev;
+
+	CHECK(outer >= 0 && inner >= 0 && a >= 0 && b >= 0 && pipe(p) == 0);
+	CHECK(add(inner, p[0], EPOLLIN, 1) == 0);
+	CHECK(add(outer, inner, EPOLLIN, 2) == 0);
+	CHECK_EQ(wait_one(outer, 0, &ev), 0);
+	CHECK_EQ(write(p[1], "x", 1), 1);
+	CHECK_EQ(wait_one(outer, 1000, &ev), 1);
+	CHECK_EQ(ev.data.u64, 2);
+	CHECK_EQ(wait_one(inner, 0, &ev), 1);
+	CHECK_EQ(ev.data.u64, 1);
+
+	CHECK_ERR(add(a, a, EPOLLIN, 0), EINVAL);
+	CHECK(add(a, b, EPOLLIN, 0) == 0);
+	CHECK_ERR(add(b, a, EPOLLIN, 0), ELOOP);
+	close(a);
+	close(b);
+	close(inner);
+	close(outer);
+	close(p[0]);
+	close(p[1]);
+}
+
+static void test_mod(void)
+{
+	int ep = epoll_create1(0), s[2];
+	struct epoll_event ev;
+
+	CHECK(ep >= 0 && socketpair(AF_UNIX, SOCK_STREAM, 0, s) == 0);
+	CHECK(add(ep, s[0], EPOLLIN, 1) == 0);
+	CHECK_EQ(wait_one(ep, 0, &ev), 0);
+	/* Already writable: reported with no I/O happening. */
+	ev.events = EPOLLOUT;
+	ev.data.u64 = 2;
+	CHECK(epoll_ctl(ep, EPOLL_CTL_MOD, s[0], &ev) == 0);
+	CHECK_EQ(wait_one(ep, 0, &ev), 1);
+	CHECK_EQ(ev.data.u64, 2);
+	CHECK(ev.events & EPOLLOUT);
+	close(s[0]);
+	close(s[1]);
+	close(ep);
+}
+
+static void test_dup_keys(void)
+{
+	int ep = epoll_create1(0), p[2], d;
+	struct epoll_event evs[4];
+
+	CHECK(ep >= 0 && pipe(p) == 0);
+	d = dup(p[0]);
+	CHECK(d >= 0);
+	/* One file through two fds makes two interests. */
+	CHECK(add(ep, p[0], EPOLLIN, 1) == 0);
+	CHECK(add(ep, d, EPOLLIN, 2) == 0);
+	CHECK_ERR(add(ep, d, EPOLLIN, 2), EEXIST);
+	CHECK_EQ(write(p[1], "x", 1), 1);
+	CHECK_EQ(epoll_wait(ep, evs, 4, 0), 2);
+
+	/* Closing one fd leaves its interest firing while the dup keeps the
+	 * file open, and it can't be deleted by fd any more. */
+	close(p[0]);
+	CHECK_EQ(epoll_wait(ep, evs, 4, 0), 2);
+	CHECK(epoll_ctl(ep, EPOLL_CTL_DEL, d, NULL) == 0);
+	CHECK_EQ(epoll_wait(ep, evs, 4, 0), 1);
+	CHECK_EQ(evs[0].data.u64, 1);
+	CHECK_ERR(epoll_ctl(ep, EPOLL_CTL_DEL, p[0], NULL), EBADF);
+	/* It goes with the file. */
+	close(d);
+	CHECK_EQ(epoll_wait(ep, evs, 4, 0), 0);
+	close(p[1]);
+	close(ep);
+}
+
+static void test_pwait2(void)
+{
+	int ep = epoll_create1(0);
+	struct timespec ts = { 0, 500000 };
+	struct epoll_event ev;
+	int64_t start, took;
+
+	CHECK(ep >= 0);
+	start = now_ns(CLOCK_MONOTONIC);
+	CHECK_EQ(syscall(SYS_epoll_pwait2, ep, &ev, 1, &ts, NULL, 8), 0);
+	took = now_ns(CLOCK_MONOTONIC) - start;
+	if (took < 500000 || took > 3000000)
+		FAIL("500us epoll_pwait2 took %lldus", (long long)took / 1000);
+	ts.tv_nsec = 1000000000;
+	CHECK_ERR(syscall(SYS_epoll_pwait2, ep, &ev, 1, &ts, NULL, 8), EINVAL);
+	close(ep);
+}
+
+int main(void)
+{
+	test_edge();
+	test_oneshot();
+	test_exclusive();
+	test_nesting();
+	test_mod();
+	test_dup_keys();
+	test_pwait2();
+	return 0;
+}
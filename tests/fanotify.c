@@ -0,0 +1,167 @@
+/* fanotify: events on one file by one process merge while queued, closes
+ * are reported by access mode, a write clears the ignored mask unless it
+ * survives modify, and a listener denying an open fails it with EPERM. */
+
+#include "common.h"
+
+#include <sys/fanotify.h>
+
+#define ALL (FAN_OPEN | FAN_ACCESS | FAN_MODIFY | FAN_CLOSE)
+
+static int group;
+
+/* Reads what is queued, closing the fds that come with it, and returns
+ * the number of events with their masks in `masks`. */
+static int events(uint64_t *masks, int max)
+{
+	char buf[4096];
+	ssize_t len = read(group, buf, sizeof(buf));
+	int n = 0;
+
+	if (len < 0 && errno == EAGAIN)
+		return 0;
+	CHECK(len > 0);
+	for (struct fanotify_event_metadata *ev = (void *)buf;
+	     FAN_EVENT_OK(ev, len); ev = FAN_EVENT_NEXT(ev, len)) {
+		CHECK(n < max);
+		CHECK_EQ(ev->pid, getpid());
+		masks[n++] = ev->mask;
+		if (ev->fd >= 0)
+			close(ev->fd);
+	}
+	return n;
+}
+
+/* Expects a single event with `mask`. */
+static void expect(uint64_t mask)
+{
+	uint64_t masks[8];
+	int n = events(masks, 8);
+
+	if (n != 1 || masks[0] != mask)
+		FAIL("got %d events, first %#llx, want one %#llx", n,
+		     n ? (unsigned long long)masks[0] : 0ULL,
+		     (unsigned long long)mask);
+}
+
+static void open_close(int flags)
+{
+	int fd = open("fan.dat", flags);
+
+	CHECK(fd >= 0);
+	if ((flags & O_ACCMODE) != O_RDONLY)
+		CHECK_EQ(write(fd, "x", 1), 1);
+	close(fd);
+}
+
+static void test_merge_and_close(void)
+{
+	int fd;
+
+	CHECK(fanotify_mark(group, FAN_MARK_ADD, ALL, AT_FDCWD, "fan.dat") ==
+	      0);
+	/* Open, two writes and close make one event. */
+	fd = open("fan.dat", O_WRONLY);
+	CHECK(fd >= 0);
+	CHECK_EQ(write(fd, "ab", 2), 2);
+	CHECK_EQ(write(fd, "cd", 2), 2);
+	close(fd);
+	expect(FAN_OPEN | FAN_MODIFY | FAN_CLOSE_WRITE);
+
+	open_close(O_RDONLY);
+	expect(FAN_OPEN | FAN_CLOSE_NOWRITE);
+
+	/* Events on another file stay apart. */
+	fd = open("other.dat", O_RDWR | O_CREAT, 0644);
+	CHECK(fd >= 0);
+	close(fd);
+	CHECK(fanotify_mark(group, FAN_MARK_ADD, FAN_CLOSE, AT_FDCWD,
+			    "other.dat") == 0);
+	open_close(O_RDONLY);
+	fd = open("other.dat", O_RDONLY);
+	CHECK(fd >= 0);
+	close(fd);
+	{
+		uint64_t masks[8];
+
+		CHECK_EQ(events(masks, 8), 2);
+		CHECK_EQ(masks[0], FAN_OPEN | FAN_CLOSE_NOWRITE);
+		CHECK_EQ(masks[1], FAN_CLOSE_NOWRITE);
+	}
+}
+
+static void test_ignored(void)
+{
+	/* Ignoring opens lasts until the file is written to. */
+	CHECK(fanotify_mark(group, FAN_MARK_ADD | FAN_MARK_IGNORED_MASK,
+			    FAN_OPEN, AT_FDCWD, "fan.dat") == 0);
+	open_close(O_RDONLY);
+	expect(FAN_CLOSE_NOWRITE);
+	open_close(O_WRONLY);
+	expect(FAN_MODIFY | FAN_CLOSE_WRITE);
+	open_close(O_RDONLY);
+	expect(FAN_OPEN | FAN_CLOSE_NOWRITE);
+
+	/* Unless it survives modify. */
+	CHECK(fanotify_mark(group,
+			    FAN_MARK_ADD | FAN_MARK_IGNORED_MASK |
+				    FAN_MARK_IGNORED_SURV_MODIFY,
+			    FAN_OPEN, AT_FDCWD, "fan.dat") == 0);
+	open_close(O_WRONLY);
+	expect(FAN_MODIFY | FAN_CLOSE_WRITE);
+	open_close(O_RDONLY);
+	expect(FAN_CLOSE_NOWRITE);
+	CHECK(fanotify_mark(group, FAN_MARK_FLUSH, 0, AT_FDCWD, NULL) == 0);
+}
+
+static void test_deny(void)
+{
+	int perm = fanotify_init(FAN_CLASS_CONTENT, O_RDONLY);
+	struct fanotify_event_metadata ev;
+	struct fanotify_response resp;
+	pid_t pid;
+
+	CHECK(perm >= 0);
+	CHECK(fanotify_mark(perm, FAN_MARK_ADD, FAN_OPEN_PERM, AT_FDCWD,
+			    "fan.dat") == 0);
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		int fd = open("fan.dat", O_RDONLY);
+
+		_exit(fd < 0 && errno == EPERM ? 0 : 1);
+	}
+	CHECK_EQ(read(perm, &ev, sizeof(ev)), sizeof(ev));
+	CHECK_EQ(ev.mask, FAN_OPEN_PERM);
+	CHECK_EQ(ev.pid, pid);
+	resp.fd = ev.fd;
+	resp.response = FAN_DENY;
+	CHECK_EQ(write(perm, &resp, sizeof(resp)), sizeof(resp));
+	close(ev.fd);
+	CHECK_EQ(wait_child(pid), 0);
+	close(perm);
+}
+
+int main(void)
+{
+	int fd;
+
+	require_root();
+	fd = open("fan.dat", O_RDWR | O_CREAT | O_TRUNC, 0644);
+	CHECK(fd >= 0);
+	close(fd);
+	group = fanotify_init(FAN_CLASS_NOTIF | FAN_NONBLOCK, O_RDONLY);
+	CHECK(group >= 0);
+	/* Unknown event bits are refused. */
+	CHECK_ERR(fanotify_mark(group, FAN_MARK_ADD, 0x100000000ULL, AT_FDCWD,
+				"fan.dat"),
+		  EINVAL);
+
+	test_merge_and_close();
+	test_ignored();
+	test_deny();
+	close(group);
+	unlink("fan.dat");
+	unlink("other.dat");
+	return 0;
+}
@@ -0,0 +1,215 @@
+/* Shared open file descriptions: descriptors from dup, fork and SCM_RIGHTS
+ * share the file offset, the status flags and the readahead state, so a
+ * scan continues where the other process left it, while another open of
+ * the same file starts over on its own. */
+
+#include "common.h"
+
+#include <sys/socket.h>
+
+#define SIZE (256 << 10)
+#define HALF (SIZE / 2)
+
+static char buf[SIZE];
+
+/* Returns the readahead counter `key` from the fdinfo of `fd`. */
+static long long counter(int fd, const char *key)
+{
+	char path[64], info[1024], want[64];
+	const char *at;
+	ssize_t len;
+	int info_fd;
+
+	snprintf(path, sizeof(path), "/proc/self/fdinfo/%d", fd);
+	info_fd = open(path, O_RDONLY);
+	CHECK(info_fd >= 0);
+	len = read(info_fd, info, sizeof(info) - 1);
+	CHECK(len > 0);
+	info[len] = 0;
+	close(info_fd);
+	snprintf(want, sizeof(want), "\n%s: ", key);
+	at = strstr(info, want);
+	if (!at)
+		FAIL("no %s in fdinfo:\n%s", key, info);
+	return strtoll(at + strlen(want), NULL, 10);
+}
+
+static long long reads(int fd)
+{
+	return counter(fd, "cache_hits") + counter(fd, "cache_misses");
+}
+
+/* Reads from `fd` to the end of the file, which must be what fill() wrote
+ * from `offset` on. */
+static int read_rest(int fd, off_t offset)
+{
+	ssize_t len, done = 0;
+
+	while ((len = read(fd, buf + done, 16 << 10)) > 0)
+		done += len;
+	if (len < 0 || done != SIZE - offset)
+		return 1;
+	return filled(buf, offset, done, 'f') ? 0 : 2;
+}
+
+static void test_dup(void)
+{
+	int a = open("share.dat", O_RDONLY);
+	int b = dup(a);
+	pid_t pid;
+
+	CHECK(a >= 0 && b >= 0);
+	CHECK_EQ(read(a, buf, 100), 100);
+	CHECK_EQ(lseek(b, 0, SEEK_CUR), 100);
+	CHECK_EQ(lseek(b, 1000, SEEK_SET), 1000);
+	CHECK_EQ(lseek(a, 0, SEEK_CUR), 1000);
+
+	CHECK(fcntl(a, F_SETFL, O_NONBLOCK) == 0);
+	CHECK(fcntl(b, F_GETFL) & O_NONBLOCK);
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0)
+		_exit(fcntl(b, F_GETFL) & O_NONBLOCK &&
+				      fcntl(a, F_SETFL, 0) == 0 ?
+			      0 :
+			      1);
+	CHECK_EQ(wait_child(pid), 0);
+	/* Cleared by the child. */
+	CHECK(!(fcntl(a, F_GETFL) & O_NONBLOCK));
+	close(a);
+	close(b);
+}
+
+/* The parent reads half, the child the rest, and the file is read whole
+ * exactly once, with the readahead window carried on. */
+static void test_fork(void)
+{
+	int fd = open("share.dat", O_RDONLY);
+	int other;
+	long long resets, before;
+	pid_t pid;
+
+	CHECK(fd >= 0);
+	for (off_t off = 0; off < HALF; off += 16 << 10)
+		CHECK_EQ(read(fd, buf + off, 16 << 10), 16 << 10);
+	CHECK(filled(buf, 0, HALF, 'f'));
+	resets = counter(fd, "pattern_resets");
+	before = reads(fd);
+
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		if (lseek(fd, 0, SEEK_CUR) != HALF)
+			_exit(3);
+		/* The same counters, not a fresh copy. */
+		if (reads(fd) != before)
+			_exit(4);
+		_exit(read_rest(fd, HALF));
+	}
+	CHECK_EQ(wait_child(pid), 0);
+	CHECK_EQ(lseek(fd, 0, SEEK_CUR), SIZE);
+	CHECK_EQ(read(fd, buf, 1), 0);
+	/* The child's reads went to this state, and kept its window. */
+	CHECK(reads(fd) > before);
+	CHECK_EQ(counter(fd, "pattern_resets"), resets);
+
+	/* Opening the file again gives a separate description. */
+	other = open("share.dat", O_RDONLY);
+	CHECK(other >= 0);
+	CHECK_EQ(lseek(other, 0, SEEK_CUR), 0);
+	CHECK_EQ(reads(other), 0);
+	CHECK_EQ(read(other, buf, 100), 100);
+	CHECK(filled(buf, 0, 100, 'f'));
+	CHECK_EQ(lseek(fd, 0, SEEK_CUR), SIZE);
+	close(other);
+	close(fd);
+}
+
+static int recv_fd(int sock)
+{
+	char data, control[CMSG_SPACE(sizeof(int))];
+	struct iovec iov = { &data, 1 };
+	struct msghdr msg = {
+		.msg_iov = &iov,
+		.msg_iovlen = 1,
+		.msg_control = control,
+		.msg_controllen = sizeof(control),
+	};
+	struct cmsghdr *cmsg;
+	int fd;
+
+	if (recvmsg(sock, &msg, MSG_CMSG_CLOEXEC) != 1)
+		return -1;
+	cmsg = CMSG_FIRSTHDR(&msg);
+	if (!cmsg || cmsg->cmsg_level != SOL_SOCKET ||
+	    cmsg->cmsg_type != SCM_RIGHTS)
+		return -1;
+	memcpy(&fd, CMSG_DATA(cmsg), sizeof(fd));
+	return fd;
+}
+
+static void send_fd(int sock, int fd)
+{
+	char data = 'x', control[CMSG_SPACE(sizeof(int))] = { 0 };
+	struct iovec iov = { &data, 1 };
+	struct msghdr msg = {
+		.msg_iov = &iov,
+		.msg_iovlen = 1,
+		.msg_control = control,
+		.msg_controllen = sizeof(control),
+	};
+	struct cmsghdr *cmsg = CMSG_FIRSTHDR(&msg);
+
+	cmsg->cmsg_level = SOL_SOCKET;
+	cmsg->cmsg_type = SCM_RIGHTS;
+	cmsg->cmsg_len = CMSG_LEN(sizeof(int));
+	memcpy(CMSG_DATA(cmsg), &fd, sizeof(fd));
+	CHECK_EQ(sendmsg(sock, &msg, 0), 1);
+}
+
+/* A descriptor passed to a process that never had the file continues at
+ * the sender's offset. */
+static void test_pass(void)
+{
+	int sv[2], fd;
+	pid_t pid;
+
+	CHECK(socketpair(AF_UNIX, SOCK_STREAM, 0, sv) == 0);
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		close(sv[0]);
+		fd = recv_fd(sv[1]);
+		if (fd < 0)
+			_exit(3);
+		if (!(fcntl(fd, F_GETFD) & FD_CLOEXEC))
+			_exit(4);
+		if (!(fcntl(fd, F_GETFL) & O_NONBLOCK))
+			_exit(5);
+		_exit(read_rest(fd, SIZE / 4));
+	}
+	close(sv[1]);
+	fd = open("share.dat", O_RDONLY | O_NONBLOCK);
+	CHECK(fd >= 0);
+	CHECK_EQ(read(fd, buf, SIZE / 4), SIZE / 4);
+	send_fd(sv[0], fd);
+	CHECK_EQ(wait_child(pid), 0);
+	CHECK_EQ(lseek(fd, 0, SEEK_CUR), SIZE);
+	close(fd);
+	close(sv[0]);
+}
+
+int main(void)
+{
+	int fd = open("share.dat", O_RDWR | O_CREAT | O_TRUNC, 0644);
+
+	CHECK(fd >= 0);
+	fill(fd, 0, SIZE, 'f');
+	close(fd);
+
+	test_dup();
+	test_fork();
+	test_pass();
+	unlink("share.dat");
+	return 0;
+}
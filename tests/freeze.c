@@ -0,0 +1,54 @@
+/* FIFREEZE/FITHAW on a tmpfs: a write started while frozen blocks, reads
+ * still work and nothing is dirty, and the write completes on thaw. */
+
+#include "common.h"
+
+#include <linux/fs.h>
+#include <sys/ioctl.h>
+#include <sys/mount.h>
+
+#define DIR "/tmp/freeze"
+#define FILE_PATH DIR "/f"
+
+int main(void)
+{
+	char buf[16] = { 0 };
+	int fd, status;
+	pid_t pid;
+
+	require_root();
+	mkdir(DIR, 0755);
+	CHECK(mount("tmpfs", DIR, "tmpfs", 0, NULL) == 0);
+	fd = open(FILE_PATH, O_RDWR | O_CREAT | O_TRUNC, 0644);
+	CHECK(fd >= 0);
+	CHECK_EQ(write(fd, "before", 6), 6);
+
+	sync();
+	CHECK(ioctl(fd, FIFREEZE, 0) == 0);
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		int wfd = open(FILE_PATH, O_WRONLY);
+
+		if (wfd < 0 || pwrite(wfd, "after!", 6, 0) != 6)
+			_exit(1);
+		_exit(0);
+	}
+
+	sleep_ms(300);
+	CHECK_EQ(waitpid(pid, &status, WNOHANG), 0);
+	CHECK_EQ(pread(fd, buf, sizeof(buf), 0), 6);
+	CHECK(!memcmp(buf, "before", 6));
+	CHECK_EQ(meminfo_kb("Dirty"), 0);
+
+	CHECK(ioctl(fd, FITHAW, 0) == 0);
+	CHECK_EQ(wait_child(pid), 0);
+	CHECK_EQ(pread(fd, buf, sizeof(buf), 0), 6);
+	CHECK(!memcmp(buf, "after!", 6));
+	CHECK_ERR(ioctl(fd, FITHAW, 0), EINVAL);
+
+	close(fd);
+	CHECK(umount(DIR) == 0);
+	rmdir(DIR);
+	return 0;
+}
@@ -0,0 +1,58 @@
+/* readv/writev and their positioned forms over pipes and files. */
+
+#include "common.h"
+
+#include <limits.h>
+#include <sys/uio.h>
+
+int main(void)
+{
+	char a[] = "hello, ", b[] = "vectored ", c[] = "world";
+	struct iovec out[3] = { { a, 7 }, { b, 9 }, { c, 5 } };
+	char buf[64] = { 0 }, x[4], y[8], z[16];
+	struct iovec in[3] = { { x, 4 }, { y, 8 }, { z, 16 } };
+	int p[2], fd;
+
+	/* Three segments reach a pipe as one message and leave in order. */
+	CHECK(pipe(p) == 0);
+	CHECK_EQ(writev(p[1], out, 3), 21);
+	CHECK_EQ(read(p[0], buf, sizeof(buf)), 21);
+	CHECK(!strcmp(buf, "hello, vectored world"));
+
+	CHECK_EQ(writev(p[1], out, 3), 21);
+	CHECK_EQ(readv(p[0], in, 3), 21);
+	CHECK(!memcmp(x, "hell", 4));
+	CHECK(!memcmp(y, "o, vecto", 8));
+	CHECK(!memcmp(z, "red world", 9));
+
+	/* Empty segments are skipped. */
+	out[1].iov_len = 0;
+	CHECK_EQ(writev(p[1], out, 3), 12);
+	memset(buf, 0, sizeof(buf));
+	CHECK_EQ(read(p[0], buf, sizeof(buf)), 12);
+	CHECK(!strcmp(buf, "hello, world"));
+	out[1].iov_len = 9;
+
+	CHECK_ERR(writev(p[1], out, -1), EINVAL);
+	CHECK_ERR(writev(p[1], out, IOV_MAX + 1), EINVAL);
+	out[2].iov_base = (void *)8;
+	CHECK_ERR(writev(p[1], &out[2], 1), EFAULT);
+	out[2].iov_base = c;
+	close(p[0]);
+	close(p[1]);
+
+	fd = open("iovec.dat", O_RDWR | O_CREAT | O_TRUNC, 0644);
+	CHECK(fd >= 0);
+	CHECK_EQ(pwritev(fd, out, 3, 100), 21);
+	CHECK_EQ(lseek(fd, 0, SEEK_CUR), 0);
+	CHECK_EQ(lseek(fd, 0, SEEK_END), 121);
+	CHECK_EQ(preadv(fd, in, 3, 100), 21);
+	CHECK(!memcmp(x, "hell", 4) && !memcmp(z, "red world", 9));
+	/* Short at the end of the file. */
+	CHECK_EQ(preadv(fd, in, 3, 110), 11);
+	CHECK(!memcmp(x, "tore", 4));
+	CHECK(!memcmp(y, "d world", 7));
+	close(fd);
+	unlink("iovec.dat");
+	return 0;
+}
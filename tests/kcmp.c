@@ -0,0 +1,97 @@
+/* kcmp(2): dup'd fds share an open file description and independently
+ * opened ones do not, processes cloned with CLONE_FILES share their fd
+ * table, and only root may compare processes of another user. */
+
+#include "common.h"
+
+#include <sched.h>
+#include <signal.h>
+
+#define KCMP_FILE 0
+#define KCMP_VM 1
+#define KCMP_FILES 2
+#define KCMP_FS 3
+
+static int kcmp(pid_t pid1, pid_t pid2, int type, long idx1, long idx2)
+{
+	return syscall(SYS_kcmp, pid1, pid2, type, idx1, idx2);
+}
+
+static int pipefd[2];
+
+static int wait_for_parent(void *arg)
+{
+	char c;
+
+	(void)arg;
+	return read(pipefd[0], &c, 1) == 1 ? 0 : 1;
+}
+
+/* Run as nobody: its own process is fine, its root parent is not. */
+static int compare_with_root(void *arg)
+{
+	pid_t self = getpid(), root = *(pid_t *)arg;
+
+	if (kcmp(self, self, KCMP_VM, 0, 0) != 0)
+		return 1;
+	if (kcmp(self, root, KCMP_VM, 0, 0) != -1 || errno != EPERM)
+		return 2;
+	if (kcmp(root, root, KCMP_FILES, 0, 0) != -1 || errno != EPERM)
+		return 3;
+	return 0;
+}
+
+/* Starts a child cloned with `flags` that waits for a byte on the pipe. */
+static pid_t start_child(int flags)
+{
+	static char stacks[2][64 << 10];
+	static int next;
+	char *stack = stacks[next++] + sizeof(stacks[0]);
+	pid_t pid = clone(wait_for_parent, stack, flags | SIGCHLD, NULL);
+
+	CHECK(pid > 0);
+	return pid;
+}
+
+int main(void)
+{
+	pid_t self = getpid(), shared, forked;
+	int fd, dup_fd, other;
+
+	fd = open("/proc/self/stat", O_RDONLY);
+	CHECK(fd >= 0);
+	dup_fd = dup(fd);
+	CHECK(dup_fd >= 0);
+	other = open("/proc/self/stat", O_RDONLY);
+	CHECK(other >= 0);
+
+	CHECK_EQ(kcmp(self, self, KCMP_FILE, fd, dup_fd), 0);
+	CHECK(kcmp(self, self, KCMP_FILE, fd, other) != 0);
+	/* The ordering is consistent both ways. */
+	CHECK_EQ(kcmp(self, self, KCMP_FILE, fd, other) +
+			 kcmp(self, self, KCMP_FILE, other, fd),
+		 3);
+	CHECK_ERR(kcmp(self, self, KCMP_FILE, fd, 999), EBADF);
+	CHECK_ERR(kcmp(self, self, 100, 0, 0), EINVAL);
+
+	CHECK(pipe(pipefd) == 0);
+	shared = start_child(CLONE_FILES);
+	forked = start_child(0);
+
+	CHECK_EQ(kcmp(self, shared, KCMP_FILES, 0, 0), 0);
+	CHECK(kcmp(self, forked, KCMP_FILES, 0, 0) != 0);
+	/* A forked child has the same open files in a table of its own. */
+	CHECK_EQ(kcmp(self, forked, KCMP_FILE, fd, fd), 0);
+	CHECK(kcmp(self, forked, KCMP_VM, 0, 0) != 0);
+	CHECK(kcmp(self, forked, KCMP_FS, 0, 0) != 0);
+	CHECK_EQ(kcmp(self, self, KCMP_VM, 0, 0), 0);
+
+	CHECK_EQ(write(pipefd[1], "xx", 2), 2);
+	CHECK_EQ(wait_child(shared), 0);
+	CHECK_EQ(wait_child(forked), 0);
+	CHECK_ERR(kcmp(self, forked, KCMP_VM, 0, 0), ESRCH);
+
+	if (geteuid() == 0)
+		CHECK_EQ(as_nobody(compare_with_root, &self), 0);
+	return 0;
+}
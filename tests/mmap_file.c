@@ -0,0 +1,115 @@
+/* Shared file mappings: a file built only through a mapping has the size,
+ * content, timestamps and blocks another process expects, msync makes the
+ * stores visible to read(2) through another fd, and a page wholly past the
+ * end of file raises SIGBUS. */
+
+#include "common.h"
+
+#include <signal.h>
+#include <sys/mman.h>
+
+#define PAGE 4096
+
+static int check_from_child(void *arg)
+{
+	char buf[3 * PAGE];
+	struct stat st;
+	int fd = open((const char *)arg, O_RDONLY);
+
+	if (fd < 0 || fstat(fd, &st) < 0)
+		return 1;
+	if (st.st_size != 3 * PAGE || st.st_blocks * 512 < 3 * PAGE)
+		return 2;
+	if (read(fd, buf, sizeof(buf)) != sizeof(buf) ||
+	    !filled(buf, 0, sizeof(buf), 'm'))
+		return 3;
+	return 0;
+}
+
+static void test_build_by_mmap(const char *path)
+{
+	struct stat before, after;
+	char *map;
+	pid_t pid;
+	int fd;
+
+	fd = open(path, O_RDWR | O_CREAT | O_TRUNC, 0644);
+	CHECK(fd >= 0);
+	CHECK(ftruncate(fd, 3 * PAGE) == 0);
+	CHECK(fstat(fd, &before) == 0);
+	map = mmap(NULL, 3 * PAGE, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+	CHECK(map != MAP_FAILED);
+	sleep_ms(10);
+	for (int i = 0; i < 3 * PAGE; i++)
+		map[i] = (char)('m' + i % 251);
+	CHECK(msync(map, 3 * PAGE, MS_SYNC) == 0);
+	CHECK(fstat(fd, &after) == 0);
+	CHECK_EQ(after.st_size, 3 * PAGE);
+	CHECK(after.st_mtim.tv_sec > before.st_mtim.tv_sec ||
+	      (after.st_mtim.tv_sec == before.st_mtim.tv_sec &&
+	       after.st_mtim.tv_nsec > before.st_mtim.tv_nsec));
+
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0)
+		_exit(check_from_child((void *)path));
+	CHECK_EQ(wait_child(pid), 0);
+	munmap(map, 3 * PAGE);
+	close(fd);
+}
+
+static void test_msync_visible(const char *path)
+{
+	char buf[16];
+	char *map;
+	int fd = open(path, O_RDWR), other = open(path, O_RDONLY);
+
+	CHECK(fd >= 0 && other >= 0);
+	map = mmap(NULL, 3 * PAGE, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+	CHECK(map != MAP_FAILED);
+	memcpy(map + PAGE + 100, "scribbled", 9);
+	CHECK(msync(map + PAGE, PAGE, MS_SYNC) == 0);
+	CHECK_EQ(pread(other, buf, 9, PAGE + 100), 9);
+	CHECK(!memcmp(buf, "scribbled", 9));
+	/* Invalid flags and misaligned addresses are refused. */
+	CHECK_ERR(msync(map + 1, PAGE, MS_SYNC), EINVAL);
+	CHECK_ERR(msync(map, PAGE, MS_SYNC | MS_ASYNC), EINVAL);
+	CHECK(msync(map, PAGE, MS_INVALIDATE) == 0);
+	munmap(map, 3 * PAGE);
+	close(fd);
+	close(other);
+}
+
+static void test_sigbus(const char *path)
+{
+	int fd = open(path, O_RDWR);
+	volatile char *map;
+	pid_t pid;
+
+	CHECK(fd >= 0);
+	CHECK(ftruncate(fd, PAGE) == 0);
+	map = mmap(NULL, 2 * PAGE, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+	CHECK(map != MAP_FAILED);
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		/* The first page is fine, the second is past the end. */
+		map[PAGE - 1] = 1;
+		(void)map[PAGE];
+		_exit(0);
+	}
+	CHECK_EQ(wait_child(pid), 128 + SIGBUS);
+	munmap((void *)map, 2 * PAGE);
+	close(fd);
+}
+
+int main(void)
+{
+	const char *path = "mmap.dat";
+
+	test_build_by_mmap(path);
+	test_msync_visible(path);
+	test_sigbus(path);
+	unlink(path);
+	return 0;
+}
@@ -0,0 +1,106 @@
+/* POSIX message queues: priority order, a timed send on a full queue,
+ * mq_notify firing exactly once, poll readiness, and open permissions. */
+
+#include "common.h"
+
+#include <mqueue.h>
+#include <poll.h>
+#include <signal.h>
+
+#define NAME "/starry-test"
+
+static volatile sig_atomic_t notified;
+
+static void on_notify(int sig)
+{
+	(void)sig;
+	notified++;
+}
+
+static int open_as_nobody(void *arg)
+{
+	(void)arg;
+	if (mq_open(NAME, O_RDONLY) != (mqd_t)-1)
+		return 1;
+	return errno == EACCES ? 0 : 2;
+}
+
+int main(void)
+{
+	struct mq_attr attr = { .mq_maxmsg = 4, .mq_msgsize = 32 };
+	struct sigevent sev = { .sigev_notify = SIGEV_SIGNAL,
+				.sigev_signo = SIGUSR1 };
+	struct timespec ts;
+	struct pollfd pfd;
+	char buf[32];
+	unsigned prio;
+	int64_t start;
+	mqd_t q;
+
+	mq_unlink(NAME);
+	q = mq_open(NAME, O_RDWR | O_CREAT | O_EXCL, 0600, &attr);
+	CHECK(q != (mqd_t)-1);
+	CHECK_ERR(mq_open(NAME, O_RDWR | O_CREAT | O_EXCL, 0600, &attr), EEXIST);
+
+	/* Highest priority first, FIFO within one. */
+	CHECK(mq_send(q, "low", 4, 1) == 0);
+	CHECK(mq_send(q, "high", 5, 9) == 0);
+	CHECK(mq_send(q, "mid", 4, 5) == 0);
+	CHECK(mq_send(q, "high2", 6, 9) == 0);
+	CHECK_EQ(mq_receive(q, buf, sizeof(buf), &prio), 5);
+	CHECK(!strcmp(buf, "high") && prio == 9);
+	CHECK_EQ(mq_receive(q, buf, sizeof(buf), &prio), 6);
+	CHECK(!strcmp(buf, "high2") && prio == 9);
+	CHECK_EQ(mq_receive(q, buf, sizeof(buf), &prio), 4);
+	CHECK(!strcmp(buf, "mid") && prio == 5);
+	CHECK_EQ(mq_receive(q, buf, sizeof(buf), &prio), 4);
+	CHECK(!strcmp(buf, "low") && prio == 1);
+	CHECK_ERR(mq_receive(q, buf, 8, NULL), EMSGSIZE);
+	CHECK_ERR(mq_send(q, buf, 33, 0), EMSGSIZE);
+
+	/* A full queue times a send out. */
+	for (int i = 0; i < 4; i++)
+		CHECK(mq_send(q, "x", 2, 0) == 0);
+	clock_gettime(CLOCK_REALTIME, &ts);
+	ts.tv_nsec += 200 * 1000000;
+	if (ts.tv_nsec >= 1000000000) {
+		ts.tv_sec++;
+		ts.tv_nsec -= 1000000000;
+	}
+	start = mono_ms();
+	CHECK_ERR(mq_timedsend(q, "y", 2, 0, &ts), ETIMEDOUT);
+	CHECK(mono_ms() - start >= 150);
+	CHECK(mq_getattr(q, &attr) == 0);
+	CHECK_EQ(attr.mq_curmsgs, 4);
+
+	/* Readable while there are messages, writable while there is room. */
+	pfd.fd = q;
+	pfd.events = POLLIN | POLLOUT;
+	CHECK_EQ(poll(&pfd, 1, 0), 1);
+	CHECK_EQ(pfd.revents & (POLLIN | POLLOUT), POLLIN);
+	for (int i = 0; i < 4; i++)
+		CHECK_EQ(mq_receive(q, buf, sizeof(buf), NULL), 2);
+	CHECK_EQ(poll(&pfd, 1, 0), 1);
+	CHECK_EQ(pfd.revents & (POLLIN | POLLOUT), POLLOUT);
+
+	/* A notification fires once, for a message to an empty queue. */
+	signal(SIGUSR1, on_notify);
+	CHECK(mq_notify(q, &sev) == 0);
+	CHECK_ERR(mq_notify(q, &sev), EBUSY);
+	CHECK(mq_send(q, "n1", 3, 0) == 0);
+	sleep_ms(50);
+	CHECK_EQ(notified, 1);
+	CHECK(mq_receive(q, buf, sizeof(buf), NULL) == 3);
+	CHECK(mq_send(q, "n2", 3, 0) == 0);
+	sleep_ms(50);
+	CHECK_EQ(notified, 1);
+	CHECK(mq_receive(q, buf, sizeof(buf), NULL) == 3);
+
+	/* The mode the queue was created with is enforced. */
+	CHECK_EQ(as_nobody(open_as_nobody, NULL), 0);
+
+	CHECK(mq_close(q) == 0);
+	CHECK(mq_unlink(NAME) == 0);
+	CHECK_ERR(mq_unlink(NAME), ENOENT);
+	return 0;
+}
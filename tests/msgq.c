@@ -0,0 +1,148 @@
+/* System V message queues: typed messages sent by one process and picked
+ * by each msgrcv selector in another, truncation, the EIDRM wakeup of a
+ * blocked receiver, and who may change msg_qbytes with IPC_SET. */
+
+#include "common.h"
+
+#include <sys/ipc.h>
+#include <sys/msg.h>
+
+struct msg {
+	long mtype;
+	char mtext[64];
+};
+
+static void put(int id, long type, const char *text)
+{
+	struct msg m = { .mtype = type };
+
+	strcpy(m.mtext, text);
+	CHECK(msgsnd(id, &m, strlen(text) + 1, 0) == 0);
+}
+
+static void expect(int id, long msgtyp, int flags, long type, const char *text)
+{
+	struct msg m;
+	ssize_t len = msgrcv(id, &m, sizeof(m.mtext), msgtyp, flags);
+
+	CHECK(len >= 0);
+	CHECK_EQ(m.mtype, type);
+	CHECK_EQ(len, strlen(text) + 1);
+	if (strcmp(m.mtext, text))
+		FAIL("msgrcv(%ld) got \"%s\", want \"%s\"", msgtyp, m.mtext,
+		     text);
+}
+
+/* Run as the owner of a fresh queue, without root. */
+static int set_qbytes_as_owner(void *arg)
+{
+	long mnb = *(long *)arg;
+	struct msqid_ds ds;
+	int id = msgget(IPC_PRIVATE, IPC_CREAT | 0600);
+
+	if (id < 0 || msgctl(id, IPC_STAT, &ds) < 0)
+		return 1;
+	if ((long)ds.msg_qbytes != mnb)
+		return 2;
+	ds.msg_qbytes = mnb + 1;
+	if (msgctl(id, IPC_SET, &ds) == 0 || errno != EPERM)
+		return 3;
+	ds.msg_qbytes = mnb / 2;
+	if (msgctl(id, IPC_SET, &ds) < 0)
+		return 4;
+	ds.msg_qbytes = 0;
+	if (msgctl(id, IPC_SET, &ds) == 0 || errno != EINVAL)
+		return 5;
+	if (msgctl(id, IPC_STAT, &ds) < 0 || (long)ds.msg_qbytes != mnb / 2)
+		return 6;
+	msgctl(id, IPC_RMID, NULL);
+	return 0;
+}
+
+static void test_qbytes(void)
+{
+	long mnb = read_long("/proc/sys/kernel/msgmnb");
+	struct msqid_ds ds;
+	int id;
+
+	CHECK(mnb > 0);
+	if (geteuid() != 0) {
+		CHECK_EQ(set_qbytes_as_owner(&mnb), 0);
+		return;
+	}
+	CHECK_EQ(as_nobody(set_qbytes_as_owner, &mnb), 0);
+	/* Root may go past the default. */
+	id = msgget(IPC_PRIVATE, IPC_CREAT | 0600);
+	CHECK(id >= 0);
+	CHECK(msgctl(id, IPC_STAT, &ds) == 0);
+	ds.msg_qbytes = 2 * mnb;
+	CHECK(msgctl(id, IPC_SET, &ds) == 0);
+	CHECK(msgctl(id, IPC_STAT, &ds) == 0);
+	CHECK_EQ(ds.msg_qbytes, 2 * mnb);
+	CHECK(msgctl(id, IPC_RMID, NULL) == 0);
+}
+
+int main(void)
+{
+	struct msqid_ds ds;
+	struct msg m = { .mtype = 1 };
+	int id;
+	pid_t pid;
+
+	id = msgget(IPC_PRIVATE, IPC_CREAT | 0600);
+	CHECK(id >= 0);
+
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		put(id, 3, "three");
+		put(id, 1, "one");
+		put(id, 2, "two");
+		put(id, 5, "five");
+		put(id, 1, "one again");
+		put(id, 4, "four");
+		_exit(0);
+	}
+	CHECK_EQ(wait_child(pid), 0);
+
+	CHECK(msgctl(id, IPC_STAT, &ds) == 0);
+	CHECK_EQ(ds.msg_qnum, 6);
+	CHECK_EQ(ds.msg_lspid, pid);
+
+	/* The first, then by type, the lowest type up to 2, any but type 1. */
+	expect(id, 0, 0, 3, "three");
+	expect(id, 2, 0, 2, "two");
+	expect(id, -2, 0, 1, "one");
+	expect(id, 1, MSG_EXCEPT, 5, "five");
+	expect(id, -10, 0, 1, "one again");
+	CHECK_ERR(msgrcv(id, &m, sizeof(m.mtext), 7, IPC_NOWAIT), ENOMSG);
+
+	/* Too long for the buffer: left queued, or cut with MSG_NOERROR. */
+	CHECK_ERR(msgrcv(id, &m, 2, 0, 0), E2BIG);
+	CHECK_EQ(msgrcv(id, &m, 2, 0, MSG_NOERROR), 2);
+	CHECK_EQ(m.mtype, 4);
+	CHECK(!memcmp(m.mtext, "fo", 2));
+	CHECK(msgctl(id, IPC_STAT, &ds) == 0);
+	CHECK_EQ(ds.msg_qnum, 0);
+	CHECK_EQ(ds.msg_cbytes, 0);
+	CHECK_EQ(ds.msg_lrpid, getpid());
+
+	m.mtype = 0;
+	CHECK_ERR(msgsnd(id, &m, 1, 0), EINVAL);
+
+	/* A receiver blocked on the queue wakes with EIDRM when it goes. */
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		if (msgrcv(id, &m, sizeof(m.mtext), 0, 0) >= 0)
+			_exit(1);
+		_exit(errno == EIDRM ? 0 : 2);
+	}
+	sleep_ms(200);
+	CHECK(msgctl(id, IPC_RMID, NULL) == 0);
+	CHECK_EQ(wait_child(pid), 0);
+	CHECK_ERR(msgsnd(id, &m, 1, 0), EINVAL);
+
+	test_qbytes();
+	return 0;
+}
@@ -0,0 +1,119 @@
+/* poll/ppoll: POLLNVAL per fd, POLLHUP and POLLRDHUP, a zero timeout, and
+ * a poller woken when the other end of its pipe is closed by another
+ * thread. */
+
+#include "common.h"
+
+#include <poll.h>
+#include <pthread.h>
+#include <signal.h>
+#include <sys/socket.h>
+
+static void test_nval(void)
+{
+	int p[2], closed;
+	struct pollfd fds[3];
+
+	CHECK(pipe(p) == 0);
+	CHECK_EQ(write(p[1], "x", 1), 1);
+	closed = dup(p[0]);
+	CHECK(closed >= 0);
+	close(closed);
+
+	fds[0] = (struct pollfd){ .fd = p[0], .events = POLLIN };
+	fds[1] = (struct pollfd){ .fd = closed, .events = POLLIN };
+	fds[2] = (struct pollfd){ .fd = -1, .events = POLLIN };
+	CHECK_EQ(poll(fds, 3, 1000), 2);
+	CHECK_EQ(fds[0].revents, POLLIN);
+	CHECK_EQ(fds[1].revents, POLLNVAL);
+	CHECK_EQ(fds[2].revents, 0);
+
+	/* Only the bad fd: reported right away, with no wait. */
+	CHECK_EQ(poll(&fds[1], 1, 5000), 1);
+	CHECK_EQ(fds[1].revents, POLLNVAL);
+	close(p[0]);
+	close(p[1]);
+}
+
+static void test_hup(void)
+{
+	int p[2], s[2];
+	struct pollfd pfd;
+	struct timespec zero = { 0, 0 };
+	int64_t start;
+
+	CHECK(pipe(p) == 0);
+	CHECK_EQ(write(p[1], "x", 1), 1);
+	close(p[1]);
+	pfd = (struct pollfd){ .fd = p[0], .events = POLLIN };
+	CHECK_EQ(poll(&pfd, 1, 0), 1);
+	CHECK_EQ(pfd.revents, POLLIN | POLLHUP);
+	close(p[0]);
+
+	/* Writers see POLLERR once there is no reader. */
+	CHECK(pipe(p) == 0);
+	close(p[0]);
+	pfd = (struct pollfd){ .fd = p[1], .events = POLLOUT };
+	CHECK_EQ(poll(&pfd, 1, 0), 1);
+	CHECK(pfd.revents & POLLERR);
+	close(p[1]);
+
+	/* A peer shutting down its writing side raises POLLRDHUP. */
+	CHECK(socketpair(AF_UNIX, SOCK_STREAM, 0, s) == 0);
+	pfd = (struct pollfd){ .fd = s[0], .events = POLLIN | POLLRDHUP };
+	start = mono_ms();
+	CHECK_EQ(ppoll(&pfd, 1, &zero, NULL), 0);
+	CHECK(mono_ms() - start < 10);
+	CHECK(shutdown(s[1], SHUT_WR) == 0);
+	CHECK_EQ(poll(&pfd, 1, 1000), 1);
+	CHECK(pfd.revents & POLLRDHUP);
+	CHECK(pfd.revents & POLLIN);
+	CHECK(!(pfd.revents & POLLHUP));
+	close(s[1]);
+	CHECK_EQ(poll(&pfd, 1, 1000), 1);
+	CHECK(pfd.revents & POLLHUP);
+	close(s[0]);
+}
+
+static int closer_fd;
+
+static void *close_later(void *arg)
+{
+	(void)arg;
+	sleep_ms(100);
+	close(closer_fd);
+	return NULL;
+}
+
+static void test_close_wakes(void)
+{
+	int p[2];
+	char buf[4096] = { 0 };
+	struct pollfd pfd;
+	pthread_t thread;
+	int64_t start;
+
+	CHECK(pipe2(p, O_NONBLOCK) == 0);
+	while (write(p[1], buf, sizeof(buf)) > 0)
+		;
+	CHECK(errno == EAGAIN);
+	/* Another thread closes the only read end while we wait to write. */
+	closer_fd = p[0];
+	CHECK(pthread_create(&thread, NULL, close_later, NULL) == 0);
+	pfd = (struct pollfd){ .fd = p[1], .events = POLLOUT };
+	start = mono_ms();
+	CHECK_EQ(poll(&pfd, 1, 5000), 1);
+	CHECK(mono_ms() - start < 1000);
+	CHECK(pfd.revents & POLLERR);
+	pthread_join(thread, NULL);
+	close(p[1]);
+}
+
+int main(void)
+{
+	signal(SIGPIPE, SIG_IGN);
+	test_nval();
+	test_hup();
+	test_close_wakes();
+	return 0;
+}
@@ -0,0 +1,119 @@
+/* Readahead as seen through mincore(2): readahead(2) populates the page
+ * cache, and sequential reads after lseek forward or backward get a window
+ * again within two reads. */
+
+#include "common.h"
+
+#include <sys/mman.h>
+
+#define PAGE 4096
+#define SIZE (4 << 20)
+#define CHUNK (16 << 10)
+
+static unsigned char *map;
+
+static int cached(off_t offset)
+{
+	unsigned char vec;
+
+	CHECK(mincore(map + offset / PAGE * PAGE, PAGE, &vec) == 0);
+	return vec & 1;
+}
+
+/* Whether some page in `start..end` gets cached within a second. Readahead
+ * past the read itself runs in the background. */
+static int any_cached_soon(off_t start, off_t end)
+{
+	int64_t deadline = mono_ms() + 1000;
+
+	do {
+		for (off_t off = start; off < end; off += PAGE)
+			if (cached(off))
+				return 1;
+		sleep_ms(10);
+	} while (mono_ms() < deadline);
+	return 0;
+}
+
+/* Writes the file without going through the page cache, so that none of
+ * it is cached to begin with. */
+static void create_uncached(const char *path)
+{
+	void *buf;
+	int fd = open(path, O_WRONLY | O_CREAT | O_TRUNC | O_DIRECT, 0644);
+
+	CHECK(fd >= 0);
+	CHECK(posix_memalign(&buf, PAGE, CHUNK) == 0);
+	for (off_t off = 0; off < SIZE; off += CHUNK) {
+		for (int i = 0; i < CHUNK; i++)
+			((char *)buf)[i] = (char)('r' + (off + i) % 251);
+		CHECK_EQ(pwrite(fd, buf, CHUNK, off), CHUNK);
+	}
+	free(buf);
+	close(fd);
+}
+
+static void read_chunk(int fd)
+{
+	static char buf[CHUNK];
+	off_t off = lseek(fd, 0, SEEK_CUR);
+
+	CHECK_EQ(read(fd, buf, CHUNK), CHUNK);
+	CHECK(filled(buf, off, CHUNK, 'r'));
+}
+
+/* Seeks to `offset` and reads sequentially: within two reads something
+ * past what was read must be cached. */
+static void seek_then_read(int fd, off_t offset)
+{
+	CHECK(!cached(offset + 2 * CHUNK));
+	CHECK_EQ(lseek(fd, offset, SEEK_SET), offset);
+	read_chunk(fd);
+	read_chunk(fd);
+	if (!any_cached_soon(offset + 2 * CHUNK, offset + 2 * CHUNK + (256 << 10)))
+		FAIL("no readahead after seeking to %ld", (long)offset);
+}
+
+int main(void)
+{
+	int fd, wfd;
+
+	create_uncached("ra.dat");
+	fd = open("ra.dat", O_RDONLY);
+	CHECK(fd >= 0);
+	map = mmap(NULL, SIZE, PROT_READ, MAP_SHARED, fd, 0);
+	CHECK(map != MAP_FAILED);
+	for (off_t off = 0; off < SIZE; off += PAGE)
+		if (cached(off))
+			FAIL("page at %ld cached before any read", (long)off);
+
+	/* readahead(2) brings in exactly what it is asked for, at least. */
+	CHECK(readahead(fd, 1 << 20, 64 << 10) == 0);
+	for (off_t off = 1 << 20; off < (1 << 20) + (64 << 10); off += PAGE)
+		CHECK(cached(off));
+	wfd = open("ra.dat", O_WRONLY);
+	CHECK(wfd >= 0);
+	CHECK_ERR(readahead(wfd, 0, PAGE), EBADF);
+	CHECK_ERR(readahead(fd, -1, PAGE), EINVAL);
+	close(wfd);
+
+	/* Forward past the readahead above, then back before it. */
+	seek_then_read(fd, 2 << 20);
+	seek_then_read(fd, 512 << 10);
+	/* And forward again from there, across a gap. */
+	seek_then_read(fd, 3 << 20);
+
+	/* mincore wants an aligned address and a mapped range. */
+	{
+		unsigned char vec;
+
+		CHECK_ERR(mincore(map + 1, PAGE, &vec), EINVAL);
+		CHECK(munmap(map + SIZE - PAGE, PAGE) == 0);
+		CHECK_ERR(mincore(map + SIZE - PAGE, PAGE, &vec), ENOMEM);
+	}
+
+	munmap(map, SIZE - PAGE);
+	close(fd);
+	unlink("ra.dat");
+	return 0;
+}
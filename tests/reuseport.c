@@ -0,0 +1,128 @@
+/* SO_REUSEPORT: four listeners bound to one port by four threads share
+ * 1000 connections, each accepted exactly once and spread about evenly; a
+ * socket of another user cannot join the group, and UDP sockets don't take
+ * the option at all. */
+
+#include "common.h"
+
+#include <arpa/inet.h>
+#include <netinet/in.h>
+#include <pthread.h>
+#include <sched.h>
+#include <stdatomic.h>
+#include <sys/socket.h>
+
+#define LISTENERS 4
+#define CONNS 1000
+
+static struct sockaddr_in addr;
+static pthread_barrier_t bound;
+static atomic_int accepted;
+static atomic_int counts[LISTENERS];
+static atomic_int seen[CONNS];
+
+static int reuseport_socket(void)
+{
+	int one = 1;
+	int fd = socket(AF_INET, SOCK_STREAM, 0);
+
+	CHECK(fd >= 0);
+	CHECK(setsockopt(fd, SOL_SOCKET, SO_REUSEPORT, &one, sizeof(one)) == 0);
+	return fd;
+}
+
+static void *listener(void *arg)
+{
+	int me = (int)(intptr_t)arg;
+	int fd = reuseport_socket();
+
+	CHECK(bind(fd, (struct sockaddr *)&addr, sizeof(addr)) == 0);
+	CHECK(listen(fd, 128) == 0);
+	pthread_barrier_wait(&bound);
+	for (;;) {
+		int conn = accept(fd, NULL, NULL);
+		uint32_t id;
+
+		CHECK(conn >= 0);
+		CHECK_EQ(read(conn, &id, sizeof(id)), sizeof(id));
+		CHECK(id < CONNS);
+		atomic_fetch_add(&seen[id], 1);
+		atomic_fetch_add(&counts[me], 1);
+		atomic_fetch_add(&accepted, 1);
+		close(conn);
+	}
+	return NULL;
+}
+
+static int join_as_other_user(void *arg)
+{
+	(void)arg;
+	int fd = reuseport_socket();
+
+	if (bind(fd, (struct sockaddr *)&addr, sizeof(addr)) == 0)
+		return 1;
+	return errno == EADDRINUSE ? 0 : 2;
+}
+
+static void test_udp(void)
+{
+	int one = 1, val;
+	socklen_t len = sizeof(val);
+	int fd = socket(AF_INET, SOCK_DGRAM, 0);
+
+	CHECK(fd >= 0);
+	CHECK_ERR(setsockopt(fd, SOL_SOCKET, SO_REUSEPORT, &one, sizeof(one)),
+		  ENOPROTOOPT);
+	CHECK_ERR(getsockopt(fd, SOL_SOCKET, SO_REUSEPORT, &val, &len),
+		  ENOPROTOOPT);
+	close(fd);
+}
+
+int main(void)
+{
+	pthread_t threads[LISTENERS];
+	int64_t deadline;
+
+	addr.sin_family = AF_INET;
+	addr.sin_port = htons(47493);
+	addr.sin_addr.s_addr = htonl(INADDR_LOOPBACK);
+
+	pthread_barrier_init(&bound, NULL, LISTENERS + 1);
+	for (int i = 0; i < LISTENERS; i++)
+		CHECK(pthread_create(&threads[i], NULL, listener,
+				     (void *)(intptr_t)i) == 0);
+	pthread_barrier_wait(&bound);
+
+	for (uint32_t id = 0; id < CONNS; id++) {
+		int fd = socket(AF_INET, SOCK_STREAM, 0);
+
+		CHECK(fd >= 0);
+		CHECK(connect(fd, (struct sockaddr *)&addr, sizeof(addr)) == 0);
+		CHECK_EQ(write(fd, &id, sizeof(id)), sizeof(id));
+		close(fd);
+		/* Keep the backlog from overflowing. */
+		while ((int)id - atomic_load(&accepted) > 64)
+			sched_yield();
+	}
+
+	deadline = mono_ms() + 30000;
+	while (atomic_load(&accepted) < CONNS && mono_ms() < deadline)
+		sleep_ms(10);
+	CHECK_EQ(atomic_load(&accepted), CONNS);
+	for (int id = 0; id < CONNS; id++)
+		if (atomic_load(&seen[id]) != 1)
+			FAIL("connection %d accepted %d times", id,
+			     atomic_load(&seen[id]));
+	for (int i = 0; i < LISTENERS; i++) {
+		int n = atomic_load(&counts[i]);
+
+		printf("listener %d accepted %d\n", i, n);
+		/* An even share is 250. */
+		if (n < CONNS / LISTENERS / 2)
+			FAIL("listener %d accepted only %d", i, n);
+	}
+
+	CHECK_EQ(as_nobody(join_as_other_user, NULL), 0);
+	test_udp();
+	return 0;
+}
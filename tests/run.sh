@@ -0,0 +1,38 @@
+#!/bin/sh
+# Runs every test program in /tests/bin inside the guest and prints one
+# PASS/FAIL/SKIP line each, then a summary line that scripts/ci-test.py
+# looks for. Exit status 77 means the test skipped itself.
+
+BIN=/tests/bin
+WORK=/tests/work
+LIMIT=${TEST_TIMEOUT:-120}
+
+passed=0
+failed=0
+skipped=0
+
+mkdir -p "$WORK"
+for test in "$BIN"/*; do
+	name=${test##*/}
+	rm -rf "$WORK"/*
+	cd "$WORK" || exit 1
+	timeout "$LIMIT" "$test"
+	status=$?
+	cd /
+	case $status in
+	0)
+		echo "PASS: $name"
+		passed=$((passed + 1))
+		;;
+	77)
+		echo "SKIP: $name"
+		skipped=$((skipped + 1))
+		;;
+	*)
+		echo "FAIL: $name (status $status)"
+		failed=$((failed + 1))
+		;;
+	esac
+done
+
+echo "TESTS DONE: passed=$passed failed=$failed skipped=$skipped"
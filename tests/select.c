@@ -0,0 +1,176 @@
+/* select/pselect: the timeout left is written back, nfds 0 is a plain
+ * sleep, a zero timeout does not wait, a signal ends the wait with EINTR,
+ * pselect's mask lets a pending signal in at once, and closing the write
+ * end of a pipe wakes a reader. */
+
+#include "common.h"
+
+#include <signal.h>
+#include <sys/select.h>
+#include <sys/time.h>
+
+static volatile sig_atomic_t usr1s;
+
+static void on_usr1(int sig)
+{
+	(void)sig;
+	usr1s++;
+}
+
+/* Forks a child which writes a byte to `fd` after `ms`, or closes it if
+ * `close_only`. */
+static pid_t later(int fd, int ms, int close_only)
+{
+	pid_t pid = fork();
+
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		sleep_ms(ms);
+		if (!close_only && write(fd, "x", 1) != 1)
+			_exit(1);
+		_exit(0);
+	}
+	return pid;
+}
+
+static void test_timeout_left(int p[2])
+{
+	struct timeval tv = { 0, 500000 };
+	fd_set rfds;
+	pid_t pid = later(p[1], 100, 0);
+	long left;
+	char c;
+
+	FD_ZERO(&rfds);
+	FD_SET(p[0], &rfds);
+	CHECK_EQ(select(p[0] + 1, &rfds, NULL, NULL, &tv), 1);
+	CHECK(FD_ISSET(p[0], &rfds));
+	left = tv.tv_sec * 1000 + tv.tv_usec / 1000;
+	if (left < 150 || left > 450)
+		FAIL("%ldms left of 500 after about 100", left);
+	CHECK_EQ(read(p[0], &c, 1), 1);
+	CHECK_EQ(wait_child(pid), 0);
+
+	/* Timing out leaves zero. */
+	tv.tv_sec = 0;
+	tv.tv_usec = 20000;
+	CHECK_EQ(select(p[0] + 1, &rfds, NULL, NULL, &tv), 0);
+	CHECK(tv.tv_sec == 0 && tv.tv_usec == 0);
+	CHECK(!FD_ISSET(p[0], &rfds));
+}
+
+static void test_sleep_and_poll(int p[2])
+{
+	struct timeval tv = { 0, 30000 };
+	struct timeval zero = { 0, 0 };
+	fd_set rfds, wfds, efds;
+	int64_t start = now_ns(CLOCK_MONOTONIC), took;
+
+	CHECK_EQ(select(0, NULL, NULL, NULL, &tv), 0);
+	took = now_ns(CLOCK_MONOTONIC) - start;
+	if (took < 30000000 || took > 60000000)
+		FAIL("30ms select(0) took %lldus", (long long)took / 1000);
+
+	FD_ZERO(&rfds);
+	FD_ZERO(&wfds);
+	FD_ZERO(&efds);
+	FD_SET(p[0], &rfds);
+	FD_SET(p[1], &wfds);
+	FD_SET(p[0], &efds);
+	FD_SET(p[1], &efds);
+	start = now_ns(CLOCK_MONOTONIC);
+	CHECK_EQ(select(p[1] + 1, &rfds, &wfds, &efds, &zero), 1);
+	CHECK(now_ns(CLOCK_MONOTONIC) - start < 10000000);
+	CHECK(!FD_ISSET(p[0], &rfds) && FD_ISSET(p[1], &wfds));
+	/* Pipes have no exceptional conditions. */
+	CHECK(!FD_ISSET(p[0], &efds) && !FD_ISSET(p[1], &efds));
+
+	FD_ZERO(&rfds);
+	FD_SET(1000, &rfds);
+	CHECK_ERR(select(1001, &rfds, NULL, NULL, &zero), EBADF);
+	CHECK_ERR(select(-1, NULL, NULL, NULL, &zero), EINVAL);
+}
+
+static void test_eintr(int p[2])
+{
+	fd_set rfds;
+	pid_t parent = getpid(), pid;
+
+	signal(SIGUSR1, on_usr1);
+	usr1s = 0;
+	pid = fork();
+	CHECK(pid >= 0);
+	if (pid == 0) {
+		sleep_ms(100);
+		kill(parent, SIGUSR1);
+		_exit(0);
+	}
+	FD_ZERO(&rfds);
+	FD_SET(p[0], &rfds);
+	CHECK_ERR(select(p[0] + 1, &rfds, NULL, NULL, NULL), EINTR);
+	CHECK_EQ(usr1s, 1);
+	CHECK_EQ(wait_child(pid), 0);
+}
+
+static void test_pselect_mask(int p[2])
+{
+	struct timespec ts = { 5, 0 };
+	sigset_t block, empty, now;
+	fd_set rfds;
+	int64_t start;
+
+	usr1s = 0;
+	sigemptyset(&block);
+	sigaddset(&block, SIGUSR1);
+	sigemptyset(&empty);
+	CHECK(sigprocmask(SIG_BLOCK, &block, NULL) == 0);
+	raise(SIGUSR1);
+	CHECK_EQ(usr1s, 0);
+
+	FD_ZERO(&rfds);
+	FD_SET(p[0], &rfds);
+	start = mono_ms();
+	CHECK_ERR(pselect(p[0] + 1, &rfds, NULL, NULL, &ts, &empty), EINTR);
+	CHECK(mono_ms() - start < 1000);
+	CHECK_EQ(usr1s, 1);
+	/* The old mask is back. */
+	CHECK(sigprocmask(SIG_BLOCK, NULL, &now) == 0);
+	CHECK(sigismember(&now, SIGUSR1));
+	CHECK(sigprocmask(SIG_UNBLOCK, &block, NULL) == 0);
+	CHECK_EQ(usr1s, 1);
+}
+
+static void test_hup(void)
+{
+	int p[2];
+	fd_set rfds;
+	struct timeval tv = { 5, 0 };
+	int64_t start = mono_ms();
+	pid_t pid;
+	char c;
+
+	CHECK(pipe(p) == 0);
+	pid = later(p[1], 100, 1);
+	/* Only the child holds the write end now. */
+	close(p[1]);
+	FD_ZERO(&rfds);
+	FD_SET(p[0], &rfds);
+	CHECK_EQ(select(p[0] + 1, &rfds, NULL, NULL, &tv), 1);
+	CHECK(mono_ms() - start < 1000);
+	CHECK_EQ(read(p[0], &c, 1), 0);
+	CHECK_EQ(wait_child(pid), 0);
+	close(p[0]);
+}
+
+int main(void)
+{
+	int p[2];
+
+	CHECK(pipe(p) == 0);
+	test_timeout_left(p);
+	test_sleep_and_poll(p);
+	test_eintr(p);
+	test_pselect_mask(p);
+	test_hup();
+	return 0;
+}
@@ -0,0 +1,184 @@
+/* timerfd: clocks, validation, tick accounting of periodic timers,
+ * TFD_IOC_SET_TICKS, stat and fdinfo, and absolute and relative wall clock
+ * timers across a step of the clock. Sub-millisecond timers are tested in
+ * timerfd_hires.c and many timers at once in timerfd_wheel.c. */
+
+#include "common.h"
+
+#include <poll.h>
+#include <sys/ioctl.h>
+#include <sys/time.h>
+#include <sys/timerfd.h>
+
+#ifndef TFD_IOC_SET_TICKS
+#define TFD_IOC_SET_TICKS _IOW('T', 0, uint64_t)
+#endif
+
+static void arm(int fd, int flags, long value_ns, long interval_ns)
+{
+	struct itimerspec its = {
+		.it_interval = { interval_ns / 1000000000,
+				 interval_ns % 1000000000 },
+		.it_value = { value_ns / 1000000000, value_ns % 1000000000 },
+	};
+
+	CHECK(timerfd_settime(fd, flags, &its, NULL) == 0);
+}
+
+static uint64_t ticks(int fd)
+{
+	uint64_t n;
+
+	CHECK_EQ(read(fd, &n, sizeof(n)), sizeof(n));
+	return n;
+}
+
+static int readable_within(int fd, int ms)
+{
+	struct pollfd pfd = { .fd = fd, .events = POLLIN };
+
+	return poll(&pfd, 1, ms) == 1;
+}
+
+static void step_clock(long seconds)
+{
+	struct timespec ts;
+
+	CHECK(clock_gettime(CLOCK_REALTIME, &ts) == 0);
+	ts.tv_sec += seconds;
+	CHECK(clock_settime(CLOCK_REALTIME, &ts) == 0);
+}
+
+static void test_create(void)
+{
+	struct itimerspec its = { 0 };
+	int fd;
+
+	CHECK_ERR(timerfd_create(100, 0), EINVAL);
+	CHECK_ERR(timerfd_create(CLOCK_MONOTONIC, 0x1234), EINVAL);
+	fd = timerfd_create(CLOCK_BOOTTIME, TFD_NONBLOCK);
+	CHECK(fd >= 0);
+	CHECK_ERR(read(fd, &its, 8), EAGAIN);
+	CHECK_ERR(read(fd, &its, 4), EINVAL);
+
+	its.it_value.tv_nsec = 1000000000;
+	CHECK_ERR(timerfd_settime(fd, 0, &its, NULL), EINVAL);
+	its.it_value.tv_nsec = -1;
+	CHECK_ERR(timerfd_settime(fd, 0, &its, NULL), EINVAL);
+	its.it_value.tv_nsec = 0;
+	its.it_interval.tv_sec = -1;
+	CHECK_ERR(timerfd_settime(fd, 0, &its, NULL), EINVAL);
+	its.it_interval.tv_sec = 0;
+	CHECK_ERR(timerfd_settime(fd, 0x100, &its, NULL), EINVAL);
+
+	arm(fd, 0, 10 * 1000000, 0);
+	CHECK(readable_within(fd, 1000));
+	CHECK_EQ(ticks(fd), 1);
+	close(fd);
+}
+
+/* Periodic ticks follow wall time, however late the reader is. */
+static void test_periodic(void)
+{
+	int fd = timerfd_create(CLOCK_MONOTONIC, 0);
+	int64_t start, slept;
+	uint64_t n;
+
+	CHECK(fd >= 0);
+	start = now_ns(CLOCK_MONOTONIC);
+	arm(fd, 0, 1000000, 1000000);
+	sleep_ms(300);
+	n = ticks(fd);
+	slept = (now_ns(CLOCK_MONOTONIC) - start) / 1000000;
+	if ((int64_t)n > slept + 1 || (int64_t)n < slept * 95 / 100 - 1)
+		FAIL("%llu ticks of 1ms in %lldms", (unsigned long long)n,
+		     (long long)slept);
+	close(fd);
+}
+
+static void test_ticks_ioctl_and_stat(void)
+{
+	int a = timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK);
+	int b = timerfd_create(CLOCK_MONOTONIC, 0);
+	uint64_t set = 42;
+	struct stat sa, sb;
+	char path[64], info[512];
+	int fd;
+	ssize_t len;
+
+	CHECK(a >= 0 && b >= 0);
+	CHECK(ioctl(a, TFD_IOC_SET_TICKS, &set) == 0);
+	CHECK(readable_within(a, 0));
+	CHECK_EQ(ticks(a), 42);
+	CHECK_ERR(read(a, &set, 8), EAGAIN);
+
+	CHECK(fstat(a, &sa) == 0 && fstat(b, &sb) == 0);
+	CHECK(sa.st_ino != sb.st_ino);
+	CHECK_EQ(sa.st_dev, sb.st_dev);
+
+	arm(b, 0, 3600L * 1000000000, 1000000000);
+	snprintf(path, sizeof(path), "/proc/self/fdinfo/%d", b);
+	fd = open(path, O_RDONLY);
+	CHECK(fd >= 0);
+	len = read(fd, info, sizeof(info) - 1);
+	CHECK(len > 0);
+	info[len] = 0;
+	close(fd);
+	if (!strstr(info, "clockid: 1\n") || !strstr(info, "ticks: 0\n") ||
+	    !strstr(info, "it_value: (3599, ") ||
+	    !strstr(info, "it_interval: (1, 0)\n"))
+		FAIL("unexpected fdinfo:\n%s", info);
+	close(a);
+	close(b);
+}
+
+static void test_clock_step(void)
+{
+	struct timespec now;
+	int abs, cancel, rel;
+	uint64_t n;
+	int64_t start;
+
+	if (geteuid() != 0)
+		return;
+	abs = timerfd_create(CLOCK_REALTIME, TFD_NONBLOCK);
+	cancel = timerfd_create(CLOCK_REALTIME, TFD_NONBLOCK);
+	rel = timerfd_create(CLOCK_REALTIME, 0);
+	CHECK(abs >= 0 && cancel >= 0 && rel >= 0);
+
+	CHECK(clock_gettime(CLOCK_REALTIME, &now) == 0);
+	arm(abs, TFD_TIMER_ABSTIME, (now.tv_sec + 5) * 1000000000 + now.tv_nsec,
+	    0);
+	arm(cancel, TFD_TIMER_ABSTIME | TFD_TIMER_CANCEL_ON_SET,
+	    (now.tv_sec + 5) * 1000000000 + now.tv_nsec, 0);
+	start = mono_ms();
+	arm(rel, 0, 1000000000, 0);
+
+	step_clock(3600);
+	/* The absolute target is past now, and the other is cancelled. */
+	CHECK(readable_within(abs, 500));
+	CHECK_EQ(ticks(abs), 1);
+	CHECK(readable_within(cancel, 500));
+	CHECK_ERR(read(cancel, &n, 8), ECANCELED);
+	CHECK_ERR(read(cancel, &n, 8), EAGAIN);
+	/* A relative timer takes as long as it would have. */
+	CHECK(!readable_within(rel, 0));
+	CHECK_EQ(ticks(rel), 1);
+	if (mono_ms() - start < 900 || mono_ms() - start > 2000)
+		FAIL("1s relative timer took %lldms",
+		     (long long)(mono_ms() - start));
+	step_clock(-3600);
+
+	close(abs);
+	close(cancel);
+	close(rel);
+}
+
+int main(void)
+{
+	test_create();
+	test_periodic();
+	test_ticks_ioctl_and_stat();
+	test_clock_step();
+	return 0;
+}
@@ -0,0 +1,116 @@
+/* timerfd below the scheduler tick: a short one-shot fires well before the
+ * next millisecond, and periodic timers of 500us, 50us and 20us count one
+ * tick per period, whether the reader keeps up or comes late. */
+
+#include "common.h"
+
+#include <sys/prctl.h>
+#include <sys/timerfd.h>
+
+static void arm(int fd, long value_ns, long interval_ns)
+{
+	struct itimerspec its = {
+		.it_interval = { interval_ns / 1000000000,
+				 interval_ns % 1000000000 },
+		.it_value = { value_ns / 1000000000, value_ns % 1000000000 },
+	};
+
+	CHECK(timerfd_settime(fd, 0, &its, NULL) == 0);
+}
+
+static uint64_t ticks(int fd)
+{
+	uint64_t n;
+
+	CHECK_EQ(read(fd, &n, sizeof(n)), sizeof(n));
+	return n;
+}
+
+/* A 300us timer fires well before the next millisecond. */
+static void test_short(void)
+{
+	int fd = timerfd_create(CLOCK_MONOTONIC, 0);
+	int64_t lat[21];
+	struct timespec res;
+
+	CHECK(fd >= 0);
+	CHECK(clock_getres(CLOCK_MONOTONIC, &res) == 0);
+	CHECK(res.tv_sec == 0 && res.tv_nsec < 1000000);
+	for (int i = 0; i < 21; i++) {
+		int64_t start = now_ns(CLOCK_MONOTONIC);
+
+		arm(fd, 300000, 0);
+		CHECK_EQ(ticks(fd), 1);
+		lat[i] = now_ns(CLOCK_MONOTONIC) - start;
+		CHECK(lat[i] >= 300000);
+	}
+	/* The median; some may be late on a busy host. */
+	for (int i = 0; i < 21; i++)
+		for (int j = i + 1; j < 21; j++)
+			if (lat[j] < lat[i]) {
+				int64_t t = lat[i];
+				lat[i] = lat[j];
+				lat[j] = t;
+			}
+	if (lat[10] > 2000000)
+		FAIL("300us timer took %lldus", (long long)lat[10] / 1000);
+	close(fd);
+}
+
+/* Reads a timer with period `interval_ns` for `ms`, and checks that the
+ * ticks add up to the time that passed. */
+static void test_reader(long interval_ns, long ms)
+{
+	int fd = timerfd_create(CLOCK_MONOTONIC, 0);
+	int64_t start, elapsed;
+	uint64_t n = 0, want;
+
+	CHECK(fd >= 0);
+	start = now_ns(CLOCK_MONOTONIC);
+	arm(fd, interval_ns, interval_ns);
+	do {
+		n += ticks(fd);
+		elapsed = now_ns(CLOCK_MONOTONIC) - start;
+	} while (elapsed < ms * 1000000);
+	want = elapsed / interval_ns;
+	/* Never ahead of the clock; behind by what the last read missed. */
+	if (n > want || n < want * 9 / 10)
+		FAIL("%llu ticks of %ldus in %lldus, want %llu",
+		     (unsigned long long)n, interval_ns / 1000,
+		     (long long)elapsed / 1000, (unsigned long long)want);
+	close(fd);
+}
+
+/* A reader that sleeps through many periods gets them all in one read. */
+static void test_late_reader(long interval_ns, long ms)
+{
+	int fd = timerfd_create(CLOCK_MONOTONIC, 0);
+	int64_t start, elapsed;
+	uint64_t n, want;
+
+	CHECK(fd >= 0);
+	start = now_ns(CLOCK_MONOTONIC);
+	arm(fd, interval_ns, interval_ns);
+	sleep_ms(ms);
+	n = ticks(fd);
+	elapsed = now_ns(CLOCK_MONOTONIC) - start;
+	want = elapsed / interval_ns;
+	if (n > want || n < (uint64_t)ms * 1000000 / interval_ns * 9 / 10)
+		FAIL("%llu ticks of %ldus after sleeping %ldms, want %llu",
+		     (unsigned long long)n, interval_ns / 1000, ms,
+		     (unsigned long long)want);
+	close(fd);
+}
+
+int main(void)
+{
+	test_short();
+	/* 500us for a second is about 2000 ticks. */
+	test_reader(500000, 1000);
+	/* Periods this short are not rounded up to the default slack. */
+	CHECK(prctl(PR_SET_TIMERSLACK, 1, 0, 0, 0) == 0);
+	test_reader(50000, 200);
+	test_late_reader(50000, 100);
+	test_late_reader(20000, 100);
+	return 0;
+}
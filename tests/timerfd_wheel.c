@@ -0,0 +1,173 @@
+/* timerfd on the shared timer wheel: many timers armed at once each fire
+ * once and not early, disarming and re-arming take back the old deadline,
+ * and re-arming thousands of times neither leaks timers nor slows down the
+ * ones that are near. */
+
+#include "common.h"
+
+#include <poll.h>
+#include <sys/resource.h>
+#include <sys/timerfd.h>
+
+#define MANY 900
+#define HOUR (3600L * 1000000000)
+
+static void arm(int fd, long value_ns)
+{
+	struct itimerspec its = {
+		.it_value = { value_ns / 1000000000, value_ns % 1000000000 },
+	};
+
+	CHECK(timerfd_settime(fd, 0, &its, NULL) == 0);
+}
+
+static int readable_within(int fd, int ms)
+{
+	struct pollfd pfd = { .fd = fd, .events = POLLIN };
+
+	return poll(&pfd, 1, ms) == 1;
+}
+
+/* Deadlines from 2ms to about 450ms, some on the same millisecond and
+ * some close enough to be kept apart from the wheel. */
+static void test_many(void)
+{
+	static struct pollfd pfd[MANY];
+	static int64_t due[MANY];
+	int64_t start = now_ns(CLOCK_MONOTONIC);
+	int left = MANY;
+	uint64_t n;
+
+	for (int i = 0; i < MANY; i++) {
+		long value = 2000000 + (long)(i * 7 % MANY) * 500000;
+
+		pfd[i].fd = timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK);
+		pfd[i].events = POLLIN;
+		CHECK(pfd[i].fd >= 0);
+		due[i] = start + value;
+		arm(pfd[i].fd, value);
+	}
+	while (left) {
+		int ready = poll(pfd, MANY, 2000);
+
+		if (ready <= 0)
+			FAIL("%d of %d timers never fired", left, MANY);
+		for (int i = 0; i < MANY; i++) {
+			if (!(pfd[i].revents & POLLIN))
+				continue;
+			if (now_ns(CLOCK_MONOTONIC) < due[i])
+				FAIL("timer %d fired %lldus early", i,
+				     (long long)(due[i] -
+						 now_ns(CLOCK_MONOTONIC)) /
+					     1000);
+			CHECK_EQ(read(pfd[i].fd, &n, 8), 8);
+			CHECK_EQ(n, 1);
+			/* Readable once; poll no longer looks at it. */
+			pfd[i].fd = ~pfd[i].fd;
+			left--;
+		}
+	}
+	sleep_ms(20);
+	for (int i = 0; i < MANY; i++) {
+		int fd = ~pfd[i].fd;
+
+		CHECK_ERR(read(fd, &n, 8), EAGAIN);
+		close(fd);
+	}
+}
+
+static void test_rearm(void)
+{
+	int fd = timerfd_create(CLOCK_MONOTONIC, 0);
+	int64_t start;
+	uint64_t n;
+
+	CHECK(fd >= 0);
+	/* Disarming takes the deadline back. */
+	arm(fd, 10000000);
+	arm(fd, 0);
+	CHECK(!readable_within(fd, 50));
+
+	/* So does moving it, both later and earlier. */
+	arm(fd, 10000000);
+	arm(fd, HOUR);
+	CHECK(!readable_within(fd, 50));
+	start = mono_ms();
+	arm(fd, 10000000);
+	CHECK(readable_within(fd, 1000));
+	CHECK_EQ(read(fd, &n, 8), 8);
+	CHECK_EQ(n, 1);
+	if (mono_ms() - start < 10)
+		FAIL("10ms timer fired after %lldms",
+		     (long long)(mono_ms() - start));
+	close(fd);
+}
+
+/* A crowd of timers all due in an hour, re-armed over and over, stays out
+ * of the way of one due in 20ms. */
+static void test_storm(void)
+{
+	static int fds[MANY];
+	int near = timerfd_create(CLOCK_MONOTONIC, 0);
+	int64_t start = mono_ms();
+	uint64_t n;
+
+	CHECK(near >= 0);
+	for (int i = 0; i < MANY; i++) {
+		fds[i] = timerfd_create(CLOCK_MONOTONIC, 0);
+		CHECK(fds[i] >= 0);
+	}
+	for (int round = 0; round < 100; round++)
+		for (int i = 0; i < MANY; i++)
+			arm(fds[i], HOUR);
+	if (mono_ms() - start > 20000)
+		FAIL("%d re-arms took %lldms", 100 * MANY,
+		     (long long)(mono_ms() - start));
+
+	start = now_ns(CLOCK_MONOTONIC);
+	arm(near, 20000000);
+	CHECK_EQ(read(near, &n, 8), 8);
+	CHECK_EQ(n, 1);
+	start = (now_ns(CLOCK_MONOTONIC) - start) / 1000000;
+	if (start < 20 || start > 200)
+		FAIL("20ms timer took %lldms", (long long)start);
+	for (int i = 0; i < MANY; i++)
+		close(fds[i]);
+	close(near);
+}
+
+/* Dropping an armed timerfd leaves nothing behind to fire. */
+static void test_churn(void)
+{
+	int64_t start = mono_ms();
+	int first = -1;
+
+	for (int i = 0; i < 10000; i++) {
+		int fd = timerfd_create(CLOCK_MONOTONIC, 0);
+
+		CHECK(fd >= 0);
+		if (first < 0)
+			first = fd;
+		CHECK_EQ(fd, first);
+		arm(fd, HOUR);
+		close(fd);
+	}
+	if (mono_ms() - start > 20000)
+		FAIL("10000 timerfds took %lldms", (long long)(mono_ms() - start));
+}
+
+int main(void)
+{
+	struct rlimit lim;
+
+	CHECK(getrlimit(RLIMIT_NOFILE, &lim) == 0);
+	if (lim.rlim_cur < MANY + 16) {
+		fprintf(stderr, "needs %d fds\n", MANY + 16);
+		return SKIP;
+	}
+	test_many();
+	test_rearm();
+	test_storm();
+	test_churn();
+	return 0;
+}
@@ -0,0 +1,133 @@
+/* alarm/pause, setitimer, POSIX timers and clock_nanosleep. */
+
+#include "common.h"
+
+#include <signal.h>
+#include <sys/time.h>
+
+static volatile sig_atomic_t alarms, usr1s;
+
+static void on_alarm(int sig)
+{
+	(void)sig;
+	alarms++;
+}
+
+static void on_usr1(int sig)
+{
+	(void)sig;
+	usr1s++;
+}
+
+static void test_alarm_pause(void)
+{
+	int64_t start;
+
+	signal(SIGALRM, on_alarm);
+	alarms = 0;
+	CHECK_EQ(alarm(1), 0);
+	start = mono_ms();
+	CHECK_ERR(pause(), EINTR);
+	CHECK_EQ(alarms, 1);
+	if (mono_ms() - start < 900 || mono_ms() - start > 1500)
+		FAIL("alarm(1) took %lldms", (long long)(mono_ms() - start));
+
+	/* A newer alarm replaces the older one, which returns what was left. */
+	CHECK_EQ(alarm(10), 0);
+	CHECK_EQ(alarm(0), 10);
+}
+
+static void test_itimer(void)
+{
+	struct itimerval it = { .it_interval = { 0, 100000 },
+				.it_value = { 0, 100000 } };
+	struct itimerval old;
+	int64_t start = mono_ms();
+
+	alarms = 0;
+	CHECK(setitimer(ITIMER_REAL, &it, NULL) == 0);
+	while (alarms < 3 && mono_ms() - start < 2000)
+		pause();
+	CHECK(getitimer(ITIMER_REAL, &old) == 0);
+	CHECK_EQ(old.it_interval.tv_usec, 100000);
+	memset(&it, 0, sizeof(it));
+	CHECK(setitimer(ITIMER_REAL, &it, NULL) == 0);
+	CHECK(alarms >= 3);
+	if (mono_ms() - start < 250)
+		FAIL("three 100ms periods in %lldms",
+		     (long long)(mono_ms() - start));
+
+	it.it_value.tv_usec = 1000000;
+	CHECK_ERR(setitimer(ITIMER_REAL, &it, NULL), EINVAL);
+	CHECK_ERR(setitimer(42, &it, NULL), EINVAL);
+}
+
+static void test_posix_timer(void)
+{
+	struct sigevent sev = { .sigev_notify = SIGEV_SIGNAL,
+				.sigev_signo = SIGUSR1 };
+	struct itimerspec its = { .it_value = { 0, 50000000 } };
+	struct itimerspec cur;
+	timer_t timer;
+
+	signal(SIGUSR1, on_usr1);
+	usr1s = 0;
+	CHECK(timer_create(CLOCK_MONOTONIC, &sev, &timer) == 0);
+	CHECK(timer_settime(timer, 0, &its, NULL) == 0);
+	CHECK(timer_gettime(timer, &cur) == 0);
+	CHECK(cur.it_value.tv_sec == 0 && cur.it_value.tv_nsec > 0 &&
+	      cur.it_value.tv_nsec <= 50000000);
+	sleep_ms(200);
+	CHECK_EQ(usr1s, 1);
+	CHECK(timer_gettime(timer, &cur) == 0);
+	CHECK(cur.it_value.tv_sec == 0 && cur.it_value.tv_nsec == 0);
+
+	/* Disarmed before it fires, it never does. */
+	CHECK(timer_settime(timer, 0, &its, NULL) == 0);
+	memset(&its, 0, sizeof(its));
+	CHECK(timer_settime(timer, 0, &its, NULL) == 0);
+	sleep_ms(100);
+	CHECK_EQ(usr1s, 1);
+	CHECK(timer_delete(timer) == 0);
+	CHECK_ERR(timer_delete(timer), EINVAL);
+}
+
+static void test_clock_nanosleep(void)
+{
+	struct timespec deadline, rem;
+	struct itimerval it = { .it_value = { 0, 50000 } };
+	int64_t start;
+
+	/* An absolute deadline. */
+	CHECK(clock_gettime(CLOCK_MONOTONIC, &deadline) == 0);
+	deadline.tv_nsec += 100000000;
+	if (deadline.tv_nsec >= 1000000000) {
+		deadline.tv_sec++;
+		deadline.tv_nsec -= 1000000000;
+	}
+	CHECK_EQ(clock_nanosleep(CLOCK_MONOTONIC, TIMER_ABSTIME, &deadline,
+				 NULL),
+		 0);
+	CHECK(now_ns(CLOCK_MONOTONIC) >=
+	      deadline.tv_sec * 1000000000LL + deadline.tv_nsec);
+
+	/* Interrupted, a relative sleep reports what was left. */
+	signal(SIGALRM, on_alarm);
+	CHECK(setitimer(ITIMER_REAL, &it, NULL) == 0);
+	start = mono_ms();
+	deadline.tv_sec = 2;
+	deadline.tv_nsec = 0;
+	CHECK_EQ(clock_nanosleep(CLOCK_MONOTONIC, 0, &deadline, &rem), EINTR);
+	CHECK(mono_ms() - start < 1000);
+	CHECK(rem.tv_sec * 1000 + rem.tv_nsec / 1000000 >= 1000);
+	CHECK(rem.tv_sec * 1000 + rem.tv_nsec / 1000000 <= 2000);
+}
+
+int main(void)
+{
+	test_alarm_pause();
+	test_itimer();
+	test_posix_timer();
+	test_clock_nanosleep();
+	return 0;
+}
@@ -0,0 +1,106 @@
+/* tmpfs limits and mount options: a size= mount fills to exactly its size
+ * with ENOSPC for the first write past it, punched holes are writable
+ * again at once, and read_ahead_kb= sets the readahead limit of the files
+ * on the mount, which F_SET_RA_PAGES overrides per open file. */
+
+#include "common.h"
+
+#include <sys/mount.h>
+#include <sys/statfs.h>
+
+#define DIR_A "/tmp/tmpfs-a"
+#define DIR_B "/tmp/tmpfs-b"
+#define PAGE 4096
+#define F_SET_RA_PAGES (1024 + 64)
+#define F_GET_RA_PAGES (1024 + 65)
+
+static void test_size(void)
+{
+	char chunk[1000];
+	struct statfs st;
+	off_t written = 0;
+	int fd;
+
+	CHECK(mount("tmpfs", DIR_A, "tmpfs", 0, "size=64k") == 0);
+	CHECK(statfs(DIR_A, &st) == 0);
+	CHECK_EQ(st.f_blocks * st.f_bsize, 64 << 10);
+	CHECK_EQ(st.f_bfree, st.f_blocks);
+
+	fd = open(DIR_A "/fill", O_RDWR | O_CREAT, 0644);
+	CHECK(fd >= 0);
+	memset(chunk, 'f', sizeof(chunk));
+	for (;;) {
+		ssize_t n = write(fd, chunk, sizeof(chunk));
+
+		if (n < 0)
+			break;
+		CHECK_EQ(n, sizeof(chunk));
+		written += n;
+	}
+	CHECK_EQ(errno, ENOSPC);
+	/* The 66th write would have needed a 17th page. */
+	CHECK_EQ(written, 65000);
+	CHECK_EQ(write(fd, chunk, 65536 - written), 65536 - written);
+	CHECK_ERR(write(fd, chunk, 1), ENOSPC);
+	CHECK_EQ(lseek(fd, 0, SEEK_END), 65536);
+	CHECK(statfs(DIR_A, &st) == 0);
+	CHECK_EQ(st.f_bfree, 0);
+
+	/* Punching two pages out frees them for the next write. */
+	CHECK(fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, PAGE,
+			2 * PAGE) == 0);
+	CHECK(statfs(DIR_A, &st) == 0);
+	CHECK_EQ(st.f_bfree * st.f_bsize, 2 * PAGE);
+	CHECK_EQ(pwrite(fd, chunk, sizeof(chunk), PAGE), sizeof(chunk));
+	CHECK_EQ(pwrite(fd, chunk, sizeof(chunk), 2 * PAGE), sizeof(chunk));
+	CHECK_ERR(pwrite(fd, chunk, 1, 65536), ENOSPC);
+	/* And unlinking frees it all. */
+	close(fd);
+	CHECK(unlink(DIR_A "/fill") == 0);
+	CHECK(statfs(DIR_A, &st) == 0);
+	CHECK_EQ(st.f_bfree, st.f_blocks);
+	CHECK(umount(DIR_A) == 0);
+
+	CHECK_ERR(mount("tmpfs", DIR_A, "tmpfs", 0, "size=12q"), EINVAL);
+}
+
+static void test_readahead_options(void)
+{
+	int a, b;
+
+	CHECK(mount("tmpfs", DIR_A, "tmpfs", 0, "read_ahead_kb=16") == 0);
+	CHECK(mount("tmpfs", DIR_B, "tmpfs", 0, "read_ahead_kb=512") == 0);
+	a = open(DIR_A "/f", O_RDWR | O_CREAT, 0644);
+	b = open(DIR_B "/f", O_RDWR | O_CREAT, 0644);
+	CHECK(a >= 0 && b >= 0);
+	CHECK_EQ(fcntl(a, F_GET_RA_PAGES), 16 * 1024 / PAGE);
+	CHECK_EQ(fcntl(b, F_GET_RA_PAGES), 512 * 1024 / PAGE);
+
+	CHECK(fcntl(a, F_SET_RA_PAGES, 64) == 0);
+	CHECK_EQ(fcntl(a, F_GET_RA_PAGES), 64);
+	CHECK_ERR(fcntl(a, F_SET_RA_PAGES, 1), EINVAL);
+	/* Only that open file changes. */
+	{
+		int again = open(DIR_A "/f", O_RDONLY);
+
+		CHECK(again >= 0);
+		CHECK_EQ(fcntl(again, F_GET_RA_PAGES), 16 * 1024 / PAGE);
+		close(again);
+	}
+	close(a);
+	close(b);
+	CHECK(umount(DIR_A) == 0);
+	CHECK(umount(DIR_B) == 0);
+}
+
+int main(void)
+{
+	require_root();
+	mkdir(DIR_A, 0755);
+	mkdir(DIR_B, 0755);
+	test_size();
+	test_readahead_options();
+	rmdir(DIR_A);
+	rmdir(DIR_B);
+	return 0;
+}
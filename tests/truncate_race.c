@@ -0,0 +1,83 @@
+/* Reads racing with truncate and append: one appender, one truncater and
+ * four readers for a few seconds. No read may return bytes the file never
+ * held, such as zeroes past a shrinking end, or more than was there. */
+
+#include "common.h"
+
+#include <pthread.h>
+#include <sched.h>
+#include <stdatomic.h>
+
+#define RECORD 512
+#define READERS 4
+
+static int fd;
+static atomic_int stop;
+static atomic_long reads, eofs;
+
+static void *appender(void *arg)
+{
+	char rec[RECORD];
+
+	(void)arg;
+	memset(rec, 'R', sizeof(rec));
+	while (!atomic_load(&stop)) {
+		CHECK_EQ(write(fd, rec, sizeof(rec)), RECORD);
+		if (lseek(fd, 0, SEEK_END) > (1 << 20))
+			sched_yield();
+	}
+	return NULL;
+}
+
+static void *truncater(void *arg)
+{
+	(void)arg;
+	while (!atomic_load(&stop)) {
+		sleep_ms(5);
+		CHECK(ftruncate(fd, 0) == 0);
+	}
+	return NULL;
+}
+
+static void *reader(void *arg)
+{
+	char buf[4 * RECORD];
+	unsigned seed = (unsigned)(intptr_t)arg;
+
+	while (!atomic_load(&stop)) {
+		off_t off = (rand_r(&seed) % 256) * RECORD;
+		ssize_t n = pread(fd, buf, sizeof(buf), off);
+
+		CHECK(n >= 0 && n <= (ssize_t)sizeof(buf));
+		if (n == 0)
+			atomic_fetch_add(&eofs, 1);
+		for (ssize_t i = 0; i < n; i++)
+			if (buf[i] != 'R')
+				FAIL("byte %ld of a read at %ld is %d",
+				     (long)i, (long)off, buf[i]);
+		atomic_fetch_add(&reads, 1);
+	}
+	return NULL;
+}
+
+int main(void)
+{
+	pthread_t threads[READERS + 2];
+
+	fd = open("race.dat", O_RDWR | O_CREAT | O_TRUNC | O_APPEND, 0644);
+	CHECK(fd >= 0);
+	CHECK(pthread_create(&threads[0], NULL, appender, NULL) == 0);
+	CHECK(pthread_create(&threads[1], NULL, truncater, NULL) == 0);
+	for (int i = 0; i < READERS; i++)
+		CHECK(pthread_create(&threads[2 + i], NULL, reader,
+				     (void *)(intptr_t)(i + 1)) == 0);
+	sleep_ms(3000);
+	atomic_store(&stop, 1);
+	for (int i = 0; i < READERS + 2; i++)
+		pthread_join(threads[i], NULL);
+	printf("%ld reads, %ld at EOF\n", (long)reads, (long)eofs);
+	CHECK(reads > 0);
+	close(fd);
+	unlink("race.dat");
+	return 0;
+}